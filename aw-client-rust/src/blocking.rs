@@ -1,11 +1,13 @@
+use std::collections::VecDeque;
 use std::future::Future;
 use std::{collections::HashMap, error::Error};
 
 use chrono::{DateTime, Utc};
 
-use aw_models::{Bucket, Event};
+use aw_models::{BatchResult, Bucket, Event};
 
 use super::AwClient as AsyncAwClient;
+use super::Batch as AsyncBatch;
 
 pub struct AwClient {
     client: AsyncAwClient,
@@ -16,7 +18,7 @@ pub struct AwClient {
 
 impl std::fmt::Debug for AwClient {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "AwClient(baseurl={:?})", self.client.baseurl)
+        write!(f, "{:?}", self.client)
     }
 }
 
@@ -38,8 +40,15 @@ macro_rules! proxy_method
 
 impl AwClient {
     pub fn new(host: &str, port: u16, name: &str) -> Result<AwClient, Box<dyn Error>> {
-        let async_client = AsyncAwClient::new(host, port, name)?;
+        AwClient::from_async(AsyncAwClient::new(host, port, name)?)
+    }
+
+    /// See `AwClient::from_baseurl` of the async client
+    pub fn from_baseurl(baseurl: &str, name: &str) -> Result<AwClient, Box<dyn Error>> {
+        AwClient::from_async(AsyncAwClient::from_baseurl(baseurl, name)?)
+    }
 
+    fn from_async(async_client: AsyncAwClient) -> Result<AwClient, Box<dyn Error>> {
         Ok(AwClient {
             baseurl: async_client.baseurl.clone(),
             name: async_client.name.clone(),
@@ -61,6 +70,16 @@ impl AwClient {
         stop: Option<DateTime<Utc>>,
         limit: Option<u64>
     );
+    proxy_method!(
+        get_events_page,
+        (Vec<Event>, Option<String>),
+        bucketname: &str,
+        start: Option<DateTime<Utc>>,
+        stop: Option<DateTime<Utc>>,
+        limit: u64,
+        ascending: bool,
+        cursor: Option<String>
+    );
     proxy_method!(
         query,
         Vec<serde_json::Value>,
@@ -79,4 +98,124 @@ impl AwClient {
     proxy_method!(delete_event, (), bucketname: &str, event_id: i64);
     proxy_method!(get_event_count, i64, bucketname: &str);
     proxy_method!(get_info, aw_models::Info,);
+
+    /// See `AwClient::batch` of the async client
+    pub fn batch(&self) -> Batch<'_> {
+        Batch {
+            batch: self.client.batch(),
+        }
+    }
+
+    /// Iterate over all events in a bucket, fetching them page_size at a time
+    pub fn iter_events<'a>(
+        &'a self,
+        bucketname: &'a str,
+        start: Option<DateTime<Utc>>,
+        stop: Option<DateTime<Utc>>,
+        page_size: u64,
+        ascending: bool,
+    ) -> EventIter<'a> {
+        EventIter {
+            client: self,
+            bucketname,
+            start,
+            stop,
+            page_size,
+            ascending,
+            page: VecDeque::new(),
+            cursor: None,
+            done: false,
+        }
+    }
+}
+
+/// See `Batch` of the async client
+pub struct Batch<'a> {
+    batch: AsyncBatch<'a>,
+}
+
+impl Batch<'_> {
+    pub fn create_bucket(self, bucket: &Bucket) -> Self {
+        Batch {
+            batch: self.batch.create_bucket(bucket),
+        }
+    }
+
+    pub fn create_bucket_simple(self, bucketname: &str, buckettype: &str) -> Self {
+        Batch {
+            batch: self.batch.create_bucket_simple(bucketname, buckettype),
+        }
+    }
+
+    pub fn heartbeat(self, bucketname: &str, event: &Event, pulsetime: f64) -> Self {
+        Batch {
+            batch: self.batch.heartbeat(bucketname, event, pulsetime),
+        }
+    }
+
+    pub fn insert_events(self, bucketname: &str, events: Vec<Event>) -> Self {
+        Batch {
+            batch: self.batch.insert_events(bucketname, events),
+        }
+    }
+
+    pub fn set_setting(self, key: &str, value: serde_json::Value) -> Self {
+        Batch {
+            batch: self.batch.set_setting(key, value),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.batch.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.batch.is_empty()
+    }
+
+    pub fn send(self) -> Result<Vec<BatchResult>, reqwest::Error> {
+        block_on(self.batch.send())
+    }
+}
+
+/// Iterator over all events in a bucket, see AwClient::iter_events
+pub struct EventIter<'a> {
+    client: &'a AwClient,
+    bucketname: &'a str,
+    start: Option<DateTime<Utc>>,
+    stop: Option<DateTime<Utc>>,
+    page_size: u64,
+    ascending: bool,
+    page: VecDeque<Event>,
+    cursor: Option<String>,
+    done: bool,
+}
+
+impl<'a> Iterator for EventIter<'a> {
+    type Item = Result<Event, reqwest::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.page.is_empty() && !self.done {
+            let res = self.client.get_events_page(
+                self.bucketname,
+                self.start,
+                self.stop,
+                self.page_size,
+                self.ascending,
+                self.cursor.take(),
+            );
+            match res {
+                Ok((events, next_cursor)) => {
+                    self.page.extend(events);
+                    self.done = next_cursor.is_none();
+                    self.cursor = next_cursor;
+                }
+                Err(err) => {
+                    self.done = true;
+                    return Some(Err(err));
+                }
+            }
+        }
+        self.page.pop_front().map(Ok)
+    }
 }
@@ -6,37 +6,62 @@ extern crate serde_json;
 extern crate tokio;
 
 pub mod blocking;
+#[cfg(unix)]
+mod unix;
 
+use std::path::PathBuf;
 use std::{collections::HashMap, error::Error};
 
 use chrono::{DateTime, Utc};
 use serde_json::{json, Map};
 
-pub use aw_models::{Bucket, BucketMetadata, Event};
+pub use aw_models::{BatchOperation, BatchResult, Bucket, BucketMetadata, Event};
 
 pub struct AwClient {
     client: reqwest::Client,
     pub baseurl: reqwest::Url,
     pub name: String,
     pub hostname: String,
+    socket_path: Option<PathBuf>,
 }
 
 impl std::fmt::Debug for AwClient {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "AwClient(baseurl={:?})", self.baseurl)
+        match &self.socket_path {
+            Some(socket_path) => write!(f, "AwClient(socket={socket_path:?})"),
+            None => write!(f, "AwClient(baseurl={:?})", self.baseurl),
+        }
     }
 }
 
+/// How long a request may take, including reading the response
+const REQUEST_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(120);
+
 fn get_hostname() -> String {
     return gethostname::gethostname().to_string_lossy().to_string();
 }
 
 impl AwClient {
     pub fn new(host: &str, port: u16, name: &str) -> Result<AwClient, Box<dyn Error>> {
-        let baseurl = reqwest::Url::parse(&format!("http://{}:{}", host, port))?;
+        AwClient::from_baseurl(&format!("http://{}:{}", host, port), name)
+    }
+
+    /// Create a client for the server at the given base URL, which is either http://host:port or
+    /// unix:///path/to/aw-server.sock for a server listening on a Unix socket
+    pub fn from_baseurl(baseurl: &str, name: &str) -> Result<AwClient, Box<dyn Error>> {
+        let mut baseurl = reqwest::Url::parse(baseurl)?;
+        let mut socket_path = None;
+        if baseurl.scheme() == "unix" {
+            if cfg!(not(unix)) {
+                return Err("Unix sockets are not supported on this platform".into());
+            }
+            socket_path = Some(PathBuf::from(baseurl.path()));
+            // Requests are still built with a HTTP URL, they are only sent differently
+            baseurl = reqwest::Url::parse("http://localhost")?;
+        }
         let hostname = get_hostname();
         let client = reqwest::Client::builder()
-            .timeout(std::time::Duration::from_secs(120))
+            .timeout(REQUEST_TIMEOUT)
             .build()?;
 
         Ok(AwClient {
@@ -44,15 +69,25 @@ impl AwClient {
             baseurl,
             name: name.to_string(),
             hostname,
+            socket_path,
         })
     }
 
+    async fn send(
+        &self,
+        request: reqwest::RequestBuilder,
+    ) -> Result<reqwest::Response, reqwest::Error> {
+        #[cfg(unix)]
+        if let Some(socket_path) = &self.socket_path {
+            return unix::send(socket_path, request.build()?, REQUEST_TIMEOUT).await;
+        }
+        request.send().await
+    }
+
     pub async fn get_bucket(&self, bucketname: &str) -> Result<Bucket, reqwest::Error> {
         let url = format!("{}/api/0/buckets/{}", self.baseurl, bucketname);
         let bucket = self
-            .client
-            .get(url)
-            .send()
+            .send(self.client.get(url))
             .await?
             .error_for_status()?
             .json()
@@ -62,21 +97,17 @@ impl AwClient {
 
     pub async fn get_buckets(&self) -> Result<HashMap<String, Bucket>, reqwest::Error> {
         let url = format!("{}/api/0/buckets/", self.baseurl);
-        self.client.get(url).send().await?.json().await
+        self.send(self.client.get(url)).await?.json().await
     }
 
     pub async fn create_bucket(&self, bucket: &Bucket) -> Result<(), reqwest::Error> {
         let url = format!("{}/api/0/buckets/{}", self.baseurl, bucket.id);
-        self.client.post(url).json(bucket).send().await?;
+        self.send(self.client.post(url).json(bucket)).await?;
         Ok(())
     }
 
-    pub async fn create_bucket_simple(
-        &self,
-        bucketname: &str,
-        buckettype: &str,
-    ) -> Result<(), reqwest::Error> {
-        let bucket = Bucket {
+    fn simple_bucket(&self, bucketname: &str, buckettype: &str) -> Bucket {
+        Bucket {
             bid: None,
             id: bucketname.to_string(),
             client: self.name.clone(),
@@ -87,13 +118,21 @@ impl AwClient {
             events: None,
             created: None,
             last_updated: None,
-        };
+        }
+    }
+
+    pub async fn create_bucket_simple(
+        &self,
+        bucketname: &str,
+        buckettype: &str,
+    ) -> Result<(), reqwest::Error> {
+        let bucket = self.simple_bucket(bucketname, buckettype);
         self.create_bucket(&bucket).await
     }
 
     pub async fn delete_bucket(&self, bucketname: &str) -> Result<(), reqwest::Error> {
         let url = format!("{}/api/0/buckets/{}", self.baseurl, bucketname);
-        self.client.delete(url).send().await?;
+        self.send(self.client.delete(url)).await?;
         Ok(())
     }
 
@@ -112,16 +151,11 @@ impl AwClient {
             .collect();
 
         // Result is a sequence, one element per timeperiod
-        self.client
-            .post(url)
-            .json(&json!({
-                "query": query.split('\n').collect::<Vec<&str>>(),
-                "timeperiods": timeperiods_str,
-            }))
-            .send()
-            .await?
-            .json()
-            .await
+        let request = self.client.post(url).json(&json!({
+            "query": query.split('\n').collect::<Vec<&str>>(),
+            "timeperiods": timeperiods_str,
+        }));
+        self.send(request).await?.json().await
     }
 
     pub async fn get_events(
@@ -149,7 +183,49 @@ impl AwClient {
             url.query_pairs_mut()
                 .append_pair("limit", s.to_string().as_str());
         };
-        self.client.get(url).send().await?.json().await
+        self.send(self.client.get(url)).await?.json().await
+    }
+
+    /// Get a single page of events
+    ///
+    /// Returns the events together with the cursor of the next page, which is None on the last
+    /// page. Use `blocking::AwClient::iter_events` to walk through all pages.
+    pub async fn get_events_page(
+        &self,
+        bucketname: &str,
+        start: Option<DateTime<Utc>>,
+        stop: Option<DateTime<Utc>>,
+        limit: u64,
+        ascending: bool,
+        cursor: Option<String>,
+    ) -> Result<(Vec<Event>, Option<String>), reqwest::Error> {
+        let mut url = reqwest::Url::parse(
+            format!("{}/api/0/buckets/{}/events", self.baseurl, bucketname).as_str(),
+        )
+        .unwrap();
+
+        if let Some(s) = start {
+            url.query_pairs_mut()
+                .append_pair("start", s.to_rfc3339().as_str());
+        };
+        if let Some(s) = stop {
+            url.query_pairs_mut()
+                .append_pair("end", s.to_rfc3339().as_str());
+        };
+        url.query_pairs_mut()
+            .append_pair("limit", limit.to_string().as_str())
+            .append_pair("order", if ascending { "asc" } else { "desc" });
+        if let Some(c) = cursor {
+            url.query_pairs_mut().append_pair("cursor", c.as_str());
+        };
+        let res = self.send(self.client.get(url)).await?.error_for_status()?;
+        let next_cursor = res
+            .headers()
+            .get("X-Next-Cursor")
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.to_string());
+        let events = res.json().await?;
+        Ok((events, next_cursor))
     }
 
     pub async fn insert_event(
@@ -159,7 +235,7 @@ impl AwClient {
     ) -> Result<(), reqwest::Error> {
         let url = format!("{}/api/0/buckets/{}/events", self.baseurl, bucketname);
         let eventlist = vec![event.clone()];
-        self.client.post(url).json(&eventlist).send().await?;
+        self.send(self.client.post(url).json(&eventlist)).await?;
         Ok(())
     }
 
@@ -169,7 +245,7 @@ impl AwClient {
         events: Vec<Event>,
     ) -> Result<(), reqwest::Error> {
         let url = format!("{}/api/0/buckets/{}/events", self.baseurl, bucketname);
-        self.client.post(url).json(&events).send().await?;
+        self.send(self.client.post(url).json(&events)).await?;
         Ok(())
     }
 
@@ -183,7 +259,7 @@ impl AwClient {
             "{}/api/0/buckets/{}/heartbeat?pulsetime={}",
             self.baseurl, bucketname, pulsetime
         );
-        self.client.post(url).json(&event).send().await?;
+        self.send(self.client.post(url).json(&event)).await?;
         Ok(())
     }
 
@@ -196,16 +272,14 @@ impl AwClient {
             "{}/api/0/buckets/{}/events/{}",
             self.baseurl, bucketname, event_id
         );
-        self.client.delete(url).send().await?;
+        self.send(self.client.delete(url)).await?;
         Ok(())
     }
 
     pub async fn get_event_count(&self, bucketname: &str) -> Result<i64, reqwest::Error> {
         let url = format!("{}/api/0/buckets/{}/events/count", self.baseurl, bucketname);
         let res = self
-            .client
-            .get(url)
-            .send()
+            .send(self.client.get(url))
             .await?
             .error_for_status()?
             .text()
@@ -219,6 +293,82 @@ impl AwClient {
 
     pub async fn get_info(&self) -> Result<aw_models::Info, reqwest::Error> {
         let url = format!("{}/api/0/info", self.baseurl);
-        self.client.get(url).send().await?.json().await
+        self.send(self.client.get(url)).await?.json().await
+    }
+
+    /// Start a batch of operations which are sent to the server in a single request
+    pub fn batch(&self) -> Batch<'_> {
+        Batch {
+            client: self,
+            operations: Vec::new(),
+        }
+    }
+}
+
+/// Operations which are sent in a single request and committed to the datastore together
+///
+/// The operations are done in order, each of them succeeds or fails on its own. `send` returns
+/// the result of every operation.
+pub struct Batch<'a> {
+    client: &'a AwClient,
+    operations: Vec<BatchOperation>,
+}
+
+impl Batch<'_> {
+    /// Create the bucket unless it already exists
+    pub fn create_bucket(mut self, bucket: &Bucket) -> Self {
+        self.operations.push(BatchOperation::CreateBucket {
+            bucket: bucket.clone(),
+        });
+        self
+    }
+
+    pub fn create_bucket_simple(self, bucketname: &str, buckettype: &str) -> Self {
+        let bucket = self.client.simple_bucket(bucketname, buckettype);
+        self.create_bucket(&bucket)
+    }
+
+    pub fn heartbeat(mut self, bucketname: &str, event: &Event, pulsetime: f64) -> Self {
+        self.operations.push(BatchOperation::Heartbeat {
+            bucket_id: bucketname.to_string(),
+            pulsetime,
+            event: event.clone(),
+        });
+        self
+    }
+
+    pub fn insert_events(mut self, bucketname: &str, events: Vec<Event>) -> Self {
+        self.operations.push(BatchOperation::InsertEvents {
+            bucket_id: bucketname.to_string(),
+            events,
+        });
+        self
+    }
+
+    pub fn set_setting(mut self, key: &str, value: serde_json::Value) -> Self {
+        self.operations.push(BatchOperation::SetSetting {
+            key: key.to_string(),
+            value,
+        });
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.operations.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.operations.is_empty()
+    }
+
+    pub async fn send(self) -> Result<Vec<BatchResult>, reqwest::Error> {
+        let url = format!("{}/api/0/batch", self.client.baseurl);
+        let request = self.client.client.post(url).json(&self.operations);
+        self.client
+            .send(request)
+            .await?
+            .error_for_status()?
+            .json()
+            .await
     }
 }
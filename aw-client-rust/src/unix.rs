@@ -0,0 +1,72 @@
+//! Sends requests to a server listening on a Unix socket, which reqwest can't do on its own
+
+use std::error::Error;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+use hyper::header::{HeaderValue, HOST};
+use tokio::net::UnixStream;
+
+type BoxError = Box<dyn Error + Send + Sync>;
+
+/// Send the request, failing if there's no response within the timeout of the request or else
+/// within timeout, like reqwest does for the timeout of its client
+pub async fn send(
+    socket_path: &Path,
+    request: reqwest::Request,
+    timeout: Duration,
+) -> Result<reqwest::Response, reqwest::Error> {
+    let timeout = request.timeout().copied().unwrap_or(timeout);
+    let response = match tokio::time::timeout(timeout, send_request(socket_path, request)).await {
+        Ok(response) => response,
+        Err(elapsed) => Err(io::Error::new(io::ErrorKind::TimedOut, elapsed).into()),
+    };
+    match response {
+        Ok(response) => Ok(response.into()),
+        Err(err) => Err(connection_error(err).await),
+    }
+}
+
+async fn send_request(
+    socket_path: &Path,
+    request: reqwest::Request,
+) -> Result<hyper::Response<hyper::Body>, BoxError> {
+    let stream = UnixStream::connect(socket_path).await?;
+    let (mut sender, connection) = hyper::client::conn::handshake(stream).await?;
+    // The connection is driven until the response body has been read
+    tokio::spawn(connection);
+
+    let uri = match request.url().query() {
+        Some(query) => format!("{}?{}", request.url().path(), query),
+        None => request.url().path().to_string(),
+    };
+    let body = request
+        .body()
+        .and_then(|body| body.as_bytes())
+        .map(|body| body.to_vec())
+        .unwrap_or_default();
+    let mut hyper_request = hyper::Request::builder()
+        .method(request.method().clone())
+        .uri(uri)
+        .body(hyper::Body::from(body))?;
+    *hyper_request.headers_mut() = request.headers().clone();
+    hyper_request
+        .headers_mut()
+        .insert(HOST, HeaderValue::from_static("localhost"));
+
+    Ok(sender.send_request(hyper_request).await?)
+}
+
+/// reqwest::Error can't be constructed outside of reqwest, so failing to talk to the server is
+/// reported as the error of a response body which fails with the underlying error
+async fn connection_error(err: BoxError) -> reqwest::Error {
+    let body = hyper::Body::wrap_stream(futures_util::stream::once(async move {
+        Err::<Vec<u8>, BoxError>(err)
+    }));
+    let response: reqwest::Response = hyper::Response::new(body).into();
+    response
+        .bytes()
+        .await
+        .expect_err("a failing body must fail to be read")
+}
@@ -42,6 +42,8 @@ mod test {
 
         let state = ServerState {
             datastore: Mutex::new(aw_datastore::Datastore::new_in_memory(false)),
+            profiles: aw_server::profiles::Profiles::in_memory(),
+            query_cache: aw_server::query_cache::QueryCache::new(),
             asset_resolver: AssetResolver::new(None),
             device_id: "test_id".to_string(),
         };
@@ -110,6 +112,13 @@ mod test {
         println!("Events: {events:?}");
         assert!(events[0].duration == Duration::seconds(1));
 
+        // Walk through all events page by page
+        let all_events: Vec<Event> = client
+            .iter_events(&bucketname, None, None, 1, true)
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(all_events.len(), events.len());
+
         // Query
         let query = format!(
             "events = query_bucket(\"{}\");
@@ -135,6 +144,127 @@ RETURN = events;",
 
         client.delete_bucket(&bucketname).unwrap();
 
+        // Batch
+        let batch_bucketname = format!("aw-client-rust-test-batch_{}", client.hostname);
+        let heartbeat = event.clone();
+        let mut next_heartbeat = event.clone();
+        next_heartbeat.timestamp = heartbeat.timestamp + Duration::seconds(2);
+        let results = client
+            .batch()
+            .create_bucket_simple(&batch_bucketname, buckettype)
+            .create_bucket_simple(&batch_bucketname, buckettype)
+            .heartbeat(&batch_bucketname, &heartbeat, 10.0)
+            .heartbeat(&batch_bucketname, &next_heartbeat, 10.0)
+            .heartbeat("nonexistent", &heartbeat, 10.0)
+            .set_setting("batch", serde_json::json!(true))
+            .send()
+            .unwrap();
+        let statuses: Vec<u16> = results.iter().map(|result| result.status).collect();
+        assert_eq!(statuses, vec![200, 304, 200, 200, 404, 200]);
+        assert!(results[4].error.is_some());
+        let merged = &results[3].events.as_ref().unwrap()[0];
+        assert_eq!(merged.duration, Duration::seconds(2));
+        let events = client
+            .get_events(&batch_bucketname, None, None, None)
+            .unwrap();
+        assert_eq!(events.len(), 1);
+        client.delete_bucket(&batch_bucketname).unwrap();
+
         shutdown_handler.notify();
     }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unix_socket() {
+        use aw_server::endpoints::{AssetResolver, ServerState};
+        use aw_server::unix_socket::UnixSocketServer;
+        use rocket::data::ToByteUnit;
+        use rocket::tokio::sync::oneshot;
+        use std::os::unix::fs::PermissionsExt;
+
+        let socket_path =
+            std::env::temp_dir().join(format!("aw-client-rust-test-{}.sock", std::process::id()));
+        let client = AwClient::from_baseurl(
+            &format!("unix://{}", socket_path.display()),
+            "aw-client-rust-test",
+        )
+        .expect("Client creation failed");
+
+        // Nothing is listening yet
+        match client.get_info() {
+            Ok(_) => panic!("Got info without a server"),
+            Err(err) => assert!(err.status().is_none()),
+        }
+
+        let state = ServerState {
+            datastore: Mutex::new(aw_datastore::Datastore::new_in_memory(false)),
+            profiles: aw_server::profiles::Profiles::in_memory(),
+            query_cache: aw_server::query_cache::QueryCache::new(),
+            asset_resolver: AssetResolver::new(None),
+            device_id: "test_id".to_string(),
+        };
+        let server =
+            aw_server::endpoints::build_rocket(state, aw_server::config::AWConfig::default());
+        // Request bodies are read in full, but only up to the limit
+        let limits = rocket::data::Limits::default().limit("json", 64.kibibytes());
+        let figment = server.figment().clone().merge(("limits", limits));
+        let server = server.configure(figment);
+        let (shutdown_sender, shutdown_receiver) = oneshot::channel::<()>();
+        let server_path = socket_path.clone();
+        let server_thread = thread::spawn(move || {
+            block_on(async move {
+                let server = UnixSocketServer::bind(server, &server_path).await.unwrap();
+                server
+                    .serve(async move {
+                        let _ = shutdown_receiver.await;
+                    })
+                    .await
+            })
+        });
+        wait_for_server(20, &client);
+        let mode = std::fs::metadata(&socket_path)
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let bucketname = "aw-client-rust-test-unix";
+        client
+            .create_bucket_simple(bucketname, "test-type")
+            .unwrap();
+        let event = Event {
+            id: None,
+            timestamp: DateTime::parse_from_rfc3339("2017-12-30T01:00:00+00:00")
+                .unwrap()
+                .into(),
+            duration: Duration::seconds(1),
+            data: Map::new(),
+        };
+        client.insert_event(bucketname, &event).unwrap();
+        let events = client.get_events(bucketname, None, None, None).unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].duration, Duration::seconds(1));
+
+        // Errors of the server come through like over TCP
+        let err = client.get_bucket("no-such-bucket").unwrap_err();
+        assert_eq!(err.status(), Some(reqwest::StatusCode::NOT_FOUND));
+
+        let many_events = |count: usize| vec![event.clone(); count];
+        let err = client
+            .batch()
+            .insert_events(bucketname, many_events(2000))
+            .send()
+            .unwrap_err();
+        assert_eq!(err.status(), Some(reqwest::StatusCode::PAYLOAD_TOO_LARGE));
+        // Responses larger than a chunk are streamed in several
+        for _ in 0..5 {
+            client.insert_events(bucketname, many_events(200)).unwrap();
+        }
+        let events = client.get_events(bucketname, None, None, None).unwrap();
+        assert_eq!(events.len(), 1001);
+
+        shutdown_sender.send(()).unwrap();
+        server_thread.join().unwrap();
+        assert!(!socket_path.exists());
+    }
 }
@@ -8,14 +8,24 @@ use rusqlite::Connection;
 
 use serde_json::value::Value;
 
+use aw_models::AuditEntry;
 use aw_models::Bucket;
+use aw_models::BucketImportResult;
 use aw_models::BucketMetadata;
 use aw_models::Event;
+use aw_models::ExportRecord;
+use aw_models::ImportAction;
+use aw_models::ImportConflict;
+use aw_models::ImportSummary;
 
 use rusqlite::params;
 use rusqlite::types::ToSql;
 
 use super::DatastoreError;
+use super::EventCursor;
+
+/// Number of imported events inserted at a time
+pub const IMPORT_BATCH_SIZE: usize = 1000;
 
 fn _get_db_version(conn: &Connection) -> i32 {
     conn.pragma_query_value(None, "user_version", |row| row.get(0))
@@ -29,8 +39,9 @@ fn _get_db_version(conn: &Connection) -> i32 {
  * 2: Added 'data' field to 'buckets' table
  * 3: see: https://github.com/ActivityWatch/aw-server-rust/pull/52
  * 4: Added 'key_value' table for storing key - value pairs
+ * 5: Added 'audit_log' table for recording requests which changed data
  */
-static NEWEST_DB_VERSION: i32 = 4;
+static NEWEST_DB_VERSION: i32 = 5;
 
 fn _create_tables(conn: &Connection, version: i32) -> bool {
     let mut first_init = false;
@@ -52,6 +63,10 @@ fn _create_tables(conn: &Connection, version: i32) -> bool {
         _migrate_v3_to_v4(conn);
     }
 
+    if version < 5 {
+        _migrate_v4_to_v5(conn);
+    }
+
     first_init
 }
 
@@ -169,6 +184,33 @@ fn _migrate_v3_to_v4(conn: &Connection) {
         .expect("Failed to update database version!");
 }
 
+fn _migrate_v4_to_v5(conn: &Connection) {
+    info!("Upgrading database to v5, adding table for the audit log");
+    // AUTOINCREMENT so that ids of pruned entries are never reused
+    conn.execute(
+        "CREATE TABLE audit_log (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        timestamp INTEGER NOT NULL,
+        method TEXT NOT NULL,
+        route TEXT NOT NULL,
+        affected TEXT NOT NULL,
+        status INTEGER NOT NULL,
+        user_agent TEXT,
+        client TEXT
+    );",
+        [],
+    )
+    .expect("Failed to upgrade db and add audit log table");
+    conn.execute(
+        "CREATE INDEX audit_log_timestamp_index ON audit_log(timestamp)",
+        [],
+    )
+    .expect("Failed to upgrade db and add audit log index");
+
+    conn.pragma_update(None, "user_version", 5)
+        .expect("Failed to update database version!");
+}
+
 pub struct DatastoreInstance {
     buckets_cache: HashMap<String, Bucket>,
     first_init: bool,
@@ -705,6 +747,34 @@ impl DatastoreInstance {
         endtime_opt: Option<DateTime<Utc>>,
         limit_opt: Option<u64>,
     ) -> Result<Vec<Event>, DatastoreError> {
+        let (events, _next) = self.get_events_page(
+            conn,
+            bucket_id,
+            starttime_opt,
+            endtime_opt,
+            limit_opt,
+            false,
+            None,
+        )?;
+        Ok(events)
+    }
+
+    /// Like get_events, but ordered by (starttime, id) in the given direction and starting after
+    /// the cursor if one is given.
+    ///
+    /// Also returns a cursor pointing at the last returned event if the page was full, which can
+    /// be used to fetch the next page.
+    #[allow(clippy::too_many_arguments)]
+    pub fn get_events_page(
+        &mut self,
+        conn: &Connection,
+        bucket_id: &str,
+        starttime_opt: Option<DateTime<Utc>>,
+        endtime_opt: Option<DateTime<Utc>>,
+        limit_opt: Option<u64>,
+        ascending: bool,
+        cursor_opt: Option<EventCursor>,
+    ) -> Result<(Vec<Event>, Option<EventCursor>), DatastoreError> {
         let bucket = self.get_bucket(bucket_id)?;
 
         let mut list = Vec::new();
@@ -715,28 +785,40 @@ impl DatastoreInstance {
         };
         let endtime_filter_ns: i64 = match endtime_opt {
             Some(dt) => dt.timestamp_nanos_opt().unwrap(),
-            None => std::i64::MAX,
+            None => i64::MAX,
         };
         if starttime_filter_ns > endtime_filter_ns {
             warn!("Starttime in event query was lower than endtime!");
-            return Ok(list);
+            return Ok((list, None));
         }
         let limit = match limit_opt {
             Some(l) => l as i64,
             None => -1,
         };
+        // Without a cursor, start from the very first/last row
+        let cursor = match cursor_opt {
+            Some(cursor) => cursor,
+            None if ascending => EventCursor::new(i64::MIN, i64::MIN),
+            None => EventCursor::new(i64::MAX, i64::MIN),
+        };
+        // Events with the same starttime are always ordered by ascending id
+        let (cmp, order) = match ascending {
+            true => (">", "ASC"),
+            false => ("<", "DESC"),
+        };
 
-        let mut stmt = match conn.prepare(
+        let mut stmt = match conn.prepare(&format!(
             "
                 SELECT id, starttime, endtime, data
                 FROM events
                 WHERE bucketrow = ?1
                     AND endtime >= ?2
                     AND starttime <= ?3
-                ORDER BY starttime DESC
+                    AND (starttime {cmp} ?5 OR (starttime = ?5 AND id > ?6))
+                ORDER BY starttime {order}, id ASC
                 LIMIT ?4
-            ;",
-        ) {
+            ;"
+        )) {
             Ok(stmt) => stmt,
             Err(err) => {
                 return Err(DatastoreError::InternalError(format!(
@@ -751,12 +833,16 @@ impl DatastoreInstance {
                 &starttime_filter_ns,
                 &endtime_filter_ns,
                 &limit,
+                &cursor.starttime_ns,
+                &cursor.id,
             ],
             |row| {
                 let id = row.get(0)?;
                 let mut starttime_ns: i64 = row.get(1)?;
                 let mut endtime_ns: i64 = row.get(2)?;
                 let data_str: String = row.get(3)?;
+                // The cursor refers to the stored starttime, not the clamped one
+                let row_cursor = EventCursor::new(starttime_ns, id);
 
                 if starttime_ns < starttime_filter_ns {
                     starttime_ns = starttime_filter_ns
@@ -771,12 +857,15 @@ impl DatastoreInstance {
                 let data: serde_json::map::Map<String, Value> =
                     serde_json::from_str(&data_str).unwrap();
 
-                Ok(Event {
-                    id: Some(id),
-                    timestamp: DateTime::from_timestamp(time_seconds, time_subnanos).unwrap(),
-                    duration: Duration::nanoseconds(duration_ns),
-                    data,
-                })
+                Ok((
+                    Event {
+                        id: Some(id),
+                        timestamp: DateTime::from_timestamp(time_seconds, time_subnanos).unwrap(),
+                        duration: Duration::nanoseconds(duration_ns),
+                        data,
+                    },
+                    row_cursor,
+                ))
             },
         ) {
             Ok(rows) => rows,
@@ -786,14 +875,24 @@ impl DatastoreInstance {
                 )))
             }
         };
+        let mut last_cursor = None;
         for row in rows {
             match row {
-                Ok(event) => list.push(event),
+                Ok((event, row_cursor)) => {
+                    list.push(event);
+                    last_cursor = Some(row_cursor);
+                }
                 Err(err) => warn!("Corrupt event in bucket {}: {}", bucket_id, err),
             };
         }
 
-        Ok(list)
+        // Only hand out a cursor if there might be more events to fetch
+        let next_cursor = match limit_opt {
+            Some(l) if list.len() as u64 >= l => last_cursor,
+            _ => None,
+        };
+
+        Ok((list, next_cursor))
     }
 
     pub fn get_event_count(
@@ -852,6 +951,245 @@ impl DatastoreInstance {
         Ok(count)
     }
 
+    /// Import buckets and their events, resolving conflicts with existing buckets according to
+    /// on_conflict
+    ///
+    /// Each bucket record is followed by the event records of that bucket, events can also be
+    /// included in the bucket itself. The records are imported one chunk at a time as they arrive,
+    /// a chunk which is an error fails the import. The whole import is done within a savepoint
+    /// which is rolled back if anything fails, or if it's a dry run.
+    ///
+    /// If a bucket already exists and on_conflict is fail, the import fails with ImportConflict
+    /// and a summary of the import up to and including that bucket.
+    pub fn import<I>(
+        &mut self,
+        conn: &Connection,
+        records: I,
+        on_conflict: ImportConflict,
+        dry_run: bool,
+    ) -> Result<ImportSummary, DatastoreError>
+    where
+        I: IntoIterator<Item = Result<Vec<ExportRecord>, DatastoreError>>,
+    {
+        if let Err(err) = conn.execute_batch("SAVEPOINT import") {
+            return Err(DatastoreError::InternalError(format!(
+                "Failed to start import: {err}"
+            )));
+        }
+        let mut buckets = Vec::new();
+        let res = self.import_records(conn, records, on_conflict, &mut buckets);
+        let end_sql = match (&res, dry_run) {
+            (Ok(_), false) => "RELEASE import",
+            _ => "ROLLBACK TO import; RELEASE import",
+        };
+        if let Err(err) = conn.execute_batch(end_sql) {
+            return Err(DatastoreError::InternalError(format!(
+                "Failed to finish import: {err}"
+            )));
+        }
+        if res.is_err() || dry_run {
+            // The cache has to match the rolled back database again
+            self.buckets_cache.clear();
+            self.get_stored_buckets(conn)?;
+        }
+        match res {
+            Ok(()) => Ok(ImportSummary { dry_run, buckets }),
+            Err(DatastoreError::BucketAlreadyExists(bucket_id))
+                if on_conflict == ImportConflict::Fail =>
+            {
+                buckets.push(BucketImportResult {
+                    bucket_id,
+                    action: ImportAction::Failed,
+                    imported_as: None,
+                    events_imported: 0,
+                    events_skipped: 0,
+                });
+                Err(DatastoreError::ImportConflict(ImportSummary {
+                    dry_run,
+                    buckets,
+                }))
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    fn import_records<I>(
+        &mut self,
+        conn: &Connection,
+        records: I,
+        on_conflict: ImportConflict,
+        results: &mut Vec<BucketImportResult>,
+    ) -> Result<(), DatastoreError>
+    where
+        I: IntoIterator<Item = Result<Vec<ExportRecord>, DatastoreError>>,
+    {
+        let mut events: Vec<Event> = Vec::new();
+        for chunk in records {
+            for record in chunk? {
+                match record {
+                    ExportRecord::Bucket(mut bucket) => {
+                        if let Some(result) = results.last_mut() {
+                            self.import_events(conn, result, &mut events)?;
+                        }
+                        if let Some(bucket_events) = bucket.events.take() {
+                            events = bucket_events.take_inner();
+                        }
+                        results.push(self.import_bucket(conn, bucket, on_conflict)?);
+                    }
+                    ExportRecord::Event(event) => {
+                        if results.is_empty() {
+                            return Err(DatastoreError::InternalError(
+                                "Imported event does not follow a bucket".to_string(),
+                            ));
+                        }
+                        events.push(event);
+                    }
+                }
+                if events.len() >= IMPORT_BATCH_SIZE {
+                    self.import_events(conn, results.last_mut().unwrap(), &mut events)?;
+                }
+            }
+        }
+        if let Some(result) = results.last_mut() {
+            self.import_events(conn, result, &mut events)?;
+        }
+        Ok(())
+    }
+
+    fn import_bucket(
+        &mut self,
+        conn: &Connection,
+        mut bucket: Bucket,
+        on_conflict: ImportConflict,
+    ) -> Result<BucketImportResult, DatastoreError> {
+        let bucket_id = bucket.id.clone();
+        let action = match (self.buckets_cache.contains_key(&bucket_id), on_conflict) {
+            (false, _) => ImportAction::Created,
+            (true, ImportConflict::Fail) => {
+                return Err(DatastoreError::BucketAlreadyExists(bucket_id))
+            }
+            (true, ImportConflict::Skip) => ImportAction::Skipped,
+            (true, ImportConflict::Merge) => ImportAction::Merged,
+            (true, ImportConflict::Rename) => ImportAction::Renamed,
+        };
+        let imported_as = match action {
+            ImportAction::Skipped | ImportAction::Failed => None,
+            ImportAction::Merged => Some(bucket_id.clone()),
+            ImportAction::Created | ImportAction::Renamed => {
+                if action == ImportAction::Renamed {
+                    bucket.id = self.unused_bucket_id(&bucket_id);
+                }
+                let new_id = bucket.id.clone();
+                self.create_bucket(conn, bucket)?;
+                Some(new_id)
+            }
+        };
+        Ok(BucketImportResult {
+            bucket_id,
+            action,
+            imported_as,
+            events_imported: 0,
+            events_skipped: 0,
+        })
+    }
+
+    fn unused_bucket_id(&self, bucket_id: &str) -> String {
+        let mut new_id = format!("{bucket_id}-imported");
+        let mut n = 1;
+        while self.buckets_cache.contains_key(&new_id) {
+            n += 1;
+            new_id = format!("{bucket_id}-imported-{n}");
+        }
+        new_id
+    }
+
+    fn import_events(
+        &mut self,
+        conn: &Connection,
+        result: &mut BucketImportResult,
+        events: &mut Vec<Event>,
+    ) -> Result<(), DatastoreError> {
+        let mut events = std::mem::take(events);
+        let bucket_id = match &result.imported_as {
+            Some(bucket_id) => bucket_id.clone(),
+            // The bucket was skipped
+            None => {
+                result.events_skipped += events.len() as u64;
+                return Ok(());
+            }
+        };
+        // The ids are from another datastore, and since ids are shared by all buckets they would
+        // replace unrelated events with the same ids, even in a newly created bucket
+        for event in events.iter_mut() {
+            event.id = None;
+        }
+        if result.action == ImportAction::Merged {
+            // Inserted one at a time so that duplicates within the import are found as well
+            for event in events {
+                if self.event_exists(conn, &bucket_id, &event)? {
+                    result.events_skipped += 1;
+                } else {
+                    self.insert_events(conn, &bucket_id, vec![event])?;
+                    result.events_imported += 1;
+                }
+            }
+            return Ok(());
+        }
+        let inserted = self.insert_events(conn, &bucket_id, events)?;
+        result.events_imported += inserted.len() as u64;
+        Ok(())
+    }
+
+    /// Check if the bucket has an event with the same timestamp, duration and data
+    fn event_exists(
+        &self,
+        conn: &Connection,
+        bucket_id: &str,
+        event: &Event,
+    ) -> Result<bool, DatastoreError> {
+        let bucket = self.get_bucket(bucket_id)?;
+        let starttime_nanos = event.timestamp.timestamp_nanos_opt().unwrap();
+        let endtime_nanos = starttime_nanos + event.duration.num_nanoseconds().unwrap();
+
+        let mut stmt = match conn.prepare_cached(
+            "
+            SELECT data FROM events
+            WHERE bucketrow = ?1
+                AND starttime = ?2
+                AND endtime = ?3",
+        ) {
+            Ok(stmt) => stmt,
+            Err(err) => {
+                return Err(DatastoreError::InternalError(format!(
+                    "Failed to prepare event_exists SQL statement: {err}"
+                )))
+            }
+        };
+        let rows = match stmt.query_map(
+            [&bucket.bid.unwrap(), &starttime_nanos, &endtime_nanos],
+            |row| row.get::<_, String>(0),
+        ) {
+            Ok(rows) => rows,
+            Err(err) => {
+                return Err(DatastoreError::InternalError(format!(
+                    "Failed to query event_exists SQL statement: {err}"
+                )))
+            }
+        };
+        for data_str in rows {
+            let data_str = match data_str {
+                Ok(data_str) => data_str,
+                Err(err) => return Err(DatastoreError::InternalError(err.to_string())),
+            };
+            let data: serde_json::map::Map<String, Value> =
+                serde_json::from_str(&data_str).unwrap();
+            if data == event.data {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
     pub fn insert_key_value(
         &self,
         conn: &Connection,
@@ -923,6 +1261,7 @@ impl DatastoreInstance {
             }
         };
 
+        let prefix = pattern.split(['%', '_']).next().unwrap_or("");
         let mut output = HashMap::<String, String>::new();
         // Rusqlite's get wants index and item type as parameters.
         let result = stmt.query_map([pattern], |row| {
@@ -934,8 +1273,9 @@ impl DatastoreInstance {
                     // Unwrap to String or panic on SQL row if type is invalid. Can't happen with a
                     // properly initialized table.
                     let (key, value) = row.unwrap();
-                    // Only return keys starting with "settings.".
-                    if !key.starts_with("settings.") {
+                    // Only return keys starting with the literal prefix of the pattern, LIKE is
+                    // case insensitive.
+                    if !key.starts_with(prefix) {
                         continue;
                     }
                     output.insert(key, value);
@@ -950,4 +1290,108 @@ impl DatastoreInstance {
             },
         }
     }
+
+    pub fn insert_audit_entry(
+        &self,
+        conn: &Connection,
+        entry: &AuditEntry,
+    ) -> Result<i64, DatastoreError> {
+        let timestamp_ns = entry.timestamp.timestamp_nanos_opt().unwrap();
+        let affected = serde_json::to_string(&entry.affected).unwrap();
+        match conn.execute(
+            "
+                INSERT INTO audit_log(timestamp, method, route, affected, status, user_agent, client)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                timestamp_ns,
+                entry.method,
+                entry.route,
+                affected,
+                entry.status,
+                entry.user_agent,
+                entry.client
+            ],
+        ) {
+            Ok(_) => Ok(conn.last_insert_rowid()),
+            Err(err) => Err(DatastoreError::InternalError(format!(
+                "Failed to insert audit log entry: {err}"
+            ))),
+        }
+    }
+
+    /// Audit log entries recorded at or after since, oldest first
+    pub fn get_audit_entries(
+        &self,
+        conn: &Connection,
+        since_opt: Option<DateTime<Utc>>,
+        limit_opt: Option<u64>,
+    ) -> Result<Vec<AuditEntry>, DatastoreError> {
+        let since_ns: i64 = match since_opt {
+            Some(dt) => dt.timestamp_nanos_opt().unwrap(),
+            None => 0,
+        };
+        let limit = match limit_opt {
+            Some(l) => l as i64,
+            None => -1,
+        };
+        let mut stmt = match conn.prepare(
+            "
+                SELECT id, timestamp, method, route, affected, status, user_agent, client
+                FROM audit_log
+                WHERE timestamp >= ?1
+                ORDER BY id ASC
+                LIMIT ?2",
+        ) {
+            Ok(stmt) => stmt,
+            Err(err) => {
+                return Err(DatastoreError::InternalError(format!(
+                    "Failed to prepare get_audit_entries SQL statement: {err}"
+                )))
+            }
+        };
+        let rows = match stmt.query_map(params![since_ns, limit], |row| {
+            let timestamp_ns: i64 = row.get(1)?;
+            let affected_str: String = row.get(4)?;
+            Ok(AuditEntry {
+                id: Some(row.get(0)?),
+                timestamp: DateTime::from_timestamp_nanos(timestamp_ns),
+                method: row.get(2)?,
+                route: row.get(3)?,
+                affected: serde_json::from_str(&affected_str).unwrap_or_default(),
+                status: row.get(5)?,
+                user_agent: row.get(6)?,
+                client: row.get(7)?,
+            })
+        }) {
+            Ok(rows) => rows,
+            Err(err) => {
+                return Err(DatastoreError::InternalError(format!(
+                    "Failed to map get_audit_entries SQL statement: {err}"
+                )))
+            }
+        };
+        let mut entries = Vec::new();
+        for row in rows {
+            match row {
+                Ok(entry) => entries.push(entry),
+                Err(err) => warn!("Corrupt audit log entry: {}", err),
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Delete the audit log entries recorded before the given time, returns how many there were
+    pub fn delete_audit_entries_before(
+        &self,
+        conn: &Connection,
+        before: DateTime<Utc>,
+    ) -> Result<usize, DatastoreError> {
+        let before_ns = before.timestamp_nanos_opt().unwrap();
+        match conn.execute("DELETE FROM audit_log WHERE timestamp < ?1", [before_ns]) {
+            Ok(count) => Ok(count),
+            Err(err) => Err(DatastoreError::InternalError(format!(
+                "Failed to delete audit log entries: {err}"
+            ))),
+        }
+    }
 }
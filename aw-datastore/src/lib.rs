@@ -15,12 +15,75 @@ macro_rules! json_map {
     }};
 }
 
+use std::fmt;
+use std::str::FromStr;
+
+use aw_models::Bucket;
+use aw_models::Event;
+use aw_models::ImportSummary;
+use aw_models::TimeInterval;
+
 mod datastore;
 mod legacy_import;
 mod worker;
 
 pub use self::datastore::DatastoreInstance;
+pub use self::datastore::IMPORT_BATCH_SIZE;
 pub use self::worker::Datastore;
+pub use self::worker::ImportStream;
+
+/// Position in a bucket's event list, used for paginating through events
+///
+/// Serialized to an opaque string so that API clients can pass it back as is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EventCursor {
+    starttime_ns: i64,
+    id: i64,
+}
+
+impl EventCursor {
+    pub fn new(starttime_ns: i64, id: i64) -> Self {
+        EventCursor { starttime_ns, id }
+    }
+}
+
+impl fmt::Display for EventCursor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:x}-{:x}", self.starttime_ns as u64, self.id as u64)
+    }
+}
+
+impl FromStr for EventCursor {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid cursor '{s}'");
+        let (starttime_str, id_str) = s.split_once('-').ok_or_else(invalid)?;
+        let starttime_ns = u64::from_str_radix(starttime_str, 16).map_err(|_| invalid())?;
+        let id = u64::from_str_radix(id_str, 16).map_err(|_| invalid())?;
+        Ok(EventCursor::new(starttime_ns as i64, id as i64))
+    }
+}
+
+/// A change to a bucket, used to tell if results computed from the bucket are outdated
+#[derive(Debug, Clone)]
+pub struct BucketChange {
+    /// Version of the datastore after the change
+    pub version: u64,
+    pub bucket_id: String,
+    /// Time range of the changed events, None if the whole bucket might have changed
+    pub range: Option<TimeInterval>,
+}
+
+/// A write done as part of Datastore::batch
+#[derive(Debug, Clone)]
+pub enum BatchWrite {
+    /// Create a bucket, fails with BucketAlreadyExists if it already exists
+    CreateBucket(Bucket),
+    Heartbeat(String, Event, f64),
+    InsertEvents(String, Vec<Event>),
+    SetKeyValue(String, String),
+}
 
 #[derive(Debug, Clone)]
 pub enum DatastoreMethod {
@@ -36,6 +99,8 @@ pub enum DatastoreError {
     NoSuchKey(String),
     MpscError,
     InternalError(String),
+    /// An imported bucket already exists and the import was aborted, see DatastoreInstance::import
+    ImportConflict(ImportSummary),
     // Errors specific to when migrate is disabled
     Uninitialized(String),
     OldDbVersion(String),
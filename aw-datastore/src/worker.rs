@@ -1,6 +1,10 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::Instant;
 
 use chrono::DateTime;
 use chrono::Duration;
@@ -11,12 +15,22 @@ use rusqlite::DropBehavior;
 use rusqlite::Transaction;
 use rusqlite::TransactionBehavior;
 
+use aw_models::AuditEntry;
 use aw_models::Bucket;
+use aw_models::DatastoreHealth;
 use aw_models::Event;
+use aw_models::ExportRecord;
+use aw_models::ImportConflict;
+use aw_models::ImportSummary;
+use aw_models::TimeInterval;
 
+use crate::BatchWrite;
+use crate::BucketChange;
 use crate::DatastoreError;
 use crate::DatastoreInstance;
 use crate::DatastoreMethod;
+use crate::EventCursor;
+use crate::IMPORT_BATCH_SIZE;
 
 use mpsc_requests::ResponseReceiver;
 
@@ -26,8 +40,38 @@ type RequestReceiver = mpsc_requests::RequestReceiver<Command, Result<Response,
 #[derive(Clone)]
 pub struct Datastore {
     requester: RequestSender,
+    // Number of requests sent but not yet picked up by the worker
+    backlog: Arc<AtomicUsize>,
+    liveness: Arc<Liveness>,
+    epoch: u64,
 }
 
+/// What the worker is doing, kept up to date by the worker so it can be read without a request
+#[derive(Default)]
+struct Liveness {
+    /// When the worker last made progress on the request it is working on, None while it waits
+    busy_since: Mutex<Option<Instant>>,
+    stopped: AtomicBool,
+}
+
+impl Liveness {
+    fn set_busy(&self, busy: bool) {
+        *self.busy_since.lock().unwrap() = busy.then(Instant::now);
+    }
+}
+
+/// Marks the worker as stopped when its thread ends, also if it panicked
+struct StoppedGuard(Arc<Liveness>);
+
+impl Drop for StoppedGuard {
+    fn drop(&mut self) {
+        self.0.stopped.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Epoch of the next datastore to be opened
+static NEXT_EPOCH: AtomicU64 = AtomicU64::new(0);
+
 impl fmt::Debug for Datastore {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "Datastore()")
@@ -38,7 +82,6 @@ impl fmt::Debug for Datastore {
  * TODO:
  * - Allow read requests to go straight through a read-only db connection instead of requesting the
  * worker thread for better performance?
- * TODO: Add an separate "Import" request which does an import with an transaction
  */
 
 #[allow(clippy::large_enum_variant)]
@@ -49,13 +92,28 @@ pub enum Response {
     BucketMap(HashMap<String, Bucket>),
     Event(Event),
     EventList(Vec<Event>),
+    EventPage(Vec<Event>, Option<EventCursor>),
     Count(i64),
     KeyValue(String),
     KeyValues(HashMap<String, String>),
+    ImportSummary(ImportSummary),
+    Changes(u64, Option<Vec<BucketChange>>),
+    Health(DatastoreHealth),
+    AuditEntries(Vec<AuditEntry>),
+    Batch(Vec<Result<Vec<Event>, DatastoreError>>),
 }
 
+/// Chunks of records sent to an import, None once all of them have been sent
+type ImportReceiver = mpsc::Receiver<Option<Vec<ExportRecord>>>;
+
+/// Number of chunks of an import which can be waiting for the worker
+const IMPORT_QUEUE_SIZE: usize = 4;
+
+/// How long an import waits for its next chunk before it gives up and is rolled back
+const IMPORT_CHUNK_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
 #[allow(clippy::large_enum_variant)]
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 pub enum Command {
     CreateBucket(Bucket),
     DeleteBucket(String),
@@ -70,6 +128,14 @@ pub enum Command {
         Option<DateTime<Utc>>,
         Option<u64>,
     ),
+    GetEventsPage(
+        String,
+        Option<DateTime<Utc>>,
+        Option<DateTime<Utc>>,
+        Option<u64>,
+        bool,
+        Option<EventCursor>,
+    ),
     GetEventCount(String, Option<DateTime<Utc>>, Option<DateTime<Utc>>),
     DeleteEventsById(String, Vec<i64>),
     ForceCommit(),
@@ -77,9 +143,101 @@ pub enum Command {
     GetKeyValue(String),
     SetKeyValue(String, String),
     DeleteKeyValue(String),
+    Import(ImportReceiver, ImportConflict, bool),
+    GetChangesSince(u64),
+    GetHealth(),
+    InsertAuditEntry(AuditEntry),
+    GetAuditEntries(Option<DateTime<Utc>>, Option<u64>),
+    DeleteAuditEntriesBefore(DateTime<Utc>),
+    Batch(Vec<BatchWrite>),
     Close(),
 }
 
+/// The chunks sent to an import, ending with an error if the sender gave up or went quiet
+///
+/// Every chunk received counts as progress, so a long import does not look like a stuck worker.
+fn receive_chunks(
+    receiver: ImportReceiver,
+    liveness: Arc<Liveness>,
+) -> impl Iterator<Item = Result<Vec<ExportRecord>, DatastoreError>> {
+    std::iter::from_fn(move || match receiver.recv_timeout(IMPORT_CHUNK_TIMEOUT) {
+        Ok(Some(records)) => {
+            liveness.set_busy(true);
+            Some(Ok(records))
+        }
+        Ok(None) => None,
+        Err(RecvTimeoutError::Timeout) => Some(Err(DatastoreError::InternalError(
+            "Timed out waiting for records to import".to_string(),
+        ))),
+        Err(RecvTimeoutError::Disconnected) => Some(Err(DatastoreError::InternalError(
+            "Import was aborted".to_string(),
+        ))),
+    })
+}
+
+/// An import whose records are passed to the datastore while they are still being read
+///
+/// The import is rolled back if the stream is dropped before it is finished. The datastore does
+/// nothing else until the import is finished, so records should be sent without delay.
+pub struct ImportStream {
+    sender: Option<mpsc::SyncSender<Option<Vec<ExportRecord>>>>,
+    receiver: Option<ResponseReceiver<Result<Response, DatastoreError>>>,
+}
+
+impl ImportStream {
+    /// Pass records on to the import, waiting while the datastore is behind
+    ///
+    /// Fails with the error of the import if it has already failed.
+    pub fn send(&mut self, records: Vec<ExportRecord>) -> Result<(), DatastoreError> {
+        let sent = match &self.sender {
+            Some(sender) => sender.send(Some(records)).is_ok(),
+            None => false,
+        };
+        match sent {
+            true => Ok(()),
+            // The worker only stops receiving once the import has failed
+            false => self.collect().map(|_| ()),
+        }
+    }
+
+    /// Wait for all records to be imported
+    pub fn finish(mut self) -> Result<ImportSummary, DatastoreError> {
+        if let Some(sender) = self.sender.take() {
+            // If the import has already failed its error is collected below
+            let _ = sender.send(None);
+        }
+        self.collect()
+    }
+
+    fn collect(&mut self) -> Result<ImportSummary, DatastoreError> {
+        self.sender = None;
+        let receiver = match self.receiver.take() {
+            Some(receiver) => receiver,
+            None => {
+                return Err(DatastoreError::InternalError(
+                    "Import has already failed".to_string(),
+                ))
+            }
+        };
+        match receiver.collect().unwrap() {
+            Ok(r) => match r {
+                Response::ImportSummary(summary) => Ok(summary),
+                _ => panic!("Invalid response"),
+            },
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl Drop for ImportStream {
+    fn drop(&mut self) {
+        // The worker rolls back once the sender is gone, but still has to deliver its response
+        if self.receiver.is_some() {
+            let _ = self.collect();
+        }
+    }
+}
+
 fn _unwrap_response(
     receiver: ResponseReceiver<Result<Response, DatastoreError>>,
 ) -> Result<(), DatastoreError> {
@@ -92,6 +250,9 @@ fn _unwrap_response(
     }
 }
 
+/// Number of changes kept in memory for get_changes_since
+const MAX_TRACKED_CHANGES: usize = 10000;
+
 struct DatastoreWorker {
     responder: RequestReceiver,
     legacy_import: bool,
@@ -99,12 +260,30 @@ struct DatastoreWorker {
     uncommitted_events: usize,
     commit: bool,
     last_heartbeat: HashMap<String, Option<Event>>,
+    version: u64,
+    changes: VecDeque<BucketChange>,
+    backlog: Arc<AtomicUsize>,
+    liveness: Arc<Liveness>,
+    path: Option<String>,
+    last_commit: Option<DateTime<Utc>>,
+}
+
+/// The time range covered by the events, None if they might replace existing events
+fn events_range(events: &[Event]) -> Option<TimeInterval> {
+    if events.iter().any(|e| e.id.is_some()) {
+        return None;
+    }
+    let start = events.iter().map(|e| e.timestamp).min()?;
+    let end = events.iter().map(|e| e.calculate_endtime()).max()?;
+    Some(TimeInterval::new(start, end))
 }
 
 impl DatastoreWorker {
     pub fn new(
         responder: mpsc_requests::RequestReceiver<Command, Result<Response, DatastoreError>>,
         legacy_import: bool,
+        backlog: Arc<AtomicUsize>,
+        liveness: Arc<Liveness>,
     ) -> Self {
         DatastoreWorker {
             responder,
@@ -113,6 +292,44 @@ impl DatastoreWorker {
             uncommitted_events: 0,
             commit: false,
             last_heartbeat: HashMap::new(),
+            version: 0,
+            changes: VecDeque::new(),
+            backlog,
+            liveness,
+            path: None,
+            last_commit: None,
+        }
+    }
+
+    fn record_change(&mut self, bucket_id: &str, range: Option<TimeInterval>) {
+        self.version += 1;
+        self.changes.push_back(BucketChange {
+            version: self.version,
+            bucket_id: bucket_id.to_string(),
+            range,
+        });
+        if self.changes.len() > MAX_TRACKED_CHANGES {
+            self.changes.pop_front();
+        }
+    }
+
+    /// The current version and all changes after the given version, None if older changes than
+    /// that are no longer kept track of
+    fn changes_since(&self, version: u64) -> (u64, Option<Vec<BucketChange>>) {
+        if version >= self.version {
+            return (self.version, Some(Vec::new()));
+        }
+        match self.changes.front() {
+            Some(first) if first.version <= version + 1 => {
+                let changes = self
+                    .changes
+                    .iter()
+                    .filter(|c| c.version > version)
+                    .cloned()
+                    .collect();
+                (self.version, Some(changes))
+            }
+            _ => (self.version, None),
         }
     }
 
@@ -123,6 +340,7 @@ impl DatastoreWorker {
                 Connection::open_in_memory().expect("Failed to create in-memory datastore")
             }
             DatastoreMethod::File(path) => {
+                self.path = Some(path.clone());
                 Connection::open(path).expect("Failed to create datastore")
             }
         };
@@ -163,7 +381,10 @@ impl DatastoreWorker {
 
             self.uncommitted_events = 0;
             self.commit = false;
+            // The response to a close request is held back until everything is committed
+            let mut close_response = None;
             loop {
+                self.liveness.set_busy(false);
                 let (request, response_sender) = match self.responder.poll() {
                     Ok((req, res_sender)) => (req, res_sender),
                     Err(err) => {
@@ -173,8 +394,14 @@ impl DatastoreWorker {
                         break;
                     }
                 };
+                self.liveness.set_busy(true);
+                self.backlog.fetch_sub(1, Ordering::Relaxed);
                 let response = self.handle_request(request, &mut ds, &tx);
-                response_sender.respond(response);
+                if self.quit {
+                    close_response = Some((response_sender, response));
+                } else {
+                    response_sender.respond(response);
+                }
 
                 let now: DateTime<Utc> = Utc::now();
                 let commit_interval_passed: bool = (now - last_commit_time) > Duration::seconds(15);
@@ -191,9 +418,12 @@ impl DatastoreWorker {
                 self.commit, self.uncommitted_events
             );
             match tx.commit() {
-                Ok(_) => (),
+                Ok(_) => self.last_commit = Some(Utc::now()),
                 Err(err) => panic!("Failed to commit datastore transaction! {err}"),
             }
+            if let Some((response_sender, response)) = close_response {
+                response_sender.respond(response);
+            }
             if self.quit {
                 break;
             };
@@ -208,16 +438,21 @@ impl DatastoreWorker {
         tx: &Transaction,
     ) -> Result<Response, DatastoreError> {
         match request {
-            Command::CreateBucket(bucket) => match ds.create_bucket(tx, bucket) {
-                Ok(_) => {
-                    self.commit = true;
-                    Ok(Response::Empty())
+            Command::CreateBucket(bucket) => {
+                let bucket_id = bucket.id.clone();
+                match ds.create_bucket(tx, bucket) {
+                    Ok(_) => {
+                        self.commit = true;
+                        self.record_change(&bucket_id, None);
+                        Ok(Response::Empty())
+                    }
+                    Err(e) => Err(e),
                 }
-                Err(e) => Err(e),
-            },
+            }
             Command::DeleteBucket(bucketname) => match ds.delete_bucket(tx, &bucketname) {
                 Ok(_) => {
                     self.commit = true;
+                    self.record_change(&bucketname, None);
                     Ok(Response::Empty())
                 }
                 Err(e) => Err(e),
@@ -228,9 +463,11 @@ impl DatastoreWorker {
             },
             Command::GetBuckets() => Ok(Response::BucketMap(ds.get_buckets())),
             Command::InsertEvents(bucketname, events) => {
+                let range = events_range(&events);
                 match ds.insert_events(tx, &bucketname, events) {
                     Ok(events) => {
                         self.uncommitted_events += events.len();
+                        self.record_change(&bucketname, range);
                         self.last_heartbeat.insert(bucketname.to_string(), None); // invalidate last_heartbeat cache
                         Ok(Response::EventList(events))
                     }
@@ -241,6 +478,9 @@ impl DatastoreWorker {
                 match ds.heartbeat(tx, &bucketname, event, pulsetime, &mut self.last_heartbeat) {
                     Ok(e) => {
                         self.uncommitted_events += 1;
+                        // The event might have been merged with the last one, so the whole
+                        // merged event has changed
+                        self.record_change(&bucketname, Some(TimeInterval::from(&e)));
                         Ok(Response::Event(e))
                     }
                     Err(e) => Err(e),
@@ -258,6 +498,25 @@ impl DatastoreWorker {
                     Err(e) => Err(e),
                 }
             }
+            Command::GetEventsPage(
+                bucketname,
+                starttime_opt,
+                endtime_opt,
+                limit_opt,
+                ascending,
+                cursor_opt,
+            ) => match ds.get_events_page(
+                tx,
+                &bucketname,
+                starttime_opt,
+                endtime_opt,
+                limit_opt,
+                ascending,
+                cursor_opt,
+            ) {
+                Ok((el, next)) => Ok(Response::EventPage(el, next)),
+                Err(e) => Err(e),
+            },
             Command::GetEventCount(bucketname, starttime_opt, endtime_opt) => {
                 match ds.get_event_count(tx, &bucketname, starttime_opt, endtime_opt) {
                     Ok(n) => Ok(Response::Count(n)),
@@ -266,7 +525,10 @@ impl DatastoreWorker {
             }
             Command::DeleteEventsById(bucketname, event_ids) => {
                 match ds.delete_events_by_id(tx, &bucketname, event_ids) {
-                    Ok(()) => Ok(Response::Empty()),
+                    Ok(()) => {
+                        self.record_change(&bucketname, None);
+                        Ok(Response::Empty())
+                    }
                     Err(e) => Err(e),
                 }
             }
@@ -290,6 +552,99 @@ impl DatastoreWorker {
                 Ok(()) => Ok(Response::Empty()),
                 Err(e) => Err(e),
             },
+            Command::Import(receiver, on_conflict, dry_run) => {
+                match ds.import(
+                    tx,
+                    receive_chunks(receiver, self.liveness.clone()),
+                    on_conflict,
+                    dry_run,
+                ) {
+                    Ok(summary) => {
+                        self.commit = true;
+                        self.last_heartbeat.clear(); // invalidate last_heartbeat cache
+                        if !summary.dry_run {
+                            for result in summary.buckets.iter() {
+                                if let Some(bucket_id) = &result.imported_as {
+                                    self.record_change(bucket_id, None);
+                                }
+                            }
+                        }
+                        Ok(Response::ImportSummary(summary))
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+            Command::GetChangesSince(version) => {
+                let (current, changes) = self.changes_since(version);
+                Ok(Response::Changes(current, changes))
+            }
+            Command::GetHealth() => {
+                let pragma = |name| tx.pragma_query_value(None, name, |row| row.get::<_, i64>(0));
+                let size = match (pragma("page_count"), pragma("page_size")) {
+                    (Ok(page_count), Ok(page_size)) => (page_count * page_size) as u64,
+                    (Err(err), _) | (_, Err(err)) => {
+                        return Err(DatastoreError::InternalError(format!(
+                            "Failed to get database size: {err}"
+                        )))
+                    }
+                };
+                // ds.db_version is the version before any migrations, so ask the database
+                let db_version = match pragma("user_version") {
+                    Ok(db_version) => db_version as i32,
+                    Err(err) => {
+                        return Err(DatastoreError::InternalError(format!(
+                            "Failed to get database version: {err}"
+                        )))
+                    }
+                };
+                Ok(Response::Health(DatastoreHealth {
+                    path: self.path.clone(),
+                    size,
+                    db_version,
+                    last_commit: self.last_commit,
+                    backlog: self.backlog.load(Ordering::Relaxed),
+                }))
+            }
+            Command::InsertAuditEntry(entry) => match ds.insert_audit_entry(tx, &entry) {
+                Ok(id) => Ok(Response::Count(id)),
+                Err(e) => Err(e),
+            },
+            Command::GetAuditEntries(since_opt, limit_opt) => {
+                match ds.get_audit_entries(tx, since_opt, limit_opt) {
+                    Ok(entries) => Ok(Response::AuditEntries(entries)),
+                    Err(e) => Err(e),
+                }
+            }
+            Command::DeleteAuditEntriesBefore(before) => {
+                match ds.delete_audit_entries_before(tx, before) {
+                    Ok(count) => Ok(Response::Count(count as i64)),
+                    Err(e) => Err(e),
+                }
+            }
+            Command::Batch(writes) => {
+                let mut results = Vec::with_capacity(writes.len());
+                for write in writes {
+                    let cmd = match write {
+                        BatchWrite::CreateBucket(bucket) => Command::CreateBucket(bucket),
+                        BatchWrite::Heartbeat(bucketname, event, pulsetime) => {
+                            Command::Heartbeat(bucketname, event, pulsetime)
+                        }
+                        BatchWrite::InsertEvents(bucketname, events) => {
+                            Command::InsertEvents(bucketname, events)
+                        }
+                        BatchWrite::SetKeyValue(key, data) => Command::SetKeyValue(key, data),
+                    };
+                    results.push(match self.handle_request(cmd, ds, tx) {
+                        Ok(Response::Event(e)) => Ok(vec![e]),
+                        Ok(Response::EventList(events)) => Ok(events),
+                        Ok(_) => Ok(Vec::new()),
+                        Err(e) => Err(e),
+                    });
+                }
+                // Commit the whole batch at once, instead of leaving it to the next request
+                self.commit = true;
+                Ok(Response::Batch(results))
+            }
             Command::Close() => {
                 self.quit = true;
                 Ok(Response::Empty())
@@ -312,16 +667,48 @@ impl Datastore {
     fn _new_internal(method: DatastoreMethod, legacy_import: bool) -> Self {
         let (requester, responder) =
             mpsc_requests::channel::<Command, Result<Response, DatastoreError>>();
+        let backlog = Arc::new(AtomicUsize::new(0));
+        let worker_backlog = backlog.clone();
+        let liveness = Arc::new(Liveness::default());
+        let worker_liveness = liveness.clone();
         let _thread = thread::spawn(move || {
-            let mut di = DatastoreWorker::new(responder, legacy_import);
+            let _stopped = StoppedGuard(worker_liveness.clone());
+            let mut di =
+                DatastoreWorker::new(responder, legacy_import, worker_backlog, worker_liveness);
             di.work_loop(method);
         });
-        Datastore { requester }
+        Datastore {
+            requester,
+            backlog,
+            liveness,
+            epoch: NEXT_EPOCH.fetch_add(1, Ordering::Relaxed),
+        }
+    }
+
+    /// Unique number of this datastore among all opened by the process
+    ///
+    /// Versions start over for every datastore, so a version is only meaningful together with the
+    /// epoch of the datastore it came from.
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    fn request(
+        &self,
+        cmd: Command,
+    ) -> Result<ResponseReceiver<Result<Response, DatastoreError>>, mpsc_requests::RequestError>
+    {
+        self.backlog.fetch_add(1, Ordering::Relaxed);
+        let receiver = self.requester.request(cmd);
+        if receiver.is_err() {
+            self.backlog.fetch_sub(1, Ordering::Relaxed);
+        }
+        receiver
     }
 
     pub fn create_bucket(&self, bucket: &Bucket) -> Result<(), DatastoreError> {
         let cmd = Command::CreateBucket(bucket.clone());
-        let receiver = self.requester.request(cmd).unwrap();
+        let receiver = self.request(cmd).unwrap();
         match receiver.collect().unwrap() {
             Ok(_) => Ok(()),
             Err(e) => Err(e),
@@ -330,7 +717,7 @@ impl Datastore {
 
     pub fn delete_bucket(&self, bucket_id: &str) -> Result<(), DatastoreError> {
         let cmd = Command::DeleteBucket(bucket_id.to_string());
-        let receiver = self.requester.request(cmd).unwrap();
+        let receiver = self.request(cmd).unwrap();
         match receiver.collect().unwrap() {
             Ok(r) => match r {
                 Response::Empty() => Ok(()),
@@ -342,7 +729,7 @@ impl Datastore {
 
     pub fn get_bucket(&self, bucket_id: &str) -> Result<Bucket, DatastoreError> {
         let cmd = Command::GetBucket(bucket_id.to_string());
-        let receiver = self.requester.request(cmd).unwrap();
+        let receiver = self.request(cmd).unwrap();
         match receiver.collect().unwrap() {
             Ok(r) => match r {
                 Response::Bucket(b) => Ok(b),
@@ -354,7 +741,7 @@ impl Datastore {
 
     pub fn get_buckets(&self) -> Result<HashMap<String, Bucket>, DatastoreError> {
         let cmd = Command::GetBuckets();
-        let receiver = self.requester.request(cmd).unwrap();
+        let receiver = self.request(cmd).unwrap();
         match receiver.collect().unwrap() {
             Ok(r) => match r {
                 Response::BucketMap(bm) => Ok(bm),
@@ -372,7 +759,7 @@ impl Datastore {
         events: &[Event],
     ) -> Result<Vec<Event>, DatastoreError> {
         let cmd = Command::InsertEvents(bucket_id.to_string(), events.to_vec());
-        let receiver = self.requester.request(cmd).unwrap();
+        let receiver = self.request(cmd).unwrap();
         match receiver.collect().unwrap() {
             Ok(r) => match r {
                 Response::EventList(events) => Ok(events),
@@ -389,7 +776,7 @@ impl Datastore {
         pulsetime: f64,
     ) -> Result<Event, DatastoreError> {
         let cmd = Command::Heartbeat(bucket_id.to_string(), heartbeat, pulsetime);
-        let receiver = self.requester.request(cmd).unwrap();
+        let receiver = self.request(cmd).unwrap();
         match receiver.collect().unwrap() {
             Ok(r) => match r {
                 Response::Event(e) => Ok(e),
@@ -401,7 +788,7 @@ impl Datastore {
 
     pub fn get_event(&self, bucket_id: &str, event_id: i64) -> Result<Event, DatastoreError> {
         let cmd = Command::GetEvent(bucket_id.to_string(), event_id);
-        let receiver = self.requester.request(cmd).unwrap();
+        let receiver = self.request(cmd).unwrap();
         match receiver.collect().unwrap() {
             Ok(r) => match r {
                 Response::Event(el) => Ok(el),
@@ -419,7 +806,7 @@ impl Datastore {
         limit_opt: Option<u64>,
     ) -> Result<Vec<Event>, DatastoreError> {
         let cmd = Command::GetEvents(bucket_id.to_string(), starttime_opt, endtime_opt, limit_opt);
-        let receiver = self.requester.request(cmd).unwrap();
+        let receiver = self.request(cmd).unwrap();
         match receiver.collect().unwrap() {
             Ok(r) => match r {
                 Response::EventList(el) => Ok(el),
@@ -429,6 +816,34 @@ impl Datastore {
         }
     }
 
+    /// Get a page of events, see DatastoreInstance::get_events_page
+    pub fn get_events_page(
+        &self,
+        bucket_id: &str,
+        starttime_opt: Option<DateTime<Utc>>,
+        endtime_opt: Option<DateTime<Utc>>,
+        limit_opt: Option<u64>,
+        ascending: bool,
+        cursor_opt: Option<EventCursor>,
+    ) -> Result<(Vec<Event>, Option<EventCursor>), DatastoreError> {
+        let cmd = Command::GetEventsPage(
+            bucket_id.to_string(),
+            starttime_opt,
+            endtime_opt,
+            limit_opt,
+            ascending,
+            cursor_opt,
+        );
+        let receiver = self.request(cmd).unwrap();
+        match receiver.collect().unwrap() {
+            Ok(r) => match r {
+                Response::EventPage(el, next) => Ok((el, next)),
+                _ => panic!("Invalid response"),
+            },
+            Err(e) => Err(e),
+        }
+    }
+
     pub fn get_event_count(
         &self,
         bucket_id: &str,
@@ -436,7 +851,7 @@ impl Datastore {
         endtime_opt: Option<DateTime<Utc>>,
     ) -> Result<i64, DatastoreError> {
         let cmd = Command::GetEventCount(bucket_id.to_string(), starttime_opt, endtime_opt);
-        let receiver = self.requester.request(cmd).unwrap();
+        let receiver = self.request(cmd).unwrap();
         match receiver.collect().unwrap() {
             Ok(r) => match r {
                 Response::Count(n) => Ok(n),
@@ -452,7 +867,7 @@ impl Datastore {
         event_ids: Vec<i64>,
     ) -> Result<(), DatastoreError> {
         let cmd = Command::DeleteEventsById(bucket_id.to_string(), event_ids);
-        let receiver = self.requester.request(cmd).unwrap();
+        let receiver = self.request(cmd).unwrap();
         match receiver.collect().unwrap() {
             Ok(r) => match r {
                 Response::Empty() => Ok(()),
@@ -464,7 +879,7 @@ impl Datastore {
 
     pub fn force_commit(&self) -> Result<(), DatastoreError> {
         let cmd = Command::ForceCommit();
-        let receiver = self.requester.request(cmd).unwrap();
+        let receiver = self.request(cmd).unwrap();
         match receiver.collect().unwrap() {
             Ok(r) => match r {
                 Response::Empty() => Ok(()),
@@ -476,7 +891,7 @@ impl Datastore {
 
     pub fn get_key_values(&self, pattern: &str) -> Result<HashMap<String, String>, DatastoreError> {
         let cmd = Command::GetKeyValues(pattern.to_string());
-        let receiver = self.requester.request(cmd).unwrap();
+        let receiver = self.request(cmd).unwrap();
 
         match receiver.collect().unwrap() {
             Ok(r) => match r {
@@ -489,7 +904,7 @@ impl Datastore {
 
     pub fn get_key_value(&self, key: &str) -> Result<String, DatastoreError> {
         let cmd = Command::GetKeyValue(key.to_string());
-        let receiver = self.requester.request(cmd).unwrap();
+        let receiver = self.request(cmd).unwrap();
 
         match receiver.collect().unwrap() {
             Ok(r) => match r {
@@ -502,22 +917,170 @@ impl Datastore {
 
     pub fn set_key_value(&self, key: &str, data: &str) -> Result<(), DatastoreError> {
         let cmd = Command::SetKeyValue(key.to_string(), data.to_string());
-        let receiver = self.requester.request(cmd).unwrap();
+        let receiver = self.request(cmd).unwrap();
 
         _unwrap_response(receiver)
     }
 
     pub fn delete_key_value(&self, key: &str) -> Result<(), DatastoreError> {
         let cmd = Command::DeleteKeyValue(key.to_string());
-        let receiver = self.requester.request(cmd).unwrap();
+        let receiver = self.request(cmd).unwrap();
 
         _unwrap_response(receiver)
     }
 
+    /// Import buckets and events in a single transaction
+    ///
+    /// Each bucket record has to be followed by the event records of that bucket, events can also
+    /// be included in the bucket record itself.
+    pub fn import(
+        &self,
+        records: Vec<ExportRecord>,
+        on_conflict: ImportConflict,
+        dry_run: bool,
+    ) -> Result<ImportSummary, DatastoreError> {
+        let mut import = self.import_stream(on_conflict, dry_run);
+        // Sent in chunks so the worker reports progress while it imports
+        let mut records = records.into_iter().peekable();
+        while records.peek().is_some() {
+            import.send(records.by_ref().take(IMPORT_BATCH_SIZE).collect())?;
+        }
+        import.finish()
+    }
+
+    /// Start an import whose records are sent while they are being read, see import
+    pub fn import_stream(&self, on_conflict: ImportConflict, dry_run: bool) -> ImportStream {
+        let (sender, receiver) = mpsc::sync_channel(IMPORT_QUEUE_SIZE);
+        let cmd = Command::Import(receiver, on_conflict, dry_run);
+        ImportStream {
+            sender: Some(sender),
+            receiver: Some(self.request(cmd).unwrap()),
+        }
+    }
+
+    /// Get the current version of the datastore, which is increased by every change to a bucket
+    pub fn get_version(&self) -> Result<u64, DatastoreError> {
+        let (version, _changes) = self.get_changes_since(u64::MAX)?;
+        Ok(version)
+    }
+
+    /// Get the current version along with the changes made after the given version
+    ///
+    /// Only the latest changes are kept track of, None is returned if changes older than that
+    /// were asked for.
+    pub fn get_changes_since(
+        &self,
+        version: u64,
+    ) -> Result<(u64, Option<Vec<BucketChange>>), DatastoreError> {
+        let cmd = Command::GetChangesSince(version);
+        let receiver = self.request(cmd).unwrap();
+        match receiver.collect().unwrap() {
+            Ok(r) => match r {
+                Response::Changes(current, changes) => Ok((current, changes)),
+                _ => panic!("Invalid response"),
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Round-trip a request through the worker and report on the state of the database
+    ///
+    /// Unlike other requests this does not panic if the worker thread has stopped.
+    pub fn get_health(&self) -> Result<DatastoreHealth, DatastoreError> {
+        let receiver = match self.request(Command::GetHealth()) {
+            Ok(receiver) => receiver,
+            Err(_) => return Err(DatastoreError::MpscError),
+        };
+        match receiver.collect() {
+            Ok(Ok(Response::Health(health))) => Ok(health),
+            Ok(Ok(_)) => panic!("Invalid response"),
+            Ok(Err(e)) => Err(e),
+            Err(_) => Err(DatastoreError::MpscError),
+        }
+    }
+
+    /// How long the worker has been working on a request without making progress, None while it
+    /// waits for one
+    ///
+    /// Unlike get_health this is answered without the worker, so it works while the worker is
+    /// busy. Fails if the worker thread has stopped.
+    pub fn worker_busy_for(&self) -> Result<Option<std::time::Duration>, DatastoreError> {
+        if self.liveness.stopped.load(Ordering::Relaxed) {
+            return Err(DatastoreError::MpscError);
+        }
+        let busy_since = *self.liveness.busy_since.lock().unwrap();
+        Ok(busy_since.map(|since| since.elapsed()))
+    }
+
+    /// Do the writes in order and commit them right away, together with any other pending writes
+    ///
+    /// Each write succeeds or fails on its own, a failed write does not undo the others. The
+    /// result of a write is the events it inserted or updated.
+    pub fn batch(
+        &self,
+        writes: Vec<BatchWrite>,
+    ) -> Result<Vec<Result<Vec<Event>, DatastoreError>>, DatastoreError> {
+        let cmd = Command::Batch(writes);
+        let receiver = self.request(cmd).unwrap();
+        match receiver.collect().unwrap() {
+            Ok(r) => match r {
+                Response::Batch(results) => Ok(results),
+                _ => panic!("Invalid response"),
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Append an entry to the audit log, returns the id it was given
+    pub fn insert_audit_entry(&self, entry: &AuditEntry) -> Result<i64, DatastoreError> {
+        let cmd = Command::InsertAuditEntry(entry.clone());
+        let receiver = self.request(cmd).unwrap();
+        match receiver.collect().unwrap() {
+            Ok(r) => match r {
+                Response::Count(id) => Ok(id),
+                _ => panic!("Invalid response"),
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Get the audit log entries recorded at or after since, oldest first
+    pub fn get_audit_entries(
+        &self,
+        since_opt: Option<DateTime<Utc>>,
+        limit_opt: Option<u64>,
+    ) -> Result<Vec<AuditEntry>, DatastoreError> {
+        let cmd = Command::GetAuditEntries(since_opt, limit_opt);
+        let receiver = self.request(cmd).unwrap();
+        match receiver.collect().unwrap() {
+            Ok(r) => match r {
+                Response::AuditEntries(entries) => Ok(entries),
+                _ => panic!("Invalid response"),
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Delete the audit log entries recorded before the given time, returns how many there were
+    pub fn delete_audit_entries_before(
+        &self,
+        before: DateTime<Utc>,
+    ) -> Result<u64, DatastoreError> {
+        let cmd = Command::DeleteAuditEntriesBefore(before);
+        let receiver = self.request(cmd).unwrap();
+        match receiver.collect().unwrap() {
+            Ok(r) => match r {
+                Response::Count(count) => Ok(count as u64),
+                _ => panic!("Invalid response"),
+            },
+            Err(e) => Err(e),
+        }
+    }
+
     // Should block until worker has stopped
     pub fn close(&self) {
         info!("Sending close request to database");
-        let receiver = self.requester.request(Command::Close()).unwrap();
+        let receiver = self.request(Command::Close()).unwrap();
 
         match receiver.collect().unwrap() {
             Ok(r) => match r {
@@ -13,11 +13,17 @@ mod datastore_tests {
     use chrono::Utc;
     use serde_json::json;
 
+    use aw_datastore::BatchWrite;
     use aw_datastore::Datastore;
+    use aw_datastore::DatastoreError;
 
+    use aw_models::AuditEntry;
     use aw_models::Bucket;
     use aw_models::BucketMetadata;
     use aw_models::Event;
+    use aw_models::ExportRecord;
+    use aw_models::ImportAction;
+    use aw_models::ImportConflict;
 
     fn test_bucket() -> Bucket {
         Bucket {
@@ -209,6 +215,57 @@ mod datastore_tests {
         assert_eq!(event_count, 2);
     }
 
+    #[test]
+    fn test_events_get_page() {
+        // Setup datastore
+        let ds = Datastore::new_in_memory(false);
+        let bucket = create_test_bucket(&ds);
+
+        // Insert 5 events, two of them sharing the same timestamp
+        let now = Utc::now();
+        let mut event_list = Vec::new();
+        for i in 0..5 {
+            event_list.push(Event {
+                id: None,
+                timestamp: now + Duration::seconds(std::cmp::min(i, 3)),
+                duration: Duration::seconds(0),
+                data: json_map! {"i": json!(i)},
+            });
+        }
+        ds.insert_events(&bucket.id, &event_list).unwrap();
+
+        // Events with the same timestamp are ordered by id in both directions
+        for (ascending, expected) in [(true, [0, 1, 2, 3, 4]), (false, [3, 4, 2, 1, 0])] {
+            let mut fetched = Vec::new();
+            let mut cursor = None;
+            loop {
+                let (page, next) = ds
+                    .get_events_page(&bucket.id, None, None, Some(2), ascending, cursor)
+                    .unwrap();
+                assert!(page.len() <= 2);
+                fetched.extend(page);
+                match next {
+                    Some(next) => cursor = Some(next.to_string().parse().unwrap()),
+                    None => break,
+                }
+            }
+            let order: Vec<i64> = fetched
+                .iter()
+                .map(|e| e.data["i"].as_i64().unwrap())
+                .collect();
+            assert_eq!(order, expected);
+        }
+
+        // Without a limit everything fits in one page
+        let (all, next) = ds
+            .get_events_page(&bucket.id, None, None, None, true, None)
+            .unwrap();
+        assert_eq!(all.len(), 5);
+        assert!(next.is_none());
+
+        assert!("not-a-cursor".parse::<aw_datastore::EventCursor>().is_err());
+    }
+
     /// Tests that events that cover a timeperiod get included when that timeperiod is queried.
     #[test]
     fn test_get_events_filters_cover() {
@@ -494,4 +551,357 @@ mod datastore_tests {
             );
         }
     }
+
+    #[test]
+    fn test_import_conflicts() {
+        let ds = Datastore::new_in_memory(false);
+        let bucket = create_test_bucket(&ds);
+        let e1 = Event {
+            id: None,
+            timestamp: Utc::now(),
+            duration: Duration::seconds(1),
+            data: json_map! {"key": json!("value")},
+        };
+        let mut e2 = e1.clone();
+        e2.timestamp += Duration::seconds(1);
+        ds.insert_events(&bucket.id, &[e1.clone()]).unwrap();
+
+        let mut other_bucket = test_bucket();
+        other_bucket.id = "otherid".to_string();
+        let records = || {
+            vec![
+                ExportRecord::Bucket(other_bucket.clone()),
+                ExportRecord::Event(e1.clone()),
+                ExportRecord::Bucket(bucket.clone()),
+                ExportRecord::Event(e1.clone()),
+                ExportRecord::Event(e2.clone()),
+                ExportRecord::Event(e2.clone()),
+            ]
+        };
+
+        // Fail, nothing should be imported and the summary ends with the existing bucket
+        let summary = match ds.import(records(), ImportConflict::Fail, false) {
+            Err(DatastoreError::ImportConflict(summary)) => summary,
+            res => panic!("Expected an import conflict, got {res:?}"),
+        };
+        assert_eq!(summary.buckets.len(), 2);
+        assert_eq!(summary.buckets[0].action, ImportAction::Created);
+        assert_eq!(summary.buckets[0].events_imported, 1);
+        assert_eq!(summary.buckets[1].bucket_id, bucket.id);
+        assert_eq!(summary.buckets[1].action, ImportAction::Failed);
+        assert_eq!(summary.buckets[1].imported_as, None);
+        assert!(ds.get_bucket(&other_bucket.id).is_err());
+
+        // Dry run, nothing should be imported but the summary should be complete
+        let summary = ds.import(records(), ImportConflict::Merge, true).unwrap();
+        assert!(summary.dry_run);
+        assert_eq!(summary.buckets.len(), 2);
+        assert_eq!(summary.buckets[0].action, ImportAction::Created);
+        assert_eq!(summary.buckets[0].events_imported, 1);
+        assert_eq!(summary.buckets[1].action, ImportAction::Merged);
+        assert_eq!(summary.buckets[1].events_imported, 1);
+        assert_eq!(summary.buckets[1].events_skipped, 2);
+        assert!(ds.get_bucket(&other_bucket.id).is_err());
+        assert_eq!(ds.get_event_count(&bucket.id, None, None).unwrap(), 1);
+
+        // Skip
+        let summary = ds.import(records(), ImportConflict::Skip, false).unwrap();
+        assert_eq!(summary.buckets[1].action, ImportAction::Skipped);
+        assert_eq!(summary.buckets[1].imported_as, None);
+        assert_eq!(summary.buckets[1].events_skipped, 3);
+        assert_eq!(ds.get_event_count(&other_bucket.id, None, None).unwrap(), 1);
+        assert_eq!(ds.get_event_count(&bucket.id, None, None).unwrap(), 1);
+
+        // Merge, only the events which don't already exist are added
+        let summary = ds.import(records(), ImportConflict::Merge, false).unwrap();
+        assert_eq!(summary.buckets[0].events_imported, 0);
+        assert_eq!(summary.buckets[1].events_imported, 1);
+        assert_eq!(ds.get_event_count(&other_bucket.id, None, None).unwrap(), 1);
+        assert_eq!(ds.get_event_count(&bucket.id, None, None).unwrap(), 2);
+
+        // Rename
+        let summary = ds.import(records(), ImportConflict::Rename, false).unwrap();
+        assert_eq!(summary.buckets[1].action, ImportAction::Renamed);
+        assert_eq!(
+            summary.buckets[1].imported_as,
+            Some("testid-imported".to_string())
+        );
+        assert_eq!(summary.buckets[1].events_imported, 3);
+        assert_eq!(
+            ds.get_event_count("testid-imported", None, None).unwrap(),
+            3
+        );
+        assert_eq!(ds.get_event_count(&bucket.id, None, None).unwrap(), 2);
+        let summary = ds.import(records(), ImportConflict::Rename, false).unwrap();
+        assert_eq!(
+            summary.buckets[1].imported_as,
+            Some("testid-imported-2".to_string())
+        );
+    }
+
+    #[test]
+    fn test_import_event_ids() {
+        let ds = Datastore::new_in_memory(false);
+        let bucket = create_test_bucket(&ds);
+        let e1 = Event {
+            id: None,
+            timestamp: Utc::now(),
+            duration: Duration::seconds(1),
+            data: json_map! {"key": json!("value")},
+        };
+        let existing = ds.insert_events(&bucket.id, &[e1.clone()]).unwrap();
+        let existing_id = existing[0].id.unwrap();
+
+        // An export from another machine, whose event ids collide with the existing event
+        let mut other_bucket = test_bucket();
+        other_bucket.id = "otherid".to_string();
+        let mut imported = e1.clone();
+        imported.id = Some(existing_id);
+        imported.data = json_map! {"key": json!("imported")};
+        let records = vec![
+            ExportRecord::Bucket(other_bucket.clone()),
+            ExportRecord::Event(imported),
+        ];
+        let summary = ds.import(records, ImportConflict::Fail, false).unwrap();
+        assert_eq!(summary.buckets[0].action, ImportAction::Created);
+        assert_eq!(summary.buckets[0].events_imported, 1);
+
+        // The existing event is left as is and the imported one gets a new id
+        let event = ds.get_event(&bucket.id, existing_id).unwrap();
+        assert_eq!(event.data, e1.data);
+        let imported = ds.get_events(&other_bucket.id, None, None, None).unwrap();
+        assert_eq!(imported.len(), 1);
+        assert_ne!(imported[0].id, Some(existing_id));
+        assert_eq!(imported[0].data, json_map! {"key": json!("imported")});
+    }
+
+    #[test]
+    fn test_import_stream() {
+        let ds = Datastore::new_in_memory(false);
+        let bucket = test_bucket();
+        let event = Event {
+            id: None,
+            timestamp: Utc::now(),
+            duration: Duration::seconds(1),
+            data: json_map! {"key": json!("value")},
+        };
+
+        // An import which is dropped before it is finished is rolled back
+        let mut import = ds.import_stream(ImportConflict::Fail, false);
+        import
+            .send(vec![ExportRecord::Bucket(bucket.clone())])
+            .unwrap();
+        import
+            .send(vec![ExportRecord::Event(event.clone())])
+            .unwrap();
+        drop(import);
+        assert!(ds.get_bucket(&bucket.id).is_err());
+
+        let mut import = ds.import_stream(ImportConflict::Fail, false);
+        import
+            .send(vec![ExportRecord::Bucket(bucket.clone())])
+            .unwrap();
+        for _ in 0..3 {
+            import
+                .send(vec![ExportRecord::Event(event.clone())])
+                .unwrap();
+        }
+        let summary = import.finish().unwrap();
+        assert_eq!(summary.buckets[0].events_imported, 3);
+        assert_eq!(ds.get_event_count(&bucket.id, None, None).unwrap(), 3);
+
+        // The error of a failed import is returned by send or finish, depending on when the
+        // datastore got to it
+        let mut import = ds.import_stream(ImportConflict::Fail, false);
+        let res = match import.send(vec![ExportRecord::Bucket(bucket.clone())]) {
+            Ok(()) => import.finish().map(|_| ()),
+            Err(e) => Err(e),
+        };
+        assert!(matches!(res, Err(DatastoreError::ImportConflict(_))));
+    }
+
+    #[test]
+    fn test_worker_busy_for() {
+        let ds = Datastore::new_in_memory(false);
+        let bucket = test_bucket();
+        let wait_until = |busy: bool| {
+            for _ in 0..100 {
+                if ds.worker_busy_for().unwrap().is_some() == busy {
+                    return;
+                }
+                std::thread::sleep(std::time::Duration::from_millis(10));
+            }
+            panic!("Worker busy was never {busy}");
+        };
+        wait_until(false);
+
+        // An import keeps the worker busy until it is finished, chunks count as progress
+        let mut import = ds.import_stream(ImportConflict::Fail, false);
+        import
+            .send(vec![ExportRecord::Bucket(bucket.clone())])
+            .unwrap();
+        wait_until(true);
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        let waited = ds.worker_busy_for().unwrap().unwrap();
+        assert!(waited >= std::time::Duration::from_millis(100));
+        import.send(vec![]).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        assert!(ds.worker_busy_for().unwrap().unwrap() < waited);
+        import.finish().unwrap();
+        wait_until(false);
+
+        ds.close();
+        assert!(matches!(
+            ds.worker_busy_for(),
+            Err(DatastoreError::MpscError)
+        ));
+    }
+
+    #[test]
+    fn test_changes_since() {
+        let ds = Datastore::new_in_memory(false);
+        let version_before = ds.get_version().unwrap();
+        let bucket = create_test_bucket(&ds);
+        let version_created = ds.get_version().unwrap();
+        assert!(version_created > version_before);
+
+        let e1 = Event {
+            id: None,
+            timestamp: Utc::now(),
+            duration: Duration::seconds(1),
+            data: json_map! {"key": json!("value")},
+        };
+        ds.insert_events(&bucket.id, &[e1.clone()]).unwrap();
+        ds.heartbeat(&bucket.id, e1.clone(), 10.0).unwrap();
+
+        let (version, changes) = ds.get_changes_since(version_created).unwrap();
+        let changes = changes.unwrap();
+        assert_eq!(version, ds.get_version().unwrap());
+        assert_eq!(changes.len(), 2);
+        assert!(changes.iter().all(|c| c.bucket_id == bucket.id));
+        assert_eq!(changes[0].range, Some((&e1).into()));
+
+        // Nothing changed since the current version
+        let (_, changes) = ds.get_changes_since(version).unwrap();
+        assert_eq!(changes.unwrap().len(), 0);
+
+        // Changes where it's not known which events were affected cover the whole bucket
+        ds.delete_bucket(&bucket.id).unwrap();
+        let (_, changes) = ds.get_changes_since(version).unwrap();
+        assert_eq!(changes.unwrap()[0].range, None);
+    }
+
+    #[test]
+    fn test_health() {
+        let ds = Datastore::new_in_memory(false);
+        let health = ds.get_health().unwrap();
+        assert_eq!(health.path, None);
+        assert!(health.size > 0);
+        assert_eq!(health.db_version, 5);
+        assert_eq!(health.backlog, 0);
+
+        // Creating a bucket commits
+        create_test_bucket(&ds);
+        let health = ds.get_health().unwrap();
+        assert!(health.last_commit.is_some());
+
+        // Once the worker has stopped the health check fails instead of panicking
+        ds.close();
+        assert!(ds.get_health().is_err());
+    }
+
+    #[test]
+    fn test_audit_log() {
+        let ds = Datastore::new_in_memory(false);
+        let now = Utc::now();
+        let entry = |timestamp, route: &str| AuditEntry {
+            id: None,
+            timestamp,
+            method: "DELETE".to_string(),
+            route: route.to_string(),
+            affected: vec!["testid".to_string()],
+            status: 200,
+            user_agent: Some("aw-client-rust".to_string()),
+            client: None,
+        };
+        let old = entry(now - Duration::days(100), "/api/0/buckets/<bucket_id>");
+        let new = entry(now, "/api/0/settings/<key>");
+        let old_id = ds.insert_audit_entry(&old).unwrap();
+        let new_id = ds.insert_audit_entry(&new).unwrap();
+        assert!(new_id > old_id);
+
+        let entries = ds.get_audit_entries(None, None).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].id, Some(old_id));
+        assert_eq!(entries[0].affected, old.affected);
+        assert_eq!(entries[0].user_agent, old.user_agent);
+        assert_eq!(entries[1].route, new.route);
+
+        let entries = ds
+            .get_audit_entries(Some(now - Duration::days(1)), None)
+            .unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].id, Some(new_id));
+        assert_eq!(ds.get_audit_entries(None, Some(1)).unwrap().len(), 1);
+
+        let deleted = ds
+            .delete_audit_entries_before(now - Duration::days(30))
+            .unwrap();
+        assert_eq!(deleted, 1);
+        let entries = ds.get_audit_entries(None, None).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].id, Some(new_id));
+
+        // Ids are not reused after pruning
+        ds.delete_audit_entries_before(now + Duration::days(1))
+            .unwrap();
+        let id = ds.insert_audit_entry(&new).unwrap();
+        assert!(id > new_id);
+    }
+
+    #[test]
+    fn test_batch() {
+        let ds = Datastore::new_in_memory(false);
+        let bucket = test_bucket();
+        let e1 = Event {
+            id: None,
+            timestamp: Utc::now(),
+            duration: Duration::seconds(0),
+            data: json_map! {"key": json!("value")},
+        };
+        let mut e2 = e1.clone();
+        e2.timestamp = e1.timestamp + Duration::seconds(5);
+
+        let version_before = ds.get_version().unwrap();
+        let results = ds
+            .batch(vec![
+                BatchWrite::CreateBucket(bucket.clone()),
+                BatchWrite::CreateBucket(bucket.clone()),
+                BatchWrite::Heartbeat(bucket.id.clone(), e1.clone(), 10.0),
+                BatchWrite::Heartbeat(bucket.id.clone(), e2.clone(), 10.0),
+                BatchWrite::InsertEvents("nonexistent".to_string(), vec![e1.clone()]),
+                BatchWrite::SetKeyValue("key".to_string(), "value".to_string()),
+            ])
+            .unwrap();
+        assert_eq!(results.len(), 6);
+        assert_eq!(results[0].as_ref().unwrap().len(), 0);
+        assert!(matches!(
+            results[1],
+            Err(DatastoreError::BucketAlreadyExists(_))
+        ));
+        // The second heartbeat was merged into the first one
+        let merged = &results[3].as_ref().unwrap()[0];
+        assert_eq!(merged.duration, Duration::seconds(5));
+        assert!(matches!(results[4], Err(DatastoreError::NoSuchBucket(_))));
+        assert!(results[5].is_ok());
+
+        // The writes went through like they would on their own
+        assert!(ds.get_version().unwrap() > version_before);
+        assert_eq!(
+            ds.get_events(&bucket.id, None, None, None).unwrap().len(),
+            1
+        );
+        assert_eq!(ds.get_key_value("key").unwrap(), "value");
+        assert!(ds.get_health().unwrap().last_commit.is_some());
+    }
 }
@@ -0,0 +1,22 @@
+use chrono::{DateTime, Utc};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// A request which changed data, as recorded in the audit log
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+pub struct AuditEntry {
+    /// Set by the datastore, increasing in the order the entries were recorded
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<i64>,
+    pub timestamp: DateTime<Utc>,
+    pub method: String,
+    /// The route which handled the request, like /api/0/buckets/<bucket_id>
+    pub route: String,
+    /// IDs of the buckets, events, settings or other objects the request was made for
+    pub affected: Vec<String>,
+    /// Status code of the response, failed attempts are recorded as well
+    pub status: u16,
+    pub user_agent: Option<String>,
+    /// The client of the bucket the request was made for
+    pub client: Option<String>,
+}
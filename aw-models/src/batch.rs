@@ -0,0 +1,47 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::Bucket;
+use crate::Event;
+
+/// An operation of a batch request
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum BatchOperation {
+    /// Create the bucket unless a bucket with its id already exists
+    CreateBucket {
+        bucket: Bucket,
+    },
+    Heartbeat {
+        bucket_id: String,
+        pulsetime: f64,
+        event: Event,
+    },
+    InsertEvents {
+        bucket_id: String,
+        events: Vec<Event>,
+    },
+    SetSetting {
+        key: String,
+        value: Value,
+    },
+}
+
+/// The outcome of an operation of a batch request
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+pub struct BatchResult {
+    /// The status code the operation would have had as a request of its own
+    pub status: u16,
+    /// The events inserted by insert_events, or the event a heartbeat was merged into
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub events: Option<Vec<Event>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl BatchResult {
+    pub fn is_ok(&self) -> bool {
+        self.status < 400
+    }
+}
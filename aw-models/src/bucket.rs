@@ -42,6 +42,16 @@ pub struct BucketsExport {
     pub buckets: HashMap<String, Bucket>,
 }
 
+/// A single line of a streamed (NDJSON) export
+///
+/// Each bucket is written without its events, followed by one line per event of that bucket.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportRecord {
+    Bucket(Bucket),
+    Event(Event),
+}
+
 #[test]
 fn test_bucket() {
     let b = Bucket {
@@ -0,0 +1,66 @@
+use std::str::FromStr;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// What to do when an imported bucket already exists
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ImportConflict {
+    /// Abort the whole import
+    #[default]
+    Fail,
+    /// Leave the existing bucket as is and don't import the bucket
+    Skip,
+    /// Add the events which are not already in the existing bucket
+    Merge,
+    /// Import the bucket under a new unused id
+    Rename,
+}
+
+impl FromStr for ImportConflict {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fail" => Ok(ImportConflict::Fail),
+            "skip" => Ok(ImportConflict::Skip),
+            "merge" => Ok(ImportConflict::Merge),
+            "rename" => Ok(ImportConflict::Rename),
+            _ => Err(format!(
+                "Invalid conflict strategy '{s}', needs to be fail, skip, merge or rename"
+            )),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ImportAction {
+    Created,
+    Skipped,
+    Merged,
+    Renamed,
+    /// The bucket already existed, which aborted the import
+    Failed,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+pub struct BucketImportResult {
+    /// ID of the bucket in the import
+    pub bucket_id: String,
+    pub action: ImportAction,
+    /// ID the bucket was imported as, None if it was skipped
+    pub imported_as: Option<String>,
+    pub events_imported: u64,
+    /// Events which were left out, either because the bucket was skipped or because they were
+    /// already in the bucket merged into
+    pub events_skipped: u64,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+pub struct ImportSummary {
+    /// If true nothing was written, the summary only tells what would have been done
+    pub dry_run: bool,
+    pub buckets: Vec<BucketImportResult>,
+}
@@ -1,3 +1,4 @@
+use chrono::{DateTime, Utc};
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
@@ -8,3 +9,24 @@ pub struct Info {
     pub testing: bool,
     pub device_id: String,
 }
+
+/// State of the datastore, as reported by its worker thread
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+pub struct DatastoreHealth {
+    /// Path of the database file, None for in-memory databases
+    pub path: Option<String>,
+    /// Size of the database in bytes
+    pub size: u64,
+    pub db_version: i32,
+    /// When the worker last committed a transaction
+    pub last_commit: Option<DateTime<Utc>>,
+    /// Number of requests waiting to be handled by the worker
+    pub backlog: usize,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+pub struct Health {
+    /// Time it took for a request to make it through the datastore worker, in milliseconds
+    pub round_trip_ms: f64,
+    pub datastore: DatastoreHealth,
+}
@@ -16,19 +16,37 @@ macro_rules! json_map {
     }};
 }
 
+mod audit;
+mod batch;
 mod bucket;
 mod duration;
 mod event;
+mod import;
 mod info;
 mod query;
 mod timeinterval;
 mod tryvec;
 
+pub use self::audit::AuditEntry;
+pub use self::batch::BatchOperation;
+pub use self::batch::BatchResult;
 pub use self::bucket::Bucket;
 pub use self::bucket::BucketMetadata;
 pub use self::bucket::BucketsExport;
+pub use self::bucket::ExportRecord;
 pub use self::event::Event;
+pub use self::import::BucketImportResult;
+pub use self::import::ImportAction;
+pub use self::import::ImportConflict;
+pub use self::import::ImportSummary;
+pub use self::info::DatastoreHealth;
+pub use self::info::Health;
 pub use self::info::Info;
 pub use self::query::Query;
+pub use self::query::QueryModule;
+pub use self::query::QueryParam;
+pub use self::query::QueryValidate;
+pub use self::query::SavedQuery;
+pub use self::query::SavedQueryRun;
 pub use self::timeinterval::TimeInterval;
 pub use self::tryvec::TryVec;
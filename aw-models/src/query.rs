@@ -1,11 +1,58 @@
-use serde::Deserialize;
+use std::collections::HashMap;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
 
 use crate::TimeInterval;
 
 // TODO Implement serialize once TimeInterval has implemented it
-#[derive(Deserialize, Clone, Debug)]
+#[derive(Deserialize, JsonSchema, Clone, Debug)]
 pub struct Query {
     //#[serde(with = "DurationSerialization")]
+    /// Time periods to run the query over, formatted as `<start>/<end>` in rfc3339
+    #[schemars(with = "Vec<String>")]
     pub timeperiods: Vec<TimeInterval>,
     pub query: Vec<String>,
 }
+
+/// A query2 program to check without running it
+#[derive(Deserialize, JsonSchema, Clone, Debug)]
+pub struct QueryValidate {
+    pub query: Vec<String>,
+    /// Names of the parameters the query will be run with
+    #[serde(default)]
+    pub params: Vec<String>,
+}
+
+/// query2 code stored on the server which queries can import by name
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+pub struct QueryModule {
+    pub query: Vec<String>,
+}
+
+/// A query2 program stored on the server which can be run by name
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+pub struct SavedQuery {
+    pub query: Vec<String>,
+    /// Parameters which are bound as variables when the query is run
+    #[serde(default)]
+    pub params: Vec<QueryParam>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+pub struct QueryParam {
+    pub name: String,
+    /// Value used when the parameter is not given, the parameter is required if there is none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default: Option<Value>,
+}
+
+#[derive(Deserialize, JsonSchema, Clone, Debug)]
+pub struct SavedQueryRun {
+    /// Time periods to run the query over, formatted as `<start>/<end>` in rfc3339
+    #[schemars(with = "Vec<String>")]
+    pub timeperiods: Vec<TimeInterval>,
+    #[serde(default)]
+    pub params: HashMap<String, Value>,
+}
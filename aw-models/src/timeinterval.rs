@@ -10,7 +10,7 @@ use chrono::Utc;
 
 // TODO: Implement serialize
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct TimeInterval {
     start: DateTime<Utc>,
     end: DateTime<Utc>,
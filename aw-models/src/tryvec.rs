@@ -22,6 +22,11 @@ impl<T: JsonSchema> JsonSchema for TryParse<T> {
     fn json_schema(gen: &mut SchemaGenerator) -> Schema {
         gen.subschema_for::<T>()
     }
+
+    // Inline the schema of T instead of emitting a "Try<...>" definition
+    fn is_referenceable() -> bool {
+        false
+    }
 }
 
 impl<'de, T: DeserializeOwned + JsonSchema> Deserialize<'de> for TryParse<T> {
@@ -17,7 +17,9 @@ macro_rules! json_map {
 
 #[cfg(test)]
 mod query_benchmarks {
-    use chrono::Duration;
+    use std::sync::atomic::AtomicBool;
+
+    use chrono::{DateTime, Duration, Utc};
     use criterion::Criterion;
     use serde_json::json;
     use serde_json::Map;
@@ -28,6 +30,8 @@ mod query_benchmarks {
     use aw_models::BucketMetadata;
     use aw_models::Event;
     use aw_models::TimeInterval;
+    use aw_query::QueryLimits;
+    use aw_query::VarEnv;
 
     static BUCKETNAME: &str = "testbucket";
     static TIME_INTERVAL: &str = "1980-01-01T00:00:00Z/2080-01-02T00:00:00Z";
@@ -102,11 +106,55 @@ mod query_benchmarks {
             })
         });
     }
+
+    pub fn bench_timeperiods(c: &mut Criterion) {
+        let ds = setup_datastore();
+        create_bucket(&ds, BUCKETNAME.to_string());
+
+        // 30 days with 2000 events each
+        let start: DateTime<Utc> = "2000-01-01T00:00:00Z".parse().unwrap();
+        let mut event_list = Vec::new();
+        for i in 0..(30 * 2000) {
+            event_list.push(Event {
+                id: None,
+                timestamp: start + Duration::seconds(i * 43),
+                duration: Duration::seconds(40),
+                data: json_map! {"number": i % 20},
+            });
+        }
+        ds.insert_events(BUCKETNAME, &event_list).unwrap();
+
+        let intervals: Vec<TimeInterval> = (0..30)
+            .map(|day| {
+                TimeInterval::new(start + Duration::days(day), start + Duration::days(day + 1))
+            })
+            .collect();
+        let code = "
+            events = query_bucket(\"testbucket\");
+            events = flood(events);
+            events = merge_events_by_keys(events, [\"number\"]);
+            return sort_by_duration(events);
+        ";
+        c.bench_function("bench 30 timeperiods", |b| {
+            b.iter(|| {
+                aw_query::query_timeperiods(
+                    code,
+                    &intervals,
+                    &ds,
+                    &VarEnv::new(),
+                    &QueryLimits::default(),
+                    &AtomicBool::new(false),
+                )
+                .unwrap();
+            })
+        });
+    }
 }
 
 criterion_group!(
     benches,
     query_benchmarks::bench_assign,
-    query_benchmarks::bench_many_events
+    query_benchmarks::bench_many_events,
+    query_benchmarks::bench_timeperiods
 );
 criterion_main!(benches);
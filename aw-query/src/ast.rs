@@ -22,12 +22,37 @@ pub enum Expr_ {
     Mod(Box<Expr>, Box<Expr>),
 
     Equal(Box<Expr>, Box<Expr>),
+    NotEqual(Box<Expr>, Box<Expr>),
+    Less(Box<Expr>, Box<Expr>),
+    LessEqual(Box<Expr>, Box<Expr>),
+    Greater(Box<Expr>, Box<Expr>),
+    GreaterEqual(Box<Expr>, Box<Expr>),
+
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
 
     Var(String),
+    // e[index], e.attribute is e["attribute"]
+    Index(Box<Expr>, Box<Expr>),
+    // e[start:end]
+    Slice(Box<Expr>, Option<Box<Expr>>, Option<Box<Expr>>),
     Assign(String, Box<Expr>),
     Function(String, Box<Expr>),
+    // e(args), where e evaluates to a function
+    Call(Box<Expr>, Box<Expr>),
     If(Vec<(Box<Expr>, Vec<Expr>)>),
+    // for var in list { block }
+    For(String, Box<Expr>, Vec<Expr>),
+    // [expr for var in list if cond]
+    Comprehension(Box<Expr>, String, Box<Expr>, Option<Box<Expr>>),
+    // def name(params) { block }
+    Def(String, Vec<String>, Vec<Expr>),
+    // fn(params) => expr, kept as the block { return expr; }
+    Lambda(Vec<String>, Vec<Expr>),
     Return(Box<Expr>),
+    // import "name"; binds the variables defined by the module as the dict name
+    Import(String),
 
     Bool(bool),
     Number(f64),
@@ -35,3 +60,60 @@ pub enum Expr_ {
     List(Vec<Expr>),
     Dict(HashMap<String, Expr>),
 }
+
+impl Expr {
+    /// Call f on this expression and every expression inside of it
+    pub fn visit<F: FnMut(&Expr)>(&self, f: &mut F) {
+        use Expr_::*;
+        f(self);
+        match &self.node {
+            Add(a, b)
+            | Sub(a, b)
+            | Mul(a, b)
+            | Div(a, b)
+            | Mod(a, b)
+            | Equal(a, b)
+            | NotEqual(a, b)
+            | Less(a, b)
+            | LessEqual(a, b)
+            | Greater(a, b)
+            | GreaterEqual(a, b)
+            | And(a, b)
+            | Or(a, b)
+            | Index(a, b)
+            | Call(a, b) => {
+                a.visit(f);
+                b.visit(f);
+            }
+            Not(e) | Assign(_, e) | Function(_, e) | Return(e) => e.visit(f),
+            If(ifs) => {
+                for (cond, block) in ifs {
+                    cond.visit(f);
+                    block.iter().for_each(|e| e.visit(f));
+                }
+            }
+            For(_, list, block) => {
+                list.visit(f);
+                block.iter().for_each(|e| e.visit(f));
+            }
+            Slice(e, start, end) => {
+                e.visit(f);
+                for bound in [start, end].into_iter().flatten() {
+                    bound.visit(f);
+                }
+            }
+            Comprehension(e, _, list, cond) => {
+                e.visit(f);
+                list.visit(f);
+                if let Some(cond) = cond {
+                    cond.visit(f);
+                }
+            }
+            Def(_, _, block) | Lambda(_, block) | List(block) => {
+                block.iter().for_each(|e| e.visit(f))
+            }
+            Dict(d) => d.values().for_each(|e| e.visit(f)),
+            Var(_) | Import(_) | Bool(_) | Number(_) | String(_) => (),
+        }
+    }
+}
@@ -0,0 +1,340 @@
+use std::collections::HashMap;
+
+use crate::ast::*;
+use crate::functions::{self, Type};
+use crate::lexer::{Lexer, Span};
+use crate::parser;
+use crate::{parse_error, ErrorLocation, QueryError};
+
+/// How severe a problem found by validate is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    /// The query fails when it is run
+    Error,
+    /// The query runs but likely not as intended
+    Warning,
+}
+
+/// A problem in a query found without running it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub location: Option<ErrorLocation>,
+}
+
+/// What a name refers to while checking a query
+#[derive(Debug, Clone)]
+enum Symbol {
+    Value(Type),
+    Builtin,
+    /// A function defined in the query taking this many parameters
+    Closure(usize),
+}
+
+type Scope = HashMap<String, Symbol>;
+
+/// Find problems in a query without running it or reading any events
+///
+/// Variables and functions have to be defined before they are used, and the arguments of builtins
+/// are checked against their signatures as far as their types are known without running the
+/// query. params are the names of the parameters the query is run with and bucket_ids are the
+/// buckets which exist, queried buckets which are not among them are warned about.
+pub fn validate(code: &str, params: &[String], bucket_ids: &[String]) -> Vec<Diagnostic> {
+    let program = match parser::parse(Lexer::new(code)) {
+        Ok(program) => program,
+        Err(e) => {
+            let e = parse_error(code, e);
+            let message = match e.error {
+                QueryError::ParsingError(message) => message,
+                error => error.to_string(),
+            };
+            return vec![Diagnostic {
+                severity: Severity::Error,
+                message,
+                location: e.location,
+            }];
+        }
+    };
+
+    let mut checker = Checker {
+        code,
+        bucket_ids,
+        diagnostics: Vec::new(),
+    };
+    let mut builtins = HashMap::new();
+    functions::fill_env(&mut builtins);
+    let mut scope: Scope = builtins
+        .keys()
+        .map(|name| {
+            debug_assert!(
+                functions::signature(name).is_some(),
+                "builtin {name} has no signature"
+            );
+            (name.clone(), Symbol::Builtin)
+        })
+        .collect();
+    scope.insert("TIMEINTERVAL".to_string(), Symbol::Value(Type::String));
+    for param in params {
+        if scope.contains_key(param) {
+            checker.report(
+                Severity::Error,
+                format!("Parameter {param} would shadow a builtin"),
+                None,
+            );
+        }
+        scope.insert(param.clone(), Symbol::Value(Type::Any));
+    }
+
+    checker.check_block(&mut scope, &program.stmts);
+    if !program.stmts.iter().any(returns) {
+        checker.report(
+            Severity::Error,
+            "The query never returns a value".to_string(),
+            None,
+        );
+    }
+    checker.diagnostics
+}
+
+/// Whether a statement outside of any function contains a return
+fn returns(stmt: &Expr) -> bool {
+    match &stmt.node {
+        Expr_::Return(_) => true,
+        Expr_::If(ifs) => ifs.iter().any(|(_, block)| block.iter().any(returns)),
+        Expr_::For(_, _, block) => block.iter().any(returns),
+        _ => false,
+    }
+}
+
+struct Checker<'a> {
+    code: &'a str,
+    bucket_ids: &'a [String],
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Checker<'_> {
+    fn report(&mut self, severity: Severity, message: String, span: Option<Span>) {
+        self.diagnostics.push(Diagnostic {
+            severity,
+            message,
+            location: span.map(|span| ErrorLocation::new(self.code, span)),
+        });
+    }
+
+    fn check_block(&mut self, scope: &mut Scope, block: &[Expr]) {
+        for expr in block {
+            self.check_expr(scope, expr);
+        }
+    }
+
+    /// Check a function body, which sees the names defined before the function and its params
+    fn check_closure(&mut self, scope: &Scope, params: &[String], body: &[Expr]) {
+        let mut inner = scope.clone();
+        for param in params {
+            inner.insert(param.clone(), Symbol::Value(Type::Any));
+        }
+        self.check_block(&mut inner, body);
+    }
+
+    /// Check an expression and return the type it evaluates to
+    fn check_expr(&mut self, scope: &mut Scope, expr: &Expr) -> Type {
+        use Expr_::*;
+        match &expr.node {
+            Add(a, b) => match (self.check_expr(scope, a), self.check_expr(scope, b)) {
+                (Type::Number, Type::Number) => Type::Number,
+                (Type::String, Type::String) => Type::String,
+                (Type::List, Type::List) => Type::List,
+                _ => Type::Any,
+            },
+            Sub(a, b) | Mul(a, b) | Div(a, b) | Mod(a, b) => {
+                match (self.check_expr(scope, a), self.check_expr(scope, b)) {
+                    (Type::Number, Type::Number) => Type::Number,
+                    _ => Type::Any,
+                }
+            }
+            Equal(a, b)
+            | NotEqual(a, b)
+            | Less(a, b)
+            | LessEqual(a, b)
+            | Greater(a, b)
+            | GreaterEqual(a, b)
+            | And(a, b)
+            | Or(a, b) => {
+                self.check_expr(scope, a);
+                self.check_expr(scope, b);
+                Type::Bool
+            }
+            Not(e) => {
+                self.check_expr(scope, e);
+                Type::Bool
+            }
+            Var(name) => match scope.get(name) {
+                Some(Symbol::Value(t)) => *t,
+                Some(Symbol::Builtin) | Some(Symbol::Closure(_)) => Type::Function,
+                None => {
+                    self.report(
+                        Severity::Error,
+                        format!("Variable {name} is not defined"),
+                        Some(expr.span),
+                    );
+                    Type::Any
+                }
+            },
+            Index(e, index) => {
+                self.check_expr(scope, e);
+                self.check_expr(scope, index);
+                Type::Any
+            }
+            Slice(e, start, end) => {
+                let t = self.check_expr(scope, e);
+                for bound in [start, end].into_iter().flatten() {
+                    self.check_expr(scope, bound);
+                }
+                match t {
+                    Type::String | Type::List => t,
+                    _ => Type::Any,
+                }
+            }
+            Assign(name, e) => {
+                let t = self.check_expr(scope, e);
+                let symbol = match &e.node {
+                    Lambda(params, _) => Symbol::Closure(params.len()),
+                    _ => Symbol::Value(t),
+                };
+                scope.insert(name.clone(), symbol);
+                Type::None
+            }
+            Function(name, args) => self.check_call(scope, expr.span, name, args),
+            Call(fun, args) => {
+                self.check_expr(scope, fun);
+                self.check_expr(scope, args);
+                Type::Any
+            }
+            Def(name, params, body) => {
+                scope.insert(name.clone(), Symbol::Closure(params.len()));
+                self.check_closure(scope, params, body);
+                Type::None
+            }
+            Lambda(params, body) => {
+                self.check_closure(scope, params, body);
+                Type::Function
+            }
+            If(ifs) => {
+                for (cond, block) in ifs {
+                    self.check_expr(scope, cond);
+                    self.check_block(scope, block);
+                }
+                Type::None
+            }
+            For(var, list, block) => {
+                self.check_expr(scope, list);
+                scope.insert(var.clone(), Symbol::Value(Type::Any));
+                self.check_block(scope, block);
+                Type::None
+            }
+            Comprehension(e, var, list, cond) => {
+                self.check_expr(scope, list);
+                let mut inner = scope.clone();
+                inner.insert(var.clone(), Symbol::Value(Type::Any));
+                self.check_expr(&mut inner, e);
+                if let Some(cond) = cond {
+                    self.check_expr(&mut inner, cond);
+                }
+                Type::List
+            }
+            Return(e) => {
+                self.check_expr(scope, e);
+                Type::None
+            }
+            // What a module defines is only known once it has been loaded
+            Import(name) => {
+                scope.insert(name.clone(), Symbol::Value(Type::Dict));
+                Type::None
+            }
+            Bool(_) => Type::Bool,
+            Number(_) => Type::Number,
+            String(_) => Type::String,
+            List(list) => {
+                self.check_block(scope, list);
+                Type::List
+            }
+            Dict(d) => {
+                for e in d.values() {
+                    self.check_expr(scope, e);
+                }
+                Type::Dict
+            }
+        }
+    }
+
+    fn check_call(&mut self, scope: &mut Scope, span: Span, name: &str, args: &Expr) -> Type {
+        let args = match &args.node {
+            Expr_::List(args) => args,
+            _ => unreachable!(),
+        };
+        let arg_types: Vec<Type> = args.iter().map(|e| self.check_expr(scope, e)).collect();
+        match scope.get(name) {
+            Some(Symbol::Builtin) => {
+                let signature = match functions::signature(name) {
+                    Some(signature) => signature,
+                    None => return Type::Any,
+                };
+                if let Err(message) = signature.check(name, &arg_types) {
+                    self.report(Severity::Error, message, Some(span));
+                }
+                self.check_bucket(name, args);
+                signature.returns
+            }
+            Some(Symbol::Closure(nparams)) => {
+                if *nparams != args.len() {
+                    let message = format!(
+                        "Function {} expects {} parameters, got {}",
+                        name,
+                        nparams,
+                        args.len()
+                    );
+                    self.report(Severity::Error, message, Some(span));
+                }
+                Type::Any
+            }
+            Some(Symbol::Value(Type::Any)) | Some(Symbol::Value(Type::Function)) => Type::Any,
+            Some(Symbol::Value(t)) => {
+                let message = format!("{name} is a {t:?}, not a function");
+                self.report(Severity::Error, message, Some(span));
+                Type::Any
+            }
+            None => {
+                let message = format!("Function {name} is not defined");
+                self.report(Severity::Error, message, Some(span));
+                Type::Any
+            }
+        }
+    }
+
+    /// Warn about buckets which are queried by their literal name but do not exist
+    fn check_bucket(&mut self, name: &str, args: &[Expr]) {
+        let (bucket, span) = match args.first() {
+            Some(Expr {
+                node: Expr_::String(bucket),
+                span,
+            }) => (bucket, *span),
+            _ => return,
+        };
+        let exists = match name {
+            "query_bucket" => self.bucket_ids.iter().any(|id| id == bucket),
+            "find_bucket" => self
+                .bucket_ids
+                .iter()
+                .any(|id| id.starts_with(bucket.as_str())),
+            _ => return,
+        };
+        if !exists {
+            let message = match name {
+                "query_bucket" => format!("There is no bucket named {bucket}"),
+                _ => format!("There is no bucket starting with {bucket}"),
+            };
+            self.report(Severity::Warning, message, Some(span));
+        }
+    }
+}
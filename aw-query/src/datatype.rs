@@ -1,17 +1,19 @@
+use std::cmp::Ordering;
 use std::collections::HashMap;
 use std::fmt;
+use std::sync::Arc;
 
 use super::functions;
 use super::QueryError;
+use crate::ast::Expr;
+use crate::VarEnv;
 use aw_models::Event;
 use aw_transform::classify::{RegexRule, Rule};
 
-use serde::{Serialize, Serializer};
+use serde::{ser, Serialize, Serializer};
 use serde_json::value::Value;
 use serde_json::Number;
 
-// TODO: greater/less comparisons
-
 #[derive(Clone, Serialize)]
 #[serde(untagged)]
 pub enum DataType {
@@ -24,6 +26,36 @@ pub enum DataType {
     Dict(HashMap<String, DataType>),
     #[serde(serialize_with = "serialize_function")]
     Function(String, functions::QueryFn),
+    #[serde(serialize_with = "serialize_closure")]
+    Closure(Arc<Closure>),
+}
+
+/// A function defined in a query, either with def or as a lambda
+pub struct Closure {
+    /// Name of the function, None for lambdas
+    pub(crate) name: Option<String>,
+    pub(crate) params: Vec<String>,
+    pub(crate) body: Vec<Expr>,
+    /// The variables used by the body, as they were when the function was defined
+    pub(crate) env: VarEnv,
+    /// Whether the function was defined by an imported module rather than the query
+    pub(crate) from_module: bool,
+}
+
+impl Closure {
+    pub fn name(&self) -> &str {
+        self.name.as_deref().unwrap_or("<lambda>")
+    }
+}
+
+fn serialize_closure<S>(closure: &Arc<Closure>, _serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    Err(ser::Error::custom(format!(
+        "Function {} cannot be part of the result of a query",
+        closure.name()
+    )))
 }
 
 #[allow(clippy::trivially_copy_pass_by_ref)]
@@ -53,6 +85,7 @@ impl fmt::Debug for DataType {
             DataType::List(l) => write!(f, "List({l:?})"),
             DataType::Dict(d) => write!(f, "Dict({d:?})"),
             DataType::Function(name, _fun) => write!(f, "Function({name})"),
+            DataType::Closure(closure) => write!(f, "Closure({})", closure.name()),
         }
     }
 }
@@ -76,6 +109,122 @@ impl DataType {
             ))),
         }
     }
+
+    /// Get an item of a list or string by position, the value of a key of a dict or a field of an
+    /// event, like value[key]
+    pub fn index(self, key: &DataType) -> Result<DataType, QueryError> {
+        match (self, key) {
+            (DataType::List(mut l), DataType::Number(n)) => {
+                let i = list_index(l.len(), *n)?;
+                Ok(l.swap_remove(i))
+            }
+            (DataType::String(s), DataType::Number(n)) => {
+                let i = list_index(s.chars().count(), *n)?;
+                Ok(DataType::String(s.chars().nth(i).unwrap().to_string()))
+            }
+            (DataType::Dict(mut d), DataType::String(k)) => match d.remove(k) {
+                Some(value) => Ok(value),
+                None => Err(QueryError::IndexError(format!("Dict has no key {k}"))),
+            },
+            (DataType::Event(e), DataType::String(field)) => match field.as_str() {
+                "timestamp" => Ok(DataType::from(&serde_json::json!(e.timestamp))),
+                "duration" => Ok(DataType::Number(
+                    (e.duration.num_milliseconds() as f64) / 1000.0,
+                )),
+                "data" => Ok(DataType::from(&Value::Object(e.data))),
+                _ => Err(QueryError::IndexError(format!(
+                    "Event has no field {field}, only timestamp, duration and data"
+                ))),
+            },
+            (value, key) => Err(QueryError::InvalidType(format!(
+                "Cannot index {value:?} with {key:?}"
+            ))),
+        }
+    }
+
+    /// Get the items of a list or string from start up to but not including end, like
+    /// value[start:end]
+    pub fn slice(
+        self,
+        start: Option<&DataType>,
+        end: Option<&DataType>,
+    ) -> Result<DataType, QueryError> {
+        let len = match &self {
+            DataType::List(l) => l.len(),
+            DataType::String(s) => s.chars().count(),
+            value => {
+                return Err(QueryError::InvalidType(format!(
+                    "Cannot slice {value:?}, only lists and strings"
+                )))
+            }
+        };
+        let start = slice_bound(len, start, 0)?;
+        let end = slice_bound(len, end, len)?.max(start);
+        match self {
+            DataType::List(l) => Ok(DataType::List(
+                l.into_iter().skip(start).take(end - start).collect(),
+            )),
+            DataType::String(s) => Ok(DataType::String(
+                s.chars().skip(start).take(end - start).collect(),
+            )),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Order two numbers or two strings, raising an error for any other values
+    pub fn query_cmp(&self, other: &DataType) -> Result<Ordering, QueryError> {
+        match (self, other) {
+            (DataType::Number(n1), DataType::Number(n2)) => match n1.partial_cmp(n2) {
+                Some(ordering) => Ok(ordering),
+                None => Err(QueryError::MathError(format!(
+                    "Cannot compare {n1} with {n2}"
+                ))),
+            },
+            (DataType::String(s1), DataType::String(s2)) => Ok(s1.cmp(s2)),
+            (DataType::Bool(_), DataType::Bool(_)) => Err(QueryError::InvalidType(
+                "Cannot compare booleans with <, <=, > or >=".to_string(),
+            )),
+            _ => Err(QueryError::InvalidType(format!(
+                "Cannot compare values of types {self:?} and {other:?}, only numbers and strings can be ordered"
+            ))),
+        }
+    }
+}
+
+fn whole_number(n: f64) -> Result<i64, QueryError> {
+    if n.fract() != 0.0 {
+        return Err(QueryError::InvalidType(format!(
+            "Index must be a whole number, got {n}"
+        )));
+    }
+    Ok(n as i64)
+}
+
+/// Position of index in a list of length len, negative indexes count from the end
+fn list_index(len: usize, index: f64) -> Result<usize, QueryError> {
+    let i = whole_number(index)?;
+    let pos = if i < 0 { len as i64 + i } else { i };
+    if pos < 0 || pos >= len as i64 {
+        return Err(QueryError::IndexError(format!(
+            "Index {i} is out of range for length {len}"
+        )));
+    }
+    Ok(pos as usize)
+}
+
+/// Position of a bound of a slice of a list of length len, clamped to the list
+fn slice_bound(len: usize, bound: Option<&DataType>, default: usize) -> Result<usize, QueryError> {
+    let i = match bound {
+        None => return Ok(default),
+        Some(DataType::Number(n)) => whole_number(*n)?,
+        Some(value) => {
+            return Err(QueryError::InvalidType(format!(
+                "Slice bounds must be numbers, got {value:?}"
+            )))
+        }
+    };
+    let pos = if i < 0 { len as i64 + i } else { i };
+    Ok(pos.clamp(0, len as i64) as usize)
 }
 
 /* Required for query_eq when comparing two dicts */
@@ -257,6 +406,24 @@ impl TryFrom<&DataType> for Value {
     }
 }
 
+impl From<&Value> for DataType {
+    fn from(value: &Value) -> Self {
+        match value {
+            Value::Null => DataType::None(),
+            Value::Bool(b) => DataType::Bool(*b),
+            // All numbers in JSON can be represented as a f64, possibly with loss of precision
+            Value::Number(n) => DataType::Number(n.as_f64().unwrap()),
+            Value::String(s) => DataType::String(s.to_string()),
+            Value::Array(a) => DataType::List(a.iter().map(DataType::from).collect()),
+            Value::Object(o) => DataType::Dict(
+                o.iter()
+                    .map(|(k, v)| (k.to_string(), DataType::from(v)))
+                    .collect(),
+            ),
+        }
+    }
+}
+
 impl TryFrom<&DataType> for Vec<Value> {
     type Error = QueryError;
     fn try_from(value: &DataType) -> Result<Self, Self::Error> {
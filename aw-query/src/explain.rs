@@ -0,0 +1,174 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use aw_models::Event;
+
+use crate::lexer::Span;
+use crate::limits::json_size;
+use crate::DataType;
+
+/// Where a query spent its time and how much it loaded from the datastore
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Explanation {
+    /// Seconds the whole query took, including parsing it
+    pub duration: f64,
+    pub events_loaded: u64,
+    /// Size of the events loaded from the datastore once serialized as JSON
+    pub bytes_loaded: u64,
+    /// Statements which were executed, in the order they appear in the code
+    pub statements: Vec<StatementExplanation>,
+    /// Builtins which were called, the one which took the longest first
+    pub builtins: Vec<BuiltinExplanation>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StatementExplanation {
+    pub line: usize,
+    /// The code of the statement, only its first line if it spans several
+    pub code: String,
+    pub executions: u64,
+    /// Seconds spent on all executions, including the statements nested in it
+    pub duration: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BuiltinExplanation {
+    pub name: String,
+    pub calls: u64,
+    /// Seconds spent in all calls
+    pub duration: f64,
+    /// Events passed to the builtin over all calls
+    pub events_in: u64,
+    /// Events returned by the builtin over all calls
+    pub events_out: u64,
+}
+
+struct StatementStats {
+    span: Span,
+    executions: u64,
+    duration: Duration,
+}
+
+#[derive(Default)]
+struct BuiltinStats {
+    calls: u64,
+    duration: Duration,
+    events_in: u64,
+    events_out: u64,
+}
+
+#[derive(Default)]
+struct Stats {
+    events_loaded: u64,
+    bytes_loaded: u64,
+    // Statements are told apart by where they start in the code
+    statements: HashMap<usize, StatementStats>,
+    builtins: HashMap<String, BuiltinStats>,
+}
+
+/// Collects an Explanation while a query runs
+pub struct Explainer {
+    start: Instant,
+    stats: RefCell<Stats>,
+}
+
+impl Default for Explainer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Explainer {
+    pub fn new() -> Explainer {
+        Explainer {
+            start: Instant::now(),
+            stats: RefCell::new(Stats::default()),
+        }
+    }
+
+    pub fn statement(&self, span: Span, duration: Duration) {
+        let mut stats = self.stats.borrow_mut();
+        let statement = stats
+            .statements
+            .entry(span.lo)
+            .or_insert_with(|| StatementStats {
+                span,
+                executions: 0,
+                duration: Duration::ZERO,
+            });
+        statement.executions += 1;
+        statement.duration += duration;
+    }
+
+    pub fn builtin(&self, name: &str, duration: Duration, events_in: usize, events_out: usize) {
+        let mut stats = self.stats.borrow_mut();
+        let builtin = stats.builtins.entry(name.to_string()).or_default();
+        builtin.calls += 1;
+        builtin.duration += duration;
+        builtin.events_in += events_in as u64;
+        builtin.events_out += events_out as u64;
+    }
+
+    pub fn loaded(&self, events: &[Event]) {
+        let bytes: usize = events.iter().map(json_size).sum();
+        let mut stats = self.stats.borrow_mut();
+        stats.events_loaded += events.len() as u64;
+        stats.bytes_loaded += bytes as u64;
+    }
+
+    pub fn finish(self, code: &str) -> Explanation {
+        let duration = self.start.elapsed().as_secs_f64();
+        let stats = self.stats.into_inner();
+
+        let mut statements: Vec<StatementStats> = stats.statements.into_values().collect();
+        statements.sort_by_key(|statement| statement.span.lo);
+        let statements = statements
+            .into_iter()
+            .map(|statement| StatementExplanation {
+                line: statement.span.line,
+                code: code[statement.span.lo..statement.span.hi]
+                    .lines()
+                    .next()
+                    .unwrap_or("")
+                    .trim()
+                    .to_string(),
+                executions: statement.executions,
+                duration: statement.duration.as_secs_f64(),
+            })
+            .collect();
+
+        let mut builtins: Vec<BuiltinExplanation> = stats
+            .builtins
+            .into_iter()
+            .map(|(name, builtin)| BuiltinExplanation {
+                name,
+                calls: builtin.calls,
+                duration: builtin.duration.as_secs_f64(),
+                events_in: builtin.events_in,
+                events_out: builtin.events_out,
+            })
+            .collect();
+        builtins.sort_by(|a, b| b.duration.total_cmp(&a.duration));
+
+        Explanation {
+            duration,
+            events_loaded: stats.events_loaded,
+            bytes_loaded: stats.bytes_loaded,
+            statements,
+            builtins,
+        }
+    }
+}
+
+/// The number of events in data, including the ones in lists and dicts inside of it
+pub fn count_events(data: &DataType) -> usize {
+    match data {
+        DataType::Event(_) => 1,
+        DataType::List(list) => list.iter().map(count_events).sum(),
+        DataType::Dict(dict) => dict.values().map(count_events).sum(),
+        _ => 0,
+    }
+}
@@ -1,10 +1,10 @@
 use crate::DataType;
+use crate::QueryContext;
 use crate::QueryError;
 use crate::VarEnv;
-use aw_datastore::Datastore;
 
 pub type QueryFn =
-    fn(args: Vec<DataType>, env: &VarEnv, ds: &Datastore) -> Result<DataType, QueryError>;
+    fn(args: Vec<DataType>, env: &VarEnv, ctx: &QueryContext) -> Result<DataType, QueryError>;
 
 pub fn fill_env(env: &mut VarEnv) {
     env.insert(
@@ -113,22 +113,227 @@ pub fn fill_env(env: &mut VarEnv) {
         "union_no_overlap".to_string(),
         DataType::Function("union_no_overlap".into(), qfunctions::union_no_overlap),
     );
+    env.insert(
+        "len".to_string(),
+        DataType::Function("len".into(), qfunctions::len),
+    );
+    env.insert(
+        "lower".to_string(),
+        DataType::Function("lower".into(), qfunctions::lower),
+    );
+    env.insert(
+        "upper".to_string(),
+        DataType::Function("upper".into(), qfunctions::upper),
+    );
+    env.insert(
+        "replace".to_string(),
+        DataType::Function("replace".into(), qfunctions::replace),
+    );
+    env.insert(
+        "regex_replace".to_string(),
+        DataType::Function("regex_replace".into(), qfunctions::regex_replace),
+    );
+    env.insert(
+        "regex_extract".to_string(),
+        DataType::Function("regex_extract".into(), qfunctions::regex_extract),
+    );
+    env.insert(
+        "split".to_string(),
+        DataType::Function("split".into(), qfunctions::split),
+    );
+    env.insert(
+        "format".to_string(),
+        DataType::Function("format".into(), qfunctions::format),
+    );
+    env.insert(
+        "map".to_string(),
+        DataType::Function("map".into(), qfunctions::map),
+    );
+    env.insert(
+        "filter".to_string(),
+        DataType::Function("filter".into(), qfunctions::filter),
+    );
+    env.insert(
+        "reduce".to_string(),
+        DataType::Function("reduce".into(), qfunctions::reduce),
+    );
+    env.insert(
+        "sort_by".to_string(),
+        DataType::Function("sort_by".into(), qfunctions::sort_by),
+    );
+    env.insert(
+        "now".to_string(),
+        DataType::Function("now".into(), qfunctions::now),
+    );
+    env.insert(
+        "timeinterval_start".to_string(),
+        DataType::Function("timeinterval_start".into(), qfunctions::timeinterval_start),
+    );
+    env.insert(
+        "timeinterval_end".to_string(),
+        DataType::Function("timeinterval_end".into(), qfunctions::timeinterval_end),
+    );
+    env.insert(
+        "date_add".to_string(),
+        DataType::Function("date_add".into(), qfunctions::date_add),
+    );
+    env.insert(
+        "date_diff".to_string(),
+        DataType::Function("date_diff".into(), qfunctions::date_diff),
+    );
+    env.insert(
+        "split_by_period".to_string(),
+        DataType::Function("split_by_period".into(), qfunctions::split_by_period),
+    );
+}
+
+/// The type of a value as far as it is known without running a query
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Type {
+    Any,
+    None,
+    Bool,
+    Number,
+    String,
+    List,
+    Dict,
+    Function,
+}
+
+impl Type {
+    /// Whether a value of type other can be passed where a value of this type is expected
+    pub fn accepts(self, other: Type) -> bool {
+        self == Type::Any || other == Type::Any || self == other
+    }
+}
+
+/// The arguments a builtin accepts and the type of what it returns
+pub struct Signature {
+    /// Every accepted list of arguments
+    pub overloads: &'static [&'static [Type]],
+    /// Whether the last argument of an overload can be repeated any number of times, or left out
+    pub variadic: bool,
+    pub returns: Type,
+}
+
+/// The signature of the builtin called name, every builtin in fill_env has one
+pub fn signature(name: &str) -> Option<Signature> {
+    use Type::*;
+    let fixed = |overloads, returns| Signature {
+        overloads,
+        variadic: false,
+        returns,
+    };
+    let signature = match name {
+        "print" => Signature {
+            overloads: &[&[Any]],
+            variadic: true,
+            returns: None,
+        },
+        "query_bucket" => fixed(&[&[String]], List),
+        "query_bucket_names" => fixed(&[&[]], List),
+        "find_bucket" => fixed(&[&[String], &[String, String]], String),
+        "contains" => fixed(&[&[List, Any], &[Dict, String]], Bool),
+        "flood" | "sort_by_duration" | "sort_by_timestamp" | "split_url_events" => {
+            fixed(&[&[List]], List)
+        }
+        "categorize" | "tag" | "merge_events_by_keys" => fixed(&[&[List, List]], List),
+        "filter_period_intersect" | "period_union" | "union_no_overlap" => {
+            fixed(&[&[List, List]], List)
+        }
+        "limit_events" => fixed(&[&[List, Number]], List),
+        "sum_durations" => fixed(&[&[List]], Number),
+        "chunk_events_by_key" => fixed(&[&[List, String]], List),
+        "filter_keyvals" | "exclude_keyvals" => fixed(&[&[List, String, List]], List),
+        "filter_keyvals_regex" => fixed(&[&[List, String, String]], List),
+        "concat" => Signature {
+            overloads: &[&[List]],
+            variadic: true,
+            returns: List,
+        },
+        "len" => fixed(&[&[List], &[Dict], &[String]], Number),
+        "lower" | "upper" => fixed(&[&[String], &[List, String]], Any),
+        "replace" | "regex_replace" => fixed(
+            &[&[String, String, String], &[List, String, String, String]],
+            Any,
+        ),
+        "regex_extract" => fixed(&[&[String, String], &[List, String, String, String]], Any),
+        "split" => fixed(&[&[String, String]], List),
+        "format" => Signature {
+            overloads: &[&[String, Any]],
+            variadic: true,
+            returns: String,
+        },
+        "map" | "filter" | "sort_by" => fixed(&[&[List, Function]], List),
+        "reduce" => fixed(&[&[List, Function, Any]], Any),
+        "now" | "timeinterval_start" | "timeinterval_end" => fixed(&[&[]], String),
+        "date_add" => fixed(&[&[String, Number], &[String, String]], String),
+        "date_diff" => fixed(&[&[String, String]], Number),
+        "split_by_period" => fixed(&[&[List, String, Number]], Dict),
+        _ => return Option::None,
+    };
+    Some(signature)
+}
+
+impl Signature {
+    /// Check arguments of the given types against the overloads, returning why none accepts them
+    pub fn check(&self, name: &str, args: &[Type]) -> Result<(), String> {
+        let accepts = |overload: &[Type]| {
+            let arity_ok = match self.variadic {
+                true => args.len() + 1 >= overload.len(),
+                false => args.len() == overload.len(),
+            };
+            arity_ok
+                && args.iter().enumerate().all(|(i, arg)| {
+                    let expected = overload.get(i).or(overload.last());
+                    expected.is_some_and(|expected| expected.accepts(*arg))
+                })
+        };
+        if self.overloads.iter().any(|overload| accepts(overload)) {
+            return Ok(());
+        }
+        let expected: Vec<String> = self
+            .overloads
+            .iter()
+            .map(|overload| {
+                let mut types: Vec<String> = overload.iter().map(|t| format!("{t:?}")).collect();
+                if self.variadic {
+                    if let Some(last) = types.last_mut() {
+                        last.push_str("...");
+                    }
+                }
+                format!("{}({})", name, types.join(", "))
+            })
+            .collect();
+        let got: Vec<String> = args.iter().map(|t| format!("{t:?}")).collect();
+        Err(format!(
+            "{}({}) does not match {}",
+            name,
+            got.join(", "),
+            expected.join(" or ")
+        ))
+    }
 }
 
 mod qfunctions {
-    use aw_datastore::Datastore;
+    use std::cmp::Ordering;
+    use std::collections::HashMap;
+
     use aw_models::Event;
     use aw_transform::classify::Rule;
+    use chrono::{Duration, SecondsFormat, Utc};
 
     use super::validate;
+    use crate::interpret::call_function;
     use crate::DataType;
+    use crate::QueryContext;
     use crate::QueryError;
     use crate::VarEnv;
 
     pub fn print(
         args: Vec<DataType>,
         _env: &VarEnv,
-        _ds: &Datastore,
+        _ctx: &QueryContext,
     ) -> Result<DataType, QueryError> {
         for arg in args {
             info!("{:?}", arg);
@@ -139,7 +344,7 @@ mod qfunctions {
     pub fn query_bucket(
         args: Vec<DataType>,
         env: &VarEnv,
-        ds: &Datastore,
+        ctx: &QueryContext,
     ) -> Result<DataType, QueryError> {
         // Typecheck
         validate::args_length(&args, 1)?;
@@ -147,11 +352,16 @@ mod qfunctions {
         let bucket_id: String = (&args[0]).try_into()?;
         let interval = validate::get_timeinterval(env)?;
 
-        let events = match ds.get_events(
+        // Fetch one more event than allowed so that we know if the limit was exceeded
+        let limit = ctx
+            .limiter
+            .events_remaining()
+            .map(|remaining| remaining as u64 + 1);
+        let events = match ctx.ds.get_events(
             bucket_id.as_str(),
             Some(*interval.start()),
             Some(*interval.end()),
-            None,
+            limit,
         ) {
             Ok(events) => events,
             Err(e) => {
@@ -160,6 +370,10 @@ mod qfunctions {
                 )))
             }
         };
+        ctx.limiter.load_events(events.len())?;
+        if let Some(explainer) = &ctx.explainer {
+            explainer.loaded(&events);
+        }
         let mut ret = Vec::new();
         for event in events {
             ret.push(DataType::Event(event));
@@ -170,11 +384,11 @@ mod qfunctions {
     pub fn query_bucket_names(
         args: Vec<DataType>,
         _env: &VarEnv,
-        ds: &Datastore,
+        ctx: &QueryContext,
     ) -> Result<DataType, QueryError> {
         validate::args_length(&args, 0)?;
         let mut bucketnames: Vec<DataType> = Vec::new();
-        let buckets = match ds.get_buckets() {
+        let buckets = match ctx.ds.get_buckets() {
             Ok(buckets) => buckets,
             Err(e) => {
                 return Err(QueryError::BucketQueryError(format!(
@@ -191,7 +405,7 @@ mod qfunctions {
     pub fn find_bucket(
         args: Vec<DataType>,
         _env: &VarEnv,
-        ds: &Datastore,
+        ctx: &QueryContext,
     ) -> Result<DataType, QueryError> {
         validate::args_length(&args, 1).or_else(|_| validate::args_length(&args, 2))?;
 
@@ -201,7 +415,7 @@ mod qfunctions {
             _ => None,
         };
 
-        let buckets = match ds.get_buckets() {
+        let buckets = match ctx.ds.get_buckets() {
             Ok(buckets) => buckets,
             Err(e) => {
                 return Err(QueryError::BucketQueryError(format!(
@@ -232,7 +446,7 @@ mod qfunctions {
     pub fn contains(
         args: Vec<DataType>,
         _env: &VarEnv,
-        _ds: &Datastore,
+        _ctx: &QueryContext,
     ) -> Result<DataType, QueryError> {
         // typecheck
         validate::args_length(&args, 2)?;
@@ -260,7 +474,7 @@ mod qfunctions {
     pub fn flood(
         args: Vec<DataType>,
         _env: &VarEnv,
-        _ds: &Datastore,
+        _ctx: &QueryContext,
     ) -> Result<DataType, QueryError> {
         // typecheck
         validate::args_length(&args, 1)?;
@@ -278,7 +492,7 @@ mod qfunctions {
     pub fn categorize(
         args: Vec<DataType>,
         _env: &VarEnv,
-        _ds: &Datastore,
+        _ctx: &QueryContext,
     ) -> Result<DataType, QueryError> {
         // typecheck
         validate::args_length(&args, 2)?;
@@ -297,7 +511,7 @@ mod qfunctions {
     pub fn tag(
         args: Vec<DataType>,
         _env: &VarEnv,
-        _ds: &Datastore,
+        _ctx: &QueryContext,
     ) -> Result<DataType, QueryError> {
         // typecheck
         validate::args_length(&args, 2)?;
@@ -316,7 +530,7 @@ mod qfunctions {
     pub fn sort_by_duration(
         args: Vec<DataType>,
         _env: &VarEnv,
-        _ds: &Datastore,
+        _ctx: &QueryContext,
     ) -> Result<DataType, QueryError> {
         // typecheck
         validate::args_length(&args, 1)?;
@@ -335,7 +549,7 @@ mod qfunctions {
     pub fn limit_events(
         args: Vec<DataType>,
         _env: &VarEnv,
-        _ds: &Datastore,
+        _ctx: &QueryContext,
     ) -> Result<DataType, QueryError> {
         // typecheck
         validate::args_length(&args, 2)?;
@@ -355,7 +569,7 @@ mod qfunctions {
     pub fn sort_by_timestamp(
         args: Vec<DataType>,
         _env: &VarEnv,
-        _ds: &Datastore,
+        _ctx: &QueryContext,
     ) -> Result<DataType, QueryError> {
         // typecheck
         validate::args_length(&args, 1)?;
@@ -374,7 +588,7 @@ mod qfunctions {
     pub fn sum_durations(
         args: Vec<DataType>,
         _env: &VarEnv,
-        _ds: &Datastore,
+        _ctx: &QueryContext,
     ) -> Result<DataType, QueryError> {
         // typecheck
         validate::args_length(&args, 1)?;
@@ -393,7 +607,7 @@ mod qfunctions {
     pub fn merge_events_by_keys(
         args: Vec<DataType>,
         _env: &VarEnv,
-        _ds: &Datastore,
+        _ctx: &QueryContext,
     ) -> Result<DataType, QueryError> {
         // typecheck
         validate::args_length(&args, 2)?;
@@ -411,7 +625,7 @@ mod qfunctions {
     pub fn chunk_events_by_key(
         args: Vec<DataType>,
         _env: &VarEnv,
-        _ds: &Datastore,
+        _ctx: &QueryContext,
     ) -> Result<DataType, QueryError> {
         // typecheck
         validate::args_length(&args, 2)?;
@@ -429,7 +643,7 @@ mod qfunctions {
     pub fn filter_keyvals(
         args: Vec<DataType>,
         _env: &VarEnv,
-        _ds: &Datastore,
+        _ctx: &QueryContext,
     ) -> Result<DataType, QueryError> {
         // typecheck
         validate::args_length(&args, 3)?;
@@ -445,26 +659,17 @@ mod qfunctions {
         Ok(DataType::List(filtered_tagged_events))
     }
 
-    use fancy_regex::RegexBuilder;
-
     pub fn filter_keyvals_regex(
         args: Vec<DataType>,
         _env: &VarEnv,
-        _ds: &Datastore,
+        _ctx: &QueryContext,
     ) -> Result<DataType, QueryError> {
         // typecheck
         validate::args_length(&args, 3)?;
         let events = (&args[0]).try_into()?;
         let key: String = (&args[1]).try_into()?;
         let regex_str: String = (&args[2]).try_into()?;
-        let regex = match RegexBuilder::new(&regex_str).build() {
-            Ok(regex) => regex,
-            Err(e) => {
-                return Err(QueryError::RegexCompileError(format!(
-                    "Failed to compile regex string '{regex_str}': {e}"
-                )))
-            }
-        };
+        let regex = validate::regex(&regex_str)?;
 
         let mut filtered_events = aw_transform::filter_keyvals_regex(events, &key, &regex);
         let mut filtered_tagged_events = Vec::new();
@@ -477,7 +682,7 @@ mod qfunctions {
     pub fn exclude_keyvals(
         args: Vec<DataType>,
         _env: &VarEnv,
-        _ds: &Datastore,
+        _ctx: &QueryContext,
     ) -> Result<DataType, QueryError> {
         // typecheck
         validate::args_length(&args, 3)?;
@@ -496,7 +701,7 @@ mod qfunctions {
     pub fn filter_period_intersect(
         args: Vec<DataType>,
         _env: &VarEnv,
-        _ds: &Datastore,
+        _ctx: &QueryContext,
     ) -> Result<DataType, QueryError> {
         // typecheck
         validate::args_length(&args, 2)?;
@@ -514,7 +719,7 @@ mod qfunctions {
     pub fn split_url_events(
         args: Vec<DataType>,
         _env: &VarEnv,
-        _ds: &Datastore,
+        _ctx: &QueryContext,
     ) -> Result<DataType, QueryError> {
         // typecheck
         validate::args_length(&args, 1)?;
@@ -531,7 +736,7 @@ mod qfunctions {
     pub fn concat(
         args: Vec<DataType>,
         _env: &VarEnv,
-        _ds: &Datastore,
+        _ctx: &QueryContext,
     ) -> Result<DataType, QueryError> {
         let mut event_list = Vec::new();
         for arg in args {
@@ -546,7 +751,7 @@ mod qfunctions {
     pub fn period_union(
         args: Vec<DataType>,
         _env: &VarEnv,
-        _ds: &Datastore,
+        _ctx: &QueryContext,
     ) -> Result<DataType, QueryError> {
         // typecheck
         validate::args_length(&args, 2)?;
@@ -564,7 +769,7 @@ mod qfunctions {
     pub fn union_no_overlap(
         args: Vec<DataType>,
         _env: &VarEnv,
-        _ds: &Datastore,
+        _ctx: &QueryContext,
     ) -> Result<DataType, QueryError> {
         // typecheck
         validate::args_length(&args, 2)?;
@@ -578,11 +783,438 @@ mod qfunctions {
         }
         Ok(DataType::List(result_tagged))
     }
+
+    pub fn len(
+        args: Vec<DataType>,
+        _env: &VarEnv,
+        _ctx: &QueryContext,
+    ) -> Result<DataType, QueryError> {
+        // typecheck
+        validate::args_length(&args, 1)?;
+        let len = match &args[0] {
+            DataType::List(l) => l.len(),
+            DataType::Dict(d) => d.len(),
+            DataType::String(s) => s.chars().count(),
+            invalid_type => {
+                return Err(QueryError::InvalidFunctionParameters(format!(
+                    "function len got {invalid_type:?}, expected type List, Dict or String"
+                )))
+            }
+        };
+        Ok(DataType::Number(len as f64))
+    }
+
+    pub fn map(
+        args: Vec<DataType>,
+        env: &VarEnv,
+        ctx: &QueryContext,
+    ) -> Result<DataType, QueryError> {
+        // typecheck
+        validate::args_length(&args, 2)?;
+        let list: Vec<DataType> = (&args[0]).try_into()?;
+
+        let mut mapped = Vec::new();
+        for item in list {
+            ctx.limiter.iteration()?;
+            mapped.push(call_function(&args[1], vec![item], env, ctx)?);
+        }
+        Ok(DataType::List(mapped))
+    }
+
+    pub fn filter(
+        args: Vec<DataType>,
+        env: &VarEnv,
+        ctx: &QueryContext,
+    ) -> Result<DataType, QueryError> {
+        // typecheck
+        validate::args_length(&args, 2)?;
+        let list: Vec<DataType> = (&args[0]).try_into()?;
+
+        let mut filtered = Vec::new();
+        for item in list {
+            ctx.limiter.iteration()?;
+            match call_function(&args[1], vec![item.clone()], env, ctx)? {
+                DataType::Bool(true) => filtered.push(item),
+                DataType::Bool(false) => (),
+                invalid_type => {
+                    return Err(QueryError::InvalidType(format!(
+                    "function filter expected its function to return a Bool, got {invalid_type:?}"
+                )))
+                }
+            }
+        }
+        Ok(DataType::List(filtered))
+    }
+
+    pub fn reduce(
+        args: Vec<DataType>,
+        env: &VarEnv,
+        ctx: &QueryContext,
+    ) -> Result<DataType, QueryError> {
+        // typecheck
+        validate::args_length(&args, 3)?;
+        let list: Vec<DataType> = (&args[0]).try_into()?;
+
+        let mut acc = args[2].clone();
+        for item in list {
+            ctx.limiter.iteration()?;
+            acc = call_function(&args[1], vec![acc, item], env, ctx)?;
+        }
+        Ok(acc)
+    }
+
+    pub fn sort_by(
+        args: Vec<DataType>,
+        env: &VarEnv,
+        ctx: &QueryContext,
+    ) -> Result<DataType, QueryError> {
+        // typecheck
+        validate::args_length(&args, 2)?;
+        let list: Vec<DataType> = (&args[0]).try_into()?;
+
+        let mut keyed = Vec::new();
+        for item in list {
+            ctx.limiter.iteration()?;
+            let key = call_function(&args[1], vec![item.clone()], env, ctx)?;
+            keyed.push((key, item));
+        }
+        // Sorting can't fail part way, so remember the first keys which could not be compared
+        let mut cmp_err = None;
+        keyed.sort_by(|(k1, _), (k2, _)| match k1.query_cmp(k2) {
+            Ok(ordering) => ordering,
+            Err(e) => {
+                cmp_err.get_or_insert(e);
+                Ordering::Equal
+            }
+        });
+        if let Some(e) = cmp_err {
+            return Err(e);
+        }
+        Ok(DataType::List(
+            keyed.into_iter().map(|(_, item)| item).collect(),
+        ))
+    }
+
+    /// What the string builtins change, either a string or the values of a key of events
+    enum StringTarget {
+        String(String),
+        Keyvals(Vec<Event>, String),
+    }
+
+    impl StringTarget {
+        /// Split args into the target and the nparams arguments following it
+        ///
+        /// The target is either a string as the first argument, or a list of events and a key as
+        /// the first two arguments.
+        fn from_args(
+            args: &[DataType],
+            nparams: usize,
+        ) -> Result<(StringTarget, &[DataType]), QueryError> {
+            match args.first() {
+                Some(DataType::List(_)) => {
+                    validate::args_length(args, nparams + 2)?;
+                    let events: Vec<Event> = (&args[0]).try_into()?;
+                    let key: String = (&args[1]).try_into()?;
+                    Ok((StringTarget::Keyvals(events, key), &args[2..]))
+                }
+                _ => {
+                    validate::args_length(args, nparams + 1)?;
+                    let s: String = (&args[0]).try_into()?;
+                    Ok((StringTarget::String(s), &args[1..]))
+                }
+            }
+        }
+
+        fn map<F: FnMut(&str) -> String>(self, mut f: F) -> DataType {
+            match self {
+                StringTarget::String(s) => DataType::String(f(&s)),
+                StringTarget::Keyvals(events, key) => {
+                    let mut events = aw_transform::map_keyvals(events, &key, f);
+                    let mut tagged_events = Vec::new();
+                    for event in events.drain(..) {
+                        tagged_events.push(DataType::Event(event));
+                    }
+                    DataType::List(tagged_events)
+                }
+            }
+        }
+    }
+
+    pub fn lower(
+        args: Vec<DataType>,
+        _env: &VarEnv,
+        _ctx: &QueryContext,
+    ) -> Result<DataType, QueryError> {
+        let (target, _) = StringTarget::from_args(&args, 0)?;
+        Ok(target.map(|s| s.to_lowercase()))
+    }
+
+    pub fn upper(
+        args: Vec<DataType>,
+        _env: &VarEnv,
+        _ctx: &QueryContext,
+    ) -> Result<DataType, QueryError> {
+        let (target, _) = StringTarget::from_args(&args, 0)?;
+        Ok(target.map(|s| s.to_uppercase()))
+    }
+
+    pub fn replace(
+        args: Vec<DataType>,
+        _env: &VarEnv,
+        _ctx: &QueryContext,
+    ) -> Result<DataType, QueryError> {
+        let (target, params) = StringTarget::from_args(&args, 2)?;
+        let from: String = (&params[0]).try_into()?;
+        let to: String = (&params[1]).try_into()?;
+        Ok(target.map(|s| s.replace(&from, &to)))
+    }
+
+    pub fn regex_replace(
+        args: Vec<DataType>,
+        _env: &VarEnv,
+        _ctx: &QueryContext,
+    ) -> Result<DataType, QueryError> {
+        let (target, params) = StringTarget::from_args(&args, 2)?;
+        let regex_str: String = (&params[0]).try_into()?;
+        let regex = validate::regex(&regex_str)?;
+        let replacement: String = (&params[1]).try_into()?;
+        Ok(target.map(|s| aw_transform::regex_replace(s, &regex, &replacement)))
+    }
+
+    pub fn regex_extract(
+        args: Vec<DataType>,
+        _env: &VarEnv,
+        _ctx: &QueryContext,
+    ) -> Result<DataType, QueryError> {
+        match args.first() {
+            // regex_extract(events, key, regex, new_key)
+            Some(DataType::List(_)) => {
+                validate::args_length(&args, 4)?;
+                let events: Vec<Event> = (&args[0]).try_into()?;
+                let key: String = (&args[1]).try_into()?;
+                let regex_str: String = (&args[2]).try_into()?;
+                let regex = validate::regex(&regex_str)?;
+                let new_key: String = (&args[3]).try_into()?;
+
+                let mut events =
+                    aw_transform::extract_keyvals_regex(events, &key, &regex, &new_key);
+                let mut tagged_events = Vec::new();
+                for event in events.drain(..) {
+                    tagged_events.push(DataType::Event(event));
+                }
+                Ok(DataType::List(tagged_events))
+            }
+            // regex_extract(string, regex)
+            _ => {
+                validate::args_length(&args, 2)?;
+                let s: String = (&args[0]).try_into()?;
+                let regex_str: String = (&args[1]).try_into()?;
+                let regex = validate::regex(&regex_str)?;
+                match aw_transform::regex_extract(&s, &regex) {
+                    Some(extracted) => Ok(DataType::String(extracted)),
+                    None => Ok(DataType::None()),
+                }
+            }
+        }
+    }
+
+    pub fn split(
+        args: Vec<DataType>,
+        _env: &VarEnv,
+        _ctx: &QueryContext,
+    ) -> Result<DataType, QueryError> {
+        // typecheck
+        validate::args_length(&args, 2)?;
+        let s: String = (&args[0]).try_into()?;
+        let separator: String = (&args[1]).try_into()?;
+        if separator.is_empty() {
+            return Err(QueryError::InvalidFunctionParameters(
+                "function split got an empty separator".to_string(),
+            ));
+        }
+        Ok(DataType::List(
+            s.split(&separator)
+                .map(|part| DataType::String(part.to_string()))
+                .collect(),
+        ))
+    }
+
+    /// Replace each {} in the template with the next argument
+    pub fn format(
+        args: Vec<DataType>,
+        _env: &VarEnv,
+        _ctx: &QueryContext,
+    ) -> Result<DataType, QueryError> {
+        let template: String = match args.first() {
+            Some(template) => template.try_into()?,
+            None => {
+                return Err(QueryError::InvalidFunctionParameters(
+                    "function format needs at least a template".to_string(),
+                ))
+            }
+        };
+        let parts: Vec<&str> = template.split("{}").collect();
+        validate::args_length(&args, parts.len())?;
+
+        let mut formatted = parts[0].to_string();
+        for (part, value) in parts[1..].iter().zip(&args[1..]) {
+            match value {
+                DataType::String(s) => formatted.push_str(s),
+                DataType::Number(n) => formatted.push_str(&n.to_string()),
+                DataType::Bool(b) => formatted.push_str(&b.to_string()),
+                value => match serde_json::to_string(value) {
+                    Ok(json) => formatted.push_str(&json),
+                    Err(e) => {
+                        return Err(QueryError::InvalidFunctionParameters(format!(
+                            "function format cannot format {value:?}: {e}"
+                        )))
+                    }
+                },
+            }
+            formatted.push_str(part);
+        }
+        Ok(DataType::String(formatted))
+    }
+
+    /// The current time, or the end of the time period if it has already ended
+    ///
+    /// Queries over time periods which have ended thereby always give the same result, which is
+    /// what allows them to be cached.
+    pub fn now(
+        args: Vec<DataType>,
+        env: &VarEnv,
+        _ctx: &QueryContext,
+    ) -> Result<DataType, QueryError> {
+        // typecheck
+        validate::args_length(&args, 0)?;
+        let mut now = Utc::now();
+        if let Ok(interval) = validate::get_timeinterval(env) {
+            now = now.min(*interval.end());
+        }
+        Ok(DataType::String(
+            now.to_rfc3339_opts(SecondsFormat::AutoSi, true),
+        ))
+    }
+
+    pub fn timeinterval_start(
+        args: Vec<DataType>,
+        env: &VarEnv,
+        _ctx: &QueryContext,
+    ) -> Result<DataType, QueryError> {
+        // typecheck
+        validate::args_length(&args, 0)?;
+        let interval = validate::get_timeinterval(env)?;
+        Ok(DataType::String(
+            interval
+                .start()
+                .to_rfc3339_opts(SecondsFormat::AutoSi, true),
+        ))
+    }
+
+    pub fn timeinterval_end(
+        args: Vec<DataType>,
+        env: &VarEnv,
+        _ctx: &QueryContext,
+    ) -> Result<DataType, QueryError> {
+        // typecheck
+        validate::args_length(&args, 0)?;
+        let interval = validate::get_timeinterval(env)?;
+        Ok(DataType::String(
+            interval.end().to_rfc3339_opts(SecondsFormat::AutoSi, true),
+        ))
+    }
+
+    /// Add a duration in seconds or a string like "15m" to a timestamp, keeping its offset
+    pub fn date_add(
+        args: Vec<DataType>,
+        _env: &VarEnv,
+        _ctx: &QueryContext,
+    ) -> Result<DataType, QueryError> {
+        // typecheck
+        validate::args_length(&args, 2)?;
+        let timestamp = validate::timestamp(&args[0])?;
+        let duration = validate::duration(&args[1])?;
+
+        match timestamp.checked_add_signed(duration) {
+            Some(result) => Ok(DataType::String(
+                result.to_rfc3339_opts(SecondsFormat::AutoSi, true),
+            )),
+            None => Err(QueryError::MathError(format!(
+                "{timestamp} + {duration} is out of range"
+            ))),
+        }
+    }
+
+    /// The number of seconds from the second timestamp to the first
+    pub fn date_diff(
+        args: Vec<DataType>,
+        _env: &VarEnv,
+        _ctx: &QueryContext,
+    ) -> Result<DataType, QueryError> {
+        // typecheck
+        validate::args_length(&args, 2)?;
+        let a = validate::timestamp(&args[0])?;
+        let b = validate::timestamp(&args[1])?;
+
+        let diff = a.signed_duration_since(b);
+        Ok(DataType::Number(diff.num_milliseconds() as f64 / 1000.0))
+    }
+
+    /// Split events into the periods of TIMEINTERVAL, returned as a dict keyed by period start
+    pub fn split_by_period(
+        args: Vec<DataType>,
+        env: &VarEnv,
+        ctx: &QueryContext,
+    ) -> Result<DataType, QueryError> {
+        // typecheck
+        validate::args_length(&args, 3)?;
+        let events: Vec<Event> = (&args[0]).try_into()?;
+        let period = validate::duration(&args[1])?;
+        let offset = validate::tz_offset(&args[2])?;
+        let interval = validate::get_timeinterval(env)?;
+
+        if period < Duration::minutes(1) {
+            return Err(QueryError::InvalidFunctionParameters(format!(
+                "function split_by_period got period {period}, expected at least 1 minute"
+            )));
+        }
+        // Count the periods before allocating them, the interval may be long
+        let periods = interval.duration().num_milliseconds() / period.num_milliseconds() + 2;
+        ctx.limiter
+            .iterations(usize::try_from(periods).unwrap_or(usize::MAX))?;
+
+        let split = aw_transform::split_by_period(
+            events,
+            *interval.start(),
+            *interval.end(),
+            period,
+            offset,
+        );
+        let mut dict = HashMap::new();
+        for (start, events) in split {
+            dict.insert(
+                start.to_rfc3339_opts(SecondsFormat::AutoSi, true),
+                DataType::List(events.into_iter().map(DataType::Event).collect()),
+            );
+        }
+        Ok(DataType::Dict(dict))
+    }
 }
 
 mod validate {
     use crate::{DataType, QueryError, VarEnv};
     use aw_models::TimeInterval;
+    use chrono::{DateTime, Duration, FixedOffset};
+    use fancy_regex::{Regex, RegexBuilder};
+
+    pub fn regex(regex_str: &str) -> Result<Regex, QueryError> {
+        match RegexBuilder::new(regex_str).build() {
+            Ok(regex) => Ok(regex),
+            Err(e) => Err(QueryError::RegexCompileError(format!(
+                "Failed to compile regex string '{regex_str}': {e}"
+            ))),
+        }
+    }
 
     pub fn args_length(args: &[DataType], len: usize) -> Result<(), QueryError> {
         if args.len() != len {
@@ -618,4 +1250,57 @@ mod validate {
             ))),
         }
     }
+
+    /// A duration given as a number of seconds or as a string like "90s", "15m", "1h", "1d" or "1w"
+    pub fn duration(arg: &DataType) -> Result<Duration, QueryError> {
+        let invalid = || {
+            QueryError::InvalidFunctionParameters(format!(
+                "Expected a duration in seconds or a string like \"1h\", got {arg:?}"
+            ))
+        };
+        let (amount, unit) = match arg {
+            DataType::Number(seconds) => (*seconds, 1),
+            DataType::String(s) => {
+                let (amount, unit) =
+                    s.split_at(s.len() - s.chars().last().map_or(0, char::len_utf8));
+                let unit = match unit {
+                    "s" => 1,
+                    "m" => 60,
+                    "h" => 60 * 60,
+                    "d" => 24 * 60 * 60,
+                    "w" => 7 * 24 * 60 * 60,
+                    _ => return Err(invalid()),
+                };
+                (amount.parse::<f64>().map_err(|_| invalid())?, unit)
+            }
+            _ => return Err(invalid()),
+        };
+        let ms = amount * unit as f64 * 1000.0;
+        if !ms.is_finite() || ms.abs() > i64::MAX as f64 / 2.0 {
+            return Err(invalid());
+        }
+        Ok(Duration::milliseconds(ms.round() as i64))
+    }
+
+    pub fn timestamp(arg: &DataType) -> Result<DateTime<FixedOffset>, QueryError> {
+        let s: String = arg.try_into()?;
+        match DateTime::parse_from_rfc3339(&s) {
+            Ok(timestamp) => Ok(timestamp),
+            Err(e) => Err(QueryError::InvalidFunctionParameters(format!(
+                "Failed to parse timestamp '{s}': {e}"
+            ))),
+        }
+    }
+
+    /// A timezone offset given as a number of hours east of UTC
+    pub fn tz_offset(arg: &DataType) -> Result<FixedOffset, QueryError> {
+        let hours: f64 = arg.try_into()?;
+        let seconds = (hours * 3600.0).round();
+        match FixedOffset::east_opt(seconds as i32) {
+            Some(offset) if seconds.abs() < 24.0 * 3600.0 => Ok(offset),
+            _ => Err(QueryError::InvalidFunctionParameters(format!(
+                "Expected a timezone offset between -24 and 24 hours, got {hours}"
+            ))),
+        }
+    }
 }
@@ -1,16 +1,56 @@
+use std::cell::{Cell, RefCell};
+use std::cmp::Ordering;
 use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Instant;
 
-use crate::functions;
+use crate::explain::{count_events, Explainer};
+use crate::functions::{self, QueryFn};
+use crate::modules;
 
 use aw_datastore::Datastore;
 use aw_models::TimeInterval;
 
 use crate::ast::*;
+use crate::datatype::Closure;
+use crate::lexer::Span;
+use crate::limits::Limiter;
 use crate::DataType;
 use crate::QueryError;
 
 pub type VarEnv = HashMap<String, DataType>;
 
+/// How deeply functions defined in a query may call each other, whatever its limits are
+///
+/// Every call nests several frames of the interpreter, so this is what keeps a recursive query
+/// within the stack of the thread it runs on.
+pub const MAX_CALL_DEPTH: usize = 256;
+
+/// What a query has access to while it is being interpreted
+pub struct QueryContext<'a> {
+    pub ds: &'a Datastore,
+    pub limiter: Limiter<'a>,
+    /// Span of the innermost expression which failed, if any
+    pub error_span: Cell<Option<Span>>,
+    /// How many calls of functions defined in the query are being interpreted
+    pub call_depth: Cell<usize>,
+    /// Names of the modules being imported, the innermost last
+    pub imports: RefCell<Vec<String>>,
+    /// How deep the interpreter is in code of imported modules, whose spans are not in the query
+    pub module_depth: Cell<usize>,
+    /// Collects where the query spends its time, if it is being explained
+    pub explainer: Option<Explainer>,
+}
+
+impl QueryContext<'_> {
+    /// Remember span as where the query failed, unless an expression inside it failed first
+    fn failed_at(&self, span: Span) {
+        if self.error_span.get().is_none() {
+            self.error_span.set(Some(span));
+        }
+    }
+}
+
 fn init_env(ti: &TimeInterval) -> VarEnv {
     let mut env = HashMap::new();
     env.insert("TIMEINTERVAL".to_string(), DataType::String(ti.to_string()));
@@ -21,28 +61,318 @@ fn init_env(ti: &TimeInterval) -> VarEnv {
 pub fn interpret_prog(
     p: Program,
     ti: &TimeInterval,
-    ds: &Datastore,
+    params: &VarEnv,
+    ctx: &QueryContext,
 ) -> Result<DataType, QueryError> {
     let mut env = init_env(ti);
+    for (name, value) in params {
+        if env.contains_key(name) {
+            return Err(QueryError::InvalidFunctionParameters(format!(
+                "Parameter {name} would shadow a builtin"
+            )));
+        }
+        env.insert(name.clone(), value.clone());
+    }
     for expr in p.stmts {
-        interpret_expr(&mut env, ds, expr)?;
+        interpret_stmt(&mut env, ctx, expr)?;
     }
     match env.remove("RETURN") {
-        Some(ret) => Ok(ret),
+        Some(ret) => {
+            ctx.limiter.check_result_size(&ret)?;
+            Ok(ret)
+        }
         None => Err(QueryError::EmptyQuery()),
     }
 }
 
+fn interpret_stmt(
+    env: &mut HashMap<String, DataType>,
+    ctx: &QueryContext,
+    expr: Expr,
+) -> Result<DataType, QueryError> {
+    if let Err(e) = ctx.limiter.statement() {
+        ctx.failed_at(expr.span);
+        return Err(e);
+    }
+    let explainer = match &ctx.explainer {
+        // Statements of modules can't be shown as part of the query
+        Some(explainer) if ctx.module_depth.get() == 0 => explainer,
+        _ => return interpret_expr(env, ctx, expr),
+    };
+    let span = expr.span;
+    let start = Instant::now();
+    let res = interpret_expr(env, ctx, expr);
+    explainer.statement(span, start.elapsed());
+    res
+}
+
 fn interpret_expr(
     env: &mut HashMap<String, DataType>,
-    ds: &Datastore,
+    ctx: &QueryContext,
+    expr: Expr,
+) -> Result<DataType, QueryError> {
+    let span = expr.span;
+    if let Err(e) = ctx.limiter.enter() {
+        ctx.failed_at(span);
+        return Err(e);
+    }
+    let res = interpret_node(env, ctx, expr);
+    ctx.limiter.leave();
+    if res.is_err() {
+        ctx.failed_at(span);
+    }
+    res
+}
+
+fn interpret_ordering(
+    env: &mut HashMap<String, DataType>,
+    ctx: &QueryContext,
+    lhs: Expr,
+    rhs: Expr,
+) -> Result<Ordering, QueryError> {
+    let lhs_res = interpret_expr(env, ctx, lhs)?;
+    let rhs_res = interpret_expr(env, ctx, rhs)?;
+    lhs_res.query_cmp(&rhs_res)
+}
+
+fn interpret_bool(
+    env: &mut HashMap<String, DataType>,
+    ctx: &QueryContext,
+    expr: Expr,
+    op: &str,
+) -> Result<bool, QueryError> {
+    match interpret_expr(env, ctx, expr)? {
+        DataType::Bool(b) => Ok(b),
+        _ => Err(QueryError::InvalidType(format!(
+            "Cannot use {op} on something that is not a boolean!"
+        ))),
+    }
+}
+
+/// Interpret the statements of a block, stopping early at a return inside of a function
+fn interpret_block(
+    env: &mut HashMap<String, DataType>,
+    ctx: &QueryContext,
+    block: Vec<Expr>,
+) -> Result<(), QueryError> {
+    for expr in block {
+        interpret_stmt(env, ctx, expr)?;
+        if returned(env, ctx) {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Whether the function being called has returned
+///
+/// Outside of functions a return does not stop the query, the last value returned is its result.
+fn returned(env: &HashMap<String, DataType>, ctx: &QueryContext) -> bool {
+    ctx.call_depth.get() > 0 && env.contains_key("RETURN")
+}
+
+fn make_closure(
+    env: &HashMap<String, DataType>,
+    ctx: &QueryContext,
+    name: Option<String>,
+    params: Vec<String>,
+    body: Vec<Expr>,
+) -> DataType {
+    let mut captured = VarEnv::new();
+    let mut capture = |var: &str| {
+        if let Some(value) = env.get(var) {
+            captured.insert(var.to_string(), value.clone());
+        }
+    };
+    // Builtins read the time interval of the query from the environment they are called in
+    capture("TIMEINTERVAL");
+    for expr in &body {
+        expr.visit(&mut |e| match &e.node {
+            Expr_::Var(var) | Expr_::Function(var, _) => capture(var),
+            _ => (),
+        });
+    }
+    DataType::Closure(Arc::new(Closure {
+        name,
+        params,
+        body,
+        env: captured,
+        from_module: ctx.module_depth.get() > 0,
+    }))
+}
+
+fn call_closure(
+    closure: &Arc<Closure>,
+    args: Vec<DataType>,
+    ctx: &QueryContext,
+) -> Result<DataType, QueryError> {
+    if args.len() != closure.params.len() {
+        return Err(QueryError::InvalidFunctionParameters(format!(
+            "Function {} expects {} parameters, got {}",
+            closure.name(),
+            closure.params.len(),
+            args.len()
+        )));
+    }
+    let mut env = closure.env.clone();
+    env.remove("RETURN");
+    // Functions defined with def can call themselves
+    if let Some(name) = &closure.name {
+        env.insert(name.clone(), DataType::Closure(closure.clone()));
+    }
+    env.extend(closure.params.iter().cloned().zip(args));
+    if ctx.call_depth.get() >= MAX_CALL_DEPTH {
+        return Err(QueryError::ResourceLimit(format!(
+            "Query called functions nested deeper than {MAX_CALL_DEPTH} levels"
+        )));
+    }
+    ctx.call_depth.set(ctx.call_depth.get() + 1);
+    let res = match closure.from_module {
+        true => in_module(ctx, || interpret_block(&mut env, ctx, closure.body.clone())),
+        false => interpret_block(&mut env, ctx, closure.body.clone()),
+    };
+    ctx.call_depth.set(ctx.call_depth.get() - 1);
+    res?;
+    Ok(env.remove("RETURN").unwrap_or(DataType::None()))
+}
+
+/// Interpret code of a module
+///
+/// Spans in modules are not in the code of the query, so errors in modules are reported at the
+/// expression of the query which used the module instead.
+fn in_module<T, F>(ctx: &QueryContext, f: F) -> Result<T, QueryError>
+where
+    F: FnOnce() -> Result<T, QueryError>,
+{
+    ctx.module_depth.set(ctx.module_depth.get() + 1);
+    let res = f();
+    ctx.module_depth.set(ctx.module_depth.get() - 1);
+    if res.is_err() {
+        ctx.error_span.set(None);
+    }
+    res
+}
+
+/// Run the module called name and return the variables it defined
+fn import_module(env: &VarEnv, ctx: &QueryContext, name: &str) -> Result<DataType, QueryError> {
+    let imports = ctx.imports.borrow().clone();
+    if imports.iter().any(|import| import == name) {
+        return Err(QueryError::ImportError(format!(
+            "Import cycle: {} -> {}",
+            imports.join(" -> "),
+            name
+        )));
+    }
+    let stmts = modules::statements(ctx, name)?;
+
+    let mut builtins = VarEnv::new();
+    functions::fill_env(&mut builtins);
+    let mut module_env = builtins.clone();
+    if let Some(ti) = env.get("TIMEINTERVAL") {
+        module_env.insert("TIMEINTERVAL".to_string(), ti.clone());
+    }
+    ctx.imports.borrow_mut().push(name.to_string());
+    let res = in_module(ctx, || {
+        for stmt in stmts.iter() {
+            interpret_stmt(&mut module_env, ctx, stmt.clone())?;
+        }
+        Ok(())
+    });
+    ctx.imports.borrow_mut().pop();
+    res?;
+
+    // Only what the module defined itself is exported
+    module_env.retain(|var, value| match (var.as_str(), builtins.get(var)) {
+        ("TIMEINTERVAL" | "RETURN", _) => false,
+        (_, Some(DataType::Function(..))) => !matches!(value, DataType::Function(..)),
+        _ => true,
+    });
+    Ok(DataType::Dict(module_env))
+}
+
+/// Call a builtin, recording its time and the events it got and returned when explaining
+fn call_builtin(
+    name: &str,
+    fun: &QueryFn,
+    args: Vec<DataType>,
+    env: &VarEnv,
+    ctx: &QueryContext,
+) -> Result<DataType, QueryError> {
+    let explainer = match &ctx.explainer {
+        Some(explainer) => explainer,
+        None => return fun(args, env, ctx),
+    };
+    let events_in = args.iter().map(count_events).sum();
+    let start = Instant::now();
+    let res = fun(args, env, ctx);
+    let duration = start.elapsed();
+    if let Ok(data) = &res {
+        explainer.builtin(name, duration, events_in, count_events(data));
+    }
+    res
+}
+
+/// Call a builtin function or a function defined in the query with args
+pub fn call_function(
+    fun: &DataType,
+    args: Vec<DataType>,
+    env: &VarEnv,
+    ctx: &QueryContext,
+) -> Result<DataType, QueryError> {
+    match fun {
+        DataType::Function(name, fun) => call_builtin(name, fun, args, env, ctx),
+        DataType::Closure(closure) => call_closure(closure, args, ctx),
+        data => Err(QueryError::InvalidFunctionParameters(format!(
+            "Expected a function, got {data:?}"
+        ))),
+    }
+}
+
+fn interpret_iterable(
+    env: &mut HashMap<String, DataType>,
+    ctx: &QueryContext,
+    expr: Expr,
+) -> Result<Vec<DataType>, QueryError> {
+    match interpret_expr(env, ctx, expr)? {
+        DataType::List(l) => Ok(l),
+        _ => Err(QueryError::InvalidType(
+            "Cannot iterate over something that is not a list!".to_string(),
+        )),
+    }
+}
+
+fn interpret_comprehension(
+    env: &mut HashMap<String, DataType>,
+    ctx: &QueryContext,
+    expr: Expr,
+    var: &str,
+    items: Vec<DataType>,
+    cond: Option<Expr>,
+) -> Result<DataType, QueryError> {
+    let mut l = Vec::new();
+    for item in items {
+        ctx.limiter.iteration()?;
+        env.insert(var.to_string(), item);
+        if let Some(cond) = &cond {
+            if !interpret_bool(env, ctx, cond.clone(), "if")? {
+                continue;
+            }
+        }
+        l.push(interpret_expr(env, ctx, expr.clone())?);
+    }
+    Ok(DataType::List(l))
+}
+
+fn interpret_node(
+    env: &mut HashMap<String, DataType>,
+    ctx: &QueryContext,
     expr: Expr,
 ) -> Result<DataType, QueryError> {
     use crate::ast::Expr_::*;
     match expr.node {
         Add(a, b) => {
-            let a_res = interpret_expr(env, ds, *a)?;
-            let b_res = interpret_expr(env, ds, *b)?;
+            let a_res = interpret_expr(env, ctx, *a)?;
+            let b_res = interpret_expr(env, ctx, *b)?;
             let res = match a_res {
                 DataType::Number(n1) => match b_res {
                     DataType::Number(n2) => DataType::Number(n1 + n2),
@@ -86,8 +416,8 @@ fn interpret_expr(
             Ok(res)
         }
         Sub(a, b) => {
-            let a_res = interpret_expr(env, ds, *a)?;
-            let b_res = interpret_expr(env, ds, *b)?;
+            let a_res = interpret_expr(env, ctx, *a)?;
+            let b_res = interpret_expr(env, ctx, *b)?;
             let a_num = match a_res {
                 DataType::Number(n) => n,
                 _ => {
@@ -107,8 +437,8 @@ fn interpret_expr(
             Ok(DataType::Number(a_num - b_num))
         }
         Mul(a, b) => {
-            let a_res = interpret_expr(env, ds, *a)?;
-            let b_res = interpret_expr(env, ds, *b)?;
+            let a_res = interpret_expr(env, ctx, *a)?;
+            let b_res = interpret_expr(env, ctx, *b)?;
             let a_num = match a_res {
                 DataType::Number(n) => n,
                 _ => {
@@ -128,8 +458,8 @@ fn interpret_expr(
             Ok(DataType::Number(a_num * b_num))
         }
         Div(a, b) => {
-            let a_res = interpret_expr(env, ds, *a)?;
-            let b_res = interpret_expr(env, ds, *b)?;
+            let a_res = interpret_expr(env, ctx, *a)?;
+            let b_res = interpret_expr(env, ctx, *b)?;
             let a_num = match a_res {
                 DataType::Number(n) => n,
                 _ => {
@@ -154,8 +484,8 @@ fn interpret_expr(
             Ok(DataType::Number(a_num / b_num))
         }
         Mod(a, b) => {
-            let a_res = interpret_expr(env, ds, *a)?;
-            let b_res = interpret_expr(env, ds, *b)?;
+            let a_res = interpret_expr(env, ctx, *a)?;
+            let b_res = interpret_expr(env, ctx, *b)?;
             let a_num = match a_res {
                 DataType::Number(n) => n,
                 _ => {
@@ -175,15 +505,65 @@ fn interpret_expr(
             Ok(DataType::Number(a_num % b_num))
         }
         Equal(lhs, rhs) => {
-            let lhs_res = interpret_expr(env, ds, *lhs)?;
-            let rhs_res = interpret_expr(env, ds, *rhs)?;
+            let lhs_res = interpret_expr(env, ctx, *lhs)?;
+            let rhs_res = interpret_expr(env, ctx, *rhs)?;
             Ok(DataType::Bool(lhs_res.query_eq(&rhs_res)?))
         }
+        NotEqual(lhs, rhs) => {
+            let lhs_res = interpret_expr(env, ctx, *lhs)?;
+            let rhs_res = interpret_expr(env, ctx, *rhs)?;
+            Ok(DataType::Bool(!lhs_res.query_eq(&rhs_res)?))
+        }
+        Less(lhs, rhs) => {
+            let ordering = interpret_ordering(env, ctx, *lhs, *rhs)?;
+            Ok(DataType::Bool(ordering == Ordering::Less))
+        }
+        LessEqual(lhs, rhs) => {
+            let ordering = interpret_ordering(env, ctx, *lhs, *rhs)?;
+            Ok(DataType::Bool(ordering != Ordering::Greater))
+        }
+        Greater(lhs, rhs) => {
+            let ordering = interpret_ordering(env, ctx, *lhs, *rhs)?;
+            Ok(DataType::Bool(ordering == Ordering::Greater))
+        }
+        GreaterEqual(lhs, rhs) => {
+            let ordering = interpret_ordering(env, ctx, *lhs, *rhs)?;
+            Ok(DataType::Bool(ordering != Ordering::Less))
+        }
+        // The right hand side of and/or is only interpreted when it decides the result
+        And(lhs, rhs) => {
+            let res =
+                interpret_bool(env, ctx, *lhs, "and")? && interpret_bool(env, ctx, *rhs, "and")?;
+            Ok(DataType::Bool(res))
+        }
+        Or(lhs, rhs) => {
+            let res =
+                interpret_bool(env, ctx, *lhs, "or")? || interpret_bool(env, ctx, *rhs, "or")?;
+            Ok(DataType::Bool(res))
+        }
+        Not(e) => Ok(DataType::Bool(!interpret_bool(env, ctx, *e, "not")?)),
         Assign(var, b) => {
-            let val = interpret_expr(env, ds, *b)?;
+            let val = interpret_expr(env, ctx, *b)?;
             env.insert(var, val);
             Ok(DataType::None())
         }
+        Index(e, key) => {
+            let value = interpret_expr(env, ctx, *e)?;
+            let key = interpret_expr(env, ctx, *key)?;
+            value.index(&key)
+        }
+        Slice(e, start, end) => {
+            let value = interpret_expr(env, ctx, *e)?;
+            let start = match start {
+                Some(start) => Some(interpret_expr(env, ctx, *start)?),
+                None => None,
+            };
+            let end = match end {
+                Some(end) => Some(interpret_expr(env, ctx, *end)?),
+                None => None,
+            };
+            value.slice(start.as_ref(), end.as_ref())
+        }
         // FIXME: avoid clone, it's slow
         Var(var) => match env.get(&var) {
             Some(v) => Ok(v.clone()),
@@ -193,25 +573,45 @@ fn interpret_expr(
         Number(lit) => Ok(DataType::Number(lit)),
         String(litstr) => Ok(DataType::String(litstr)),
         Return(e) => {
-            let val = interpret_expr(env, ds, *e)?;
+            let val = interpret_expr(env, ctx, *e)?;
             // TODO: Once RETURN is deprecated we can fix this
             env.insert("RETURN".to_string(), val);
             Ok(DataType::None())
         }
         If(ifs) => {
             for (cond, block) in ifs {
-                let c = interpret_expr(env, ds, *cond)?;
+                let c = interpret_expr(env, ctx, *cond)?;
                 if c.query_eq(&DataType::Bool(true))? {
-                    for expr in block {
-                        interpret_expr(env, ds, expr)?;
-                    }
+                    interpret_block(env, ctx, block)?;
+                    break;
+                }
+            }
+            Ok(DataType::None())
+        }
+        For(var, list, block) => {
+            for item in interpret_iterable(env, ctx, *list)? {
+                ctx.limiter.iteration()?;
+                env.insert(var.clone(), item);
+                interpret_block(env, ctx, block.clone())?;
+                if returned(env, ctx) {
                     break;
                 }
             }
             Ok(DataType::None())
         }
+        Comprehension(e, var, list, cond) => {
+            let items = interpret_iterable(env, ctx, *list)?;
+            // The variable only exists inside the comprehension
+            let shadowed = env.remove(&var);
+            let res = interpret_comprehension(env, ctx, *e, &var, items, cond.map(|c| *c));
+            match shadowed {
+                Some(value) => env.insert(var, value),
+                None => env.remove(&var),
+            };
+            res
+        }
         Function(fname, e) => {
-            let args = match interpret_expr(env, ds, *e)? {
+            let args = match interpret_expr(env, ctx, *e)? {
                 DataType::List(l) => l,
                 _ => unreachable!(),
             };
@@ -219,16 +619,35 @@ fn interpret_expr(
                 Some(v) => v,
                 None => return Err(QueryError::VariableNotDefined(fname.clone())),
             };
-            let (_name, fun) = match var {
-                DataType::Function(name, fun) => (name, fun),
-                _data => return Err(QueryError::InvalidType(fname.to_string())),
+            match var {
+                DataType::Function(name, fun) => call_builtin(name, fun, args, env, ctx),
+                DataType::Closure(closure) => call_closure(&closure.clone(), args, ctx),
+                _data => Err(QueryError::InvalidType(fname.to_string())),
+            }
+        }
+        Call(fun, e) => {
+            let fun = interpret_expr(env, ctx, *fun)?;
+            let args = match interpret_expr(env, ctx, *e)? {
+                DataType::List(l) => l,
+                _ => unreachable!(),
             };
-            fun(args, env, ds)
+            call_function(&fun, args, env, ctx)
+        }
+        Def(name, params, body) => {
+            let closure = make_closure(env, ctx, Some(name.clone()), params, body);
+            env.insert(name, closure);
+            Ok(DataType::None())
+        }
+        Lambda(params, body) => Ok(make_closure(env, ctx, None, params, body)),
+        Import(name) => {
+            let module = import_module(env, ctx, &name)?;
+            env.insert(name, module);
+            Ok(DataType::None())
         }
         List(list) => {
             let mut l = Vec::new();
             for entry in list {
-                let res = interpret_expr(env, ds, entry)?;
+                let res = interpret_expr(env, ctx, entry)?;
                 l.push(res);
             }
             Ok(DataType::List(l))
@@ -236,7 +655,7 @@ fn interpret_expr(
         Dict(d) => {
             let mut dict = HashMap::new();
             for (key, val_uninterpreted) in d {
-                let val = interpret_expr(env, ds, val_uninterpreted)?;
+                let val = interpret_expr(env, ctx, val_uninterpreted)?;
                 dict.insert(key.clone(), val);
             }
             Ok(DataType::Dict(dict))
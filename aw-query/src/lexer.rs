@@ -7,7 +7,15 @@ pub enum Token {
     If,
     ElseIf,
     Else,
+    For,
+    In,
+    Def,
+    Fn,
     Return,
+    Import,
+    And,
+    Or,
+    Not,
 
     Bool(bool),
     Number(f64),
@@ -18,6 +26,12 @@ pub enum Token {
     Slash,
     Percent,
     Equals,
+    Arrow,
+    NotEquals,
+    Less,
+    LessEquals,
+    Greater,
+    GreaterEquals,
     Assign,
     LParen,
     RParen,
@@ -26,14 +40,22 @@ pub enum Token {
     LBrace,
     RBrace,
     Comma,
+    Dot,
     Colon,
     Semi,
+    Invalid(String),
 
     Whitespace,
     Newline,
     Comment,
 }
 
+/// Words matched by the lexer as something else than an identifier
+pub const KEYWORDS: &[&str] = &[
+    "if", "elif", "else", "for", "in", "def", "fn", "return", "import", "and", "or", "not", "true",
+    "false", "True", "False",
+];
+
 lexer! {
     fn next_token(text: 'a) -> (Token, &'a str);
 
@@ -45,7 +67,15 @@ lexer! {
     r#"if"# => (Token::If, text),
     r#"elif"# => (Token::ElseIf, text),
     r#"else"# => (Token::Else, text),
+    r#"for"# => (Token::For, text),
+    r#"in"# => (Token::In, text),
+    r#"def"# => (Token::Def, text),
+    r#"fn"# => (Token::Fn, text),
     r#"return"# => (Token::Return, text),
+    r#"import"# => (Token::Import, text),
+    r#"and"# => (Token::And, text),
+    r#"or"# => (Token::Or, text),
+    r#"not"# => (Token::Not, text),
 
     r#"true"# => (Token::Bool(true), text),
     r#"false"# => (Token::Bool(false), text),
@@ -68,6 +98,12 @@ lexer! {
     r#"[a-zA-Z_][a-zA-Z0-9_]*"# => (Token::Ident(text.to_owned()), text),
 
     r#"=="# => (Token::Equals, text),
+    r#"=>"# => (Token::Arrow, text),
+    r#"!="# => (Token::NotEquals, text),
+    r#"<="# => (Token::LessEquals, text),
+    r#">="# => (Token::GreaterEquals, text),
+    r#"<"# => (Token::Less, text),
+    r#">"# => (Token::Greater, text),
     r#"="# => (Token::Assign, text),
     r#"\+"# => (Token::Plus, text),
     r#"-"# => (Token::Minus, text),
@@ -81,8 +117,11 @@ lexer! {
     r#"\{"# => (Token::LBrace, text),
     r#"\}"# => (Token::RBrace, text),
     r#","# => (Token::Comma, text),
+    r#"\."# => (Token::Dot, text),
     r#":"# => (Token::Colon, text),
     r#";"# => (Token::Semi, text),
+    // Anything else is an error, which the parser reports with its location
+    r#"."# => (Token::Invalid(text.to_owned()), text),
 }
 
 pub struct Lexer<'a> {
@@ -3,7 +3,10 @@ extern crate log;
 extern crate serde;
 extern crate serde_json;
 
+use std::cell::{Cell, RefCell};
 use std::fmt;
+use std::sync::atomic::AtomicBool;
+use std::thread;
 
 use aw_models::TimeInterval;
 
@@ -12,9 +15,13 @@ use aw_datastore::Datastore;
 pub mod datatype;
 
 mod ast;
+mod check;
+mod explain;
 mod functions;
 mod interpret;
 mod lexer;
+mod limits;
+mod modules;
 #[allow(
     clippy::match_single_binding,
     clippy::redundant_closure_call,
@@ -22,11 +29,13 @@ mod lexer;
 )]
 mod parser;
 
+pub use crate::check::{validate, Diagnostic, Severity};
 pub use crate::datatype::DataType;
+pub use crate::explain::{BuiltinExplanation, Explanation, StatementExplanation};
+pub use crate::interpret::QueryContext;
 pub use crate::interpret::VarEnv;
-
-// TODO: add line numbers to errors
-// (works during lexing, but not during parsing I believe)
+pub use crate::limits::QueryLimits;
+pub use crate::modules::MODULE_NAMESPACE;
 
 #[derive(Debug)]
 pub enum QueryError {
@@ -38,10 +47,14 @@ pub enum QueryError {
     VariableNotDefined(String),
     MathError(String),
     InvalidType(String),
+    IndexError(String),
     InvalidFunctionParameters(String),
     TimeIntervalError(String),
     BucketQueryError(String),
     RegexCompileError(String),
+    ImportError(String),
+    ResourceLimit(String),
+    Cancelled(),
 }
 
 impl fmt::Display for QueryError {
@@ -50,15 +63,249 @@ impl fmt::Display for QueryError {
     }
 }
 
-pub fn query(code: &str, ti: &TimeInterval, ds: &Datastore) -> Result<DataType, QueryError> {
+/// Where in the code of a query an error happened
+#[derive(Debug, Clone, PartialEq)]
+pub struct ErrorLocation {
+    /// Line of the error, starting at 1
+    pub line: usize,
+    /// Column of the error in characters, starting at 1
+    pub column: usize,
+    /// The line of the error with carets under the failing part of it
+    pub snippet: String,
+}
+
+impl ErrorLocation {
+    fn new(code: &str, span: lexer::Span) -> ErrorLocation {
+        let line_start = code[..span.lo].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = code[span.lo..]
+            .find('\n')
+            .map_or(code.len(), |i| span.lo + i);
+        let line = &code[line_start..line_end];
+        let before = &code[line_start..span.lo];
+        let failing = &code[span.lo..span.hi.min(line_end)];
+        // Keep tabs so that the carets line up with the line above them
+        let indent: String = before
+            .chars()
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        let carets = "^".repeat(failing.chars().count().max(1));
+        ErrorLocation {
+            line: span.line,
+            column: before.chars().count() + 1,
+            snippet: format!("{}\n{}{}", line.trim_end(), indent, carets),
+        }
+    }
+}
+
+/// A QueryError together with the location in the code of the expression which failed
+///
+/// The location is None for errors which are not caused by any single expression, such as a query
+/// which never returns.
+#[derive(Debug)]
+pub struct LocatedQueryError {
+    pub error: QueryError,
+    pub location: Option<ErrorLocation>,
+}
+
+impl fmt::Display for LocatedQueryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.location {
+            Some(location) => write!(
+                f,
+                "{} at line {}, column {}\n{}",
+                self.error, location.line, location.column, location.snippet
+            ),
+            None => write!(f, "{}", self.error),
+        }
+    }
+}
+
+fn parse_error(code: &str, err: parser::ParseError) -> LocatedQueryError {
+    let (message, span) = match err.0 {
+        Some((lexer::Token::Invalid(c), span)) => (format!("Invalid character '{c}'"), span),
+        Some((_, span)) => (format!("Unexpected '{}'", &code[span.lo..span.hi]), span),
+        None => {
+            // Point at the end of the last line which is not empty
+            let end = code.trim_end().len();
+            let line = code[..end].matches('\n').count() + 1;
+            let span = lexer::Span {
+                lo: end,
+                hi: end,
+                line,
+            };
+            ("Unexpected end of query".to_string(), span)
+        }
+    };
+    LocatedQueryError {
+        error: QueryError::ParsingError(message),
+        location: Some(ErrorLocation::new(code, span)),
+    }
+}
+
+/// Check that code parses, without running it
+pub fn check_syntax(code: &str) -> Result<(), LocatedQueryError> {
+    match parser::parse(lexer::Lexer::new(code)) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(parse_error(code, e)),
+    }
+}
+
+/// Whether name can't be used as a variable, since it's a keyword, a builtin or a variable set by
+/// the interpreter
+pub fn is_reserved(name: &str) -> bool {
+    let mut env = VarEnv::new();
+    functions::fill_env(&mut env);
+    lexer::KEYWORDS.contains(&name)
+        || env.contains_key(name)
+        || matches!(name, "TIMEINTERVAL" | "RETURN")
+}
+
+/// Stack size of the threads queries are interpreted on
+///
+/// Large enough for MAX_CALL_DEPTH nested function calls even in debug builds, the memory is only
+/// used by queries which actually recurse that deep.
+const QUERY_STACK_SIZE: usize = 32 * 1024 * 1024;
+
+/// Run f on a thread with a stack large enough for any query
+fn on_query_stack<T, F>(f: F) -> T
+where
+    T: Send,
+    F: FnOnce() -> T + Send,
+{
+    thread::scope(|scope| {
+        thread::Builder::new()
+            .stack_size(QUERY_STACK_SIZE)
+            .spawn_scoped(scope, f)
+            .expect("failed to spawn query thread")
+            .join()
+            .unwrap_or_else(|e| std::panic::resume_unwind(e))
+    })
+}
+
+pub fn query(code: &str, ti: &TimeInterval, ds: &Datastore) -> Result<DataType, LocatedQueryError> {
+    query_with_limits(
+        code,
+        ti,
+        ds,
+        &VarEnv::new(),
+        &QueryLimits::default(),
+        &AtomicBool::new(false),
+    )
+}
+
+/// Run a query with params bound as variables while enforcing limits
+///
+/// The query stops with QueryError::Cancelled as soon as possible after cancelled is set.
+pub fn query_with_limits(
+    code: &str,
+    ti: &TimeInterval,
+    ds: &Datastore,
+    params: &VarEnv,
+    limits: &QueryLimits,
+    cancelled: &AtomicBool,
+) -> Result<DataType, LocatedQueryError> {
+    on_query_stack(|| run_query(code, ti, ds, params, limits, cancelled, None))
+        .map(|(data, _)| data)
+}
+
+/// Run a query like query_with_limits while measuring where it spends its time
+pub fn explain_with_limits(
+    code: &str,
+    ti: &TimeInterval,
+    ds: &Datastore,
+    params: &VarEnv,
+    limits: &QueryLimits,
+    cancelled: &AtomicBool,
+) -> Result<(DataType, Explanation), LocatedQueryError> {
+    on_query_stack(|| run_explained(code, ti, ds, params, limits, cancelled))
+}
+
+fn run_explained(
+    code: &str,
+    ti: &TimeInterval,
+    ds: &Datastore,
+    params: &VarEnv,
+    limits: &QueryLimits,
+    cancelled: &AtomicBool,
+) -> Result<(DataType, Explanation), LocatedQueryError> {
+    let explainer = explain::Explainer::new();
+    let (data, explanation) = run_query(code, ti, ds, params, limits, cancelled, Some(explainer))?;
+    Ok((data, explanation.unwrap_or_default()))
+}
+
+fn run_query(
+    code: &str,
+    ti: &TimeInterval,
+    ds: &Datastore,
+    params: &VarEnv,
+    limits: &QueryLimits,
+    cancelled: &AtomicBool,
+    explainer: Option<explain::Explainer>,
+) -> Result<(DataType, Option<Explanation>), LocatedQueryError> {
     let lexer = lexer::Lexer::new(code);
     let program = match parser::parse(lexer) {
         Ok(p) => p,
         Err(e) => {
-            // TODO: Improve parsing error message
             warn!("ParsingError: {:?}", e);
-            return Err(QueryError::ParsingError(format!("{e:?}")));
+            return Err(parse_error(code, e));
         }
     };
-    interpret::interpret_prog(program, ti, ds)
+    let ctx = QueryContext {
+        ds,
+        limiter: limits::Limiter::new(limits, cancelled),
+        error_span: Cell::new(None),
+        call_depth: Cell::new(0),
+        imports: RefCell::new(Vec::new()),
+        module_depth: Cell::new(0),
+        explainer,
+    };
+    let data = interpret::interpret_prog(program, ti, params, &ctx).map_err(|error| {
+        LocatedQueryError {
+            error,
+            location: ctx
+                .error_span
+                .get()
+                .map(|span| ErrorLocation::new(code, span)),
+        }
+    })?;
+    Ok((data, ctx.explainer.map(|explainer| explainer.finish(code))))
+}
+
+/// Run a query over several time periods, one after another
+///
+/// Results are returned in the same order as the time periods. If any of the time periods fail
+/// the error of the first failing one is returned.
+pub fn query_timeperiods(
+    code: &str,
+    intervals: &[TimeInterval],
+    ds: &Datastore,
+    params: &VarEnv,
+    limits: &QueryLimits,
+    cancelled: &AtomicBool,
+) -> Result<Vec<DataType>, LocatedQueryError> {
+    on_query_stack(|| {
+        intervals
+            .iter()
+            .map(|interval| {
+                run_query(code, interval, ds, params, limits, cancelled, None).map(|(data, _)| data)
+            })
+            .collect()
+    })
+}
+
+/// Run a query over several time periods like query_timeperiods, explaining each of them
+pub fn explain_timeperiods(
+    code: &str,
+    intervals: &[TimeInterval],
+    ds: &Datastore,
+    params: &VarEnv,
+    limits: &QueryLimits,
+    cancelled: &AtomicBool,
+) -> Result<Vec<(DataType, Explanation)>, LocatedQueryError> {
+    on_query_stack(|| {
+        intervals
+            .iter()
+            .map(|interval| run_explained(code, interval, ds, params, limits, cancelled))
+            .collect()
+    })
 }
@@ -0,0 +1,182 @@
+use std::cell::Cell;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::DataType;
+use crate::QueryError;
+
+/// Limits on the resources a single query is allowed to use
+///
+/// A limit which is None is not enforced, the default is to not limit anything.
+#[derive(Clone, Debug, Default)]
+pub struct QueryLimits {
+    /// Wall-clock time the query is allowed to run for
+    pub timeout: Option<Duration>,
+    /// Total number of events the query is allowed to load from buckets
+    pub max_events: Option<usize>,
+    /// Size in bytes of the result once serialized as JSON
+    pub max_result_size: Option<usize>,
+    /// How deeply expressions are allowed to be nested
+    pub max_depth: Option<usize>,
+    /// Number of statements the query is allowed to execute
+    pub max_statements: Option<usize>,
+    /// Total number of iterations of loops and comprehensions the query is allowed to run
+    pub max_iterations: Option<usize>,
+}
+
+/// Keeps track of the resources used by a running query
+pub struct Limiter<'a> {
+    limits: &'a QueryLimits,
+    cancelled: &'a AtomicBool,
+    deadline: Option<Instant>,
+    events: Cell<usize>,
+    statements: Cell<usize>,
+    iterations: Cell<usize>,
+    depth: Cell<usize>,
+}
+
+impl<'a> Limiter<'a> {
+    pub fn new(limits: &'a QueryLimits, cancelled: &'a AtomicBool) -> Limiter<'a> {
+        Limiter {
+            limits,
+            cancelled,
+            deadline: limits.timeout.map(|timeout| Instant::now() + timeout),
+            events: Cell::new(0),
+            statements: Cell::new(0),
+            iterations: Cell::new(0),
+            depth: Cell::new(0),
+        }
+    }
+
+    /// Fails if the query has been cancelled or has run for too long
+    pub fn check(&self) -> Result<(), QueryError> {
+        if self.cancelled.load(Ordering::Relaxed) {
+            return Err(QueryError::Cancelled());
+        }
+        if let Some(deadline) = self.deadline {
+            if Instant::now() > deadline {
+                return Err(QueryError::ResourceLimit(format!(
+                    "Query did not finish within {} seconds",
+                    self.limits.timeout.unwrap_or_default().as_secs_f64()
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn statement(&self) -> Result<(), QueryError> {
+        let statements = self.statements.get() + 1;
+        self.statements.set(statements);
+        if let Some(max) = self.limits.max_statements {
+            if statements > max {
+                return Err(QueryError::ResourceLimit(format!(
+                    "Query executed more than {max} statements"
+                )));
+            }
+        }
+        self.check()
+    }
+
+    pub fn iteration(&self) -> Result<(), QueryError> {
+        self.iterations(1)
+    }
+
+    /// Count n iterations at once, for builtins which loop without running any query code
+    pub fn iterations(&self, n: usize) -> Result<(), QueryError> {
+        let iterations = self.iterations.get().saturating_add(n);
+        self.iterations.set(iterations);
+        if let Some(max) = self.limits.max_iterations {
+            if iterations > max {
+                return Err(QueryError::ResourceLimit(format!(
+                    "Query ran more than {max} loop iterations"
+                )));
+            }
+        }
+        self.check()
+    }
+
+    pub fn enter(&self) -> Result<(), QueryError> {
+        let depth = self.depth.get() + 1;
+        self.depth.set(depth);
+        if let Some(max) = self.limits.max_depth {
+            if depth > max {
+                return Err(QueryError::ResourceLimit(format!(
+                    "Query is nested deeper than {max} levels"
+                )));
+            }
+        }
+        self.check()
+    }
+
+    pub fn leave(&self) {
+        self.depth.set(self.depth.get() - 1);
+    }
+
+    /// How many more events the query is allowed to load, if limited
+    pub fn events_remaining(&self) -> Option<usize> {
+        self.limits
+            .max_events
+            .map(|max| max.saturating_sub(self.events.get()))
+    }
+
+    pub fn load_events(&self, count: usize) -> Result<(), QueryError> {
+        let events = self.events.get() + count;
+        self.events.set(events);
+        if let Some(max) = self.limits.max_events {
+            if events > max {
+                return Err(QueryError::ResourceLimit(format!(
+                    "Query loaded more than {max} events"
+                )));
+            }
+        }
+        self.check()
+    }
+
+    pub fn check_result_size(&self, result: &DataType) -> Result<(), QueryError> {
+        let max = match self.limits.max_result_size {
+            Some(max) => max,
+            None => return Ok(()),
+        };
+        let mut counter = SizeCounter { size: 0, max };
+        // Serialization stops as soon as the counter refuses more bytes
+        if serde_json::to_writer(&mut counter, result).is_err() && counter.size > max {
+            return Err(QueryError::ResourceLimit(format!(
+                "Query result is larger than {max} bytes"
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Size in bytes of value once serialized as JSON
+pub fn json_size<T: Serialize>(value: &T) -> usize {
+    let mut counter = SizeCounter {
+        size: 0,
+        max: usize::MAX,
+    };
+    // Counting never fails, and neither does serializing events
+    let _ = serde_json::to_writer(&mut counter, value);
+    counter.size
+}
+
+struct SizeCounter {
+    size: usize,
+    max: usize,
+}
+
+impl io::Write for SizeCounter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.size += buf.len();
+        if self.size > self.max {
+            return Err(io::Error::other("result too large"));
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,68 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock};
+
+use aw_datastore::DatastoreError;
+use aw_models::QueryModule;
+
+use crate::ast::Expr;
+use crate::lexer::Lexer;
+use crate::{parse_error, parser, QueryContext, QueryError};
+
+/// Key-value namespace the code of modules is stored under, followed by the name of the module
+pub const MODULE_NAMESPACE: &str = "query.modules.";
+
+/// How many parsed modules are kept before the cache is emptied
+const MAX_CACHED_MODULES: usize = 256;
+
+/// Parsed modules by their code, so that a changed module is parsed again
+fn parsed_modules() -> &'static Mutex<HashMap<String, Arc<Vec<Expr>>>> {
+    static PARSED: OnceLock<Mutex<HashMap<String, Arc<Vec<Expr>>>>> = OnceLock::new();
+    PARSED.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// Load the code of the module called name from the datastore
+fn load(ctx: &QueryContext, name: &str) -> Result<String, QueryError> {
+    let value = match ctx.ds.get_key_value(&format!("{MODULE_NAMESPACE}{name}")) {
+        Ok(value) => value,
+        Err(DatastoreError::NoSuchKey(_)) => {
+            return Err(QueryError::ImportError(format!(
+                "There's no query module named {name}"
+            )))
+        }
+        Err(e) => {
+            return Err(QueryError::ImportError(format!(
+                "Failed to load module {name}: {e:?}"
+            )))
+        }
+    };
+    match serde_json::from_str::<QueryModule>(&value) {
+        Ok(module) => Ok(module.query.join("\n")),
+        Err(e) => Err(QueryError::ImportError(format!(
+            "Stored module {name} is invalid: {e}"
+        ))),
+    }
+}
+
+/// The statements of the module called name, parsed only if its code changed since last time
+pub fn statements(ctx: &QueryContext, name: &str) -> Result<Arc<Vec<Expr>>, QueryError> {
+    let code = load(ctx, name)?;
+    if let Some(stmts) = parsed_modules().lock().unwrap().get(&code) {
+        return Ok(stmts.clone());
+    }
+    let stmts = match parser::parse(Lexer::new(&code)) {
+        Ok(program) => Arc::new(program.stmts),
+        Err(e) => {
+            return Err(QueryError::ImportError(format!(
+                "Failed to parse module {}: {}",
+                name,
+                parse_error(&code, e)
+            )))
+        }
+    };
+    let mut parsed = parsed_modules().lock().unwrap();
+    if parsed.len() >= MAX_CACHED_MODULES {
+        parsed.clear();
+    }
+    parsed.insert(code, stmts.clone());
+    Ok(stmts)
+}
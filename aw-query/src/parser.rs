@@ -17,6 +17,13 @@ fn merge_if_vecs(lhs: Expr_, rhs: Expr_) -> Expr_ {
     Expr_::If(ifs)
 }
 
+fn lambda_return(e: Expr) -> Expr {
+    Expr {
+        span: e.span,
+        node: Expr_::Return(Box::new(e)),
+    }
+}
+
 parser! {
     fn parse_(Token, Span);
 
@@ -44,7 +51,13 @@ parser! {
 
     statement: Expr {
         ifs[x] => x,
+        _for[x] => x,
+        _def[x] => x,
         ret[x] Semi => x,
+        Import String(name) Semi => Expr {
+            span: span!(),
+            node: Expr_::Import(name),
+        },
     }
 
     ifs: Expr {
@@ -110,7 +123,33 @@ parser! {
         },
     }
 
-     ret: Expr {
+    _for: Expr {
+        For Ident(var) In binop[list] LBrace statements[block] RBrace => Expr {
+            span: span!(),
+            node: Expr_::For(var, Box::new(list), block),
+        },
+    }
+
+     _def: Expr {
+        Def Ident(name) LParen _params[params] RParen LBrace statements[block] RBrace => Expr {
+            span: span!(),
+            node: Expr_::Def(name, params, block),
+        },
+        Def Ident(name) LParen RParen LBrace statements[block] RBrace => Expr {
+            span: span!(),
+            node: Expr_::Def(name, Vec::new(), block),
+        },
+    }
+
+    _params: Vec<std::string::String> {
+        Ident(param) => vec![param],
+        _params[mut params] Comma Ident(param) => {
+            params.push(param);
+            params
+        },
+    }
+
+    ret: Expr {
         Return assign[a] => Expr {
             span: span!(),
             node: Expr_::Return(Box::new(a)),
@@ -119,37 +158,151 @@ parser! {
     }
 
     assign: Expr {
-        Ident(var) Assign binop[rhs] => Expr {
+        Ident(var) Assign expr[rhs] => Expr {
             span: span!(),
             node: Expr_::Assign(var, Box::new(rhs)),
         },
+        expr[x] => x
+    }
+
+    expr: Expr {
+        Fn LParen _params[params] RParen Arrow expr[e] => Expr {
+            span: span!(),
+            node: Expr_::Lambda(params, vec![lambda_return(e)]),
+        },
+        Fn LParen RParen Arrow expr[e] => Expr {
+            span: span!(),
+            node: Expr_::Lambda(Vec::new(), vec![lambda_return(e)]),
+        },
         binop[x] => x
     }
 
+    // Operators from the lowest to the highest precedence, all of them left-associative
     binop: Expr {
-        binop[lhs] Plus func[rhs] => Expr {
+        binop[lhs] Or _and[rhs] => Expr {
+            span: span!(),
+            node: Expr_::Or(Box::new(lhs), Box::new(rhs)),
+        },
+        _and[x] => x
+    }
+
+    _and: Expr {
+        _and[lhs] And _not[rhs] => Expr {
+            span: span!(),
+            node: Expr_::And(Box::new(lhs), Box::new(rhs)),
+        },
+        _not[x] => x
+    }
+
+    _not: Expr {
+        Not _not[e] => Expr {
+            span: span!(),
+            node: Expr_::Not(Box::new(e)),
+        },
+        _comparison[x] => x
+    }
+
+    _comparison: Expr {
+        _comparison[lhs] Equals _sum[rhs] => Expr {
+            span: span!(),
+            node: Expr_::Equal(Box::new(lhs), Box::new(rhs)),
+        },
+        _comparison[lhs] NotEquals _sum[rhs] => Expr {
+            span: span!(),
+            node: Expr_::NotEqual(Box::new(lhs), Box::new(rhs)),
+        },
+        _comparison[lhs] Less _sum[rhs] => Expr {
+            span: span!(),
+            node: Expr_::Less(Box::new(lhs), Box::new(rhs)),
+        },
+        _comparison[lhs] LessEquals _sum[rhs] => Expr {
+            span: span!(),
+            node: Expr_::LessEqual(Box::new(lhs), Box::new(rhs)),
+        },
+        _comparison[lhs] Greater _sum[rhs] => Expr {
+            span: span!(),
+            node: Expr_::Greater(Box::new(lhs), Box::new(rhs)),
+        },
+        _comparison[lhs] GreaterEquals _sum[rhs] => Expr {
+            span: span!(),
+            node: Expr_::GreaterEqual(Box::new(lhs), Box::new(rhs)),
+        },
+        _sum[x] => x
+    }
+
+    _sum: Expr {
+        _sum[lhs] Plus _product[rhs] => Expr {
             span: span!(),
             node: Expr_::Add(Box::new(lhs), Box::new(rhs)),
         },
-        binop[lhs] Minus func[rhs] => Expr {
+        _sum[lhs] Minus _product[rhs] => Expr {
             span: span!(),
             node: Expr_::Sub(Box::new(lhs), Box::new(rhs)),
         },
-        binop[lhs] Star func[rhs] => Expr {
+        _product[x] => x
+    }
+
+    _product: Expr {
+        _product[lhs] Star _postfix[rhs] => Expr {
             span: span!(),
             node: Expr_::Mul(Box::new(lhs), Box::new(rhs)),
         },
-        binop[lhs] Slash func[rhs] => Expr {
+        _product[lhs] Slash _postfix[rhs] => Expr {
             span: span!(),
             node: Expr_::Div(Box::new(lhs), Box::new(rhs)),
         },
-        binop[lhs] Percent func[rhs] => Expr {
+        _product[lhs] Percent _postfix[rhs] => Expr {
             span: span!(),
             node: Expr_::Mod(Box::new(lhs), Box::new(rhs)),
         },
-        binop[lhs] Equals func[rhs] => Expr {
+        _postfix[x] => x
+    }
+
+    _postfix: Expr {
+        _postfix[e] LBracket expr[index] RBracket => Expr {
+            span: span!(),
+            node: Expr_::Index(Box::new(e), Box::new(index)),
+        },
+        _postfix[e] Dot Ident(attr) => Expr {
             span: span!(),
-            node: Expr_::Equal(Box::new(lhs), Box::new(rhs)),
+            node: {
+                let attr = Expr { span: span!(), node: Expr_::String(attr) };
+                Expr_::Index(Box::new(e), Box::new(attr))
+            },
+        },
+        // e.method(args), calls the function in attribute method of e
+        _postfix[e] Dot Ident(attr) LParen _inner_list[l] RParen => Expr {
+            span: span!(),
+            node: {
+                let attr = Expr { span: span!(), node: Expr_::String(attr) };
+                let method = Expr { span: span!(), node: Expr_::Index(Box::new(e), Box::new(attr)) };
+                Expr_::Call(Box::new(method), Box::new(l))
+            },
+        },
+        _postfix[e] Dot Ident(attr) LParen RParen => Expr {
+            span: span!(),
+            node: {
+                let attr = Expr { span: span!(), node: Expr_::String(attr) };
+                let method = Expr { span: span!(), node: Expr_::Index(Box::new(e), Box::new(attr)) };
+                let args = Expr { span: span!(), node: Expr_::List(Vec::new()) };
+                Expr_::Call(Box::new(method), Box::new(args))
+            },
+        },
+        _postfix[e] LBracket expr[start] Colon expr[end] RBracket => Expr {
+            span: span!(),
+            node: Expr_::Slice(Box::new(e), Some(Box::new(start)), Some(Box::new(end))),
+        },
+        _postfix[e] LBracket expr[start] Colon RBracket => Expr {
+            span: span!(),
+            node: Expr_::Slice(Box::new(e), Some(Box::new(start)), None),
+        },
+        _postfix[e] LBracket Colon expr[end] RBracket => Expr {
+            span: span!(),
+            node: Expr_::Slice(Box::new(e), None, Some(Box::new(end))),
+        },
+        _postfix[e] LBracket Colon RBracket => Expr {
+            span: span!(),
+            node: Expr_::Slice(Box::new(e), None, None),
         },
         func[x] => x
     }
@@ -185,6 +338,14 @@ parser! {
 
     list: Expr {
         LBracket _inner_list[l] RBracket => l,
+        LBracket binop[e] For Ident(var) In binop[list] RBracket => Expr {
+            span: span!(),
+            node: Expr_::Comprehension(Box::new(e), var, Box::new(list), None),
+        },
+        LBracket binop[e] For Ident(var) In binop[list] If binop[cond] RBracket => Expr {
+            span: span!(),
+            node: Expr_::Comprehension(Box::new(e), var, Box::new(list), Some(Box::new(cond))),
+        },
         LBracket RBracket => Expr {
             span: span!(),
             node: {
@@ -195,7 +356,7 @@ parser! {
     }
 
     _inner_list: Expr {
-        binop[o] => Expr {
+        expr[o] => Expr {
             span: span!(),
             node: {
                 let mut list = Vec::new();
@@ -203,7 +364,7 @@ parser! {
                 Expr_::List(list)
             }
         },
-        _inner_list[l] Comma binop[o] => Expr {
+        _inner_list[l] Comma expr[o] => Expr {
             span: span!(),
             node: {
                 match l.node {
@@ -218,7 +379,7 @@ parser! {
     }
 
     dict: Expr {
-        String(k) Colon binop[v] => Expr {
+        String(k) Colon expr[v] => Expr {
             span: span!(),
             node: {
                 let mut dict = HashMap::new();
@@ -226,7 +387,7 @@ parser! {
                 Expr_::Dict(dict)
             }
         },
-        dict[d] Comma String(k) Colon binop[v] => Expr {
+        dict[d] Comma String(k) Colon expr[v] => Expr {
             span: span!(),
             node: {
                 match d.node {
@@ -258,7 +419,7 @@ parser! {
             span: span!(),
             node: Expr_::String(s),
         },
-        LParen binop[x] RParen => x
+        LParen expr[x] RParen => x
     }
 }
 
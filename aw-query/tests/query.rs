@@ -24,9 +24,15 @@ mod query_tests {
     use chrono::Duration;
     use serde_json::json;
     use std::convert::TryFrom;
+    use std::str::FromStr;
+    use std::sync::atomic::AtomicBool;
+    use std::time::Duration as StdDuration;
 
     use aw_query::DataType;
     use aw_query::QueryError;
+    use aw_query::QueryLimits;
+    use aw_query::Severity;
+    use aw_query::VarEnv;
 
     use aw_datastore::Datastore;
 
@@ -89,7 +95,7 @@ mod query_tests {
         ($v:expr, $p:pat) => {
             match $v {
                 Ok(_) => panic!("Expected an error, got {:?}", $v),
-                Err(e) => match e {
+                Err(e) => match e.error {
                     $p => (),
                     _ => panic!("Expected an error of another type, got {:?}", e),
                 },
@@ -174,6 +180,957 @@ mod query_tests {
         assert_err_type!(res, QueryError::InvalidType(_));
     }
 
+    #[test]
+    fn test_comparison() {
+        let ds = setup_datastore_empty();
+        let interval = TimeInterval::new_from_string(TIME_INTERVAL).unwrap();
+
+        let cases = [
+            ("return 1!=2;", true),
+            ("return 1!=1;", false),
+            (r#"return "a"!="a";"#, false),
+            ("return True!=False;", true),
+            ("return 1<2;", true),
+            ("return 2<2;", false),
+            ("return 2<=2;", true),
+            ("return 3<=2;", false),
+            ("return 3>2;", true),
+            ("return 2>2;", false),
+            ("return 2>=2;", true),
+            ("return 1>=2;", false),
+            (r#"return "abc"<"abd";"#, true),
+            (r#"return "b">="abc";"#, true),
+            // Arithmetic binds tighter than comparisons
+            ("return 1+1>1;", true),
+            ("return 1+1==2;", true),
+        ];
+        for (code, expected) in cases {
+            match aw_query::query(code, &interval, &ds).unwrap() {
+                DataType::Bool(b) => assert_eq!(b, expected, "{code}"),
+                ref data => panic!("Wrong datatype, {data:?}"),
+            };
+        }
+
+        // Only numbers and strings can be ordered
+        let res = aw_query::query("return 1<\"2\";", &interval, &ds);
+        assert_err_type!(res, QueryError::InvalidType(_));
+        let res = aw_query::query("return False<True;", &interval, &ds);
+        assert_err_type!(res, QueryError::InvalidType(_));
+        let res = aw_query::query("return [1]>[0];", &interval, &ds);
+        assert_err_type!(res, QueryError::InvalidType(_));
+        let res = aw_query::query("return 1!=\"1\";", &interval, &ds);
+        assert_err_type!(res, QueryError::InvalidType(_));
+    }
+
+    #[test]
+    fn test_boolean_operators() {
+        let ds = setup_datastore_empty();
+        let interval = TimeInterval::new_from_string(TIME_INTERVAL).unwrap();
+
+        let cases = [
+            ("return true and true;", true),
+            ("return true and false;", false),
+            ("return false or true;", true),
+            ("return false or false;", false),
+            ("return not false;", true),
+            ("return not not false;", false),
+            // not binds tighter than and, which binds tighter than or
+            ("return not false and false;", false),
+            ("return true or true and false;", true),
+            ("return (true or true) and false;", false),
+            ("return not 1 > 2 and 2 >= 2;", true),
+            // The right hand side is not interpreted when the left decides the result
+            ("return false and undefined_var;", false),
+            ("return true or undefined_var;", true),
+        ];
+        for (code, expected) in cases {
+            match aw_query::query(code, &interval, &ds).unwrap() {
+                DataType::Bool(b) => assert_eq!(b, expected, "{code}"),
+                ref data => panic!("Wrong datatype, {data:?}"),
+            };
+        }
+
+        let code = "a = 5; if a > 1 and a < 10 { return 1; } else { return 2; }";
+        match aw_query::query(code, &interval, &ds).unwrap() {
+            DataType::Number(n) => assert_eq!(n, 1.0),
+            ref data => panic!("Wrong datatype, {data:?}"),
+        };
+
+        // Identifiers may start with a keyword
+        let code = "order = 1; android = 2; nothing = 3; return order + android + nothing;";
+        match aw_query::query(code, &interval, &ds).unwrap() {
+            DataType::Number(n) => assert_eq!(n, 6.0),
+            ref data => panic!("Wrong datatype, {data:?}"),
+        };
+
+        let res = aw_query::query("return 1 and true;", &interval, &ds);
+        assert_err_type!(res, QueryError::InvalidType(_));
+        let res = aw_query::query("return false or \"a\";", &interval, &ds);
+        assert_err_type!(res, QueryError::InvalidType(_));
+        let res = aw_query::query("return not 0;", &interval, &ds);
+        assert_err_type!(res, QueryError::InvalidType(_));
+    }
+
+    #[test]
+    fn test_for() {
+        let ds = setup_datastore_empty();
+        let interval = TimeInterval::new_from_string(TIME_INTERVAL).unwrap();
+
+        let code = "total = 0; for n in [1, 2, 3] { total = total + n; } return total;";
+        match aw_query::query(code, &interval, &ds).unwrap() {
+            DataType::Number(n) => assert_eq!(n, 6.0),
+            ref data => panic!("Wrong datatype, {data:?}"),
+        };
+
+        // Nested loops with conditions, the loop variable is left at its last value
+        let code = r#"
+            totals = [];
+            for a in ["x", "y"] {
+                count = 0;
+                for n in [1, 2, 3, 4] {
+                    if n % 2 == 0 { count = count + 1; }
+                }
+                totals = totals + [a];
+            }
+            return [totals, a, count];
+        "#;
+        match aw_query::query(code, &interval, &ds).unwrap() {
+            DataType::List(l) => assert_eq!(
+                l,
+                vec![
+                    DataType::List(vec![
+                        DataType::String("x".to_string()),
+                        DataType::String("y".to_string())
+                    ]),
+                    DataType::String("y".to_string()),
+                    DataType::Number(2.0),
+                ]
+            ),
+            ref data => panic!("Wrong datatype, {data:?}"),
+        };
+
+        // Looping over nothing does nothing
+        let code = "a = 1; for x in [] { a = 2; } return a;";
+        match aw_query::query(code, &interval, &ds).unwrap() {
+            DataType::Number(n) => assert_eq!(n, 1.0),
+            ref data => panic!("Wrong datatype, {data:?}"),
+        };
+
+        let res = aw_query::query("for x in 1 { a = x; } return a;", &interval, &ds);
+        assert_err_type!(res, QueryError::InvalidType(_));
+    }
+
+    #[test]
+    fn test_comprehension() {
+        let ds = setup_datastore_empty();
+        let interval = TimeInterval::new_from_string(TIME_INTERVAL).unwrap();
+
+        let code = "return [n * 2 for n in [1, 2, 3]];";
+        match aw_query::query(code, &interval, &ds).unwrap() {
+            DataType::List(l) => assert_eq!(
+                l,
+                vec![
+                    DataType::Number(2.0),
+                    DataType::Number(4.0),
+                    DataType::Number(6.0)
+                ]
+            ),
+            ref data => panic!("Wrong datatype, {data:?}"),
+        };
+
+        let code = "return [n for n in [1, 2, 3, 4] if n > 2];";
+        match aw_query::query(code, &interval, &ds).unwrap() {
+            DataType::List(l) => {
+                assert_eq!(l, vec![DataType::Number(3.0), DataType::Number(4.0)])
+            }
+            ref data => panic!("Wrong datatype, {data:?}"),
+        };
+
+        // The variable of a comprehension does not leak out of it
+        let code = "n = 10; l = [n for n in [1, 2]]; return n;";
+        match aw_query::query(code, &interval, &ds).unwrap() {
+            DataType::Number(n) => assert_eq!(n, 10.0),
+            ref data => panic!("Wrong datatype, {data:?}"),
+        };
+        let res = aw_query::query("l = [n for n in [1, 2]]; return n;", &interval, &ds);
+        assert_err_type!(res, QueryError::VariableNotDefined(_));
+
+        let res = aw_query::query("return [n for n in [1, 2] if n];", &interval, &ds);
+        assert_err_type!(res, QueryError::InvalidType(_));
+        let res = aw_query::query("return [n for n in \"ab\"];", &interval, &ds);
+        assert_err_type!(res, QueryError::InvalidType(_));
+    }
+
+    #[test]
+    fn test_def() {
+        let ds = setup_datastore_populated();
+        let interval = TimeInterval::new_from_string(TIME_INTERVAL).unwrap();
+        let number = |code: &str| match aw_query::query(code, &interval, &ds).unwrap() {
+            DataType::Number(n) => n,
+            ref data => panic!("Wrong datatype, {data:?}"),
+        };
+
+        assert_eq!(
+            number("def add(a, b) { return a + b; } return add(1, 2);"),
+            3.0
+        );
+        assert_eq!(number("def one() { return 1; } return one() + one();"), 2.0);
+
+        // A return exits the function, also from inside of if and for
+        let code = r#"
+            def sign(n) {
+                if n < 0 { return 0 - 1; }
+                if n == 0 { return 0; }
+                return 1;
+            }
+            def first_even(l) {
+                for n in l {
+                    if n % 2 == 0 { return n; }
+                }
+                return 0 - 1;
+            }
+            return sign(0 - 5) * 100 + sign(0) * 10 + first_even([1, 3, 4, 6]);
+        "#;
+        assert_eq!(number(code), -96.0);
+
+        // Recursion
+        let code =
+            "def fact(n) { if n <= 1 { return 1; } return n * fact(n - 1); } return fact(5);";
+        assert_eq!(number(code), 120.0);
+
+        // Unbounded recursion fails instead of overflowing the stack, even without any limits
+        let res = aw_query::query("def f(x) { return f(x); } return f(1);", &interval, &ds);
+        assert_err_type!(res, QueryError::ResourceLimit(_));
+        let code = "def f(n) { if n == 0 { return 0; } return 1 + f(n - 1); } return f(200);";
+        assert_eq!(number(code), 200.0);
+
+        // Functions capture the variables they use when they are defined, and assigning to a
+        // variable inside of a function does not change it outside of it
+        let code = r#"
+            x = 10;
+            def add_x(n) { x = x + n; return x; }
+            x = 20;
+            return add_x(1) + x;
+        "#;
+        assert_eq!(number(code), 31.0);
+
+        // Functions without a return return nothing
+        let code = "def nothing() { a = 1; } return nothing();";
+        match aw_query::query(code, &interval, &ds).unwrap() {
+            DataType::None() => (),
+            ref data => panic!("Wrong datatype, {data:?}"),
+        };
+
+        // Builtins work inside of functions
+        let code =
+            format!("def events() {{ return query_bucket(\"{BUCKET_ID}\"); }} return events();");
+        match aw_query::query(&code, &interval, &ds).unwrap() {
+            DataType::List(l) => assert_eq!(l.len(), 2),
+            ref data => panic!("Wrong datatype, {data:?}"),
+        };
+
+        let res = aw_query::query("def f(a) { return a; } return f(1, 2);", &interval, &ds);
+        assert_err_type!(res, QueryError::InvalidFunctionParameters(_));
+        let res = aw_query::query("def f() { return a; } return f();", &interval, &ds);
+        assert_err_type!(res, QueryError::VariableNotDefined(_));
+    }
+
+    #[test]
+    fn test_lambda() {
+        let ds = setup_datastore_populated();
+        let interval = TimeInterval::new_from_string(TIME_INTERVAL).unwrap();
+        let run = |code: &str| aw_query::query(code, &interval, &ds).unwrap();
+        let numbers = |l: &[f64]| DataType::List(l.iter().map(|n| DataType::Number(*n)).collect());
+
+        match run("double = fn(n) => n * 2; return double(4);") {
+            DataType::Number(n) => assert_eq!(n, 8.0),
+            ref data => panic!("Wrong datatype, {data:?}"),
+        };
+
+        // Closures
+        let code = r#"
+            def adder(n) { return fn(m) => n + m; }
+            add2 = adder(2);
+            return add2(3);
+        "#;
+        match run(code) {
+            DataType::Number(n) => assert_eq!(n, 5.0),
+            ref data => panic!("Wrong datatype, {data:?}"),
+        };
+
+        assert_eq!(
+            run("return map([1, 2, 3], fn(n) => n * n);"),
+            numbers(&[1.0, 4.0, 9.0])
+        );
+        assert_eq!(
+            run("return filter([1, 2, 3, 4], fn(n) => n % 2 == 0);"),
+            numbers(&[2.0, 4.0])
+        );
+        assert_eq!(
+            run("return reduce([1, 2, 3, 4], fn(acc, n) => acc + n, 0);"),
+            DataType::Number(10.0)
+        );
+        assert_eq!(
+            run("return sort_by([3, 1, 2], fn(n) => 0 - n);"),
+            numbers(&[3.0, 2.0, 1.0])
+        );
+        assert_eq!(
+            run(r#"return sort_by(["bb", "a", "ccc"], fn(s) => s);"#),
+            DataType::List(vec![
+                DataType::String("a".to_string()),
+                DataType::String("bb".to_string()),
+                DataType::String("ccc".to_string()),
+            ])
+        );
+
+        // Functions defined with def and builtins can be passed too
+        let code = "def inc(n) { return n + 1; } return map([1, 2], inc);";
+        assert_eq!(run(code), numbers(&[2.0, 3.0]));
+        let code = format!(
+            "events = query_bucket(\"{BUCKET_ID}\"); return map([events, []], sum_durations);"
+        );
+        assert_eq!(run(&code), numbers(&[0.0, 0.0]));
+
+        let res = aw_query::query("return map([1], 1);", &interval, &ds);
+        assert_err_type!(res, QueryError::InvalidFunctionParameters(_));
+        let res = aw_query::query("return filter([1], fn(n) => n);", &interval, &ds);
+        assert_err_type!(res, QueryError::InvalidType(_));
+        let res = aw_query::query("return sort_by([1, 2], fn(n) => [n]);", &interval, &ds);
+        assert_err_type!(res, QueryError::InvalidType(_));
+        let res = aw_query::query("return map([1], fn(a, b) => a);", &interval, &ds);
+        assert_err_type!(res, QueryError::InvalidFunctionParameters(_));
+    }
+
+    #[test]
+    fn test_index() {
+        let ds = setup_datastore_populated();
+        let interval = TimeInterval::new_from_string(TIME_INTERVAL).unwrap();
+        let run = |code: &str| aw_query::query(code, &interval, &ds).unwrap();
+
+        assert_eq!(run("return [1, 2, 3][0];"), DataType::Number(1.0));
+        assert_eq!(
+            run("l = [1, 2, 3]; return l[1 + 1];"),
+            DataType::Number(3.0)
+        );
+        // Negative indexes count from the end
+        assert_eq!(
+            run("l = [1, 2, 3]; return l[0 - 1];"),
+            DataType::Number(3.0)
+        );
+        assert_eq!(
+            run(r#"d = {"a": {"b": [5, 6]}}; return d["a"]["b"][1];"#),
+            DataType::Number(6.0)
+        );
+        assert_eq!(
+            run(r#"d = {"a": {"b": 1}}; return d.a.b;"#),
+            DataType::Number(1.0)
+        );
+        assert_eq!(
+            run(r#"return "abc"[1];"#),
+            DataType::String("b".to_string())
+        );
+
+        // Fields of events
+        let code = format!("events = query_bucket(\"{BUCKET_ID}\"); return events[0].data.key;");
+        assert_eq!(run(&code), DataType::String("value".to_string()));
+        let code = format!("events = query_bucket(\"{BUCKET_ID}\"); return events[0].duration;");
+        assert_eq!(run(&code), DataType::Number(0.0));
+        let code =
+            format!("events = query_bucket(\"{BUCKET_ID}\"); return events[0][\"timestamp\"];");
+        match run(&code) {
+            DataType::String(s) => assert!(chrono::DateTime::parse_from_rfc3339(&s).is_ok(), "{s}"),
+            ref data => panic!("Wrong datatype, {data:?}"),
+        };
+        let code = format!(
+            "events = query_bucket(\"{BUCKET_ID}\"); return map(events, fn(e) => e.data[\"key\"]);"
+        );
+        assert_eq!(
+            run(&code),
+            DataType::List(vec![
+                DataType::String("value".to_string()),
+                DataType::String("value".to_string())
+            ])
+        );
+
+        let res = aw_query::query("return [1, 2][2];", &interval, &ds);
+        assert_err_type!(res, QueryError::IndexError(_));
+        let res = aw_query::query(r#"return {"a": 1}.b;"#, &interval, &ds);
+        assert_err_type!(res, QueryError::IndexError(_));
+        let code = format!("events = query_bucket(\"{BUCKET_ID}\"); return events[0].id;");
+        let res = aw_query::query(&code, &interval, &ds);
+        assert_err_type!(res, QueryError::IndexError(_));
+        let res = aw_query::query("return [1, 2][0.5];", &interval, &ds);
+        assert_err_type!(res, QueryError::InvalidType(_));
+        let res = aw_query::query(r#"return [1, 2]["a"];"#, &interval, &ds);
+        assert_err_type!(res, QueryError::InvalidType(_));
+        let res = aw_query::query("return 1[0];", &interval, &ds);
+        assert_err_type!(res, QueryError::InvalidType(_));
+    }
+
+    #[test]
+    fn test_slice() {
+        let ds = setup_datastore_populated();
+        let interval = TimeInterval::new_from_string(TIME_INTERVAL).unwrap();
+        let run = |code: &str| aw_query::query(code, &interval, &ds).unwrap();
+        let numbers = |l: &[f64]| DataType::List(l.iter().map(|n| DataType::Number(*n)).collect());
+
+        assert_eq!(run("return [1, 2, 3, 4][1:3];"), numbers(&[2.0, 3.0]));
+        assert_eq!(run("return [1, 2, 3, 4][2:];"), numbers(&[3.0, 4.0]));
+        assert_eq!(run("return [1, 2, 3, 4][:1];"), numbers(&[1.0]));
+        assert_eq!(
+            run("return [1, 2, 3, 4][:];"),
+            numbers(&[1.0, 2.0, 3.0, 4.0])
+        );
+        assert_eq!(run("return [1, 2, 3, 4][0 - 2:];"), numbers(&[3.0, 4.0]));
+        // Bounds outside of the list are clamped to it
+        assert_eq!(run("return [1, 2, 3, 4][2:100];"), numbers(&[3.0, 4.0]));
+        assert_eq!(run("return [1, 2, 3, 4][3:1];"), numbers(&[]));
+        assert_eq!(
+            run(r#"return "hello"[1:4];"#),
+            DataType::String("ell".to_string())
+        );
+        let code = format!("events = query_bucket(\"{BUCKET_ID}\"); return events[0:1];");
+        match run(&code) {
+            DataType::List(l) => assert_eq!(l.len(), 1),
+            ref data => panic!("Wrong datatype, {data:?}"),
+        };
+
+        let res = aw_query::query(r#"return {"a": 1}[0:1];"#, &interval, &ds);
+        assert_err_type!(res, QueryError::InvalidType(_));
+        let res = aw_query::query(r#"return [1]["a":];"#, &interval, &ds);
+        assert_err_type!(res, QueryError::InvalidType(_));
+    }
+
+    #[test]
+    fn test_len() {
+        let ds = setup_datastore_populated();
+        let interval = TimeInterval::new_from_string(TIME_INTERVAL).unwrap();
+        let run = |code: &str| aw_query::query(code, &interval, &ds).unwrap();
+
+        assert_eq!(run("return len([1, 2, 3]);"), DataType::Number(3.0));
+        assert_eq!(run("return len([]);"), DataType::Number(0.0));
+        assert_eq!(
+            run(r#"return len({"a": 1, "b": 2});"#),
+            DataType::Number(2.0)
+        );
+        assert_eq!(run(r#"return len("héllo");"#), DataType::Number(5.0));
+        let code = format!("return len(query_bucket(\"{BUCKET_ID}\"));");
+        assert_eq!(run(&code), DataType::Number(2.0));
+
+        let res = aw_query::query("return len(1);", &interval, &ds);
+        assert_err_type!(res, QueryError::InvalidFunctionParameters(_));
+    }
+
+    #[test]
+    fn test_string_functions() {
+        let ds = setup_datastore_populated();
+        let interval = TimeInterval::new_from_string(TIME_INTERVAL).unwrap();
+        let run = |code: &str| aw_query::query(code, &interval, &ds).unwrap();
+        let string = |s: &str| DataType::String(s.to_string());
+
+        assert_eq!(run(r#"return lower("HeLLo");"#), string("hello"));
+        assert_eq!(run(r#"return upper("HeLLo");"#), string("HELLO"));
+        assert_eq!(
+            run(r#"return replace("a-b-c", "-", "+");"#),
+            string("a+b+c")
+        );
+        assert_eq!(
+            run(r#"return regex_replace("Docs — Mozilla Firefox", " — Mozilla Firefox$", "");"#),
+            string("Docs")
+        );
+        assert_eq!(
+            run(r#"return regex_replace("2020-01-02", "(\d+)-(\d+)-(\d+)", "$3/$2/$1");"#),
+            string("02/01/2020")
+        );
+        assert_eq!(
+            run(r#"return regex_extract("Fix ABC-123 now", "[A-Z]+-(\d+)");"#),
+            string("123")
+        );
+        assert_eq!(
+            run(r#"return regex_extract("Nothing", "[A-Z]+-\d+");"#),
+            DataType::None()
+        );
+        assert_eq!(
+            run(r#"return split("a,b,,c", ",");"#),
+            DataType::List(vec![string("a"), string("b"), string(""), string("c")])
+        );
+        assert_eq!(
+            run(r#"return format("{} took {}s: {}", "build", 1.5, true);"#),
+            string("build took 1.5s: true")
+        );
+        assert_eq!(
+            run(r#"return format("{} {}", 2, [1, "a"]);"#),
+            string(r#"2 [1.0,"a"]"#)
+        );
+
+        // On a key of events
+        let events = format!("events = query_bucket(\"{BUCKET_ID}\");");
+        let key_values = |code: &str, key: &str| -> Vec<DataType> {
+            match run(&format!("{events} {code}")) {
+                DataType::List(l) => l
+                    .into_iter()
+                    .map(|e| match e {
+                        DataType::Event(e) => match e.data.get(key) {
+                            Some(value) => DataType::from(value),
+                            None => DataType::None(),
+                        },
+                        ref data => panic!("Wrong datatype, {data:?}"),
+                    })
+                    .collect(),
+                ref data => panic!("Wrong datatype, {data:?}"),
+            }
+        };
+        assert_eq!(
+            key_values(r#"return upper(events, "key");"#, "key"),
+            vec![string("VALUE"), string("VALUE")]
+        );
+        assert_eq!(
+            key_values(r#"return replace(events, "key", "val", "x");"#, "key"),
+            vec![string("xue"), string("xue")]
+        );
+        assert_eq!(
+            key_values(r#"return regex_replace(events, "key", "^v", "V");"#, "key"),
+            vec![string("Value"), string("Value")]
+        );
+        assert_eq!(
+            key_values(r#"return lower(events, "missing");"#, "key"),
+            vec![string("value"), string("value")]
+        );
+        assert_eq!(
+            key_values(
+                r#"return regex_extract(events, "key", "v(a.)", "extracted");"#,
+                "extracted"
+            ),
+            vec![string("al"), string("al")]
+        );
+
+        let res = aw_query::query(r#"return lower(1);"#, &interval, &ds);
+        assert_err_type!(res, QueryError::InvalidFunctionParameters(_));
+        let res = aw_query::query(r#"return replace("a", "b");"#, &interval, &ds);
+        assert_err_type!(res, QueryError::InvalidFunctionParameters(_));
+        let res = aw_query::query(r#"return regex_replace("a", "(", "");"#, &interval, &ds);
+        assert_err_type!(res, QueryError::RegexCompileError(_));
+        let res = aw_query::query(r#"return split("a", "");"#, &interval, &ds);
+        assert_err_type!(res, QueryError::InvalidFunctionParameters(_));
+        let res = aw_query::query(r#"return format("{} {}", 1);"#, &interval, &ds);
+        assert_err_type!(res, QueryError::InvalidFunctionParameters(_));
+    }
+
+    #[test]
+    fn test_time_functions() {
+        let ds = setup_datastore_with_bucket();
+        let e1 = Event {
+            id: None,
+            timestamp: chrono::DateTime::from_str("2000-01-01T20:00:00Z").unwrap(),
+            duration: Duration::hours(6),
+            data: json_map! {"key": json!("value")},
+        };
+        ds.insert_events(BUCKET_ID, &[e1]).unwrap();
+        let interval =
+            TimeInterval::new_from_string("2000-01-01T00:00:00Z/2000-01-04T00:00:00Z").unwrap();
+        let run = |code: &str| aw_query::query(code, &interval, &ds).unwrap();
+        let string = |s: &str| DataType::String(s.to_string());
+
+        assert_eq!(
+            run("return timeinterval_start();"),
+            string("2000-01-01T00:00:00Z")
+        );
+        assert_eq!(
+            run("return timeinterval_end();"),
+            string("2000-01-04T00:00:00Z")
+        );
+        // The time period has ended, so now() is its end
+        assert_eq!(run("return now();"), string("2000-01-04T00:00:00Z"));
+        let open_interval =
+            TimeInterval::new_from_string("2000-01-01T00:00:00Z/2100-01-01T00:00:00Z").unwrap();
+        let code = "return date_diff(timeinterval_end(), now());";
+        match aw_query::query(code, &open_interval, &ds).unwrap() {
+            DataType::Number(n) => assert!(n > 0.0),
+            ref data => panic!("Wrong datatype, {data:?}"),
+        }
+        assert_eq!(
+            run(r#"return date_add("2000-01-01T23:30:00+02:00", "1h");"#),
+            string("2000-01-02T00:30:00+02:00")
+        );
+        assert_eq!(
+            run(r#"return date_add(timeinterval_start(), 0 - 90);"#),
+            string("1999-12-31T23:58:30Z")
+        );
+        assert_eq!(
+            run(r#"return date_add(timeinterval_start(), "1.5d");"#),
+            string("2000-01-02T12:00:00Z")
+        );
+        assert_eq!(
+            run(r#"return date_diff("2000-01-02T00:00:00+01:00", "2000-01-01T00:00:00Z");"#),
+            DataType::Number(82800.0)
+        );
+
+        // Split per day at UTC and at UTC+2
+        let durations = |code: &str| -> Vec<(String, Vec<f64>)> {
+            let code = format!("events = query_bucket(\"{BUCKET_ID}\"); {code}");
+            let mut periods: Vec<(String, Vec<f64>)> = match run(&code) {
+                DataType::Dict(d) => d
+                    .into_iter()
+                    .map(|(start, events)| {
+                        let events: Vec<Event> = (&events).try_into().unwrap();
+                        let durations = events
+                            .iter()
+                            .map(|e| e.duration.num_seconds() as f64 / 3600.0)
+                            .collect();
+                        (start, durations)
+                    })
+                    .collect(),
+                ref data => panic!("Wrong datatype, {data:?}"),
+            };
+            periods.sort_by(|a, b| a.0.cmp(&b.0));
+            periods
+        };
+        assert_eq!(
+            durations(r#"return split_by_period(events, "1d", 0);"#),
+            vec![
+                ("2000-01-01T00:00:00Z".to_string(), vec![4.0]),
+                ("2000-01-02T00:00:00Z".to_string(), vec![2.0]),
+                ("2000-01-03T00:00:00Z".to_string(), vec![]),
+            ]
+        );
+        assert_eq!(
+            durations(r#"return split_by_period(events, "1d", 2);"#),
+            vec![
+                ("2000-01-01T00:00:00+02:00".to_string(), vec![2.0]),
+                ("2000-01-02T00:00:00+02:00".to_string(), vec![4.0]),
+                ("2000-01-03T00:00:00+02:00".to_string(), vec![]),
+                ("2000-01-04T00:00:00+02:00".to_string(), vec![]),
+            ]
+        );
+        assert_eq!(
+            durations(r#"return split_by_period(events, "1w", 0);"#),
+            vec![
+                ("1999-12-27T00:00:00Z".to_string(), vec![6.0]),
+                ("2000-01-03T00:00:00Z".to_string(), vec![]),
+            ]
+        );
+        assert_eq!(
+            durations(r#"return split_by_period(events, "1h", 0);"#).len(),
+            72
+        );
+
+        let res = aw_query::query(r#"return date_add("yesterday", 1);"#, &interval, &ds);
+        assert_err_type!(res, QueryError::InvalidFunctionParameters(_));
+        let res = aw_query::query(
+            r#"return date_add(timeinterval_start(), "1y");"#,
+            &interval,
+            &ds,
+        );
+        assert_err_type!(res, QueryError::InvalidFunctionParameters(_));
+        let res = aw_query::query(r#"return split_by_period([], "1s", 0);"#, &interval, &ds);
+        assert_err_type!(res, QueryError::InvalidFunctionParameters(_));
+        let res = aw_query::query(r#"return split_by_period([], "1d", 25);"#, &interval, &ds);
+        assert_err_type!(res, QueryError::InvalidFunctionParameters(_));
+        let limits = QueryLimits {
+            max_iterations: Some(10),
+            ..Default::default()
+        };
+        let res = aw_query::query_with_limits(
+            r#"return split_by_period([], "1h", 0);"#,
+            &interval,
+            &ds,
+            &VarEnv::new(),
+            &limits,
+            &AtomicBool::new(false),
+        );
+        assert_err_type!(res, QueryError::ResourceLimit(_));
+        // Billions of periods are refused without counting them one by one
+        let long_interval =
+            TimeInterval::new_from_string("0001-01-01T00:00:00Z/9999-01-01T00:00:00Z").unwrap();
+        let res = aw_query::query_with_limits(
+            r#"return split_by_period([], "1m", 0);"#,
+            &long_interval,
+            &ds,
+            &VarEnv::new(),
+            &limits,
+            &AtomicBool::new(false),
+        );
+        assert_err_type!(res, QueryError::ResourceLimit(_));
+    }
+
+    #[test]
+    fn test_validate() {
+        let buckets = vec![BUCKET_ID.to_string()];
+        let check = |code: &str| aw_query::validate(code, &[], &buckets);
+        let messages = |code: &str| -> Vec<(Severity, String)> {
+            check(code)
+                .into_iter()
+                .map(|d| (d.severity, d.message))
+                .collect()
+        };
+
+        assert_eq!(
+            messages(
+                r#"
+                events = query_bucket("testid");
+                def total(events) { return sum_durations(events); }
+                short = filter(events, fn(e) => e.duration < 10);
+                days = split_by_period(events, "1d", 0);
+                return {"total": total(events), "days": days, "n": len(short)};
+                "#
+            ),
+            vec![]
+        );
+
+        // Parse errors are reported alone
+        let diagnostics = check("return 1 +;");
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].severity, Severity::Error);
+        assert_eq!(diagnostics[0].message, "Unexpected ';'");
+
+        // Undefined names
+        let diagnostics = check("x = 1;\nreturn y + x;");
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].message, "Variable y is not defined");
+        let location = diagnostics[0].location.as_ref().unwrap();
+        assert_eq!((location.line, location.column), (2, 8));
+        assert_eq!(
+            messages("return nosuchfunction(1);"),
+            vec![(
+                Severity::Error,
+                "Function nosuchfunction is not defined".to_string()
+            )]
+        );
+        // Functions only see the names defined before them
+        assert_eq!(
+            messages("def f() { return later; } later = 1; return f();"),
+            vec![(Severity::Error, "Variable later is not defined".to_string())]
+        );
+
+        // Argument counts and types
+        assert_eq!(
+            messages("return len(1, 2);"),
+            vec![(
+                Severity::Error,
+                "len(Number, Number) does not match len(List) or len(Dict) or len(String)"
+                    .to_string()
+            )]
+        );
+        assert_eq!(
+            messages(r#"return limit_events(query_bucket("testid"), "10");"#),
+            vec![(
+                Severity::Error,
+                "limit_events(List, String) does not match limit_events(List, Number)".to_string()
+            )]
+        );
+        assert_eq!(messages(r#"return format("{} {}", 1, [2], "3");"#), vec![]);
+        assert_eq!(
+            messages("def f(a, b) { return a + b; } return f(1);"),
+            vec![(
+                Severity::Error,
+                "Function f expects 2 parameters, got 1".to_string()
+            )]
+        );
+        assert_eq!(
+            messages("x = 1; return x(2);"),
+            vec![(Severity::Error, "x is a Number, not a function".to_string())]
+        );
+        // Types which are only known when running are not complained about
+        assert_eq!(
+            messages("for x in [1, \"a\"] { y = x; } return lower(y);"),
+            vec![]
+        );
+
+        // Modules are only known when running
+        assert_eq!(
+            messages(r#"import "utils"; return utils.hours(utils.HOUR);"#),
+            vec![]
+        );
+
+        // Buckets and missing returns
+        assert_eq!(
+            messages(
+                r#"a = query_bucket("missing"); b = find_bucket("test"); c = find_bucket("x");"#
+            ),
+            vec![
+                (
+                    Severity::Warning,
+                    "There is no bucket named missing".to_string()
+                ),
+                (
+                    Severity::Warning,
+                    "There is no bucket starting with x".to_string()
+                ),
+                (
+                    Severity::Error,
+                    "The query never returns a value".to_string()
+                ),
+            ]
+        );
+
+        // Params
+        assert_eq!(
+            aw_query::validate("return limit + 1;", &["limit".to_string()], &buckets),
+            vec![]
+        );
+        assert_eq!(
+            aw_query::validate("return 1;", &["len".to_string()], &buckets)[0].message,
+            "Parameter len would shadow a builtin"
+        );
+    }
+
+    #[test]
+    fn test_explain() {
+        let ds = setup_datastore_populated();
+        let interval = TimeInterval::new_from_string(TIME_INTERVAL).unwrap();
+        let code = format!(
+            "events = query_bucket(\"{BUCKET_ID}\");\n\
+             for e in events {{ x = limit_events(events, 1); }}\n\
+             return sort_by_duration(events);"
+        );
+        let (data, explanation) = aw_query::explain_with_limits(
+            &code,
+            &interval,
+            &ds,
+            &VarEnv::new(),
+            &QueryLimits::default(),
+            &AtomicBool::new(false),
+        )
+        .unwrap();
+        match data {
+            DataType::List(l) => assert_eq!(l.len(), 2),
+            ref data => panic!("Wrong datatype, {data:?}"),
+        };
+
+        assert_eq!(explanation.events_loaded, 2);
+        assert!(explanation.bytes_loaded > 0);
+        assert!(explanation.duration > 0.0);
+        let statements: Vec<(usize, &str, u64)> = explanation
+            .statements
+            .iter()
+            .map(|s| (s.line, s.code.as_str(), s.executions))
+            .collect();
+        assert_eq!(
+            statements,
+            vec![
+                (1, r#"events = query_bucket("testid")"#, 1),
+                (2, "for e in events { x = limit_events(events, 1); }", 1),
+                (2, "x = limit_events(events, 1)", 2),
+                (3, "return sort_by_duration(events)", 1),
+            ]
+        );
+        let mut builtins: Vec<(&str, u64, u64, u64)> = explanation
+            .builtins
+            .iter()
+            .map(|b| (b.name.as_str(), b.calls, b.events_in, b.events_out))
+            .collect();
+        builtins.sort();
+        assert_eq!(
+            builtins,
+            vec![
+                ("limit_events", 2, 4, 2),
+                ("query_bucket", 1, 0, 2),
+                ("sort_by_duration", 1, 2, 2),
+            ]
+        );
+
+        // Builtins called by other builtins are explained too
+        let (_, explanation) = aw_query::explain_with_limits(
+            "return map([[1], [2, 3]], len);",
+            &interval,
+            &ds,
+            &VarEnv::new(),
+            &QueryLimits::default(),
+            &AtomicBool::new(false),
+        )
+        .unwrap();
+        let mut calls: Vec<(&str, u64)> = explanation
+            .builtins
+            .iter()
+            .map(|b| (b.name.as_str(), b.calls))
+            .collect();
+        calls.sort();
+        assert_eq!(calls, vec![("len", 2), ("map", 1)]);
+    }
+
+    #[test]
+    fn test_import() {
+        let ds = setup_datastore_populated();
+        let interval = TimeInterval::new_from_string(TIME_INTERVAL).unwrap();
+        let set_module = |name: &str, lines: &[&str]| {
+            let key = format!("{}{}", aw_query::MODULE_NAMESPACE, name);
+            ds.set_key_value(&key, &json!({ "query": lines }).to_string())
+                .unwrap();
+        };
+        let run = |code: &str| aw_query::query(code, &interval, &ds);
+
+        set_module(
+            "utils",
+            &[
+                "HOUR = 3600;",
+                "def hours(events) { return sum_durations(events) / HOUR; }",
+                "double = fn(x) => x * 2;",
+                "def fail() { return 1 + \"a\"; }",
+            ],
+        );
+        assert_eq!(
+            run(&format!(
+                "import \"utils\";\n\
+                 return [utils.HOUR, utils.hours(query_bucket(\"{BUCKET_ID}\")), utils.double(2)];"
+            ))
+            .unwrap(),
+            DataType::List(vec![
+                DataType::Number(3600.0),
+                DataType::Number(0.0),
+                DataType::Number(4.0)
+            ])
+        );
+        // Builtins are not part of the module
+        assert_eq!(
+            run(r#"import "utils"; return [len(utils), contains(utils, "len")];"#).unwrap(),
+            DataType::List(vec![DataType::Number(4.0), DataType::Bool(false)])
+        );
+        // Names of the module don't leak into the query
+        assert_err_type!(
+            run(r#"import "utils"; return HOUR;"#),
+            QueryError::VariableNotDefined(_)
+        );
+
+        // Modules can import modules, and changes to them are picked up
+        set_module("days", &["import \"utils\";", "DAY = 24 * utils.HOUR;"]);
+        assert_eq!(
+            run(r#"import "days"; return days.DAY;"#).unwrap(),
+            DataType::Number(86400.0)
+        );
+        set_module("days", &["DAY = 1;"]);
+        assert_eq!(
+            run(r#"import "days"; return days.DAY;"#).unwrap(),
+            DataType::Number(1.0)
+        );
+
+        // Errors in modules are reported where the query uses the module
+        let err = run("import \"utils\";\nreturn utils.fail();").unwrap_err();
+        assert!(matches!(err.error, QueryError::InvalidType(_)));
+        let location = err.location.unwrap();
+        assert_eq!((location.line, location.column), (2, 8));
+
+        set_module("a", &["import \"b\";"]);
+        set_module("b", &["import \"a\";"]);
+        let err = run(r#"import "a"; return 1;"#).unwrap_err();
+        match err.error {
+            QueryError::ImportError(msg) => assert_eq!(msg, "Import cycle: a -> b -> a"),
+            e => panic!("Expected an import error, got {e:?}"),
+        }
+        assert_eq!(err.location.unwrap().line, 1);
+
+        set_module("broken", &["x = ;"]);
+        match run(r#"import "broken"; return 1;"#).unwrap_err().error {
+            QueryError::ImportError(msg) => {
+                assert!(msg.starts_with("Failed to parse module broken: "), "{msg}")
+            }
+            e => panic!("Expected an import error, got {e:?}"),
+        }
+        assert_err_type!(
+            run(r#"import "missing"; return 1;"#),
+            QueryError::ImportError(_)
+        );
+    }
+
     #[test]
     fn test_return() {
         let ds = setup_datastore_empty();
@@ -319,7 +1276,7 @@ mod query_tests {
         let code = String::from("return no_such_function(1);");
         match aw_query::query(&code, &interval, &ds) {
             Ok(ok) => panic!("Expected QueryError, got {ok:?}"),
-            Err(e) => match e {
+            Err(e) => match e.error {
                 QueryError::VariableNotDefined(qe) => assert_eq!(qe, "no_such_function"),
                 qe => panic!("Expected QueryError::VariableNotDefined, got {qe:?}"),
             },
@@ -328,7 +1285,7 @@ mod query_tests {
         let code = String::from("invalid_type=1; return invalid_type(1);");
         match aw_query::query(&code, &interval, &ds) {
             Ok(ok) => panic!("Expected QueryError, got {ok:?}"),
-            Err(e) => match e {
+            Err(e) => match e.error {
                 QueryError::InvalidType(qe) => assert_eq!(qe, "invalid_type"),
                 qe => panic!("Expected QueryError::VariableNotDefined, got {qe:?}"),
             },
@@ -648,5 +1605,224 @@ mod query_tests {
             DataType::Number(n) => assert_eq!(n, 3.0),
             num => panic!("Expected number, got {num:?}"),
         };
+
+        let code = String::from("return 1+2*3-4/2;");
+        match aw_query::query(&code, &interval, &ds).unwrap() {
+            DataType::Number(n) => assert_eq!(n, 5.0),
+            num => panic!("Expected number, got {num:?}"),
+        };
+    }
+
+    #[test]
+    fn test_limits() {
+        let ds = setup_datastore_populated();
+        let interval = TimeInterval::new_from_string(TIME_INTERVAL).unwrap();
+        let not_cancelled = AtomicBool::new(false);
+        let no_params = VarEnv::new();
+        let run = |code: &str, limits: &QueryLimits| {
+            aw_query::query_with_limits(code, &interval, &ds, &no_params, limits, &not_cancelled)
+        };
+        let code = format!("events = query_bucket(\"{BUCKET_ID}\"); return events;");
+
+        // Within limits
+        let limits = QueryLimits {
+            timeout: Some(StdDuration::from_secs(60)),
+            max_events: Some(2),
+            max_result_size: Some(10_000),
+            max_depth: Some(10),
+            max_statements: Some(2),
+            max_iterations: Some(10),
+        };
+        match run(&code, &limits).unwrap() {
+            DataType::List(l) => assert_eq!(l.len(), 2),
+            ref data => panic!("Expected list, got {data:?}"),
+        };
+
+        let limits = QueryLimits {
+            max_events: Some(1),
+            ..Default::default()
+        };
+        assert_err_type!(run(&code, &limits), QueryError::ResourceLimit(_));
+
+        let limits = QueryLimits {
+            max_result_size: Some(10),
+            ..Default::default()
+        };
+        assert_err_type!(run(&code, &limits), QueryError::ResourceLimit(_));
+
+        let limits = QueryLimits {
+            max_statements: Some(1),
+            ..Default::default()
+        };
+        assert_err_type!(run(&code, &limits), QueryError::ResourceLimit(_));
+
+        let limits = QueryLimits {
+            max_depth: Some(3),
+            ..Default::default()
+        };
+        assert!(run("return 1+2;", &limits).is_ok());
+        assert_err_type!(run("return 1+2+3;", &limits), QueryError::ResourceLimit(_));
+
+        let limits = QueryLimits {
+            max_iterations: Some(3),
+            ..Default::default()
+        };
+        assert!(run("for x in [1, 2, 3] { a = x; } return a;", &limits).is_ok());
+        let res = run("for x in [1, 2, 3, 4] { a = x; } return a;", &limits);
+        assert_err_type!(res, QueryError::ResourceLimit(_));
+        // The limit is for all loops of the query together
+        let res = run("a = [x for x in [1, 2]]; return [x for x in a];", &limits);
+        assert_err_type!(res, QueryError::ResourceLimit(_));
+
+        let limits = QueryLimits {
+            timeout: Some(StdDuration::ZERO),
+            ..Default::default()
+        };
+        assert_err_type!(run(&code, &limits), QueryError::ResourceLimit(_));
+
+        let cancelled = AtomicBool::new(true);
+        let res = aw_query::query_with_limits(
+            &code,
+            &interval,
+            &ds,
+            &no_params,
+            &QueryLimits::default(),
+            &cancelled,
+        );
+        assert_err_type!(res, QueryError::Cancelled());
+    }
+
+    #[test]
+    fn test_timeperiods() {
+        let ds = setup_datastore_empty();
+        let no_params = VarEnv::new();
+        let no_limits = QueryLimits::default();
+        let not_cancelled = AtomicBool::new(false);
+        let intervals: Vec<TimeInterval> = (1..=9)
+            .map(|day| {
+                TimeInterval::new_from_string(&format!(
+                    "2000-01-0{day}T00:00:00Z/2000-01-0{day}T12:00:00Z"
+                ))
+                .unwrap()
+            })
+            .collect();
+
+        let code = "return TIMEINTERVAL;";
+        let results = aw_query::query_timeperiods(
+            code,
+            &intervals,
+            &ds,
+            &no_params,
+            &no_limits,
+            &not_cancelled,
+        )
+        .unwrap();
+        let expected: Vec<String> = intervals.iter().map(|ti| ti.to_string()).collect();
+        let got: Vec<String> = results
+            .into_iter()
+            .map(|data| String::try_from(&data).unwrap())
+            .collect();
+        assert_eq!(got, expected);
+
+        let code = "return nonexistent;";
+        let res = aw_query::query_timeperiods(
+            code,
+            &intervals,
+            &ds,
+            &no_params,
+            &no_limits,
+            &not_cancelled,
+        );
+        assert_err_type!(res, QueryError::VariableNotDefined(_));
+    }
+
+    #[test]
+    fn test_params() {
+        let ds = setup_datastore_empty();
+        let interval = TimeInterval::new_from_string(TIME_INTERVAL).unwrap();
+        let not_cancelled = AtomicBool::new(false);
+        let run = |code: &str, params: &VarEnv| {
+            aw_query::query_with_limits(
+                code,
+                &interval,
+                &ds,
+                params,
+                &QueryLimits::default(),
+                &not_cancelled,
+            )
+        };
+
+        let mut params = VarEnv::new();
+        params.insert("n".to_string(), DataType::from(&json!(2)));
+        params.insert("names".to_string(), DataType::from(&json!(["a", "b"])));
+        match run("return n * 3;", &params).unwrap() {
+            DataType::Number(n) => assert_eq!(n, 6.0),
+            ref data => panic!("Expected number, got {data:?}"),
+        };
+        match run("return names;", &params).unwrap() {
+            DataType::List(l) => assert_eq!(
+                l,
+                vec![
+                    DataType::String("a".to_string()),
+                    DataType::String("b".to_string())
+                ]
+            ),
+            ref data => panic!("Expected list, got {data:?}"),
+        };
+        assert_err_type!(run("return m;", &params), QueryError::VariableNotDefined(_));
+
+        let mut params = VarEnv::new();
+        params.insert("query_bucket".to_string(), DataType::Number(1.0));
+        assert_err_type!(
+            run("return 1;", &params),
+            QueryError::InvalidFunctionParameters(_)
+        );
+    }
+
+    #[test]
+    fn test_error_location() {
+        let ds = setup_datastore_empty();
+        let interval = TimeInterval::new_from_string(TIME_INTERVAL).unwrap();
+
+        // The innermost failing expression is the one pointed at
+        let code = "a = 1;\nreturn a + (2 * no_such_var);";
+        let err = aw_query::query(code, &interval, &ds).unwrap_err();
+        assert!(matches!(err.error, QueryError::VariableNotDefined(_)));
+        let location = err.location.unwrap();
+        assert_eq!(location.line, 2);
+        assert_eq!(location.column, 17);
+        assert_eq!(
+            location.snippet,
+            "return a + (2 * no_such_var);\n                ^^^^^^^^^^^"
+        );
+
+        let err = aw_query::query("return 1/0;", &interval, &ds).unwrap_err();
+        let location = err.location.unwrap();
+        assert_eq!((location.line, location.column), (1, 8));
+        assert_eq!(location.snippet, "return 1/0;\n       ^^^");
+
+        // Parse errors
+        let err = aw_query::query("a = 1;\n  b = );", &interval, &ds).unwrap_err();
+        assert_eq!(err.error.to_string(), "ParsingError(\"Unexpected ')'\")");
+        let location = err.location.unwrap();
+        assert_eq!((location.line, location.column), (2, 7));
+        assert_eq!(location.snippet, "  b = );\n      ^");
+
+        let err = aw_query::query("return 1 @ 2;", &interval, &ds).unwrap_err();
+        assert!(matches!(err.error, QueryError::ParsingError(_)));
+        assert_eq!(
+            err.to_string(),
+            "ParsingError(\"Invalid character '@'\") at line 1, column 10\nreturn 1 @ 2;\n         ^"
+        );
+
+        let err = aw_query::query("return [1, 2\n", &interval, &ds).unwrap_err();
+        let location = err.location.unwrap();
+        assert_eq!((location.line, location.column), (1, 13));
+        assert_eq!(location.snippet, "return [1, 2\n            ^");
+
+        // Not caused by any expression
+        let err = aw_query::query("a = 1;", &interval, &ds).unwrap_err();
+        assert!(matches!(err.error, QueryError::EmptyQuery()));
+        assert!(err.location.is_none());
     }
 }
@@ -8,6 +8,8 @@ use std::os::raw::c_char;
 
 use crate::device_id;
 use crate::dirs;
+use crate::profiles::Profiles;
+use crate::query_cache::QueryCache;
 
 use android_logger::Config;
 use rocket::serde::json::json;
@@ -116,6 +118,8 @@ pub mod android {
         unsafe {
             let server_state: ServerState = endpoints::ServerState {
                 datastore: Mutex::new(openDatastore()),
+                profiles: Profiles::open(dirs::profiles_dir(false).unwrap()),
+                query_cache: QueryCache::new(),
                 asset_resolver: endpoints::AssetResolver::new(None),
                 device_id: device_id::get_device_id(),
             };
@@ -1,11 +1,14 @@
 use std::fs::File;
 use std::io::{Read, Write};
+use std::time::Duration;
 
 use rocket::config::Config;
 use rocket::data::{Limits, ToByteUnit};
 use rocket::log::LogLevel;
 use serde::{Deserialize, Serialize};
 
+use aw_query::QueryLimits;
+
 use crate::dirs;
 
 // Far from an optimal way to solve it, but works and is simple
@@ -19,7 +22,7 @@ pub fn is_testing() -> bool {
     unsafe { TESTING }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct AWConfig {
     #[serde(default = "default_address")]
     pub address: String,
@@ -27,6 +30,18 @@ pub struct AWConfig {
     #[serde(default = "default_port")]
     pub port: u16,
 
+    // Set to false to only serve the API on the Unix socket
+    #[serde(default = "default_tcp")]
+    pub tcp: bool,
+
+    // Serve the API on a Unix domain socket which only the user can connect to
+    #[serde(default)]
+    pub unix_socket: bool,
+
+    // Path of the Unix socket, defaults to a socket in the user's runtime dir
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unix_socket_path: Option<String>,
+
     #[serde(skip, default = "default_testing")]
     pub testing: bool, // This is not written to the config file (serde(skip))
 
@@ -37,6 +52,70 @@ pub struct AWConfig {
     // custom visualizations are located.
     #[serde(default = "default_custom_static")]
     pub custom_static: std::collections::HashMap<String, String>,
+
+    // How many days entries are kept in the audit log, 0 keeps them forever
+    #[serde(default = "default_audit_retention_days")]
+    pub audit_retention_days: u64,
+
+    // Resource limits for queries, a limit of 0 disables it
+    #[serde(default)]
+    pub query: QueryConfig,
+
+    // Overrides the log level (trace, debug, info, warn or error) unless LOG_LEVEL is set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub log_level: Option<String>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct QueryConfig {
+    #[serde(default = "default_query_timeout")]
+    pub timeout: u64, // seconds
+
+    #[serde(default = "default_query_max_events")]
+    pub max_events: usize,
+
+    #[serde(default = "default_query_max_result_size")]
+    pub max_result_size: usize, // bytes
+
+    #[serde(default = "default_query_max_depth")]
+    pub max_depth: usize,
+
+    #[serde(default = "default_query_max_statements")]
+    pub max_statements: usize,
+
+    // Iterations of all loops and comprehensions together
+    #[serde(default = "default_query_max_iterations")]
+    pub max_iterations: usize,
+}
+
+impl Default for QueryConfig {
+    fn default() -> QueryConfig {
+        QueryConfig {
+            timeout: default_query_timeout(),
+            max_events: default_query_max_events(),
+            max_result_size: default_query_max_result_size(),
+            max_depth: default_query_max_depth(),
+            max_statements: default_query_max_statements(),
+            max_iterations: default_query_max_iterations(),
+        }
+    }
+}
+
+impl QueryConfig {
+    pub fn to_limits(&self) -> QueryLimits {
+        let nonzero = |limit: usize| if limit == 0 { None } else { Some(limit) };
+        QueryLimits {
+            timeout: match self.timeout {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            },
+            max_events: nonzero(self.max_events),
+            max_result_size: nonzero(self.max_result_size),
+            max_depth: nonzero(self.max_depth),
+            max_statements: nonzero(self.max_statements),
+            max_iterations: nonzero(self.max_iterations),
+        }
+    }
 }
 
 impl Default for AWConfig {
@@ -44,9 +123,15 @@ impl Default for AWConfig {
         AWConfig {
             address: default_address(),
             port: default_port(),
+            tcp: default_tcp(),
+            unix_socket: false,
+            unix_socket_path: None,
             testing: default_testing(),
             cors: default_cors(),
             custom_static: default_custom_static(),
+            audit_retention_days: default_audit_retention_days(),
+            query: QueryConfig::default(),
+            log_level: None,
         }
     }
 }
@@ -64,7 +149,8 @@ impl AWConfig {
         // Needed for bucket imports
         let limits = Limits::default()
             .limit("json", 1000u64.megabytes())
-            .limit("data-form", 1000u64.megabytes());
+            .limit("data-form", 1000u64.megabytes())
+            .limit("ndjson", 1000u64.megabytes());
 
         config.address = self.address.parse().unwrap();
         config.port = self.port;
@@ -79,6 +165,10 @@ fn default_address() -> String {
     "127.0.0.1".to_string()
 }
 
+fn default_tcp() -> bool {
+    true
+}
+
 fn default_cors() -> Vec<String> {
     Vec::<String>::new()
 }
@@ -99,6 +189,34 @@ fn default_custom_static() -> std::collections::HashMap<String, String> {
     std::collections::HashMap::new()
 }
 
+fn default_audit_retention_days() -> u64 {
+    90
+}
+
+fn default_query_timeout() -> u64 {
+    60
+}
+
+fn default_query_max_events() -> usize {
+    5_000_000
+}
+
+fn default_query_max_result_size() -> usize {
+    100_000_000
+}
+
+fn default_query_max_depth() -> usize {
+    256
+}
+
+fn default_query_max_statements() -> usize {
+    100_000
+}
+
+fn default_query_max_iterations() -> usize {
+    1_000_000
+}
+
 pub fn create_config(testing: bool) -> AWConfig {
     set_testing(testing);
     let mut config_path = dirs::get_config_dir().unwrap();
@@ -127,13 +245,22 @@ pub fn create_config(testing: bool) -> AWConfig {
         wfile.sync_all().expect("Unable to sync config file");
     }
 
+    read_config(testing).expect("Failed to read config")
+}
+
+/// Read the config file written by create_config, for reloading the config of a running server
+pub fn read_config(testing: bool) -> Result<AWConfig, String> {
+    let mut config_path = dirs::get_config_dir().unwrap();
+    if !testing {
+        config_path.push("config.toml")
+    } else {
+        config_path.push("config-testing.toml")
+    }
+
     debug!("Reading config at {:?}", config_path);
-    let mut rfile = File::open(config_path).expect("Failed to open config file for reading");
     let mut content = String::new();
-    rfile
-        .read_to_string(&mut content)
-        .expect("Failed to read config as a string");
-    let aw_config: AWConfig = toml::from_str(&content).expect("Failed to parse config file");
-
-    aw_config
+    File::open(&config_path)
+        .and_then(|mut rfile| rfile.read_to_string(&mut content))
+        .map_err(|err| format!("Failed to read config at {config_path:?}: {err}"))?;
+    toml::from_str(&content).map_err(|err| format!("Failed to parse config file: {err}"))
 }
@@ -65,6 +65,45 @@ pub fn get_log_dir(module: &str) -> Result<PathBuf, ()> {
     panic!("not implemented on Android");
 }
 
+/// Directory for the Unix socket, only accessible by the user
+#[cfg(all(unix, not(target_os = "android")))]
+pub fn get_runtime_dir() -> Result<PathBuf, ()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    // Fall back to the cache dir where there is no XDG_RUNTIME_DIR, like on macOS
+    let mut dir = match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) => PathBuf::from(dir),
+        None => appdirs::user_cache_dir(Some("activitywatch"), None)?,
+    };
+    dir.push("aw-server-rust");
+    fs::create_dir_all(dir.clone()).expect("Unable to create runtime dir");
+    fs::set_permissions(&dir, fs::Permissions::from_mode(0o700))
+        .expect("Unable to restrict permissions of runtime dir");
+    Ok(dir)
+}
+
+#[cfg(all(unix, not(target_os = "android")))]
+pub fn socket_path(testing: bool) -> Result<PathBuf, ()> {
+    let mut socket_path = get_runtime_dir()?;
+    if testing {
+        socket_path.push("aw-server-testing.sock");
+    } else {
+        socket_path.push("aw-server.sock");
+    }
+    Ok(socket_path)
+}
+
+/// Directory with the databases of the profiles other than the default one
+pub fn profiles_dir(testing: bool) -> Result<PathBuf, ()> {
+    let mut profiles_dir = get_data_dir()?;
+    if testing {
+        profiles_dir.push("profiles-testing");
+    } else {
+        profiles_dir.push("profiles");
+    }
+    Ok(profiles_dir)
+}
+
 pub fn db_path(testing: bool) -> Result<PathBuf, ()> {
     let mut db_path = get_data_dir()?;
     if testing {
@@ -0,0 +1,195 @@
+//! Audit log of the requests which change data
+//!
+//! Routes which change data take the Audit request guard and tell it what the request affects.
+//! Once the response is ready, the AuditLogging fairing appends an entry for the request to the
+//! audit log in the datastore of the profile, whether the request succeeded or not.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use chrono::{DateTime, Duration, Utc};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::Status;
+use rocket::request::{self, FromRequest};
+use rocket::serde::json::Json;
+use rocket::tokio::task;
+use rocket::{Request, Response};
+
+use aw_datastore::Datastore;
+use aw_models::AuditEntry;
+
+use crate::config::AWConfig;
+use crate::endpoints::{HttpErrorJson, ProfileDatastore};
+
+struct PendingEntry {
+    datastore: Datastore,
+    recorded: bool,
+    affected: Vec<String>,
+    client: Option<String>,
+}
+
+impl PendingEntry {
+    fn affect(&mut self, id: String) {
+        if !self.affected.contains(&id) {
+            self.affected.push(id);
+        }
+    }
+}
+
+/// The audit log entry of a request, until the response is ready
+#[derive(Default)]
+struct AuditRecord(Mutex<Option<PendingEntry>>);
+
+/// Records the request in the audit log
+pub struct Audit<'r> {
+    record: &'r AuditRecord,
+}
+
+impl Audit<'_> {
+    fn with_entry<F: FnOnce(&mut PendingEntry)>(&self, f: F) {
+        if let Some(entry) = self.record.0.lock().unwrap().as_mut() {
+            entry.recorded = true;
+            f(entry);
+        }
+    }
+
+    /// Record the request as affecting the given ids, each id is only recorded once
+    pub fn affects<S: ToString>(&self, ids: impl IntoIterator<Item = S>) {
+        self.with_entry(|entry| {
+            for id in ids {
+                entry.affect(id.to_string());
+            }
+        });
+    }
+
+    /// Record the request as affecting a bucket, made by the client of the bucket
+    pub fn affects_bucket(&self, bucket_id: &str) {
+        self.with_entry(|entry| {
+            if let Ok(bucket) = entry.datastore.get_bucket(bucket_id) {
+                entry.client = Some(bucket.client);
+            }
+            entry.affect(bucket_id.to_string());
+        });
+    }
+
+    pub fn client(&self, client: &str) {
+        self.with_entry(|entry| entry.client = Some(client.to_string()));
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Audit<'r> {
+    type Error = String;
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let datastore = match request.guard::<ProfileDatastore>().await {
+            request::Outcome::Success(datastore) => datastore,
+            request::Outcome::Error(err) => return request::Outcome::Error(err),
+            request::Outcome::Forward(status) => return request::Outcome::Forward(status),
+        };
+        let record = request.local_cache(AuditRecord::default);
+        *record.0.lock().unwrap() = Some(PendingEntry {
+            datastore: datastore.into_inner(),
+            recorded: false,
+            affected: Vec::new(),
+            client: None,
+        });
+        request::Outcome::Success(Audit { record })
+    }
+}
+
+/// Entries older than the retention are deleted after the first entry and then every this many
+const PRUNE_INTERVAL: u64 = 100;
+
+#[derive(Default)]
+pub struct AuditLogging {
+    recorded: AtomicU64,
+}
+
+#[rocket::async_trait]
+impl Fairing for AuditLogging {
+    fn info(&self) -> Info {
+        Info {
+            name: "AuditLogging",
+            kind: Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let pending = match request
+            .local_cache(AuditRecord::default)
+            .0
+            .lock()
+            .unwrap()
+            .take()
+        {
+            Some(pending) if pending.recorded => pending,
+            _ => return,
+        };
+        let route = match request.route() {
+            Some(route) => route.uri.path().to_string(),
+            None => request.uri().path().to_string(),
+        };
+        let entry = AuditEntry {
+            id: None,
+            timestamp: Utc::now(),
+            method: request.method().as_str().to_string(),
+            route,
+            affected: pending.affected,
+            status: response.status().code,
+            user_agent: request.headers().get_one("User-Agent").map(String::from),
+            client: pending.client,
+        };
+        let retention_days = match request.rocket().state::<AWConfig>() {
+            Some(config) => config.audit_retention_days,
+            None => 0,
+        };
+        let prune = retention_days > 0
+            && self
+                .recorded
+                .fetch_add(1, Ordering::Relaxed)
+                .is_multiple_of(PRUNE_INTERVAL);
+
+        // The datastore calls block, so they are made off the async runtime
+        let datastore = pending.datastore;
+        let write = task::spawn_blocking(move || {
+            if let Err(err) = datastore.insert_audit_entry(&entry) {
+                error!("Failed to record request in the audit log: {:?}", err);
+            }
+            if prune {
+                let before = entry.timestamp - Duration::days(retention_days as i64);
+                if let Err(err) = datastore.delete_audit_entries_before(before) {
+                    error!("Failed to prune the audit log: {:?}", err);
+                }
+            }
+        });
+        if let Err(err) = write.await {
+            error!("Failed to record request in the audit log: {:?}", err);
+        }
+    }
+}
+
+/// Get the audit log, oldest entries first
+#[get("/?<since>&<limit>")]
+pub fn audit_log_get(
+    since: Option<String>,
+    limit: Option<u64>,
+    datastore: ProfileDatastore,
+) -> Result<Json<Vec<AuditEntry>>, HttpErrorJson> {
+    let since: Option<DateTime<Utc>> = match since {
+        Some(dt_str) => match DateTime::parse_from_rfc3339(&dt_str) {
+            Ok(dt) => Some(dt.with_timezone(&Utc)),
+            Err(e) => {
+                let err_msg =
+                    format!("Failed to parse since, datetime needs to be in rfc3339 format: {e}");
+                warn!("{}", err_msg);
+                return Err(HttpErrorJson::new(Status::BadRequest, err_msg));
+            }
+        },
+        None => None,
+    };
+    match datastore.get_audit_entries(since, limit) {
+        Ok(entries) => Ok(Json(entries)),
+        Err(err) => Err(err.into()),
+    }
+}
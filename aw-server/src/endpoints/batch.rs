@@ -0,0 +1,111 @@
+//! Several writes in a single request, so that watchers don't need a round trip for each of them
+
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::State;
+
+use aw_datastore::{BatchWrite, DatastoreError};
+use aw_models::{BatchOperation, BatchResult};
+
+use crate::endpoints::bucket::resolve_local_hostname;
+use crate::endpoints::settings::parse_key;
+use crate::endpoints::{Audit, HttpErrorJson, ProfileDatastore, ServerState};
+
+fn error_result(err: HttpErrorJson) -> BatchResult {
+    BatchResult {
+        status: err.status().code,
+        events: None,
+        error: Some(err.message().to_string()),
+    }
+}
+
+/// Do the operations in order and commit them to the datastore together
+///
+/// Each operation succeeds or fails on its own, there's a result for every operation. The buckets
+/// written to and the events replaced by id are recorded in the audit log.
+#[post("/", data = "<operations>", format = "application/json")]
+pub fn batch(
+    operations: Json<Vec<BatchOperation>>,
+    datastore: ProfileDatastore,
+    state: &State<ServerState>,
+    audit: Audit<'_>,
+) -> Result<Json<Vec<BatchResult>>, HttpErrorJson> {
+    // Whether the result of each operation includes events and the ids of the events it updates,
+    // or its result if it's invalid on its own and never makes it to the datastore
+    let mut results: Vec<Result<(bool, Vec<i64>), BatchResult>> = Vec::new();
+    let mut writes = Vec::new();
+    for operation in operations.into_inner() {
+        let write = match operation {
+            BatchOperation::CreateBucket { mut bucket } => {
+                resolve_local_hostname(&mut bucket, &state.device_id);
+                audit.affects([&bucket.id]);
+                audit.client(&bucket.client);
+                Ok(BatchWrite::CreateBucket(bucket))
+            }
+            BatchOperation::Heartbeat {
+                bucket_id,
+                pulsetime,
+                event,
+            } => {
+                audit.affects_bucket(&bucket_id);
+                Ok(BatchWrite::Heartbeat(bucket_id, event, pulsetime))
+            }
+            BatchOperation::InsertEvents { bucket_id, events } => {
+                audit.affects_bucket(&bucket_id);
+                Ok(BatchWrite::InsertEvents(bucket_id, events))
+            }
+            BatchOperation::SetSetting { key, value } => {
+                audit.affects([&key]);
+                parse_key(key)
+                    .map(|key| BatchWrite::SetKeyValue(key, serde_json::to_string(&value).unwrap()))
+            }
+        };
+        match write {
+            Ok(write) => {
+                let with_events = !matches!(
+                    write,
+                    BatchWrite::CreateBucket(_) | BatchWrite::SetKeyValue(..)
+                );
+                // Events with an id replace existing events, only those updates are recorded
+                let updated_ids = match &write {
+                    BatchWrite::InsertEvents(_, events) => {
+                        events.iter().filter_map(|e| e.id).collect()
+                    }
+                    _ => Vec::new(),
+                };
+                results.push(Ok((with_events, updated_ids)));
+                writes.push(write);
+            }
+            Err(err) => results.push(Err(error_result(err))),
+        }
+    }
+
+    let mut write_results = match datastore.batch(writes) {
+        Ok(write_results) => write_results.into_iter(),
+        Err(err) => return Err(err.into()),
+    };
+    let results = results
+        .into_iter()
+        .map(|result| match result {
+            Ok((with_events, updated_ids)) => match write_results.next().unwrap() {
+                Ok(events) => {
+                    audit.affects(updated_ids);
+                    BatchResult {
+                        status: Status::Ok.code,
+                        events: with_events.then_some(events),
+                        error: None,
+                    }
+                }
+                // Buckets are only created if they are missing
+                Err(DatastoreError::BucketAlreadyExists(_)) => BatchResult {
+                    status: Status::NotModified.code,
+                    events: None,
+                    error: None,
+                },
+                Err(err) => error_result(err.into()),
+            },
+            Err(result) => result,
+        })
+        .collect();
+    Ok(Json(results))
+}
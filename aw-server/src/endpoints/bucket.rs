@@ -7,21 +7,22 @@ use chrono::DateTime;
 use chrono::Utc;
 
 use aw_models::Bucket;
-use aw_models::BucketsExport;
 use aw_models::Event;
-use aw_models::TryVec;
 
 use rocket::http::Status;
 use rocket::State;
 
-use crate::endpoints::util::BucketsExportRocket;
-use crate::endpoints::{HttpErrorJson, ServerState};
+use aw_datastore::EventCursor;
+
+use crate::endpoints::export::{parse_filter, parse_format};
+use crate::endpoints::util::{EventsPageRocket, ExportRocket, ExportStreamRocket};
+use crate::endpoints::{Audit, HttpErrorJson, ProfileDatastore, ServerState};
+use crate::export::{export_json, ExportFormat, ExportStream};
 
 #[get("/")]
 pub fn buckets_get(
-    state: &State<ServerState>,
+    datastore: ProfileDatastore,
 ) -> Result<Json<HashMap<String, Bucket>>, HttpErrorJson> {
-    let datastore = endpoints_get_lock!(state.datastore);
     match datastore.get_buckets() {
         Ok(bucketlist) => Ok(Json(bucketlist)),
         Err(err) => Err(err.into()),
@@ -31,38 +32,45 @@ pub fn buckets_get(
 #[get("/<bucket_id>")]
 pub fn bucket_get(
     bucket_id: &str,
-    state: &State<ServerState>,
+    datastore: ProfileDatastore,
 ) -> Result<Json<Bucket>, HttpErrorJson> {
-    let datastore = endpoints_get_lock!(state.datastore);
     match datastore.get_bucket(&bucket_id) {
         Ok(bucket) => Ok(Json(bucket)),
         Err(e) => Err(e.into()),
     }
 }
 
-/// Create a new bucket
-///
 /// If hostname is "!local", the hostname and device_id will be set from the server info.
 /// This is useful for watchers which are known/assumed to run locally but might not know their hostname (like aw-watcher-web).
+pub fn resolve_local_hostname(bucket: &mut Bucket, device_id: &str) {
+    if bucket.hostname == "!local" {
+        bucket.hostname = gethostname()
+            .into_string()
+            .unwrap_or_else(|_| "unknown".to_string());
+        bucket
+            .data
+            .insert("device_id".to_string(), device_id.into());
+    }
+}
+
+/// Create a new bucket
+///
+/// See resolve_local_hostname for buckets with "!local" as hostname.
 #[post("/<bucket_id>", data = "<message>", format = "application/json")]
 pub fn bucket_new(
     bucket_id: &str,
     message: Json<Bucket>,
+    datastore: ProfileDatastore,
     state: &State<ServerState>,
+    audit: Audit<'_>,
 ) -> Result<(), HttpErrorJson> {
     let mut bucket = message.into_inner();
     if bucket.id != bucket_id {
         bucket.id = bucket_id.to_string();
     }
-    if bucket.hostname == "!local" {
-        bucket.hostname = gethostname()
-            .into_string()
-            .unwrap_or_else(|_| "unknown".to_string());
-        bucket
-            .data
-            .insert("device_id".to_string(), state.device_id.clone().into());
-    }
-    let datastore = endpoints_get_lock!(state.datastore);
+    resolve_local_hostname(&mut bucket, &state.device_id);
+    audit.affects([bucket_id]);
+    audit.client(&bucket.client);
     let ret = datastore.create_bucket(&bucket);
     match ret {
         Ok(_) => Ok(()),
@@ -70,14 +78,21 @@ pub fn bucket_new(
     }
 }
 
-#[get("/<bucket_id>/events?<start>&<end>&<limit>")]
+/// Get events in a bucket
+///
+/// Events are ordered by timestamp, newest first unless order is "asc". If the response was
+/// limited, the position of the last event is returned in the X-Next-Cursor header, which can be
+/// passed as cursor to get the next page.
+#[get("/<bucket_id>/events?<start>&<end>&<limit>&<cursor>&<order>")]
 pub fn bucket_events_get(
     bucket_id: &str,
     start: Option<String>,
     end: Option<String>,
     limit: Option<u64>,
-    state: &State<ServerState>,
-) -> Result<Json<Vec<Event>>, HttpErrorJson> {
+    cursor: Option<String>,
+    order: Option<String>,
+    datastore: ProfileDatastore,
+) -> Result<EventsPageRocket, HttpErrorJson> {
     let starttime: Option<DateTime<Utc>> = match start {
         Some(dt_str) => match DateTime::parse_from_rfc3339(&dt_str) {
             Ok(dt) => Some(dt.with_timezone(&Utc)),
@@ -103,10 +118,28 @@ pub fn bucket_events_get(
         },
         None => None,
     };
-    let datastore = endpoints_get_lock!(state.datastore);
-    let res = datastore.get_events(bucket_id, starttime, endtime, limit);
+    let cursor: Option<EventCursor> = match cursor {
+        Some(cursor_str) => match cursor_str.parse() {
+            Ok(cursor) => Some(cursor),
+            Err(err_msg) => {
+                warn!("{}", err_msg);
+                return Err(HttpErrorJson::new(Status::BadRequest, err_msg));
+            }
+        },
+        None => None,
+    };
+    let ascending = match order.as_deref() {
+        None | Some("desc") => false,
+        Some("asc") => true,
+        Some(order) => {
+            let err_msg = format!("Invalid order '{order}', needs to be either asc or desc");
+            warn!("{}", err_msg);
+            return Err(HttpErrorJson::new(Status::BadRequest, err_msg));
+        }
+    };
+    let res = datastore.get_events_page(bucket_id, starttime, endtime, limit, ascending, cursor);
     match res {
-        Ok(events) => Ok(Json(events)),
+        Ok((events, next_cursor)) => Ok(EventsPageRocket::new(events, next_cursor)),
         Err(err) => Err(err.into()),
     }
 }
@@ -118,9 +151,8 @@ pub fn bucket_events_get_single(
     bucket_id: &str,
     event_id: i64,
     _unused: Option<u64>,
-    state: &State<ServerState>,
+    datastore: ProfileDatastore,
 ) -> Result<Json<Event>, HttpErrorJson> {
-    let datastore = endpoints_get_lock!(state.datastore);
     let res = datastore.get_event(bucket_id, event_id);
     match res {
         Ok(events) => Ok(Json(events)),
@@ -132,9 +164,15 @@ pub fn bucket_events_get_single(
 pub fn bucket_events_create(
     bucket_id: &str,
     events: Json<Vec<Event>>,
-    state: &State<ServerState>,
+    datastore: ProfileDatastore,
+    audit: Audit<'_>,
 ) -> Result<Json<Vec<Event>>, HttpErrorJson> {
-    let datastore = endpoints_get_lock!(state.datastore);
+    // Events with an id replace existing events, only those updates are recorded
+    let updated_ids: Vec<i64> = events.iter().filter_map(|e| e.id).collect();
+    if !updated_ids.is_empty() {
+        audit.affects_bucket(bucket_id);
+        audit.affects(updated_ids);
+    }
     let res = datastore.insert_events(bucket_id, &events);
     match res {
         Ok(events) => Ok(Json(events)),
@@ -151,10 +189,9 @@ pub fn bucket_events_heartbeat(
     bucket_id: &str,
     heartbeat_json: Json<Event>,
     pulsetime: f64,
-    state: &State<ServerState>,
+    datastore: ProfileDatastore,
 ) -> Result<Json<Event>, HttpErrorJson> {
     let heartbeat = heartbeat_json.into_inner();
-    let datastore = endpoints_get_lock!(state.datastore);
     match datastore.heartbeat(bucket_id, heartbeat, pulsetime) {
         Ok(e) => Ok(Json(e)),
         Err(err) => Err(err.into()),
@@ -164,9 +201,8 @@ pub fn bucket_events_heartbeat(
 #[get("/<bucket_id>/events/count")]
 pub fn bucket_event_count(
     bucket_id: &str,
-    state: &State<ServerState>,
+    datastore: ProfileDatastore,
 ) -> Result<Json<u64>, HttpErrorJson> {
-    let datastore = endpoints_get_lock!(state.datastore);
     let res = datastore.get_event_count(bucket_id, None, None);
     match res {
         Ok(eventcount) => Ok(Json(eventcount as u64)),
@@ -178,41 +214,50 @@ pub fn bucket_event_count(
 pub fn bucket_events_delete_by_id(
     bucket_id: &str,
     event_id: i64,
-    state: &State<ServerState>,
+    datastore: ProfileDatastore,
+    audit: Audit<'_>,
 ) -> Result<(), HttpErrorJson> {
-    let datastore = endpoints_get_lock!(state.datastore);
+    audit.affects_bucket(bucket_id);
+    audit.affects([event_id]);
     match datastore.delete_events_by_id(bucket_id, vec![event_id]) {
         Ok(_) => Ok(()),
         Err(err) => Err(err.into()),
     }
 }
 
-#[get("/<bucket_id>/export")]
+#[get("/<bucket_id>/export?<format>&<start>&<end>")]
 pub fn bucket_export(
     bucket_id: &str,
-    state: &State<ServerState>,
-) -> Result<BucketsExportRocket, HttpErrorJson> {
-    let datastore = endpoints_get_lock!(state.datastore);
-    let mut export = BucketsExport {
-        buckets: HashMap::new(),
-    };
-    let mut bucket = match datastore.get_bucket(bucket_id) {
+    format: Option<&str>,
+    start: Option<&str>,
+    end: Option<&str>,
+    datastore: ProfileDatastore,
+) -> Result<ExportRocket, HttpErrorJson> {
+    let format = parse_format(format)?;
+    let filter = parse_filter(None, None, None, start, end)?;
+    let bucket = match datastore.get_bucket(bucket_id) {
         Ok(bucket) => bucket,
         Err(err) => return Err(err.into()),
     };
-    /* TODO: Replace expect with http error */
-    let events = datastore
-        .get_events(bucket_id, None, None, None)
-        .expect("Failed to get events for bucket");
-    bucket.events = Some(TryVec::new(events));
-    export.buckets.insert(bucket_id.into(), bucket);
-
-    Ok(export.into())
+    if format != ExportFormat::Json {
+        return match ExportStream::new(datastore.clone(), format, vec![bucket], &filter) {
+            Ok(stream) => Ok(ExportRocket::Stream(ExportStreamRocket::from(stream))),
+            Err(err) => Err(err.into()),
+        };
+    }
+    match export_json(&datastore, vec![bucket], &filter) {
+        Ok(export) => Ok(ExportRocket::Json(export.into())),
+        Err(err) => Err(err.into()),
+    }
 }
 
 #[delete("/<bucket_id>")]
-pub fn bucket_delete(bucket_id: &str, state: &State<ServerState>) -> Result<(), HttpErrorJson> {
-    let datastore = endpoints_get_lock!(state.datastore);
+pub fn bucket_delete(
+    bucket_id: &str,
+    datastore: ProfileDatastore,
+    audit: Audit<'_>,
+) -> Result<(), HttpErrorJson> {
+    audit.affects_bucket(bucket_id);
     match datastore.delete_bucket(bucket_id) {
         Ok(_) => Ok(()),
         Err(err) => Err(err.into()),
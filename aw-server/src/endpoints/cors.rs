@@ -1,5 +1,10 @@
+use std::sync::{Arc, RwLock};
+
+use rocket::fairing::{Fairing, Info};
+use rocket::http::uri::{fmt::Path, Segments};
 use rocket::http::Method;
-use rocket_cors::{AllowedHeaders, AllowedOrigins};
+use rocket::{Data, Request, Response, Rocket};
+use rocket_cors::{AllowedHeaders, AllowedOrigins, Cors};
 
 use crate::config::AWConfig;
 
@@ -41,3 +46,53 @@ pub fn cors(config: &AWConfig) -> rocket_cors::Cors {
     .to_cors()
     .expect("Failed to set up CORS")
 }
+
+/// CORS fairing whose allowed origins can be changed while the server is running
+///
+/// Clones share the same config, so reloading one reloads the attached fairing.
+#[derive(Clone)]
+pub struct ReloadableCors {
+    cors: Arc<RwLock<Arc<Cors>>>,
+}
+
+impl ReloadableCors {
+    pub fn new(config: &AWConfig) -> ReloadableCors {
+        ReloadableCors {
+            cors: Arc::new(RwLock::new(Arc::new(cors(config)))),
+        }
+    }
+
+    pub fn reload(&self, config: &AWConfig) {
+        *self.cors.write().unwrap() = Arc::new(cors(config));
+    }
+
+    fn current(&self) -> Arc<Cors> {
+        self.cors.read().unwrap().clone()
+    }
+}
+
+#[rocket::async_trait]
+impl Fairing for ReloadableCors {
+    fn info(&self) -> Info {
+        self.current().info()
+    }
+
+    async fn on_ignite(&self, rocket: Rocket<rocket::Build>) -> rocket::fairing::Result {
+        self.current().on_ignite(rocket).await
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, data: &mut Data<'_>) {
+        self.current().on_request(request, data).await
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        self.current().on_response(request, response).await
+    }
+}
+
+/// Answer preflight requests which have no route of their own
+///
+/// The CORS headers are added by the fairing, unlike rocket_cors::catch_all_options_routes this
+/// does not depend on a CORS config managed by Rocket, which could not be reloaded.
+#[options("/<_path..>", rank = 100)]
+pub fn catch_all_options(_path: Segments<'_, Path>) {}
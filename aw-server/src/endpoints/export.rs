@@ -1,31 +1,73 @@
-use std::collections::HashMap;
+use rocket::http::Status;
 
-use rocket::State;
+use crate::endpoints::util::{ExportRocket, ExportStreamRocket};
+use crate::endpoints::{HttpErrorJson, ProfileDatastore};
+use crate::export::{export_json, ExportFilter, ExportFormat, ExportStream};
 
-use aw_models::BucketsExport;
-use aw_models::TryVec;
+#[derive(FromForm)]
+pub struct ExportQuery<'r> {
+    format: Option<&'r str>,
+    /// Comma separated glob patterns of bucket ids
+    buckets: Option<&'r str>,
+    hostname: Option<&'r str>,
+    #[field(name = "type")]
+    bucket_type: Option<&'r str>,
+    start: Option<&'r str>,
+    end: Option<&'r str>,
+}
 
-use crate::endpoints::util::BucketsExportRocket;
-use crate::endpoints::{HttpErrorJson, ServerState};
+pub fn parse_format(format: Option<&str>) -> Result<ExportFormat, HttpErrorJson> {
+    match format {
+        Some(format) => match format.parse() {
+            Ok(format) => Ok(format),
+            Err(err) => Err(HttpErrorJson::new(Status::BadRequest, err)),
+        },
+        None => Ok(ExportFormat::Json),
+    }
+}
 
-#[get("/")]
-pub fn buckets_export(state: &State<ServerState>) -> Result<BucketsExportRocket, HttpErrorJson> {
-    let datastore = endpoints_get_lock!(state.datastore);
-    let mut export = BucketsExport {
-        buckets: HashMap::new(),
-    };
-    let mut buckets = match datastore.get_buckets() {
+pub fn parse_filter(
+    buckets: Option<&str>,
+    hostname: Option<&str>,
+    bucket_type: Option<&str>,
+    start: Option<&str>,
+    end: Option<&str>,
+) -> Result<ExportFilter, HttpErrorJson> {
+    match ExportFilter::parse(buckets, hostname, bucket_type, start, end) {
+        Ok(filter) => Ok(filter),
+        Err(err_msg) => {
+            warn!("{}", err_msg);
+            Err(HttpErrorJson::new(Status::BadRequest, err_msg))
+        }
+    }
+}
+
+/// Export all buckets matching the filter in the query, with the events in its time range
+#[get("/?<query..>")]
+pub fn buckets_export(
+    datastore: ProfileDatastore,
+    query: ExportQuery<'_>,
+) -> Result<ExportRocket, HttpErrorJson> {
+    let format = parse_format(query.format)?;
+    let filter = parse_filter(
+        query.buckets,
+        query.hostname,
+        query.bucket_type,
+        query.start,
+        query.end,
+    )?;
+    let buckets = match filter.select_buckets(&datastore) {
         Ok(buckets) => buckets,
         Err(err) => return Err(err.into()),
     };
-    for (bid, mut bucket) in buckets.drain() {
-        let events = match datastore.get_events(&bid, None, None, None) {
-            Ok(events) => events,
-            Err(err) => return Err(err.into()),
+    if format != ExportFormat::Json {
+        return match ExportStream::new(datastore.clone(), format, buckets, &filter) {
+            Ok(stream) => Ok(ExportRocket::Stream(ExportStreamRocket::from(stream))),
+            Err(err) => Err(err.into()),
         };
-        bucket.events = Some(TryVec::new(events));
-        export.buckets.insert(bid, bucket);
     }
-
-    Ok(export.into())
+    match export_json(&datastore, buckets, &filter) {
+        Ok(export) => Ok(ExportRocket::Json(export.into())),
+        Err(err) => Err(err.into()),
+    }
 }
@@ -121,10 +121,14 @@ mod tests {
 
     use crate::config::AWConfig;
     use crate::endpoints;
+    use crate::profiles::Profiles;
+    use crate::query_cache::QueryCache;
 
     fn setup_testserver(address: String) -> Rocket<rocket::Build> {
         let state = endpoints::ServerState {
             datastore: Mutex::new(aw_datastore::Datastore::new_in_memory(false)),
+            profiles: Profiles::in_memory(),
+            query_cache: QueryCache::new(),
             asset_resolver: endpoints::AssetResolver::new(None),
             device_id: "test_id".to_string(),
         };
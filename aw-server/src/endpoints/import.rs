@@ -1,53 +1,274 @@
-use rocket::form::Form;
+use rocket::data::{Data, Limits, ToByteUnit};
+use rocket::form::{self, DataField, Form, FromForm, ValueField};
 use rocket::http::Status;
 use rocket::serde::json::Json;
-use rocket::State;
-
-use std::sync::Mutex;
+use rocket::tokio::io::{AsyncBufReadExt, BufReader};
+use rocket::tokio::task;
 
 use aw_models::BucketsExport;
+use aw_models::ExportRecord;
+use aw_models::ImportConflict;
+use aw_models::ImportSummary;
 
-use aw_datastore::Datastore;
+use aw_datastore::{Datastore, DatastoreError};
 
-use crate::endpoints::{HttpErrorJson, ServerState};
+use crate::endpoints::{Audit, HttpErrorJson, ProfileDatastore};
 
-fn import(datastore_mutex: &Mutex<Datastore>, import: BucketsExport) -> Result<(), HttpErrorJson> {
-    let datastore = endpoints_get_lock!(datastore_mutex);
-    for (_bucketname, bucket) in import.buckets {
-        match datastore.create_bucket(&bucket) {
-            Ok(_) => (),
-            Err(e) => {
-                let err_msg = format!("Failed to import bucket: {e:?}");
-                warn!("{}", err_msg);
-                return Err(HttpErrorJson::new(Status::InternalServerError, err_msg));
+fn parse_conflict(on_conflict: Option<&str>) -> Result<ImportConflict, HttpErrorJson> {
+    match on_conflict {
+        Some(on_conflict) => match on_conflict.parse() {
+            Ok(on_conflict) => Ok(on_conflict),
+            Err(err) => Err(HttpErrorJson::new(Status::BadRequest, err)),
+        },
+        None => Ok(ImportConflict::default()),
+    }
+}
+
+fn import_error(e: DatastoreError) -> HttpErrorJson {
+    if let DatastoreError::ImportConflict(_) = e {
+        return e.into();
+    }
+    let err_msg = format!("Failed to import bucket: {e:?}");
+    warn!("{}", err_msg);
+    HttpErrorJson::new(Status::InternalServerError, err_msg)
+}
+
+/// Import the records, recording the imported buckets in the audit log unless it's a dry run
+fn import_records(
+    datastore: &Datastore,
+    audit: &Audit,
+    records: Vec<ExportRecord>,
+    on_conflict: ImportConflict,
+    dry_run: Option<bool>,
+) -> Result<Json<ImportSummary>, HttpErrorJson> {
+    let dry_run = dry_run.unwrap_or(false);
+    if !dry_run {
+        audit.affects(Vec::<String>::new());
+    }
+    imported(
+        audit,
+        dry_run,
+        datastore.import(records, on_conflict, dry_run),
+    )
+}
+
+/// Respond with the summary of an import, recording the imported buckets in the audit log
+fn imported(
+    audit: &Audit,
+    dry_run: bool,
+    res: Result<ImportSummary, DatastoreError>,
+) -> Result<Json<ImportSummary>, HttpErrorJson> {
+    match res {
+        Ok(summary) => {
+            if !dry_run {
+                audit.affects(summary.buckets.iter().filter_map(|b| b.imported_as.clone()));
             }
+            Ok(Json(summary))
         }
+        Err(e) => Err(import_error(e)),
     }
-    Ok(())
 }
 
-#[post("/", data = "<json_data>", format = "application/json")]
+fn import(
+    datastore: &Datastore,
+    audit: &Audit,
+    exports: Vec<BucketsExport>,
+    on_conflict: Option<&str>,
+    dry_run: Option<bool>,
+) -> Result<Json<ImportSummary>, HttpErrorJson> {
+    let on_conflict = parse_conflict(on_conflict)?;
+    let mut buckets: Vec<_> = exports
+        .into_iter()
+        .flat_map(|export| export.buckets.into_values())
+        .collect();
+    // Import in a predictable order so that the summary is stable
+    buckets.sort_by(|a, b| a.id.cmp(&b.id));
+    let records = buckets.into_iter().map(ExportRecord::Bucket).collect();
+    import_records(datastore, audit, records, on_conflict, dry_run)
+}
+
+#[post(
+    "/?<on_conflict>&<dry_run>",
+    data = "<json_data>",
+    format = "application/json"
+)]
 pub fn bucket_import_json(
-    state: &State<ServerState>,
+    datastore: ProfileDatastore,
+    on_conflict: Option<&str>,
+    dry_run: Option<bool>,
+    audit: Audit<'_>,
     json_data: Json<BucketsExport>,
-) -> Result<(), HttpErrorJson> {
-    import(&state.datastore, json_data.into_inner())
+) -> Result<Json<ImportSummary>, HttpErrorJson> {
+    import(
+        &datastore,
+        &audit,
+        vec![json_data.into_inner()],
+        on_conflict,
+        dry_run,
+    )
 }
 
-#[derive(FromForm)]
+/// All exports in a multipart form, regardless of the names of the fields
+///
+/// The web-ui names its field buckets.json, which Rocket would parse as a nested field, and
+/// aw-server python imports every field of the form as well.
 pub struct ImportForm {
-    // FIXME: In the web-ui the name of this field is buckets.json, but "." is not allowed in field
-    // names in Rocket and just simply "buckets" seems to work apparently but not sure why.
-    // FIXME: In aw-server python it will import all fields rather just the one named
-    // "buckets.json", that should probably be done here as well.
-    #[field(name = "buckets")]
-    import: Json<BucketsExport>,
+    exports: Vec<BucketsExport>,
+}
+
+pub struct ImportFormContext<'r> {
+    exports: Vec<BucketsExport>,
+    errors: form::Errors<'r>,
+}
+
+impl<'r> ImportFormContext<'r> {
+    fn push_export(&mut self, name: &str, content: &str) {
+        match serde_json::from_str(content) {
+            Ok(export) => self.exports.push(export),
+            Err(e) => self.errors.push(form::Error::validation(format!(
+                "Invalid export in field '{name}': {e}"
+            ))),
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromForm<'r> for ImportForm {
+    type Context = ImportFormContext<'r>;
+
+    fn init(_opts: form::Options) -> Self::Context {
+        ImportFormContext {
+            exports: Vec::new(),
+            errors: form::Errors::new(),
+        }
+    }
+
+    fn push_value(ctxt: &mut Self::Context, field: ValueField<'r>) {
+        ctxt.push_export(field.name.source(), field.value);
+    }
+
+    async fn push_data(ctxt: &mut Self::Context, field: DataField<'r, '_>) {
+        let limit = field
+            .request
+            .limits()
+            .get("json")
+            .unwrap_or_else(|| 1u64.mebibytes());
+        match field.data.open(limit).into_string().await {
+            Ok(content) if content.is_complete() => {
+                ctxt.push_export(field.name.source(), &content.into_inner())
+            }
+            Ok(_) => ctxt.errors.push(form::Error::validation(format!(
+                "Field '{}' is larger than the limit of {limit}",
+                field.name.source()
+            ))),
+            Err(e) => ctxt.errors.push(e.into()),
+        }
+    }
+
+    fn finalize(ctxt: Self::Context) -> form::Result<'r, Self> {
+        if !ctxt.errors.is_empty() {
+            return Err(ctxt.errors);
+        }
+        Ok(ImportForm {
+            exports: ctxt.exports,
+        })
+    }
 }
 
-#[post("/", data = "<form>", format = "multipart/form-data")]
+#[post(
+    "/?<on_conflict>&<dry_run>",
+    data = "<form>",
+    format = "multipart/form-data"
+)]
 pub fn bucket_import_form(
-    state: &State<ServerState>,
+    datastore: ProfileDatastore,
+    on_conflict: Option<&str>,
+    dry_run: Option<bool>,
+    audit: Audit<'_>,
     form: Form<ImportForm>,
-) -> Result<(), HttpErrorJson> {
-    import(&state.datastore, form.into_inner().import.into_inner())
+) -> Result<Json<ImportSummary>, HttpErrorJson> {
+    import(
+        &datastore,
+        &audit,
+        form.into_inner().exports,
+        on_conflict,
+        dry_run,
+    )
+}
+
+/// Import an NDJSON export, one ExportRecord per line
+///
+/// The whole export is read and parsed before it is passed on to the datastore, so a slow client
+/// does not hold up the datastore while it uploads. Exports are limited to the ndjson limit.
+#[post(
+    "/?<on_conflict>&<dry_run>",
+    data = "<data>",
+    format = "application/x-ndjson"
+)]
+pub async fn bucket_import_ndjson(
+    datastore: ProfileDatastore,
+    on_conflict: Option<&str>,
+    dry_run: Option<bool>,
+    limits: &Limits,
+    audit: Audit<'_>,
+    data: Data<'_>,
+) -> Result<Json<ImportSummary>, HttpErrorJson> {
+    let on_conflict = parse_conflict(on_conflict)?;
+    let dry_run = dry_run.unwrap_or(false);
+    let limit = limits.get("ndjson").unwrap_or_else(|| 1u64.mebibytes());
+    // A byte more than the limit is read to tell an import at the limit from a larger one
+    let mut reader = BufReader::new(data.open(limit + 1.bytes()));
+
+    if !dry_run {
+        audit.affects(Vec::<String>::new());
+    }
+    let mut records = Vec::new();
+    let mut line = String::new();
+    let mut size = 0;
+    let mut seen_bucket = false;
+    let mut line_nr = 0;
+    loop {
+        line.clear();
+        match reader.read_line(&mut line).await {
+            Ok(0) => break,
+            Ok(n) => size += n as u64,
+            Err(e) => {
+                let err_msg = format!("Failed to read import: {e}");
+                return Err(HttpErrorJson::new(Status::BadRequest, err_msg));
+            }
+        }
+        if size > limit.as_u64() {
+            let err_msg = format!("Import is larger than the limit of {limit}");
+            return Err(HttpErrorJson::new(Status::PayloadTooLarge, err_msg));
+        }
+        line_nr += 1;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record: ExportRecord = match serde_json::from_str(&line) {
+            Ok(record) => record,
+            Err(e) => {
+                let err_msg = format!("Invalid record on line {line_nr}: {e}");
+                return Err(HttpErrorJson::new(Status::BadRequest, err_msg));
+            }
+        };
+        match record {
+            ExportRecord::Bucket(_) => seen_bucket = true,
+            ExportRecord::Event(_) if !seen_bucket => {
+                let err_msg = format!("Event on line {line_nr} does not follow a bucket");
+                return Err(HttpErrorJson::new(Status::BadRequest, err_msg));
+            }
+            ExportRecord::Event(_) => (),
+        }
+        records.push(record);
+    }
+    let datastore = datastore.into_inner();
+    let task = task::spawn_blocking(move || datastore.import(records, on_conflict, dry_run));
+    match task.await {
+        Ok(res) => imported(&audit, dry_run, res),
+        Err(e) => Err(HttpErrorJson::new(
+            Status::InternalServerError,
+            format!("Import panicked: {e}"),
+        )),
+    }
 }
@@ -1,18 +1,22 @@
 use rust_embed::RustEmbed;
+use std::collections::HashMap;
 use std::ffi::OsStr;
 use std::path::{Path, PathBuf};
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex, RwLock};
 
 use gethostname::gethostname;
-use rocket::fs::FileServer;
-use rocket::http::ContentType;
+use rocket::fs::NamedFile;
+use rocket::http::{ContentType, Status};
 use rocket::serde::json::Json;
-use rocket::State;
+use rocket::{Phase, Rocket, State};
 
 use crate::config::AWConfig;
+use crate::health;
+use crate::profiles::Profiles;
+use crate::query_cache::QueryCache;
 
 use aw_datastore::Datastore;
-use aw_models::Info;
+use aw_models::{Health, Info};
 
 #[derive(RustEmbed)]
 #[folder = "$AW_WEBUI_DIR"]
@@ -39,21 +43,32 @@ impl AssetResolver {
 }
 
 pub struct ServerState {
+    // The datastore of the default profile
     pub datastore: Mutex<Datastore>,
+    pub profiles: Profiles,
+    pub query_cache: QueryCache,
     pub asset_resolver: AssetResolver,
     pub device_id: String,
 }
 
 #[macro_use]
 mod util;
+mod audit;
+mod batch;
 mod bucket;
 mod cors;
 mod export;
 mod hostcheck;
 mod import;
+mod openapi;
+mod profile;
 mod query;
+mod query_module;
+mod saved_query;
 mod settings;
 
+pub use audit::Audit;
+pub use profile::ProfileDatastore;
 pub use util::HttpErrorJson;
 
 #[get("/")]
@@ -115,6 +130,57 @@ fn server_info(config: &State<AWConfig>, state: &State<ServerState>) -> Json<Inf
     })
 }
 
+/// Check that the datastore worker responds, 503 if it does not
+#[get("/")]
+async fn server_health(datastore: ProfileDatastore) -> Result<Json<Health>, HttpErrorJson> {
+    match health::check_health(datastore.into_inner()).await {
+        Ok(health) => Ok(Json(health)),
+        Err(err) => {
+            warn!("Health check failed: {}", err);
+            Err(HttpErrorJson::new(Status::ServiceUnavailable, err))
+        }
+    }
+}
+
+/// Custom static directories of watchers, served under /pages/<name>
+#[derive(Clone)]
+pub struct CustomStatic {
+    dirs: Arc<RwLock<HashMap<String, String>>>,
+}
+
+impl CustomStatic {
+    fn new(dirs: HashMap<String, String>) -> CustomStatic {
+        for (name, dir) in dirs.iter() {
+            info!(
+                "Serving /pages/{} custom static directory from {}",
+                name, dir
+            );
+        }
+        CustomStatic {
+            dirs: Arc::new(RwLock::new(dirs)),
+        }
+    }
+
+    fn reload(&self, dirs: HashMap<String, String>) {
+        let reloaded = CustomStatic::new(dirs);
+        *self.dirs.write().unwrap() = reloaded.dirs.read().unwrap().clone();
+    }
+}
+
+#[get("/pages/<name>/<path..>")]
+async fn custom_static_file(
+    name: &str,
+    path: PathBuf,
+    custom_static: &State<CustomStatic>,
+) -> Option<NamedFile> {
+    let dir = custom_static.dirs.read().unwrap().get(name)?.clone();
+    let mut file = Path::new(&dir).join(path);
+    if file.is_dir() {
+        file.push("index.html");
+    }
+    NamedFile::open(file).await.ok()
+}
+
 fn get_file(file: PathBuf, state: &State<ServerState>) -> Option<(ContentType, Vec<u8>)> {
     let asset = state.asset_resolver.resolve(&file.display().to_string())?;
 
@@ -132,14 +198,18 @@ pub fn build_rocket(server_state: ServerState, config: AWConfig) -> rocket::Rock
         "Starting aw-server-rust at {}:{}",
         config.address, config.port
     );
-    let cors = cors::cors(&config);
+    let cors = cors::ReloadableCors::new(&config);
     let hostcheck = hostcheck::HostCheck::new(&config);
-    let custom_static = config.custom_static.clone();
+    let custom_static = CustomStatic::new(config.custom_static.clone());
 
-    let mut rocket = rocket::custom(config.to_rocket_config())
+    rocket::custom(config.to_rocket_config())
         .attach(cors.clone())
         .attach(hostcheck)
+        .attach(profile::ProfileRouting)
+        .attach(audit::AuditLogging::default())
         .manage(cors)
+        .manage(custom_static)
+        .manage(server_state.query_cache.clone())
         .manage(server_state)
         .manage(config)
         .mount(
@@ -154,10 +224,13 @@ pub fn build_rocket(server_state: ServerState, config: AWConfig) -> rocket::Rock
                 // custom static files
                 root_dark,
                 root_logo,
-                root_manifest
+                root_manifest,
+                custom_static_file
             ],
         )
         .mount("/api/0/info", routes![server_info])
+        .mount("/api/0/health", routes![server_health])
+        .mount("/api/0/openapi.json", routes![openapi::openapi])
         .mount(
             "/api/0/buckets",
             routes![
@@ -174,12 +247,45 @@ pub fn build_rocket(server_state: ServerState, config: AWConfig) -> rocket::Rock
                 bucket::bucket_export
             ],
         )
-        .mount("/api/0/query", routes![query::query])
+        .mount(
+            "/api/0/query",
+            routes![
+                query::query,
+                query::query_validate,
+                query::query_cache_stats,
+                query::query_cache_clear
+            ],
+        )
+        .mount(
+            "/api/0/query/modules",
+            routes![
+                query_module::query_modules_get,
+                query_module::query_module_get,
+                query_module::query_module_set,
+                query_module::query_module_delete
+            ],
+        )
+        .mount(
+            "/api/0/queries",
+            routes![
+                saved_query::saved_queries_get,
+                saved_query::saved_query_get,
+                saved_query::saved_query_set,
+                saved_query::saved_query_delete,
+                saved_query::saved_query_run
+            ],
+        )
         .mount(
             "/api/0/import",
-            routes![import::bucket_import_json, import::bucket_import_form],
+            routes![
+                import::bucket_import_json,
+                import::bucket_import_form,
+                import::bucket_import_ndjson
+            ],
         )
         .mount("/api/0/export", routes![export::buckets_export])
+        .mount("/api/0/audit", routes![audit::audit_log_get])
+        .mount("/api/0/batch", routes![batch::batch])
         .mount(
             "/api/0/settings",
             routes![
@@ -189,17 +295,47 @@ pub fn build_rocket(server_state: ServerState, config: AWConfig) -> rocket::Rock
                 settings::settings_get,
             ],
         )
-        .mount("/", rocket_cors::catch_all_options_routes());
-
-    // for each custom static directory, mount it at the given name
-    for (name, dir) in custom_static {
-        info!(
-            "Serving /pages/{} custom static directory from {}",
-            name, dir
-        );
-        rocket = rocket.mount(&format!("/pages/{name}"), FileServer::from(dir));
+        .mount(
+            "/api/0/profiles",
+            routes![
+                profile::profiles_get,
+                profile::profile_new,
+                profile::profile_delete
+            ],
+        )
+        .mount(profile::NOT_FOUND_BASE, routes![profile::profile_not_found])
+        .mount("/", routes![cors::catch_all_options])
+}
+
+/// Applies the parts of the config which can be changed without restarting the server, which are
+/// the allowed CORS origins and the custom static directories
+///
+/// Holds on to the reloadable state of a server built by build_rocket, so that it can still be
+/// reloaded after the server has been launched.
+#[derive(Clone)]
+pub struct ConfigReloader {
+    cors: cors::ReloadableCors,
+    custom_static: CustomStatic,
+}
+
+impl ConfigReloader {
+    pub fn new<P: Phase>(rocket: &Rocket<P>) -> ConfigReloader {
+        ConfigReloader {
+            cors: rocket
+                .state::<cors::ReloadableCors>()
+                .expect("CORS is not managed by the server")
+                .clone(),
+            custom_static: rocket
+                .state::<CustomStatic>()
+                .expect("Custom static directories are not managed by the server")
+                .clone(),
+        }
+    }
+
+    pub fn reload(&self, config: &AWConfig) {
+        self.cors.reload(config);
+        self.custom_static.reload(config.custom_static.clone());
     }
-    rocket
 }
 
 mod tests {
@@ -0,0 +1,534 @@
+use std::collections::HashMap;
+
+use rocket::serde::json::{json, Json, Value};
+use schemars::gen::{SchemaGenerator, SchemaSettings};
+use schemars::JsonSchema;
+
+use aw_models::{
+    AuditEntry, BatchOperation, BatchResult, Bucket, BucketsExport, Event, ExportRecord, Health,
+    ImportSummary, Info, Query, QueryModule, QueryValidate, SavedQuery, SavedQueryRun,
+};
+
+use crate::endpoints::query::QueryValidation;
+use crate::endpoints::HttpErrorJson;
+use crate::query_cache::QueryCacheStats;
+
+/// A single operation (method + path) of the REST API
+///
+/// Paths use the OpenAPI `{param}` syntax rather than the `<param>` syntax used by Rocket.
+struct Operation {
+    method: &'static str,
+    path: &'static str,
+    summary: &'static str,
+    parameters: Vec<Value>,
+    request_body: Option<Value>,
+    response: Option<Value>,
+}
+
+fn path_param(name: &str, description: &str) -> Value {
+    json!({
+        "name": name,
+        "in": "path",
+        "required": true,
+        "description": description,
+        "schema": { "type": "string" },
+    })
+}
+
+fn query_param(name: &str, _type: &str, required: bool, description: &str) -> Value {
+    json!({
+        "name": name,
+        "in": "query",
+        "required": required,
+        "description": description,
+        "schema": { "type": _type },
+    })
+}
+
+fn schema_of<T: JsonSchema>(gen: &mut SchemaGenerator) -> Value {
+    serde_json::to_value(gen.subschema_for::<T>()).unwrap()
+}
+
+fn json_content(schema: Value) -> Value {
+    json!({ "application/json": { "schema": schema } })
+}
+
+/// Content of an export, depending on the requested format
+fn export_content(gen: &mut SchemaGenerator) -> Value {
+    json!({
+        "application/json": { "schema": schema_of::<BucketsExport>(gen) },
+        "application/x-ndjson": { "schema": schema_of::<ExportRecord>(gen) },
+        "text/csv": { "schema": { "type": "string" } },
+    })
+}
+
+fn operations(gen: &mut SchemaGenerator) -> Vec<Operation> {
+    let bucket_id = || path_param("bucket_id", "ID of the bucket");
+    let export_format = || {
+        query_param(
+            "format",
+            "string",
+            false,
+            "Either json (default), ndjson or csv. ndjson and csv are streamed",
+        )
+    };
+    let event_id = || path_param("event_id", "ID of the event");
+    let setting_key = || path_param("key", "Name of the setting");
+    let saved_query_name = || path_param("name", "Name of the saved query");
+    let module_name = || path_param("name", "Name of the query module");
+    let profile = || path_param("profile", "Name of the profile");
+
+    vec![
+        Operation {
+            method: "get",
+            path: "/api/0/info",
+            summary: "Get information about the server",
+            parameters: vec![],
+            request_body: None,
+            response: Some(json_content(schema_of::<Info>(gen))),
+        },
+        Operation {
+            method: "get",
+            path: "/api/0/health",
+            summary: "Check that the datastore responds and get diagnostics about it",
+            parameters: vec![],
+            request_body: None,
+            response: Some(json_content(schema_of::<Health>(gen))),
+        },
+        Operation {
+            method: "get",
+            path: "/api/0/openapi.json",
+            summary: "Get the OpenAPI specification of this API",
+            parameters: vec![],
+            request_body: None,
+            response: Some(json_content(json!({ "type": "object" }))),
+        },
+        Operation {
+            method: "get",
+            path: "/api/0/buckets",
+            summary: "List all buckets",
+            parameters: vec![],
+            request_body: None,
+            response: Some(json_content(schema_of::<HashMap<String, Bucket>>(gen))),
+        },
+        Operation {
+            method: "get",
+            path: "/api/0/buckets/{bucket_id}",
+            summary: "Get a bucket",
+            parameters: vec![bucket_id()],
+            request_body: None,
+            response: Some(json_content(schema_of::<Bucket>(gen))),
+        },
+        Operation {
+            method: "post",
+            path: "/api/0/buckets/{bucket_id}",
+            summary: "Create a bucket",
+            parameters: vec![bucket_id()],
+            request_body: Some(json_content(schema_of::<Bucket>(gen))),
+            response: None,
+        },
+        Operation {
+            method: "delete",
+            path: "/api/0/buckets/{bucket_id}",
+            summary: "Delete a bucket and all of its events",
+            parameters: vec![bucket_id()],
+            request_body: None,
+            response: None,
+        },
+        Operation {
+            method: "get",
+            path: "/api/0/buckets/{bucket_id}/events",
+            summary: "Get a page of events in a bucket, newest first by default",
+            parameters: vec![
+                bucket_id(),
+                query_param("start", "string", false, "rfc3339 start of time range"),
+                query_param("end", "string", false, "rfc3339 end of time range"),
+                query_param("limit", "integer", false, "Max number of events"),
+                query_param(
+                    "cursor",
+                    "string",
+                    false,
+                    "Continue after the X-Next-Cursor of a previous page",
+                ),
+                query_param("order", "string", false, "Either desc (default) or asc"),
+            ],
+            request_body: None,
+            response: Some(json_content(schema_of::<Vec<Event>>(gen))),
+        },
+        Operation {
+            method: "post",
+            path: "/api/0/buckets/{bucket_id}/events",
+            summary: "Insert events into a bucket",
+            parameters: vec![bucket_id()],
+            request_body: Some(json_content(schema_of::<Vec<Event>>(gen))),
+            response: Some(json_content(schema_of::<Vec<Event>>(gen))),
+        },
+        Operation {
+            method: "get",
+            path: "/api/0/buckets/{bucket_id}/events/count",
+            summary: "Get the number of events in a bucket",
+            parameters: vec![bucket_id()],
+            request_body: None,
+            response: Some(json_content(schema_of::<u64>(gen))),
+        },
+        Operation {
+            method: "get",
+            path: "/api/0/buckets/{bucket_id}/events/{event_id}",
+            summary: "Get a single event",
+            parameters: vec![bucket_id(), event_id()],
+            request_body: None,
+            response: Some(json_content(schema_of::<Event>(gen))),
+        },
+        Operation {
+            method: "delete",
+            path: "/api/0/buckets/{bucket_id}/events/{event_id}",
+            summary: "Delete a single event",
+            parameters: vec![bucket_id(), event_id()],
+            request_body: None,
+            response: None,
+        },
+        Operation {
+            method: "post",
+            path: "/api/0/buckets/{bucket_id}/heartbeat",
+            summary: "Send a heartbeat, merging it with the last event if possible",
+            parameters: vec![
+                bucket_id(),
+                query_param(
+                    "pulsetime",
+                    "number",
+                    true,
+                    "Max seconds between heartbeats for them to be merged",
+                ),
+            ],
+            request_body: Some(json_content(schema_of::<Event>(gen))),
+            response: Some(json_content(schema_of::<Event>(gen))),
+        },
+        Operation {
+            method: "get",
+            path: "/api/0/buckets/{bucket_id}/export",
+            summary: "Export a bucket including its events",
+            parameters: vec![
+                bucket_id(),
+                export_format(),
+                query_param("start", "string", false, "rfc3339 start of time range"),
+                query_param("end", "string", false, "rfc3339 end of time range"),
+            ],
+            request_body: None,
+            response: Some(export_content(gen)),
+        },
+        Operation {
+            method: "post",
+            path: "/api/0/query",
+            summary: "Run a query2 program over one or more time periods",
+            parameters: vec![
+                query_param(
+                    "cache",
+                    "boolean",
+                    false,
+                    "Set to false to not use cached results for time periods which have ended",
+                ),
+                query_param(
+                    "explain",
+                    "boolean",
+                    false,
+                    "Return each result as {result, explain} with statement and builtin timings",
+                ),
+            ],
+            request_body: Some(json_content(schema_of::<Query>(gen))),
+            response: Some(json_content(json!({ "type": "array", "items": {} }))),
+        },
+        Operation {
+            method: "post",
+            path: "/api/0/query/validate",
+            summary: "Check a query2 program for errors without running it",
+            parameters: vec![],
+            request_body: Some(json_content(schema_of::<QueryValidate>(gen))),
+            response: Some(json_content(schema_of::<QueryValidation>(gen))),
+        },
+        Operation {
+            method: "get",
+            path: "/api/0/query/cache",
+            summary: "Get statistics of the query result cache",
+            parameters: vec![],
+            request_body: None,
+            response: Some(json_content(schema_of::<QueryCacheStats>(gen))),
+        },
+        Operation {
+            method: "delete",
+            path: "/api/0/query/cache",
+            summary: "Clear the query result cache",
+            parameters: vec![],
+            request_body: None,
+            response: None,
+        },
+        Operation {
+            method: "post",
+            path: "/api/0/import",
+            summary: "Import buckets from an export, all at once or not at all",
+            parameters: vec![
+                query_param(
+                    "on_conflict",
+                    "string",
+                    false,
+                    "What to do with existing buckets: fail (default), skip, merge or rename",
+                ),
+                query_param(
+                    "dry_run",
+                    "boolean",
+                    false,
+                    "Only report what would be imported",
+                ),
+            ],
+            request_body: Some({
+                let export = schema_of::<BucketsExport>(gen);
+                json!({
+                    "application/json": { "schema": export },
+                    "multipart/form-data": {
+                        "schema": {
+                            "type": "object",
+                            "properties": { "buckets.json": export },
+                        },
+                    },
+                    "application/x-ndjson": { "schema": schema_of::<ExportRecord>(gen) },
+                })
+            }),
+            response: Some(json_content(schema_of::<ImportSummary>(gen))),
+        },
+        Operation {
+            method: "get",
+            path: "/api/0/export",
+            summary: "Export all buckets including their events",
+            parameters: vec![
+                export_format(),
+                query_param(
+                    "buckets",
+                    "string",
+                    false,
+                    "Comma separated glob patterns of the bucket IDs to export",
+                ),
+                query_param(
+                    "hostname",
+                    "string",
+                    false,
+                    "Only export buckets from this host",
+                ),
+                query_param("type", "string", false, "Only export buckets of this type"),
+                query_param("start", "string", false, "rfc3339 start of time range"),
+                query_param("end", "string", false, "rfc3339 end of time range"),
+            ],
+            request_body: None,
+            response: Some(export_content(gen)),
+        },
+        Operation {
+            method: "get",
+            path: "/api/0/query/modules",
+            summary: "Get all query modules",
+            parameters: vec![],
+            request_body: None,
+            response: Some(json_content(schema_of::<HashMap<String, QueryModule>>(gen))),
+        },
+        Operation {
+            method: "get",
+            path: "/api/0/query/modules/{name}",
+            summary: "Get a query module",
+            parameters: vec![module_name()],
+            request_body: None,
+            response: Some(json_content(schema_of::<QueryModule>(gen))),
+        },
+        Operation {
+            method: "post",
+            path: "/api/0/query/modules/{name}",
+            summary: "Create or replace a module which queries can use with import \"name\";",
+            parameters: vec![module_name()],
+            request_body: Some(json_content(schema_of::<QueryModule>(gen))),
+            response: None,
+        },
+        Operation {
+            method: "delete",
+            path: "/api/0/query/modules/{name}",
+            summary: "Delete a query module",
+            parameters: vec![module_name()],
+            request_body: None,
+            response: None,
+        },
+        Operation {
+            method: "get",
+            path: "/api/0/queries",
+            summary: "Get all saved queries",
+            parameters: vec![],
+            request_body: None,
+            response: Some(json_content(schema_of::<HashMap<String, SavedQuery>>(gen))),
+        },
+        Operation {
+            method: "get",
+            path: "/api/0/queries/{name}",
+            summary: "Get a saved query",
+            parameters: vec![saved_query_name()],
+            request_body: None,
+            response: Some(json_content(schema_of::<SavedQuery>(gen))),
+        },
+        Operation {
+            method: "post",
+            path: "/api/0/queries/{name}",
+            summary: "Create or replace a saved query",
+            parameters: vec![saved_query_name()],
+            request_body: Some(json_content(schema_of::<SavedQuery>(gen))),
+            response: None,
+        },
+        Operation {
+            method: "delete",
+            path: "/api/0/queries/{name}",
+            summary: "Delete a saved query",
+            parameters: vec![saved_query_name()],
+            request_body: None,
+            response: None,
+        },
+        Operation {
+            method: "post",
+            path: "/api/0/queries/{name}/run",
+            summary: "Run a saved query over one or more time periods with the given parameters",
+            parameters: vec![
+                saved_query_name(),
+                query_param(
+                    "cache",
+                    "boolean",
+                    false,
+                    "Set to false to not use cached results for time periods which have ended",
+                ),
+            ],
+            request_body: Some(json_content(schema_of::<SavedQueryRun>(gen))),
+            response: Some(json_content(json!({ "type": "array", "items": {} }))),
+        },
+        Operation {
+            method: "get",
+            path: "/api/0/profiles",
+            summary: "Get the names of all profiles. Every route under /api/0 is also available \
+                      for a profile under /api/0/profiles/{profile}",
+            parameters: vec![],
+            request_body: None,
+            response: Some(json_content(schema_of::<Vec<String>>(gen))),
+        },
+        Operation {
+            method: "post",
+            path: "/api/0/profiles/{profile}",
+            summary: "Create a profile with a datastore of its own",
+            parameters: vec![profile()],
+            request_body: None,
+            response: None,
+        },
+        Operation {
+            method: "delete",
+            path: "/api/0/profiles/{profile}",
+            summary: "Delete a profile together with all of its data",
+            parameters: vec![profile()],
+            request_body: None,
+            response: None,
+        },
+        Operation {
+            method: "post",
+            path: "/api/0/batch",
+            summary: "Do several operations in order and commit them together",
+            parameters: vec![],
+            request_body: Some(json_content(schema_of::<Vec<BatchOperation>>(gen))),
+            response: Some(json_content(schema_of::<Vec<BatchResult>>(gen))),
+        },
+        Operation {
+            method: "get",
+            path: "/api/0/audit",
+            summary: "Get the log of requests which changed data, oldest first",
+            parameters: vec![
+                query_param(
+                    "since",
+                    "string",
+                    false,
+                    "Only entries recorded at or after this time, in rfc3339 format",
+                ),
+                query_param("limit", "integer", false, "Maximum number of entries"),
+            ],
+            request_body: None,
+            response: Some(json_content(schema_of::<Vec<AuditEntry>>(gen))),
+        },
+        Operation {
+            method: "get",
+            path: "/api/0/settings",
+            summary: "Get all settings",
+            parameters: vec![],
+            request_body: None,
+            response: Some(json_content(schema_of::<HashMap<String, Value>>(gen))),
+        },
+        Operation {
+            method: "get",
+            path: "/api/0/settings/{key}",
+            summary: "Get a setting, null if unset",
+            parameters: vec![setting_key()],
+            request_body: None,
+            response: Some(json_content(schema_of::<Value>(gen))),
+        },
+        Operation {
+            method: "post",
+            path: "/api/0/settings/{key}",
+            summary: "Set a setting",
+            parameters: vec![setting_key()],
+            request_body: Some(json_content(schema_of::<Value>(gen))),
+            response: None,
+        },
+        Operation {
+            method: "delete",
+            path: "/api/0/settings/{key}",
+            summary: "Delete a setting",
+            parameters: vec![setting_key()],
+            request_body: None,
+            response: None,
+        },
+    ]
+}
+
+/// Generate an OpenAPI 3 document describing every route under /api
+pub fn openapi_spec() -> Value {
+    let mut gen = SchemaSettings::openapi3().into_generator();
+    let error_schema = schema_of::<HttpErrorJson>(&mut gen);
+
+    let mut paths = serde_json::Map::new();
+    for op in operations(&mut gen) {
+        let mut responses = serde_json::Map::new();
+        let ok = match op.response {
+            Some(content) => json!({ "description": "Success", "content": content }),
+            None => json!({ "description": "Success" }),
+        };
+        responses.insert("200".to_string(), ok);
+        responses.insert(
+            "default".to_string(),
+            json!({ "description": "Error", "content": json_content(error_schema.clone()) }),
+        );
+
+        let mut operation = json!({
+            "summary": op.summary,
+            "parameters": op.parameters,
+            "responses": responses,
+        });
+        if let Some(content) = op.request_body {
+            operation["requestBody"] = json!({ "required": true, "content": content });
+        }
+
+        let path_item = paths
+            .entry(op.path.to_string())
+            .or_insert_with(|| json!({}));
+        path_item[op.method] = operation;
+    }
+
+    const VERSION: Option<&'static str> = option_env!("CARGO_PKG_VERSION");
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "aw-server-rust",
+            "version": VERSION.unwrap_or("(unknown)"),
+        },
+        "paths": paths,
+        "components": { "schemas": gen.take_definitions() },
+    })
+}
+
+#[get("/")]
+pub fn openapi() -> Json<Value> {
+    Json(openapi_spec())
+}
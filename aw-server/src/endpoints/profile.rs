@@ -0,0 +1,192 @@
+//! Routes requests under /api/0/profiles/<profile>/ to the datastore of the profile
+//!
+//! Uses a Request Fairing which strips the profile from the URI, so that every API route is
+//! available for every profile. The routes get the datastore of the profile with the
+//! ProfileDatastore request guard, which is the default profile for requests without a profile.
+
+use std::ops::Deref;
+
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::uri::Origin;
+use rocket::http::{Method, Status};
+use rocket::request::{self, FromRequest};
+use rocket::serde::json::Json;
+use rocket::{Data, Request, State};
+
+use aw_datastore::Datastore;
+
+use crate::endpoints::{Audit, HttpErrorJson, ServerState};
+use crate::profiles::{ProfileError, DEFAULT_PROFILE};
+
+static PROFILES_BASE: &str = "/api/0/profiles/";
+pub static NOT_FOUND_BASE: &str = "/profile_fairing";
+
+/// The profile a request was made for, set by the ProfileRouting fairing
+struct SelectedProfile(Option<String>);
+
+pub struct ProfileRouting;
+
+fn profile_exists(request: &Request, name: &str) -> bool {
+    match request.rocket().state::<ServerState>() {
+        Some(state) => name == DEFAULT_PROFILE || state.profiles.get(name).is_some(),
+        None => false,
+    }
+}
+
+#[rocket::async_trait]
+impl Fairing for ProfileRouting {
+    fn info(&self) -> Info {
+        Info {
+            name: "ProfileRouting",
+            kind: Kind::Request,
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        let rest = match request.uri().path().as_str().strip_prefix(PROFILES_BASE) {
+            Some(rest) => rest.to_string(),
+            None => return,
+        };
+        let (name, path) = match rest.split_once('/') {
+            Some((name, path)) if !path.is_empty() => (name.to_string(), path.to_string()),
+            // Requests for the profile itself are handled by the profile routes
+            _ => return,
+        };
+
+        let uri = if profile_exists(request, &name) {
+            match request.uri().query() {
+                Some(query) => format!("/api/0/{path}?{query}"),
+                None => format!("/api/0/{path}"),
+            }
+        } else {
+            request.set_method(Method::Get);
+            format!("{NOT_FOUND_BASE}/{name}")
+        };
+        match Origin::parse_owned(uri) {
+            Ok(origin) => request.set_uri(origin),
+            Err(err) => {
+                warn!("Failed to route request to profile {}: {}", name, err);
+                return;
+            }
+        }
+        request.local_cache(|| SelectedProfile(Some(name)));
+    }
+}
+
+/// The datastore of the profile a request was made for
+pub struct ProfileDatastore {
+    name: String,
+    datastore: Datastore,
+}
+
+impl ProfileDatastore {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn into_inner(self) -> Datastore {
+        self.datastore
+    }
+}
+
+impl Deref for ProfileDatastore {
+    type Target = Datastore;
+
+    fn deref(&self) -> &Datastore {
+        &self.datastore
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ProfileDatastore {
+    type Error = String;
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let state = match request.rocket().state::<ServerState>() {
+            Some(state) => state,
+            None => {
+                let err_msg = "There is no server state".to_string();
+                return request::Outcome::Error((Status::InternalServerError, err_msg));
+            }
+        };
+        let name = match &request.local_cache(|| SelectedProfile(None)).0 {
+            Some(name) => name.clone(),
+            None => DEFAULT_PROFILE.to_string(),
+        };
+        if name == DEFAULT_PROFILE {
+            return match state.datastore.lock() {
+                Ok(datastore) => request::Outcome::Success(ProfileDatastore {
+                    name,
+                    datastore: datastore.clone(),
+                }),
+                Err(e) => {
+                    let err_msg = format!("Taking datastore lock failed, returning 504: {e}");
+                    warn!("{}", err_msg);
+                    request::Outcome::Error((Status::ServiceUnavailable, err_msg))
+                }
+            };
+        }
+        match state.profiles.get(&name) {
+            Some(datastore) => request::Outcome::Success(ProfileDatastore { name, datastore }),
+            // The profile was deleted after the request was routed to it
+            None => request::Outcome::Error((
+                Status::NotFound,
+                ProfileError::NoSuchProfile(name).to_string(),
+            )),
+        }
+    }
+}
+
+fn profile_error(err: ProfileError) -> HttpErrorJson {
+    let status = match err {
+        ProfileError::InvalidName(_) => Status::BadRequest,
+        ProfileError::AlreadyExists(_) => Status::Conflict,
+        ProfileError::NoSuchProfile(_) => Status::NotFound,
+        ProfileError::IOError(_) => Status::InternalServerError,
+    };
+    HttpErrorJson::new(status, err.to_string())
+}
+
+/// The names of all profiles, starting with the default profile
+#[get("/")]
+pub fn profiles_get(state: &State<ServerState>) -> Json<Vec<String>> {
+    let mut names = vec![DEFAULT_PROFILE.to_string()];
+    names.extend(state.profiles.names());
+    Json(names)
+}
+
+#[post("/<profile>")]
+pub fn profile_new(
+    state: &State<ServerState>,
+    profile: &str,
+    audit: Audit<'_>,
+) -> Result<Status, HttpErrorJson> {
+    audit.affects([profile]);
+    match state.profiles.create(profile) {
+        Ok(_) => Ok(Status::Created),
+        Err(err) => Err(profile_error(err)),
+    }
+}
+
+/// Delete a profile together with all of its data
+#[delete("/<profile>")]
+pub fn profile_delete(
+    state: &State<ServerState>,
+    profile: &str,
+    audit: Audit<'_>,
+) -> Result<(), HttpErrorJson> {
+    audit.affects([profile]);
+    if profile == DEFAULT_PROFILE {
+        return Err(HttpErrorJson::new(
+            Status::BadRequest,
+            "The default profile can't be deleted".to_string(),
+        ));
+    }
+    state.profiles.delete(profile).map_err(profile_error)
+}
+
+/// Requests for a profile which does not exist are rerouted here by the fairing
+#[get("/<profile>")]
+pub fn profile_not_found(profile: &str) -> HttpErrorJson {
+    profile_error(ProfileError::NoSuchProfile(profile.to_string()))
+}
@@ -1,29 +1,280 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
 use rocket::http::Status;
 use rocket::serde::json::{json, Json, Value};
-use rocket::State;
-
-use aw_models::Query;
-
-use crate::endpoints::{HttpErrorJson, ServerState};
-
-#[post("/", data = "<query_req>", format = "application/json")]
-pub fn query(query_req: Json<Query>, state: &State<ServerState>) -> Result<Value, HttpErrorJson> {
-    let query_code = query_req.0.query.join("\n");
-    let intervals = &query_req.0.timeperiods;
-    let mut results = Vec::new();
-    let datastore = endpoints_get_lock!(state.datastore);
-    for interval in intervals {
-        let result = match aw_query::query(&query_code, interval, &datastore) {
-            Ok(data) => data,
-            Err(e) => {
-                warn!("Query failed: {:?}", e);
-                return Err(HttpErrorJson::new(
-                    Status::InternalServerError,
-                    e.to_string(),
-                ));
-            }
-        };
-        results.push(result);
+use rocket::tokio::task;
+use rocket::{Shutdown, State};
+use schemars::JsonSchema;
+use serde::Serialize;
+
+use aw_datastore::Datastore;
+use aw_models::{Query, QueryValidate, TimeInterval};
+use aw_query::{DataType, LocatedQueryError, QueryError, Severity, VarEnv};
+
+use crate::config::{AWConfig, QueryConfig};
+use crate::endpoints::util::ErrorLocation;
+use crate::endpoints::{HttpErrorJson, ProfileDatastore};
+use crate::query_cache::{normalize_query, QueryCache, QueryCacheStats};
+
+/// Sets the cancellation flag of a query once dropped
+///
+/// Rocket does not tell a handler that its client went away before the response has started, but
+/// it drops the handler if the request is abandoned, which this turns into cancelling the query.
+struct CancelOnDrop(Arc<AtomicBool>);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+fn query_error(e: LocatedQueryError) -> HttpErrorJson {
+    warn!("Query failed: {}", e);
+    let status = match e.error {
+        QueryError::ResourceLimit(_) => Status::BadRequest,
+        QueryError::Cancelled() => Status::ServiceUnavailable,
+        _ => Status::InternalServerError,
+    };
+    let err = HttpErrorJson::new(status, e.error.to_string());
+    match e.location {
+        Some(location) => err.with_location(location.into()),
+        None => err,
+    }
+}
+
+/// Run a query over time periods in a blocking thread, cancelling it if the request is dropped or
+/// the server shuts down before it finished
+///
+/// When explaining, the result of each time period is returned together with its explanation.
+async fn run_query(
+    code: String,
+    intervals: Vec<TimeInterval>,
+    datastore: Datastore,
+    params: VarEnv,
+    explain: bool,
+    config: &QueryConfig,
+    shutdown: Shutdown,
+) -> Result<Vec<Value>, HttpErrorJson> {
+    let limits = config.to_limits();
+    let cancelled = Arc::new(AtomicBool::new(false));
+    let _cancel_on_drop = CancelOnDrop(cancelled.clone());
+    let task_cancelled = cancelled.clone();
+    let task = task::spawn_blocking(move || {
+        if explain {
+            return aw_query::explain_timeperiods(
+                &code,
+                &intervals,
+                &datastore,
+                &params,
+                &limits,
+                &task_cancelled,
+            )
+            .map(|results| {
+                results
+                    .iter()
+                    .map(|(data, explanation)| json!({"result": data, "explain": explanation}))
+                    .collect()
+            });
+        }
+        aw_query::query_timeperiods(
+            &code,
+            &intervals,
+            &datastore,
+            &params,
+            &limits,
+            &task_cancelled,
+        )
+        .map(|results| results.iter().map(|data| json!(data)).collect())
+    });
+    let result = rocket::tokio::select! {
+        result = task => result,
+        _ = shutdown => {
+            cancelled.store(true, Ordering::Relaxed);
+            return Err(query_error(LocatedQueryError {
+                error: QueryError::Cancelled(),
+                location: None,
+            }));
+        }
+    };
+    match result {
+        Ok(Ok(data)) => Ok(data),
+        Ok(Err(e)) => Err(query_error(e)),
+        Err(e) => Err(HttpErrorJson::new(
+            Status::InternalServerError,
+            format!("Query panicked: {e}"),
+        )),
+    }
+}
+
+/// A query to run over several time periods
+pub struct QueryRun<'a> {
+    pub query: &'a [String],
+    pub timeperiods: &'a [TimeInterval],
+    pub params: HashMap<String, Value>,
+    pub use_cache: bool,
+    /// Return an explanation of where the query spent its time with each result
+    pub explain: bool,
+}
+
+/// Run a query over each of the time periods, using cached results where possible
+pub async fn run_timeperiods(
+    run: QueryRun<'_>,
+    datastore: ProfileDatastore,
+    query_cache: &QueryCache,
+    config: &AWConfig,
+    shutdown: Shutdown,
+) -> Result<Value, HttpErrorJson> {
+    let query_code = run.query.join("\n");
+    let mut cache_key = normalize_query(run.query);
+    // Profiles have the same buckets with different events, so their results are kept apart. A
+    // profile which was deleted and created again is a new datastore whose versions start over.
+    // The module version keeps results computed with modules which have since changed unused.
+    cache_key.push_str(&format!(
+        "\n#profile {} {} {}",
+        datastore.name(),
+        datastore.epoch(),
+        query_cache.module_version(datastore.name())
+    ));
+    if !run.params.is_empty() {
+        // Params are part of the key, sorted so that the order they were given in doesn't matter
+        let params: BTreeMap<&String, &Value> = run.params.iter().collect();
+        cache_key.push_str(&format!("\n#params {}", json!(params)));
+    }
+    let intervals = run.timeperiods;
+
+    let mut results: Vec<Option<Value>> = vec![None; intervals.len()];
+    let mut pending = Vec::new();
+    for (i, interval) in intervals.iter().enumerate() {
+        if run.use_cache && QueryCache::is_cacheable(interval) {
+            results[i] = query_cache.get(&datastore, &cache_key, interval);
+        }
+        if results[i].is_none() {
+            pending.push(i);
+        }
+    }
+    if pending.is_empty() {
+        return Ok(json!(results));
+    }
+
+    let version = match datastore.get_version() {
+        Ok(version) => version,
+        Err(err) => return Err(err.into()),
+    };
+    let params: VarEnv = run
+        .params
+        .iter()
+        .map(|(name, value)| (name.clone(), DataType::from(value)))
+        .collect();
+    let pending_intervals = pending.iter().map(|&i| intervals[i].clone()).collect();
+    let pending_results = run_query(
+        query_code,
+        pending_intervals,
+        datastore.into_inner(),
+        params,
+        run.explain,
+        &config.query,
+        shutdown,
+    )
+    .await?;
+    for (i, result) in pending.into_iter().zip(pending_results) {
+        let interval = &intervals[i];
+        if run.use_cache && QueryCache::is_cacheable(interval) {
+            query_cache.insert(&cache_key, interval, version, result.clone());
+        }
+        results[i] = Some(result);
     }
     Ok(json!(results))
 }
+
+/// Run a query over each of the time periods
+///
+/// Results for time periods which have already ended are cached until a bucket changes within the
+/// time period, unless cache is false. Queries are subject to the limits in the [query] section
+/// of the config.
+///
+/// With explain=true each result is returned as {"result": ..., "explain": ...}, where explain has
+/// the time spent on each statement and builtin and what was loaded from the datastore. Explained
+/// queries are always run, so they never use the cache.
+#[post(
+    "/?<cache>&<explain>",
+    data = "<query_req>",
+    format = "application/json"
+)]
+pub async fn query(
+    query_req: Json<Query>,
+    cache: Option<bool>,
+    explain: Option<bool>,
+    datastore: ProfileDatastore,
+    query_cache: &State<QueryCache>,
+    config: &State<AWConfig>,
+    shutdown: Shutdown,
+) -> Result<Value, HttpErrorJson> {
+    let run = QueryRun {
+        query: &query_req.0.query,
+        timeperiods: &query_req.0.timeperiods,
+        params: HashMap::new(),
+        use_cache: cache.unwrap_or(true) && !explain.unwrap_or(false),
+        explain: explain.unwrap_or(false),
+    };
+    run_timeperiods(run, datastore, query_cache, config, shutdown).await
+}
+
+/// A problem found in a query by /api/0/query/validate
+#[derive(Serialize, JsonSchema, Debug)]
+pub struct QueryDiagnostic {
+    /// Either error, which makes the query fail when run, or warning
+    pub severity: String,
+    pub message: String,
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    pub location: Option<ErrorLocation>,
+}
+
+#[derive(Serialize, JsonSchema, Debug)]
+pub struct QueryValidation {
+    /// Whether the query has no errors, it may still have warnings
+    pub valid: bool,
+    pub diagnostics: Vec<QueryDiagnostic>,
+}
+
+/// Check a query for syntax errors, undefined names, wrong arguments to builtins and unknown
+/// buckets without running it
+#[post("/validate", data = "<validate_req>", format = "application/json")]
+pub fn query_validate(
+    validate_req: Json<QueryValidate>,
+    datastore: ProfileDatastore,
+) -> Result<Json<QueryValidation>, HttpErrorJson> {
+    let bucket_ids: Vec<String> = match datastore.get_buckets() {
+        Ok(buckets) => buckets.into_keys().collect(),
+        Err(err) => return Err(err.into()),
+    };
+    let code = validate_req.query.join("\n");
+    let diagnostics: Vec<QueryDiagnostic> =
+        aw_query::validate(&code, &validate_req.params, &bucket_ids)
+            .into_iter()
+            .map(|diagnostic| QueryDiagnostic {
+                severity: match diagnostic.severity {
+                    Severity::Error => "error",
+                    Severity::Warning => "warning",
+                }
+                .to_string(),
+                message: diagnostic.message,
+                location: diagnostic.location.map(ErrorLocation::from),
+            })
+            .collect();
+    Ok(Json(QueryValidation {
+        valid: !diagnostics.iter().any(|d| d.severity == "error"),
+        diagnostics,
+    }))
+}
+
+#[get("/cache")]
+pub fn query_cache_stats(query_cache: &State<QueryCache>) -> Json<QueryCacheStats> {
+    Json(query_cache.stats())
+}
+
+#[delete("/cache")]
+pub fn query_cache_clear(query_cache: &State<QueryCache>) {
+    query_cache.clear()
+}
@@ -0,0 +1,126 @@
+use std::collections::HashMap;
+
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::State;
+
+use aw_datastore::{Datastore, DatastoreError};
+use aw_models::QueryModule;
+use aw_query::MODULE_NAMESPACE;
+
+use crate::endpoints::util::is_identifier;
+use crate::endpoints::{Audit, HttpErrorJson, ProfileDatastore};
+use crate::query_cache::QueryCache;
+
+fn parse_name(name: &str) -> Result<String, HttpErrorJson> {
+    if !is_identifier(name) {
+        Err(HttpErrorJson::new(
+            Status::BadRequest,
+            format!("Module name {name} is not a valid identifier"),
+        ))
+    } else if name.len() >= 128 {
+        Err(HttpErrorJson::new(
+            Status::BadRequest,
+            "Too long module name".to_string(),
+        ))
+    } else {
+        Ok(format!("{MODULE_NAMESPACE}{name}"))
+    }
+}
+
+fn get_module(datastore: &Datastore, name: &str) -> Result<QueryModule, HttpErrorJson> {
+    let key = parse_name(name)?;
+    match datastore.get_key_value(&key) {
+        Ok(value) => match serde_json::from_str(&value) {
+            Ok(module) => Ok(module),
+            Err(err) => Err(HttpErrorJson::new(
+                Status::InternalServerError,
+                format!("Stored module {name} is invalid: {err}"),
+            )),
+        },
+        Err(DatastoreError::NoSuchKey(_)) => Err(HttpErrorJson::new(
+            Status::NotFound,
+            format!("There's no query module named {name}"),
+        )),
+        Err(err) => Err(err.into()),
+    }
+}
+
+#[get("/")]
+pub fn query_modules_get(
+    datastore: ProfileDatastore,
+) -> Result<Json<HashMap<String, QueryModule>>, HttpErrorJson> {
+    let stored = datastore.get_key_values(&format!("{MODULE_NAMESPACE}%"))?;
+    let mut modules = HashMap::new();
+    for (key, value) in stored {
+        let name = key
+            .strip_prefix(MODULE_NAMESPACE)
+            .unwrap_or(&key)
+            .to_string();
+        match serde_json::from_str(&value) {
+            Ok(module) => {
+                modules.insert(name, module);
+            }
+            Err(err) => warn!("Skipping invalid stored module {}: {}", name, err),
+        }
+    }
+    Ok(Json(modules))
+}
+
+#[get("/<name>")]
+pub fn query_module_get(
+    datastore: ProfileDatastore,
+    name: &str,
+) -> Result<Json<QueryModule>, HttpErrorJson> {
+    Ok(Json(get_module(&datastore, name)?))
+}
+
+/// Store a module which queries can use with import "name";
+///
+/// Cached query results of the profile are no longer used since they may have been computed with
+/// the old module.
+#[post("/<name>", data = "<module>", format = "application/json")]
+pub fn query_module_set(
+    datastore: ProfileDatastore,
+    name: &str,
+    module: Json<QueryModule>,
+    query_cache: &State<QueryCache>,
+    audit: Audit<'_>,
+) -> Result<Status, HttpErrorJson> {
+    audit.affects([name]);
+    let key = parse_name(name)?;
+    if let Err(e) = aw_query::check_syntax(&module.query.join("\n")) {
+        let err = HttpErrorJson::new(Status::BadRequest, e.error.to_string());
+        return Err(match e.location {
+            Some(location) => err.with_location(location.into()),
+            None => err,
+        });
+    }
+    let value = serde_json::to_string(&module.0).unwrap();
+    match datastore.set_key_value(&key, &value) {
+        Ok(_) => {
+            query_cache.modules_changed(datastore.name());
+            Ok(Status::Created)
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+#[delete("/<name>")]
+pub fn query_module_delete(
+    datastore: ProfileDatastore,
+    name: &str,
+    query_cache: &State<QueryCache>,
+    audit: Audit<'_>,
+) -> Result<(), HttpErrorJson> {
+    audit.affects([name]);
+    // Makes deleting a module which does not exist a 404
+    get_module(&datastore, name)?;
+    match datastore.delete_key_value(&parse_name(name)?) {
+        Ok(_) => {
+            query_cache.modules_changed(datastore.name());
+            Ok(())
+        }
+        Err(err) => Err(err.into()),
+    }
+}
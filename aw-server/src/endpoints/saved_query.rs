@@ -0,0 +1,184 @@
+use std::collections::HashMap;
+
+use rocket::http::Status;
+use rocket::serde::json::{Json, Value};
+use rocket::{Shutdown, State};
+
+use aw_datastore::{Datastore, DatastoreError};
+use aw_models::{SavedQuery, SavedQueryRun};
+
+use crate::config::AWConfig;
+use crate::endpoints::query::{run_timeperiods, QueryRun};
+use crate::endpoints::util::is_identifier;
+use crate::endpoints::{Audit, HttpErrorJson, ProfileDatastore};
+use crate::query_cache::QueryCache;
+
+const NAMESPACE: &str = "queries.";
+
+fn parse_name(name: &str) -> Result<String, HttpErrorJson> {
+    if !is_identifier(name) {
+        Err(HttpErrorJson::new(
+            Status::BadRequest,
+            format!("Query name {name} is not a valid identifier"),
+        ))
+    } else if name.len() >= 128 {
+        Err(HttpErrorJson::new(
+            Status::BadRequest,
+            "Too long query name".to_string(),
+        ))
+    } else {
+        Ok(format!("{NAMESPACE}{name}"))
+    }
+}
+
+fn get_saved_query(datastore: &Datastore, name: &str) -> Result<SavedQuery, HttpErrorJson> {
+    let key = parse_name(name)?;
+    match datastore.get_key_value(&key) {
+        Ok(value) => match serde_json::from_str(&value) {
+            Ok(saved_query) => Ok(saved_query),
+            Err(err) => Err(HttpErrorJson::new(
+                Status::InternalServerError,
+                format!("Stored query {name} is invalid: {err}"),
+            )),
+        },
+        Err(DatastoreError::NoSuchKey(_)) => Err(HttpErrorJson::new(
+            Status::NotFound,
+            format!("There's no saved query named {name}"),
+        )),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Resolve the values of the params of a saved query, falling back to their defaults
+fn bind_params(
+    saved_query: &SavedQuery,
+    mut given: HashMap<String, Value>,
+) -> Result<HashMap<String, Value>, HttpErrorJson> {
+    let mut params = HashMap::new();
+    for param in &saved_query.params {
+        let value = match given.remove(&param.name) {
+            Some(value) => value,
+            None => match &param.default {
+                Some(default) => default.clone(),
+                None => {
+                    return Err(HttpErrorJson::new(
+                        Status::BadRequest,
+                        format!("Missing value for parameter {}", param.name),
+                    ))
+                }
+            },
+        };
+        params.insert(param.name.clone(), value);
+    }
+    if let Some(name) = given.keys().next() {
+        return Err(HttpErrorJson::new(
+            Status::BadRequest,
+            format!("The query has no parameter named {name}"),
+        ));
+    }
+    Ok(params)
+}
+
+#[get("/")]
+pub fn saved_queries_get(
+    datastore: ProfileDatastore,
+) -> Result<Json<HashMap<String, SavedQuery>>, HttpErrorJson> {
+    let stored = datastore.get_key_values(&format!("{NAMESPACE}%"))?;
+    let mut saved_queries = HashMap::new();
+    for (key, value) in stored {
+        let name = key.strip_prefix(NAMESPACE).unwrap_or(&key).to_string();
+        match serde_json::from_str(&value) {
+            Ok(saved_query) => {
+                saved_queries.insert(name, saved_query);
+            }
+            Err(err) => warn!("Skipping invalid stored query {}: {}", name, err),
+        }
+    }
+    Ok(Json(saved_queries))
+}
+
+#[get("/<name>")]
+pub fn saved_query_get(
+    datastore: ProfileDatastore,
+    name: &str,
+) -> Result<Json<SavedQuery>, HttpErrorJson> {
+    Ok(Json(get_saved_query(&datastore, name)?))
+}
+
+#[post("/<name>", data = "<saved_query>", format = "application/json")]
+pub fn saved_query_set(
+    datastore: ProfileDatastore,
+    name: &str,
+    saved_query: Json<SavedQuery>,
+    audit: Audit<'_>,
+) -> Result<Status, HttpErrorJson> {
+    audit.affects([name]);
+    let key = parse_name(name)?;
+    for param in &saved_query.params {
+        if !is_identifier(&param.name) {
+            return Err(HttpErrorJson::new(
+                Status::BadRequest,
+                format!("Parameter name {} is not a valid identifier", param.name),
+            ));
+        }
+        if aw_query::is_reserved(&param.name) {
+            return Err(HttpErrorJson::new(
+                Status::BadRequest,
+                format!(
+                    "Parameter name {} is reserved by the query language",
+                    param.name
+                ),
+            ));
+        }
+    }
+    if let Err(e) = aw_query::check_syntax(&saved_query.query.join("\n")) {
+        let err = HttpErrorJson::new(Status::BadRequest, e.error.to_string());
+        return Err(match e.location {
+            Some(location) => err.with_location(location.into()),
+            None => err,
+        });
+    }
+    let value = serde_json::to_string(&saved_query.0).unwrap();
+    match datastore.set_key_value(&key, &value) {
+        Ok(_) => Ok(Status::Created),
+        Err(err) => Err(err.into()),
+    }
+}
+
+#[delete("/<name>")]
+pub fn saved_query_delete(
+    datastore: ProfileDatastore,
+    name: &str,
+    audit: Audit<'_>,
+) -> Result<(), HttpErrorJson> {
+    audit.affects([name]);
+    // Makes deleting a query which does not exist a 404
+    get_saved_query(&datastore, name)?;
+    match datastore.delete_key_value(&parse_name(name)?) {
+        Ok(_) => Ok(()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Run a saved query with the given parameter values, like /api/0/query
+#[post("/<name>/run?<cache>", data = "<run_req>", format = "application/json")]
+pub async fn saved_query_run(
+    name: &str,
+    run_req: Json<SavedQueryRun>,
+    cache: Option<bool>,
+    datastore: ProfileDatastore,
+    query_cache: &State<QueryCache>,
+    config: &State<AWConfig>,
+    shutdown: Shutdown,
+) -> Result<Value, HttpErrorJson> {
+    let saved_query = get_saved_query(&datastore, name)?;
+    let run_req = run_req.into_inner();
+    let run = QueryRun {
+        query: &saved_query.query,
+        timeperiods: &run_req.timeperiods,
+        params: bind_params(&saved_query, run_req.params)?,
+        use_cache: cache.unwrap_or(true),
+        explain: false,
+    };
+    run_timeperiods(run, datastore, query_cache, config, shutdown).await
+}
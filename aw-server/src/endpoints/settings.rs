@@ -1,15 +1,13 @@
-use crate::endpoints::ServerState;
+use crate::endpoints::ProfileDatastore;
 use rocket::http::Status;
 use rocket::serde::json::Json;
-use rocket::State;
 use std::collections::HashMap;
-use std::sync::MutexGuard;
 
-use aw_datastore::{Datastore, DatastoreError};
+use aw_datastore::DatastoreError;
 
-use crate::endpoints::HttpErrorJson;
+use crate::endpoints::{Audit, HttpErrorJson};
 
-fn parse_key(key: String) -> Result<String, HttpErrorJson> {
+pub fn parse_key(key: String) -> Result<String, HttpErrorJson> {
     let namespace: String = "settings.".to_string();
     if key.len() >= 128 {
         Err(HttpErrorJson::new(
@@ -23,9 +21,8 @@ fn parse_key(key: String) -> Result<String, HttpErrorJson> {
 
 #[get("/")]
 pub fn settings_get(
-    state: &State<ServerState>,
+    datastore: ProfileDatastore,
 ) -> Result<Json<HashMap<String, serde_json::Value>>, HttpErrorJson> {
-    let datastore = endpoints_get_lock!(state.datastore);
     let queryresults = match datastore.get_key_values("settings.%") {
         Ok(result) => Ok(result),
         Err(err) => Err(err.into()),
@@ -49,11 +46,10 @@ pub fn settings_get(
 
 #[get("/<key>")]
 pub fn setting_get(
-    state: &State<ServerState>,
+    datastore: ProfileDatastore,
     key: String,
 ) -> Result<Json<serde_json::Value>, HttpErrorJson> {
     let setting_key = parse_key(key)?;
-    let datastore = endpoints_get_lock!(state.datastore);
 
     match datastore.get_key_value(&setting_key) {
         Ok(value) => Ok(Json(serde_json::from_str(&value).unwrap())),
@@ -64,10 +60,12 @@ pub fn setting_get(
 
 #[post("/<key>", data = "<value>", format = "application/json")]
 pub fn setting_set(
-    state: &State<ServerState>,
+    datastore: ProfileDatastore,
     key: String,
     value: Json<serde_json::Value>,
+    audit: Audit<'_>,
 ) -> Result<Status, HttpErrorJson> {
+    audit.affects([&key]);
     let setting_key = parse_key(key)?;
     let value_str = match serde_json::to_string(&value.0) {
         Ok(value) => value,
@@ -78,8 +76,6 @@ pub fn setting_set(
             ))
         }
     };
-
-    let datastore: MutexGuard<'_, Datastore> = endpoints_get_lock!(state.datastore);
     let result = datastore.set_key_value(&setting_key, &value_str);
 
     match result {
@@ -89,10 +85,13 @@ pub fn setting_set(
 }
 
 #[delete("/<key>")]
-pub fn setting_delete(state: &State<ServerState>, key: String) -> Result<(), HttpErrorJson> {
+pub fn setting_delete(
+    datastore: ProfileDatastore,
+    key: String,
+    audit: Audit<'_>,
+) -> Result<(), HttpErrorJson> {
+    audit.affects([&key]);
     let setting_key = parse_key(key)?;
-
-    let datastore = endpoints_get_lock!(state.datastore);
     let result = datastore.delete_key_value(&setting_key);
 
     match result {
@@ -1,19 +1,52 @@
 use std::io::Cursor;
 
+use rocket::futures::stream;
 use rocket::http::ContentType;
 use rocket::http::Header;
 use rocket::http::Status;
 use rocket::request::Request;
+use rocket::response::stream::TextStream;
 use rocket::response::{self, Responder, Response};
+use rocket::serde::json::Json;
+use schemars::JsonSchema;
 use serde::Serialize;
 
+use aw_datastore::EventCursor;
 use aw_models::BucketsExport;
+use aw_models::Event;
+use aw_models::ImportSummary;
 
-#[derive(Serialize, Debug)]
+use crate::export::{ExportFormat, ExportStream};
+
+#[derive(Serialize, JsonSchema, Debug)]
 pub struct HttpErrorJson {
     #[serde(skip_serializing)]
+    #[schemars(skip)]
     status: Status,
     message: String,
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    location: Option<ErrorLocation>,
+    /// What a failed import got to before it failed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    summary: Option<ImportSummary>,
+}
+
+/// Where in a query an error happened, for the query editor to highlight
+#[derive(Serialize, JsonSchema, Debug)]
+pub struct ErrorLocation {
+    pub line: usize,
+    pub column: usize,
+    pub snippet: String,
+}
+
+impl From<aw_query::ErrorLocation> for ErrorLocation {
+    fn from(location: aw_query::ErrorLocation) -> Self {
+        ErrorLocation {
+            line: location.line,
+            column: location.column,
+            snippet: location.snippet,
+        }
+    }
 }
 
 impl HttpErrorJson {
@@ -21,8 +54,28 @@ impl HttpErrorJson {
         HttpErrorJson {
             status,
             message: err,
+            location: None,
+            summary: None,
         }
     }
+
+    pub fn with_location(mut self, location: ErrorLocation) -> HttpErrorJson {
+        self.location = Some(location);
+        self
+    }
+
+    pub fn with_summary(mut self, summary: ImportSummary) -> HttpErrorJson {
+        self.summary = Some(summary);
+        self
+    }
+
+    pub fn status(&self) -> Status {
+        self.status
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
 }
 
 impl<'r> Responder<'r, 'static> for HttpErrorJson {
@@ -67,6 +120,80 @@ impl<'r> Responder<'r, 'static> for BucketsExportRocket {
     }
 }
 
+/// A streamed NDJSON or CSV export, the body is written line by line while reading the datastore
+pub struct ExportStreamRocket {
+    inner: ExportStream,
+}
+
+impl From<ExportStream> for ExportStreamRocket {
+    fn from(val: ExportStream) -> Self {
+        ExportStreamRocket { inner: val }
+    }
+}
+
+impl<'r> Responder<'r, 'r> for ExportStreamRocket {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'r> {
+        let content_type = match self.inner.format() {
+            ExportFormat::Csv => ContentType::CSV,
+            _ => ContentType::new("application", "x-ndjson"),
+        };
+        let header_content = format!("attachment; filename={}", self.inner.filename());
+        // The status has already been sent when the datastore fails mid-export, so all we can do
+        // is to end the body early
+        let lines = self.inner.map_while(|line| match line {
+            Ok(line) => Some(line),
+            Err(err) => {
+                warn!("Streamed export failed: {:?}", err);
+                None
+            }
+        });
+        let mut response = TextStream(stream::iter(lines)).respond_to(req)?;
+        response.set_header(content_type);
+        response.set_header(Header::new("Content-Disposition", header_content));
+        Ok(response)
+    }
+}
+
+/// Either a JSON export or a streamed export, depending on the requested format
+pub enum ExportRocket {
+    Json(BucketsExportRocket),
+    Stream(ExportStreamRocket),
+}
+
+impl<'r> Responder<'r, 'r> for ExportRocket {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'r> {
+        match self {
+            ExportRocket::Json(export) => export.respond_to(req),
+            ExportRocket::Stream(export) => export.respond_to(req),
+        }
+    }
+}
+
+/// A page of events, with the cursor to the next page (if any) in the X-Next-Cursor header
+pub struct EventsPageRocket {
+    events: Vec<Event>,
+    next_cursor: Option<EventCursor>,
+}
+
+impl EventsPageRocket {
+    pub fn new(events: Vec<Event>, next_cursor: Option<EventCursor>) -> Self {
+        EventsPageRocket {
+            events,
+            next_cursor,
+        }
+    }
+}
+
+impl<'r> Responder<'r, 'static> for EventsPageRocket {
+    fn respond_to(self, req: &Request) -> response::Result<'static> {
+        let mut response = Json(self.events).respond_to(req)?;
+        if let Some(cursor) = self.next_cursor {
+            response.set_header(Header::new("X-Next-Cursor", cursor.to_string()));
+        }
+        Ok(response)
+    }
+}
+
 use aw_datastore::DatastoreError;
 
 impl From<DatastoreError> for HttpErrorJson {
@@ -84,6 +211,17 @@ impl From<DatastoreError> for HttpErrorJson {
                 Status::NotFound,
                 format!("The requested key(s) '{key}' do not exist"),
             ),
+            DatastoreError::ImportConflict(summary) => {
+                let bucket_id = summary.buckets.last().map(|b| b.bucket_id.clone());
+                HttpErrorJson::new(
+                    Status::Conflict,
+                    format!(
+                        "Bucket '{}' already exists, nothing was imported",
+                        bucket_id.unwrap_or_default()
+                    ),
+                )
+                .with_summary(summary)
+            }
             DatastoreError::MpscError => HttpErrorJson::new(
                 Status::InternalServerError,
                 "Unexpected Mpsc error!".to_string(),
@@ -116,3 +254,13 @@ macro_rules! endpoints_get_lock {
         }
     };
 }
+
+/// Whether name can be used as a variable in a query
+pub fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => (),
+        _ => return false,
+    }
+    chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
@@ -0,0 +1,363 @@
+//! Bucket exports, shared by the export endpoints and the export command
+//!
+//! Unlike the JSON export, which builds the whole `BucketsExport` in memory, the NDJSON and CSV
+//! exports read events page by page from the datastore and produce the output one line at a time.
+
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::str::FromStr;
+
+use chrono::{DateTime, Utc};
+use glob::Pattern;
+use serde_json::Value;
+
+use aw_datastore::{Datastore, DatastoreError, EventCursor};
+use aw_models::{Bucket, BucketsExport, Event, ExportRecord, TryVec};
+
+/// Number of events fetched from the datastore at a time
+const PAGE_SIZE: u64 = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// A single BucketsExport JSON document
+    Json,
+    /// One ExportRecord per line, each bucket followed by its events
+    Ndjson,
+    /// One event per line, with the keys of the event data flattened into columns
+    Csv,
+}
+
+impl ExportFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Json => "json",
+            ExportFormat::Ndjson => "ndjson",
+            ExportFormat::Csv => "csv",
+        }
+    }
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(ExportFormat::Json),
+            "ndjson" => Ok(ExportFormat::Ndjson),
+            "csv" => Ok(ExportFormat::Csv),
+            _ => Err(format!(
+                "Invalid export format '{s}', needs to be json, ndjson or csv"
+            )),
+        }
+    }
+}
+
+/// Which buckets and events to include in an export
+#[derive(Debug, Clone, Default)]
+pub struct ExportFilter {
+    /// Patterns of the bucket ids to export, all buckets are exported if empty
+    pub buckets: Vec<Pattern>,
+    pub hostname: Option<String>,
+    pub bucket_type: Option<String>,
+    pub start: Option<DateTime<Utc>>,
+    pub end: Option<DateTime<Utc>>,
+}
+
+fn parse_datetime(name: &str, dt_str: Option<&str>) -> Result<Option<DateTime<Utc>>, String> {
+    match dt_str {
+        Some(dt_str) => match DateTime::parse_from_rfc3339(dt_str) {
+            Ok(dt) => Ok(Some(dt.with_timezone(&Utc))),
+            Err(e) => Err(format!(
+                "Failed to parse {name}, datetime needs to be in rfc3339 format: {e}"
+            )),
+        },
+        None => Ok(None),
+    }
+}
+
+impl ExportFilter {
+    /// Parse a filter from its string representation, as used both in queries and on the
+    /// command line
+    ///
+    /// buckets is a comma separated list of glob patterns, start and end are in rfc3339 format.
+    pub fn parse(
+        buckets: Option<&str>,
+        hostname: Option<&str>,
+        bucket_type: Option<&str>,
+        start: Option<&str>,
+        end: Option<&str>,
+    ) -> Result<ExportFilter, String> {
+        let mut patterns = Vec::new();
+        for pattern in buckets.unwrap_or("").split(',').filter(|p| !p.is_empty()) {
+            match Pattern::new(pattern) {
+                Ok(pattern) => patterns.push(pattern),
+                Err(e) => return Err(format!("Invalid bucket pattern '{pattern}': {e}")),
+            }
+        }
+        Ok(ExportFilter {
+            buckets: patterns,
+            hostname: hostname.map(str::to_string),
+            bucket_type: bucket_type.map(str::to_string),
+            start: parse_datetime("starttime", start)?,
+            end: parse_datetime("endtime", end)?,
+        })
+    }
+
+    pub fn matches(&self, bucket: &Bucket) -> bool {
+        let id_matches =
+            self.buckets.is_empty() || self.buckets.iter().any(|p| p.matches(&bucket.id));
+        let hostname_matches = match &self.hostname {
+            Some(hostname) => *hostname == bucket.hostname,
+            None => true,
+        };
+        let type_matches = match &self.bucket_type {
+            Some(bucket_type) => *bucket_type == bucket._type,
+            None => true,
+        };
+        id_matches && hostname_matches && type_matches
+    }
+
+    /// The buckets in the datastore which match the filter
+    pub fn select_buckets(&self, datastore: &Datastore) -> Result<Vec<Bucket>, DatastoreError> {
+        let buckets = datastore.get_buckets()?;
+        Ok(buckets
+            .into_values()
+            .filter(|bucket| self.matches(bucket))
+            .collect())
+    }
+}
+
+/// Build a JSON export of the given buckets, including the events in the time range of the filter
+pub fn export_json(
+    datastore: &Datastore,
+    buckets: Vec<Bucket>,
+    filter: &ExportFilter,
+) -> Result<BucketsExport, DatastoreError> {
+    let mut export = BucketsExport {
+        buckets: HashMap::new(),
+    };
+    for mut bucket in buckets {
+        let events = datastore.get_events(&bucket.id, filter.start, filter.end, None)?;
+        bucket.events = Some(TryVec::new(events));
+        export.buckets.insert(bucket.id.clone(), bucket);
+    }
+    Ok(export)
+}
+
+/// Quote a CSV field if needed, according to RFC 4180
+fn csv_field(field: &str) -> String {
+    if field.contains(&[',', '"', '\n', '\r'][..]) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+fn csv_value(value: Option<&Value>) -> String {
+    match value {
+        None | Some(Value::Null) => "".to_string(),
+        Some(Value::String(s)) => csv_field(s),
+        // Nested values are kept as JSON
+        Some(value) => csv_field(&value.to_string()),
+    }
+}
+
+/// Iterator over the lines (including the trailing newline) of a streamed export
+///
+/// Events are exported oldest first. Stops after the first datastore error.
+pub struct ExportStream {
+    datastore: Datastore,
+    format: ExportFormat,
+    buckets: VecDeque<Bucket>,
+    start: Option<DateTime<Utc>>,
+    end: Option<DateTime<Utc>>,
+    /// Bucket currently being exported
+    bucket_id: String,
+    /// Position in the current bucket, None if no page has been read yet
+    cursor: Option<EventCursor>,
+    more_pages: bool,
+    page: VecDeque<Event>,
+    /// Keys of the event data, each one becoming a CSV column
+    data_keys: Vec<String>,
+    filename: String,
+    header_written: bool,
+    failed: bool,
+}
+
+impl ExportStream {
+    pub fn new(
+        datastore: Datastore,
+        format: ExportFormat,
+        mut buckets: Vec<Bucket>,
+        filter: &ExportFilter,
+    ) -> Result<ExportStream, DatastoreError> {
+        assert!(
+            format != ExportFormat::Json,
+            "JSON exports are not streamed"
+        );
+        buckets.sort_by(|a, b| a.id.cmp(&b.id));
+        let filename = match buckets.len() == 1 {
+            true => format!("aw-bucket-export_{}.{}", buckets[0].id, format.extension()),
+            false => format!("aw-buckets-export.{}", format.extension()),
+        };
+        let data_keys = match format {
+            ExportFormat::Csv => Self::collect_data_keys(&datastore, &buckets, filter)?,
+            _ => Vec::new(),
+        };
+        Ok(ExportStream {
+            datastore,
+            format,
+            buckets: buckets.into(),
+            start: filter.start,
+            end: filter.end,
+            bucket_id: String::new(),
+            cursor: None,
+            more_pages: false,
+            page: VecDeque::new(),
+            data_keys,
+            filename,
+            header_written: false,
+            failed: false,
+        })
+    }
+
+    pub fn format(&self) -> ExportFormat {
+        self.format
+    }
+
+    /// Suggested name of the file to save the export as
+    pub fn filename(&self) -> &str {
+        &self.filename
+    }
+
+    /// The CSV header has to list every data key up front, so all events are read once before
+    /// the export starts, keeping only the keys in memory.
+    fn collect_data_keys(
+        datastore: &Datastore,
+        buckets: &[Bucket],
+        filter: &ExportFilter,
+    ) -> Result<Vec<String>, DatastoreError> {
+        let mut keys = BTreeSet::new();
+        for bucket in buckets {
+            let mut cursor = None;
+            loop {
+                let (events, next) = datastore.get_events_page(
+                    &bucket.id,
+                    filter.start,
+                    filter.end,
+                    Some(PAGE_SIZE),
+                    true,
+                    cursor,
+                )?;
+                for event in events {
+                    keys.extend(event.data.into_iter().map(|(k, _)| k));
+                }
+                match next {
+                    Some(next) => cursor = Some(next),
+                    None => break,
+                }
+            }
+        }
+        Ok(keys.into_iter().collect())
+    }
+
+    fn csv_header(&self) -> String {
+        let mut columns = vec![
+            "bucket_id".to_string(),
+            "id".to_string(),
+            "timestamp".to_string(),
+            "duration".to_string(),
+        ];
+        for key in self.data_keys.iter() {
+            columns.push(csv_field(&format!("data.{key}")));
+        }
+        columns.join(",") + "\n"
+    }
+
+    fn csv_row(&self, bucket_id: &str, event: &Event) -> String {
+        let mut fields = vec![
+            csv_field(bucket_id),
+            event.id.map(|id| id.to_string()).unwrap_or_default(),
+            event.timestamp.to_rfc3339(),
+            (event.duration.num_nanoseconds().unwrap() as f64 / 1_000_000_000.0).to_string(),
+        ];
+        for key in self.data_keys.iter() {
+            fields.push(csv_value(event.data.get(key)));
+        }
+        fields.join(",") + "\n"
+    }
+
+    fn ndjson_line(record: &ExportRecord) -> String {
+        serde_json::to_string(record).unwrap() + "\n"
+    }
+
+    /// Fetch the next page of events, moving on to the next bucket when the current one is done
+    ///
+    /// Returns the line to write for a new bucket, if the format has one.
+    fn fill_page(&mut self) -> Result<Option<String>, DatastoreError> {
+        loop {
+            if self.more_pages {
+                let (events, next) = self.datastore.get_events_page(
+                    &self.bucket_id,
+                    self.start,
+                    self.end,
+                    Some(PAGE_SIZE),
+                    true,
+                    self.cursor,
+                )?;
+                self.page.extend(events);
+                self.more_pages = next.is_some();
+                self.cursor = next;
+                if !self.page.is_empty() {
+                    return Ok(None);
+                }
+            }
+            let bucket = match self.buckets.pop_front() {
+                Some(bucket) => bucket,
+                None => return Ok(None),
+            };
+            self.bucket_id = bucket.id.clone();
+            self.cursor = None;
+            self.more_pages = true;
+            if self.format == ExportFormat::Ndjson {
+                return Ok(Some(Self::ndjson_line(&ExportRecord::Bucket(bucket))));
+            }
+        }
+    }
+
+    fn next_line(&mut self) -> Result<Option<String>, DatastoreError> {
+        if self.format == ExportFormat::Csv && !self.header_written {
+            self.header_written = true;
+            return Ok(Some(self.csv_header()));
+        }
+        if self.page.is_empty() {
+            if let Some(bucket_line) = self.fill_page()? {
+                return Ok(Some(bucket_line));
+            }
+        }
+        let line = match self.page.pop_front() {
+            Some(event) => match self.format {
+                ExportFormat::Csv => self.csv_row(&self.bucket_id, &event),
+                _ => Self::ndjson_line(&ExportRecord::Event(event)),
+            },
+            None => return Ok(None),
+        };
+        Ok(Some(line))
+    }
+}
+
+impl Iterator for ExportStream {
+    type Item = Result<String, DatastoreError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        match self.next_line() {
+            Ok(line) => line.map(Ok),
+            Err(err) => {
+                self.failed = true;
+                Some(Err(err))
+            }
+        }
+    }
+}
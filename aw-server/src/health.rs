@@ -0,0 +1,65 @@
+use std::time::{Duration, Instant};
+
+use rocket::tokio::{task, time};
+
+use aw_datastore::Datastore;
+use aw_models::Health;
+
+/// How long the datastore worker may take to respond before it is considered unhealthy
+const HEALTH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Round-trip a request through the datastore worker
+///
+/// Fails if the worker has stopped or does not respond in time, which happens if it panicked or
+/// is stuck.
+pub async fn check_health(datastore: Datastore) -> Result<Health, String> {
+    let start = Instant::now();
+    let task = task::spawn_blocking(move || datastore.get_health());
+    match time::timeout(HEALTH_TIMEOUT, task).await {
+        Ok(Ok(Ok(datastore))) => Ok(Health {
+            round_trip_ms: start.elapsed().as_secs_f64() * 1000.0,
+            datastore,
+        }),
+        Ok(Ok(Err(e))) => Err(format!("Datastore worker failed: {e:?}")),
+        Ok(Err(e)) => Err(format!("Datastore health check panicked: {e}")),
+        Err(_) => Err(format!(
+            "Datastore worker did not respond within {} seconds",
+            HEALTH_TIMEOUT.as_secs()
+        )),
+    }
+}
+
+/// Check that the datastore worker is running and not stuck on a request for longer than timeout
+///
+/// Reads the progress the worker reports instead of sending it a request, so it is answered right
+/// away even while the worker is busy with a long import.
+pub fn check_worker(datastore: &Datastore, timeout: Duration) -> Result<(), String> {
+    match datastore.worker_busy_for() {
+        Ok(Some(busy_for)) if busy_for > timeout => Err(format!(
+            "Datastore worker made no progress for {} seconds",
+            busy_for.as_secs()
+        )),
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("Datastore worker failed: {e:?}")),
+    }
+}
+
+/// Ping the systemd watchdog every interval for as long as the datastore worker is healthy
+///
+/// The worker may not be stuck for longer than an interval, so a stuck worker stops the pings
+/// before systemd's own timeout of two intervals runs out.
+#[cfg(target_os = "linux")]
+pub async fn watchdog(datastore: Datastore, interval: Duration) {
+    use sd_notify::NotifyState;
+
+    let timeout = HEALTH_TIMEOUT.min(interval);
+    loop {
+        time::sleep(interval).await;
+        match check_worker(&datastore, timeout) {
+            Ok(()) => {
+                let _ = sd_notify::notify(false, &[NotifyState::Watchdog]);
+            }
+            Err(e) => warn!("Health check failed, not pinging the watchdog: {}", e),
+        }
+    }
+}
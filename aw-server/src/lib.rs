@@ -26,7 +26,13 @@ pub mod config;
 pub mod device_id;
 pub mod dirs;
 pub mod endpoints;
+pub mod export;
+pub mod health;
 pub mod logging;
+pub mod profiles;
+pub mod query_cache;
+#[cfg(all(unix, not(target_os = "android")))]
+pub mod unix_socket;
 
 #[cfg(target_os = "android")]
 pub mod android;
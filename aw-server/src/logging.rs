@@ -1,10 +1,39 @@
 use std::fs;
 use std::path::PathBuf;
+use std::sync::OnceLock;
 
 use fern::colors::{Color, ColoredLevelConfig};
 
 use crate::dirs;
 
+static DEFAULT_LOG_LEVEL: OnceLock<log::LevelFilter> = OnceLock::new();
+
+fn parse_log_level(level: &str) -> Option<log::LevelFilter> {
+    match level.to_lowercase().as_str() {
+        "trace" => Some(log::LevelFilter::Trace),
+        "debug" => Some(log::LevelFilter::Debug),
+        "info" => Some(log::LevelFilter::Info),
+        "warn" => Some(log::LevelFilter::Warn),
+        "error" => Some(log::LevelFilter::Error),
+        _ => None,
+    }
+}
+
+/// Change the log level of a logger set up by setup_logger
+///
+/// None restores the level the logger was set up with, a level set with the LOG_LEVEL
+/// environment variable always takes precedence.
+pub fn set_log_level(level: Option<&str>) -> Result<(), String> {
+    let default_log_level = *DEFAULT_LOG_LEVEL.get().unwrap_or(&log::LevelFilter::Info);
+    let log_level = match level {
+        Some(_) if std::env::var("LOG_LEVEL").is_ok() => default_log_level,
+        Some(level) => parse_log_level(level).ok_or(format!("Invalid log level {level}"))?,
+        None => default_log_level,
+    };
+    log::set_max_level(log_level);
+    Ok(())
+}
+
 pub fn setup_logger(module: &str, testing: bool, verbose: bool) -> Result<(), fern::InitError> {
     let mut logfile_path: PathBuf =
         dirs::get_log_dir(module).expect("Unable to get log dir to store logs in");
@@ -32,17 +61,12 @@ pub fn setup_logger(module: &str, testing: bool, verbose: bool) -> Result<(), fe
     };
 
     let log_level = std::env::var("LOG_LEVEL").map_or(default_log_level, |level| {
-        match level.to_lowercase().as_str() {
-            "trace" => log::LevelFilter::Trace,
-            "debug" => log::LevelFilter::Debug,
-            "info" => log::LevelFilter::Info,
-            "warn" => log::LevelFilter::Warn,
-            "error" => log::LevelFilter::Error,
-            _ => default_log_level,
-        }
+        parse_log_level(&level).unwrap_or(default_log_level)
     });
 
-    let mut dispatch = fern::Dispatch::new().level(log_level);
+    // Everything is let through here, the level is enforced with log::set_max_level so that
+    // it can be changed by set_log_level
+    let mut dispatch = fern::Dispatch::new().level(log::LevelFilter::Trace);
     // Set some Rocket messages to debug level
 
     let is_debug = matches!(log_level, log::LevelFilter::Trace | log::LevelFilter::Debug);
@@ -81,6 +105,8 @@ pub fn setup_logger(module: &str, testing: bool, verbose: bool) -> Result<(), fe
                 .chain(fern::log_file(logfile_path)?),
         )
         .apply()?;
+    log::set_max_level(log_level);
+    let _ = DEFAULT_LOG_LEVEL.set(log_level);
     Ok(())
 }
 
@@ -2,11 +2,14 @@
 extern crate log;
 
 use std::env;
+use std::fs::File;
+use std::io::{BufWriter, Write};
 use std::path::PathBuf;
 
 use clap::crate_version;
-use clap::Parser;
+use clap::{Args, Parser, Subcommand};
 
+use aw_server::query_cache::QueryCache;
 use aw_server::*;
 
 #[cfg(target_os = "linux")]
@@ -37,6 +40,14 @@ struct Opts {
     #[clap(long)]
     port: Option<String>,
 
+    /// Serve the API on a Unix socket only the user can connect to, optionally at the given path
+    #[clap(long, num_args = 0..=1, value_name = "PATH")]
+    unix_socket: Option<Option<String>>,
+
+    /// Don't listen on TCP, only serve the API on the Unix socket
+    #[clap(long)]
+    no_tcp: bool,
+
     /// Path to database override
     /// Also implies --no-legacy-import if no db found
     #[clap(long)]
@@ -57,6 +68,172 @@ struct Opts {
     /// Don't import from aw-server-python if no aw-server-rust db found
     #[clap(long)]
     no_legacy_import: bool,
+
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Export buckets from the database without starting the server
+    Export(ExportOpts),
+}
+
+#[derive(Args)]
+struct ExportOpts {
+    /// Format of the export: json, ndjson or csv
+    #[clap(long, default_value = "json")]
+    format: String,
+
+    /// Comma separated glob patterns of the bucket IDs to export, e.g. aw-watcher-window_*
+    #[clap(long)]
+    buckets: Option<String>,
+
+    /// Only export buckets from this host
+    #[clap(long)]
+    hostname: Option<String>,
+
+    /// Only export buckets of this type
+    #[clap(long = "type")]
+    bucket_type: Option<String>,
+
+    /// Only export events after this time (rfc3339)
+    #[clap(long)]
+    start: Option<String>,
+
+    /// Only export events before this time (rfc3339)
+    #[clap(long)]
+    end: Option<String>,
+
+    /// File to write the export to
+    #[clap(long, short)]
+    output: PathBuf,
+}
+
+fn run_export(opts: ExportOpts, db_path: String) -> Result<(), String> {
+    let format: export::ExportFormat = opts.format.parse()?;
+    let filter = export::ExportFilter::parse(
+        opts.buckets.as_deref(),
+        opts.hostname.as_deref(),
+        opts.bucket_type.as_deref(),
+        opts.start.as_deref(),
+        opts.end.as_deref(),
+    )?;
+    if !std::path::Path::new(&db_path).exists() {
+        return Err(format!("No database found at {db_path}"));
+    }
+    let mut writer = match File::create(&opts.output) {
+        Ok(file) => BufWriter::new(file),
+        Err(e) => return Err(format!("Failed to create {}: {e}", opts.output.display())),
+    };
+
+    let datastore = aw_datastore::Datastore::new(db_path, false);
+    let buckets = filter
+        .select_buckets(&datastore)
+        .map_err(|e| format!("Failed to get buckets: {e:?}"))?;
+    info!("Exporting {} buckets", buckets.len());
+    let res = match format {
+        export::ExportFormat::Json => {
+            let export = export::export_json(&datastore, buckets, &filter)
+                .map_err(|e| format!("Failed to export: {e:?}"))?;
+            serde_json::to_writer(&mut writer, &export).map_err(|e| e.to_string())
+        }
+        _ => {
+            let lines = export::ExportStream::new(datastore.clone(), format, buckets, &filter)
+                .map_err(|e| format!("Failed to export: {e:?}"))?;
+            lines.into_iter().try_for_each(|line| match line {
+                Ok(line) => writer.write_all(line.as_bytes()).map_err(|e| e.to_string()),
+                Err(e) => Err(format!("Failed to export: {e:?}")),
+            })
+        }
+    };
+    datastore.close();
+    res?;
+    writer.flush().map_err(|e| e.to_string())?;
+    info!("Wrote export to {}", opts.output.display());
+    Ok(())
+}
+
+/// Set custom_static if overridden, transform into map
+fn override_custom_static(config: &mut config::AWConfig, custom_static: Option<&str>) {
+    if let Some(custom_static_str) = custom_static {
+        let custom_static_map: std::collections::HashMap<String, String> = custom_static_str
+            .split(',')
+            .map(|s| {
+                let mut split = s.split('=');
+                let key = split.next().unwrap().to_string();
+                let value = split.next().unwrap().to_string();
+                (key, value)
+            })
+            .collect();
+        config.custom_static.extend(custom_static_map);
+
+        // validate paths, log error if invalid
+        // remove invalid paths
+        for (name, path) in config.custom_static.clone().iter() {
+            if !std::path::Path::new(path).exists() {
+                error!("custom_static path for {} does not exist ({})", name, path);
+                config.custom_static.remove(name);
+            }
+        }
+    }
+}
+
+fn apply_log_level(config: &config::AWConfig) {
+    if let Err(err) = logging::set_log_level(config.log_level.as_deref()) {
+        error!("{}", err);
+    }
+}
+
+/// Reload the config file every time SIGHUP is received
+///
+/// Only the CORS origins, custom static directories and log level are reloaded, changing the
+/// address or port still requires a restart.
+#[cfg(unix)]
+async fn reload_on_sighup(
+    reloaders: Vec<endpoints::ConfigReloader>,
+    testing: bool,
+    custom_static: Option<String>,
+) {
+    use rocket::tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(err) => {
+            error!(
+                "Unable to listen for SIGHUP, config reloading is disabled: {}",
+                err
+            );
+            return;
+        }
+    };
+    while hangups.recv().await.is_some() {
+        info!("Received SIGHUP, reloading config");
+        let mut config = match config::read_config(testing) {
+            Ok(config) => config,
+            Err(err) => {
+                error!("Keeping the current config: {}", err);
+                continue;
+            }
+        };
+        override_custom_static(&mut config, custom_static.as_deref());
+        apply_log_level(&config);
+        for reloader in reloaders.iter() {
+            reloader.reload(&config);
+        }
+    }
+}
+
+/// Resolves on SIGTERM or SIGINT, for when Rocket is not around to listen for them
+#[cfg(all(unix, not(target_os = "android")))]
+async fn termination_signal() {
+    use rocket::tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = signal(SignalKind::terminate()).expect("Unable to listen for SIGTERM");
+    rocket::tokio::select! {
+        _ = terminate.recv() => info!("Received SIGTERM, shutting down"),
+        _ = rocket::tokio::signal::ctrl_c() => info!("Received SIGINT, shutting down"),
+    }
 }
 
 #[rocket::main]
@@ -80,6 +257,7 @@ async fn main() -> Result<(), rocket::Error> {
     }
 
     let mut config = config::create_config(testing);
+    apply_log_level(&config);
 
     // set host if overridden
     if let Some(host) = opts.host {
@@ -91,29 +269,20 @@ async fn main() -> Result<(), rocket::Error> {
         config.port = port.parse().unwrap();
     }
 
-    // set custom_static if overridden, transform into map
-    if let Some(custom_static_str) = opts.custom_static {
-        let custom_static_map: std::collections::HashMap<String, String> = custom_static_str
-            .split(',')
-            .map(|s| {
-                let mut split = s.split('=');
-                let key = split.next().unwrap().to_string();
-                let value = split.next().unwrap().to_string();
-                (key, value)
-            })
-            .collect();
-        config.custom_static.extend(custom_static_map);
-
-        // validate paths, log error if invalid
-        // remove invalid paths
-        for (name, path) in config.custom_static.clone().iter() {
-            if !std::path::Path::new(path).exists() {
-                error!("custom_static path for {} does not exist ({})", name, path);
-                config.custom_static.remove(name);
-            }
+    // enable the unix socket if requested
+    if let Some(unix_socket_path) = opts.unix_socket {
+        config.unix_socket = true;
+        if unix_socket_path.is_some() {
+            config.unix_socket_path = unix_socket_path;
         }
     }
 
+    if opts.no_tcp {
+        config.tcp = false;
+    }
+
+    override_custom_static(&mut config, opts.custom_static.as_deref());
+
     // Set db path if overridden
     let db_path: String = if let Some(dbpath) = opts.dbpath.clone() {
         dbpath
@@ -126,6 +295,23 @@ async fn main() -> Result<(), rocket::Error> {
     };
     info!("Using DB at path {:?}", db_path);
 
+    // With a custom dbpath the profiles are kept next to it
+    let profiles_dir: PathBuf = if let Some(dbpath) = opts.dbpath.as_ref() {
+        let dbpath = PathBuf::from(dbpath);
+        let stem = dbpath.file_stem().unwrap_or_default().to_string_lossy();
+        dbpath.with_file_name(format!("{stem}-profiles"))
+    } else {
+        dirs::profiles_dir(testing).expect("Failed to get profiles dir")
+    };
+
+    if let Some(Command::Export(export_opts)) = opts.command {
+        if let Err(err) = run_export(export_opts, db_path) {
+            error!("{}", err);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
     let asset_path = opts.webpath.map(|webpath| PathBuf::from(webpath));
     info!("Using aw-webui assets at path {:?}", asset_path);
 
@@ -141,20 +327,102 @@ async fn main() -> Result<(), rocket::Error> {
         device_id::get_device_id()
     };
 
-    let server_state = endpoints::ServerState {
-        // Even if legacy_import is set to true it is disabled on Android so
-        // it will not happen there
-        datastore: Mutex::new(aw_datastore::Datastore::new(db_path, legacy_import)),
-        asset_resolver: endpoints::AssetResolver::new(asset_path),
-        device_id,
+    // Even if legacy_import is set to true it is disabled on Android so
+    // it will not happen there
+    let datastore = aw_datastore::Datastore::new(db_path, legacy_import);
+    #[cfg(target_os = "linux")]
+    let watchdog_datastore = datastore.clone();
+    let shutdown_datastore = datastore.clone();
+    let profiles = profiles::Profiles::open(profiles_dir);
+    // Both the TCP and Unix socket servers clear the cache when modules or buckets change
+    let query_cache = QueryCache::new();
+    let server_state = |datastore| endpoints::ServerState {
+        datastore: Mutex::new(datastore),
+        profiles: profiles.clone(),
+        query_cache: query_cache.clone(),
+        asset_resolver: endpoints::AssetResolver::new(asset_path.clone()),
+        device_id: device_id.clone(),
+    };
+
+    #[cfg(all(unix, not(target_os = "android")))]
+    let unix_socket = if config.unix_socket {
+        let path = match &config.unix_socket_path {
+            Some(path) => PathBuf::from(path),
+            None => dirs::socket_path(testing).expect("Failed to get socket path"),
+        };
+        let rocket = endpoints::build_rocket(server_state(datastore.clone()), config.clone());
+        match unix_socket::UnixSocketServer::bind(rocket, &path).await {
+            Ok(server) => Some(server),
+            Err(err) => {
+                error!("{}", err);
+                std::process::exit(1);
+            }
+        }
+    } else {
+        None
+    };
+    #[cfg(not(all(unix, not(target_os = "android"))))]
+    if config.unix_socket {
+        error!("Serving the API on a Unix socket is not supported on this platform");
+        std::process::exit(1);
+    }
+
+    if !config.tcp && !config.unix_socket {
+        error!("TCP is disabled and there is no Unix socket, so the API would not be served");
+        std::process::exit(1);
+    }
+    let rocket = match config.tcp {
+        true => Some(
+            endpoints::build_rocket(server_state(datastore), config)
+                .ignite()
+                .await?,
+        ),
+        false => None,
     };
 
-    let _rocket = endpoints::build_rocket(server_state, config)
-        .ignite()
-        .await?;
     #[cfg(target_os = "linux")]
-    let _ = sd_notify::notify(true, &[NotifyState::Ready]);
-    _rocket.launch().await?;
+    {
+        // The environment is kept so that the watchdog can keep notifying
+        let _ = sd_notify::notify(false, &[NotifyState::Ready]);
+        let mut watchdog_usec = 0;
+        if sd_notify::watchdog_enabled(false, &mut watchdog_usec) {
+            // Ping twice per watchdog timeout, as recommended by sd_watchdog_enabled(3)
+            let interval = std::time::Duration::from_micros(watchdog_usec / 2);
+            info!("Pinging the systemd watchdog every {:?}", interval);
+            rocket::tokio::spawn(health::watchdog(watchdog_datastore, interval));
+        }
+    }
+    #[cfg(unix)]
+    {
+        let mut reloaders: Vec<endpoints::ConfigReloader> =
+            rocket.iter().map(endpoints::ConfigReloader::new).collect();
+        #[cfg(not(target_os = "android"))]
+        reloaders.extend(unix_socket.as_ref().map(|server| server.reloader()));
+        rocket::tokio::spawn(reload_on_sighup(reloaders, testing, opts.custom_static));
+    }
+
+    // The Unix socket stops together with Rocket, or on its own signals when there is no Rocket
+    #[cfg(all(unix, not(target_os = "android")))]
+    let unix_socket = unix_socket.map(|server| match &rocket {
+        Some(rocket) => rocket::tokio::spawn(server.serve(rocket.shutdown())),
+        None => rocket::tokio::spawn(server.serve(termination_signal())),
+    });
+
+    // Rocket shuts down gracefully on SIGTERM and SIGINT, letting in-flight requests finish
+    let result = match rocket {
+        Some(rocket) => rocket.launch().await.map(|_| ()),
+        None => Ok(()),
+    };
+    #[cfg(all(unix, not(target_os = "android")))]
+    if let Some(unix_socket) = unix_socket {
+        let _ = unix_socket.await;
+    }
+
+    // Make sure everything the requests wrote is committed before exiting
+    info!("Server stopped, closing the datastore");
+    shutdown_datastore.close();
+    profiles.close();
+    result?;
 
     Ok(())
 }
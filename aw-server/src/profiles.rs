@@ -0,0 +1,212 @@
+//! Profiles are datastores isolated from each other, like separate servers for work and personal
+//! use. The default profile is the datastore the server was started with, every other profile has
+//! a database file of its own in the profiles dir.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+
+use aw_datastore::Datastore;
+
+pub const DEFAULT_PROFILE: &str = "default";
+
+#[derive(Debug, PartialEq)]
+pub enum ProfileError {
+    InvalidName(String),
+    AlreadyExists(String),
+    NoSuchProfile(String),
+    IOError(String),
+}
+
+impl std::fmt::Display for ProfileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ProfileError::InvalidName(name) => write!(
+                f,
+                "Invalid profile name {name}, it can only contain letters, digits, - and _"
+            ),
+            ProfileError::AlreadyExists(name) => write!(f, "Profile {name} already exists"),
+            ProfileError::NoSuchProfile(name) => write!(f, "There's no profile named {name}"),
+            ProfileError::IOError(msg) => write!(f, "{msg}"),
+        }
+    }
+}
+
+/// The profiles other than the default one
+///
+/// Clones share the same profiles.
+#[derive(Clone)]
+pub struct Profiles {
+    // None keeps the datastores of the profiles in memory
+    dir: Option<PathBuf>,
+    datastores: Arc<RwLock<HashMap<String, Datastore>>>,
+}
+
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 64
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+impl Profiles {
+    /// Open the profiles which have a database in dir
+    pub fn open(dir: PathBuf) -> Profiles {
+        let mut datastores = HashMap::new();
+        match fs::read_dir(&dir) {
+            Ok(entries) => {
+                for path in entries.flatten().map(|entry| entry.path()) {
+                    if path.extension().and_then(|ext| ext.to_str()) != Some("db") {
+                        continue;
+                    }
+                    let name = match path.file_stem().and_then(|stem| stem.to_str()) {
+                        Some(name) if is_valid_name(name) && name != DEFAULT_PROFILE => name,
+                        _ => continue,
+                    };
+                    info!("Opening profile {} at {:?}", name, path);
+                    let datastore = Datastore::new(path.to_str().unwrap().to_string(), false);
+                    datastores.insert(name.to_string(), datastore);
+                }
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => (),
+            Err(err) => error!("Failed to read profiles dir {:?}: {}", dir, err),
+        }
+        Profiles {
+            dir: Some(dir),
+            datastores: Arc::new(RwLock::new(datastores)),
+        }
+    }
+
+    /// Profiles which only live as long as the server, for testing
+    pub fn in_memory() -> Profiles {
+        Profiles {
+            dir: None,
+            datastores: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    fn db_path(&self, name: &str) -> Option<PathBuf> {
+        self.dir.as_ref().map(|dir| dir.join(format!("{name}.db")))
+    }
+
+    /// The names of all profiles, sorted, not including the default profile
+    pub fn names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.datastores.read().unwrap().keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    pub fn get(&self, name: &str) -> Option<Datastore> {
+        self.datastores.read().unwrap().get(name).cloned()
+    }
+
+    pub fn create(&self, name: &str) -> Result<(), ProfileError> {
+        if !is_valid_name(name) {
+            return Err(ProfileError::InvalidName(name.to_string()));
+        }
+        if name == DEFAULT_PROFILE {
+            return Err(ProfileError::AlreadyExists(name.to_string()));
+        }
+        let mut datastores = self.datastores.write().unwrap();
+        if datastores.contains_key(name) {
+            return Err(ProfileError::AlreadyExists(name.to_string()));
+        }
+        let datastore = match self.db_path(name) {
+            Some(path) => {
+                let dir = path.parent().unwrap();
+                fs::create_dir_all(dir).map_err(|err| {
+                    ProfileError::IOError(format!("Failed to create profiles dir {dir:?}: {err}"))
+                })?;
+                Datastore::new(path.to_str().unwrap().to_string(), false)
+            }
+            None => Datastore::new_in_memory(false),
+        };
+        info!("Created profile {}", name);
+        datastores.insert(name.to_string(), datastore);
+        Ok(())
+    }
+
+    /// Delete a profile together with its database
+    pub fn delete(&self, name: &str) -> Result<(), ProfileError> {
+        let datastore = match self.datastores.write().unwrap().remove(name) {
+            Some(datastore) => datastore,
+            None => return Err(ProfileError::NoSuchProfile(name.to_string())),
+        };
+        datastore.close();
+        if let Some(path) = self.db_path(name) {
+            // The journal files are only there if the datastore was not closed cleanly
+            for suffix in ["", "-wal", "-shm", "-journal"] {
+                let file = PathBuf::from(format!("{}{suffix}", path.display()));
+                match fs::remove_file(&file) {
+                    Ok(_) => (),
+                    Err(err) if err.kind() == io::ErrorKind::NotFound => (),
+                    Err(err) => {
+                        return Err(ProfileError::IOError(format!(
+                            "Failed to remove {file:?}: {err}"
+                        )))
+                    }
+                }
+            }
+        }
+        info!("Deleted profile {}", name);
+        Ok(())
+    }
+
+    /// Close the datastores of all profiles, for shutting down the server
+    pub fn close(&self) {
+        for (_, datastore) in self.datastores.write().unwrap().drain() {
+            datastore.close();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::{ProfileError, Profiles};
+
+    #[test]
+    fn test_profiles_on_disk() {
+        let dir = std::env::temp_dir().join(format!("aw-profiles-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        let profiles = Profiles::open(dir.clone());
+        assert_eq!(profiles.names(), Vec::<String>::new());
+        profiles.create("work").unwrap();
+        profiles.create("home").unwrap();
+        assert_eq!(
+            profiles.create("work"),
+            Err(ProfileError::AlreadyExists("work".to_string()))
+        );
+        assert_eq!(
+            profiles.create("default"),
+            Err(ProfileError::AlreadyExists("default".to_string()))
+        );
+        assert_eq!(
+            profiles.create("../work"),
+            Err(ProfileError::InvalidName("../work".to_string()))
+        );
+        profiles.close();
+
+        // The profiles are there again after a restart
+        let profiles = Profiles::open(dir.clone());
+        assert_eq!(profiles.names(), vec!["home", "work"]);
+        profiles.delete("work").unwrap();
+        assert!(!dir.join("work.db").exists());
+        assert_eq!(
+            profiles.delete("work"),
+            Err(ProfileError::NoSuchProfile("work".to_string()))
+        );
+        profiles.close();
+
+        let profiles = Profiles::open(dir.clone());
+        assert_eq!(profiles.names(), vec!["home"]);
+        profiles.close();
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
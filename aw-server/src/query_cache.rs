@@ -0,0 +1,204 @@
+//! Cache of query results over closed time periods
+//!
+//! Results are keyed by the normalized query and the time period it was run over. Each result
+//! remembers the datastore version it was computed at, and is only thrown away once a later change
+//! to a bucket overlaps its time period. New heartbeats therefore don't invalidate the results for
+//! earlier days.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use chrono::Utc;
+use schemars::JsonSchema;
+use serde::Serialize;
+use serde_json::Value;
+
+use aw_datastore::Datastore;
+use aw_models::TimeInterval;
+
+/// Max number of cached results, the least recently used result is evicted when full
+const MAX_ENTRIES: usize = 1000;
+
+#[derive(Hash, PartialEq, Eq, Clone)]
+struct CacheKey {
+    query: String,
+    interval: TimeInterval,
+}
+
+struct CacheEntry {
+    result: Value,
+    /// Datastore version the result is known to be valid for
+    version: u64,
+    last_used: u64,
+}
+
+#[derive(Serialize, JsonSchema, Debug, Clone, Default)]
+pub struct QueryCacheStats {
+    pub entries: usize,
+    pub hits: u64,
+    pub misses: u64,
+    /// Results which were thrown away because of a change to a bucket
+    pub invalidations: u64,
+}
+
+#[derive(Default)]
+struct QueryCacheInner {
+    entries: HashMap<CacheKey, CacheEntry>,
+    stats: QueryCacheStats,
+    uses: u64,
+    /// Number of times the query modules of each profile have changed
+    module_versions: HashMap<String, u64>,
+}
+
+/// Clones share the same cache, so that all servers of the process see the same results
+#[derive(Clone, Default)]
+pub struct QueryCache {
+    inner: Arc<Mutex<QueryCacheInner>>,
+}
+
+/// Normalize the lines of a query so that formatting differences and comments don't cause misses
+pub fn normalize_query(lines: &[String]) -> String {
+    lines
+        .iter()
+        .flat_map(|line| line.lines())
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .collect::<Vec<&str>>()
+        .join("\n")
+}
+
+impl QueryCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only closed time periods are cached, as anything after now might still change
+    pub fn is_cacheable(interval: &TimeInterval) -> bool {
+        *interval.end() <= Utc::now()
+    }
+
+    /// Get the cached result of a query, unless a bucket has changed within the time period since
+    ///
+    /// The cache is not locked while the datastore is asked for its changes, so lookups don't wait
+    /// for each other or for a busy datastore.
+    pub fn get(
+        &self,
+        datastore: &Datastore,
+        query: &str,
+        interval: &TimeInterval,
+    ) -> Option<Value> {
+        let key = CacheKey {
+            query: query.to_string(),
+            interval: interval.clone(),
+        };
+        let entry_version = {
+            let mut inner = self.inner.lock().unwrap();
+            match inner.entries.get(&key) {
+                Some(entry) => entry.version,
+                None => {
+                    inner.stats.misses += 1;
+                    return None;
+                }
+            }
+        };
+        let valid_version = match datastore.get_changes_since(entry_version) {
+            Ok((version, Some(changes))) => {
+                let affected = changes.iter().any(|change| match &change.range {
+                    Some(range) => range.intersects(interval),
+                    None => true,
+                });
+                match affected {
+                    true => None,
+                    false => Some(version),
+                }
+            }
+            // Changes are no longer tracked that far back
+            Ok((_, None)) => None,
+            Err(err) => {
+                warn!("Failed to get datastore changes: {:?}", err);
+                None
+            }
+        };
+        let mut inner = self.inner.lock().unwrap();
+        // The entry may have been replaced or evicted in the meantime, which makes it a miss
+        if inner.entries.get(&key).map(|entry| entry.version) != Some(entry_version) {
+            inner.stats.misses += 1;
+            return None;
+        }
+        match valid_version {
+            Some(version) => {
+                inner.uses += 1;
+                let uses = inner.uses;
+                let entry = inner.entries.get_mut(&key).unwrap();
+                entry.version = version;
+                entry.last_used = uses;
+                let result = entry.result.clone();
+                inner.stats.hits += 1;
+                Some(result)
+            }
+            None => {
+                inner.entries.remove(&key);
+                inner.stats.invalidations += 1;
+                inner.stats.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// Cache the result of a query, version being the datastore version before it was run
+    pub fn insert(&self, query: &str, interval: &TimeInterval, version: u64, result: Value) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.entries.len() >= MAX_ENTRIES {
+            let oldest = inner
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                inner.entries.remove(&oldest);
+            }
+        }
+        inner.uses += 1;
+        let entry = CacheEntry {
+            result,
+            version,
+            last_used: inner.uses,
+        };
+        let key = CacheKey {
+            query: query.to_string(),
+            interval: interval.clone(),
+        };
+        inner.entries.insert(key, entry);
+    }
+
+    /// Version of the query modules of a profile, which is part of the key of its results
+    ///
+    /// A query which was already running with the old modules when they changed caches its result
+    /// under the old version, so it is never used and is eventually evicted.
+    pub fn module_version(&self, profile: &str) -> u64 {
+        let inner = self.inner.lock().unwrap();
+        inner.module_versions.get(profile).copied().unwrap_or(0)
+    }
+
+    /// Stop using the results which were computed with the old query modules of a profile
+    pub fn modules_changed(&self, profile: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        *inner
+            .module_versions
+            .entry(profile.to_string())
+            .or_insert(0) += 1;
+    }
+
+    pub fn stats(&self) -> QueryCacheStats {
+        let inner = self.inner.lock().unwrap();
+        QueryCacheStats {
+            entries: inner.entries.len(),
+            ..inner.stats.clone()
+        }
+    }
+
+    pub fn clear(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.entries.clear();
+    }
+}
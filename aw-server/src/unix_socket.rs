@@ -0,0 +1,288 @@
+//! Serves the API on a Unix domain socket
+//!
+//! Unlike the TCP port, which every user on the machine can connect to, the socket is only
+//! accessible by the user running the server.
+//!
+//! Rocket 0.5 can only listen on TCP, so the requests received on the socket are dispatched to a
+//! Rocket instance of its own through a local client. The local client needs the whole body of a
+//! request, which is read up to the data limit of the server for its content type, while
+//! responses are streamed as they are produced.
+
+use std::convert::Infallible;
+use std::fs;
+use std::future::Future;
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use rocket::data::{ByteUnit, Limits};
+use rocket::http::hyper::{self, body::Bytes, body::HttpBody, server::conn::Http};
+use rocket::http::hyper::{service::service_fn, Body};
+use rocket::http::{ContentType, Header, Method, Status};
+use rocket::local::asynchronous::Client;
+use rocket::tokio::io::AsyncReadExt;
+use rocket::tokio::net::{UnixListener, UnixStream};
+use rocket::tokio::sync::{oneshot, watch};
+use rocket::tokio::task::{AbortHandle, JoinSet};
+use rocket::{Build, Rocket};
+
+use crate::endpoints::ConfigReloader;
+
+/// Size of the chunks responses are streamed in
+const RESPONSE_CHUNK_SIZE: usize = 64 * 1024;
+
+pub struct UnixSocketServer {
+    client: Arc<Client>,
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+impl UnixSocketServer {
+    pub async fn bind(rocket: Rocket<Build>, path: &Path) -> Result<UnixSocketServer, String> {
+        // A socket left behind by a server which did not shut down cleanly is replaced, unless
+        // another server is still listening on it
+        if path.exists() {
+            if UnixStream::connect(path).await.is_ok() {
+                return Err(format!("Another server is already listening on {path:?}"));
+            }
+            fs::remove_file(path)
+                .map_err(|err| format!("Failed to remove stale socket {path:?}: {err}"))?;
+        }
+        let listener = bind_private(path)?;
+        let client = Client::untracked(rocket)
+            .await
+            .map_err(|err| format!("Failed to start the server for {path:?}: {err}"))?;
+
+        Ok(UnixSocketServer {
+            client: Arc::new(client),
+            listener,
+            path: path.to_path_buf(),
+        })
+    }
+
+    pub fn reloader(&self) -> ConfigReloader {
+        ConfigReloader::new(self.client.rocket())
+    }
+
+    /// Serve requests until shutdown resolves, then wait for the requests in flight to finish
+    pub async fn serve<F: Future<Output = ()>>(self, shutdown: F) {
+        info!("Serving the API on Unix socket {:?}", self.path);
+        let (shutdown_sender, shutdown_receiver) = watch::channel(false);
+        let mut connections = JoinSet::new();
+        rocket::tokio::pin!(shutdown);
+        loop {
+            rocket::tokio::select! {
+                accepted = self.listener.accept() => match accepted {
+                    Ok((stream, _)) => {
+                        connections.spawn(serve_connection(
+                            self.client.clone(),
+                            stream,
+                            shutdown_receiver.clone(),
+                        ));
+                    }
+                    Err(err) => warn!("Failed to accept connection on Unix socket: {}", err),
+                },
+                _ = &mut shutdown => break,
+            }
+        }
+
+        let _ = shutdown_sender.send(true);
+        while connections.join_next().await.is_some() {}
+        if let Err(err) = fs::remove_file(&self.path) {
+            warn!("Failed to remove Unix socket {:?}: {}", self.path, err);
+        }
+    }
+}
+
+async fn serve_connection(
+    client: Arc<Client>,
+    stream: UnixStream,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    let service = service_fn(move |request| dispatch(client.clone(), request));
+    let connection = Http::new()
+        .http1_only(true)
+        .serve_connection(stream, service);
+    rocket::tokio::pin!(connection);
+    let result = rocket::tokio::select! {
+        result = connection.as_mut() => result,
+        _ = shutdown.changed() => {
+            connection.as_mut().graceful_shutdown();
+            connection.await
+        }
+    };
+    if let Err(err) = result {
+        debug!("Unix socket connection failed: {}", err);
+    }
+}
+
+/// Bind a socket at path which only the current user can connect to
+///
+/// The socket is created in a directory only the current user can access and moved to path once
+/// its permissions are restricted, so that nobody else can connect in between.
+fn bind_private(path: &Path) -> Result<UnixListener, String> {
+    let name = path
+        .file_name()
+        .ok_or_else(|| format!("Invalid socket path {path:?}"))?;
+    let dir = path.with_file_name(format!(
+        ".{}.{}",
+        name.to_string_lossy(),
+        std::process::id()
+    ));
+    fs::DirBuilder::new()
+        .mode(0o700)
+        .create(&dir)
+        .map_err(|err| format!("Failed to create {dir:?}: {err}"))?;
+    let tmp_path = dir.join(name);
+    let res = UnixListener::bind(&tmp_path)
+        .map_err(|err| format!("Failed to bind {path:?}: {err}"))
+        .and_then(|listener| {
+            fs::set_permissions(&tmp_path, fs::Permissions::from_mode(0o600))
+                .map_err(|err| format!("Failed to restrict permissions of {path:?}: {err}"))?;
+            fs::rename(&tmp_path, path)
+                .map_err(|err| format!("Failed to move socket to {path:?}: {err}"))?;
+            Ok(listener)
+        });
+    if let Err(err) = fs::remove_dir_all(&dir) {
+        warn!("Failed to remove {:?}: {}", dir, err);
+    }
+    res
+}
+
+fn error_response(status: Status) -> hyper::Response<Body> {
+    hyper::Response::builder()
+        .status(status.code)
+        .body(Body::empty())
+        .unwrap()
+}
+
+/// The data limit of the server for a request body of the given content type
+///
+/// The names of the limits are the ones used by the data guards of the endpoints, bodies of any
+/// other type are allowed to be as large as the largest of them.
+fn body_limit(limits: &Limits, content_type: Option<&ContentType>) -> ByteUnit {
+    let names = ["json", "data-form", "form", "ndjson", "bytes", "string"];
+    let name = match content_type {
+        Some(ct) if ct.is_json() => Some("json"),
+        Some(ct) if ct.is_form_data() => Some("data-form"),
+        Some(ct) if ct.is_form() => Some("form"),
+        Some(ct) if ct.top() == "application" && ct.sub() == "x-ndjson" => Some("ndjson"),
+        _ => None,
+    };
+    let limit = |name: &str| limits.get(name).unwrap_or_default();
+    match name {
+        Some(name) => limit(name),
+        None => names
+            .iter()
+            .map(|name| limit(name))
+            .max()
+            .unwrap_or_default(),
+    }
+}
+
+/// Aborts a task when dropped, unless it is disarmed first
+struct AbortOnDrop(Option<AbortHandle>);
+
+impl AbortOnDrop {
+    fn disarm(mut self) {
+        self.0 = None;
+    }
+}
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        if let Some(handle) = self.0.take() {
+            handle.abort();
+        }
+    }
+}
+
+/// Read a request body, None if it is larger than limit
+async fn read_body(mut body: Body, limit: ByteUnit) -> Result<Option<Vec<u8>>, hyper::Error> {
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        if (bytes.len() + chunk.len()) as u64 > limit.as_u64() {
+            return Ok(None);
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    Ok(Some(bytes))
+}
+
+async fn dispatch(
+    client: Arc<Client>,
+    request: hyper::Request<Body>,
+) -> Result<hyper::Response<Body>, Infallible> {
+    let (parts, body) = request.into_parts();
+    let method = match Method::from_str(parts.method.as_str()) {
+        Ok(method) => method,
+        Err(_) => return Ok(error_response(Status::MethodNotAllowed)),
+    };
+    let uri = parts
+        .uri
+        .path_and_query()
+        .map_or("/", |uri| uri.as_str())
+        .to_string();
+    let content_type = parts
+        .headers
+        .get(hyper::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(ContentType::parse_flexible);
+    let limit = body_limit(&client.rocket().config().limits, content_type.as_ref());
+    let body = match read_body(body, limit).await {
+        Ok(Some(body)) => body,
+        Ok(None) => return Ok(error_response(Status::PayloadTooLarge)),
+        Err(_) => return Ok(error_response(Status::BadRequest)),
+    };
+
+    // The response borrows the client, so it is streamed from a task which holds on to it. Hyper
+    // drops this future when the client disconnects, which aborts the task until the response
+    // has started, so the handler is dropped and its queries are cancelled as they are over TCP.
+    let (head_sender, head_receiver) = oneshot::channel();
+    let task = rocket::tokio::spawn(async move {
+        let mut local_request = client.req(method, uri).body(body);
+        for (name, value) in parts.headers.iter() {
+            if let Ok(value) = value.to_str() {
+                local_request.add_header(Header::new(name.as_str().to_string(), value.to_string()));
+            }
+        }
+        let mut local_response = local_request.dispatch().await;
+
+        let mut response = hyper::Response::builder().status(local_response.status().code);
+        for header in local_response.headers().iter() {
+            response = response.header(header.name().as_str(), header.value());
+        }
+        let (mut body_sender, body) = Body::channel();
+        if head_sender.send(response.body(body).unwrap()).is_err() {
+            return;
+        }
+        let mut buf = vec![0; RESPONSE_CHUNK_SIZE];
+        loop {
+            match local_response.read(&mut buf).await {
+                Ok(0) => break,
+                Ok(n) => {
+                    let chunk = Bytes::copy_from_slice(&buf[..n]);
+                    // The connection was closed
+                    if body_sender.send_data(chunk).await.is_err() {
+                        break;
+                    }
+                }
+                Err(err) => {
+                    warn!("Failed to read response for Unix socket: {}", err);
+                    body_sender.abort();
+                    break;
+                }
+            }
+        }
+    });
+    let abort = AbortOnDrop(Some(task.abort_handle()));
+    let response = head_receiver.await;
+    // Once the response has started, the task stops by itself when the body can't be sent
+    abort.disarm();
+    match response {
+        Ok(response) => Ok(response),
+        Err(_) => Ok(error_response(Status::InternalServerError)),
+    }
+}
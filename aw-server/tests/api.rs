@@ -10,18 +10,26 @@ mod api_tests {
     use std::collections::HashMap;
     use std::sync::Mutex;
 
-    use rocket::http::{ContentType, Header, Status};
+    use rocket::data::ToByteUnit;
+    use rocket::http::{ContentType, Header, Method, Status};
     use serde_json::{json, Value};
 
     use aw_server::config;
     use aw_server::endpoints;
+    use aw_server::health;
+    use aw_server::profiles::Profiles;
+    use aw_server::query_cache::QueryCache;
 
-    use aw_models::{Bucket, BucketsExport};
-    use rocket::local::blocking::Client;
+    use aw_models::{
+        Bucket, BucketsExport, ExportRecord, ImportAction, ImportConflict, ImportSummary,
+    };
+    use rocket::local::blocking::{Client, LocalResponse};
 
     fn setup_testserver() -> rocket::Rocket<rocket::Build> {
         let state = endpoints::ServerState {
             datastore: Mutex::new(aw_datastore::Datastore::new_in_memory(false)),
+            profiles: Profiles::in_memory(),
+            query_cache: QueryCache::new(),
             asset_resolver: endpoints::AssetResolver::new(None),
             device_id: "test_id".to_string(),
         };
@@ -268,6 +276,76 @@ mod api_tests {
         assert_eq!(res.status(), rocket::http::Status::Ok);
     }
 
+    #[test]
+    fn test_events_pagination() {
+        let server = setup_testserver();
+        let client = Client::untracked(server).expect("valid instance");
+
+        let res = client
+            .post("/api/0/buckets/id")
+            .header(ContentType::JSON)
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body(r#"{"id": "id", "type": "type", "client": "client", "hostname": "hostname"}"#)
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+        let res = client
+            .post("/api/0/buckets/id/events")
+            .header(ContentType::JSON)
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body(
+                r#"[
+                {"timestamp": "2018-01-01T01:01:01Z", "duration": 1.0, "data": {}},
+                {"timestamp": "2018-01-01T01:01:02Z", "duration": 1.0, "data": {}},
+                {"timestamp": "2018-01-01T01:01:03Z", "duration": 1.0, "data": {}}
+            ]"#,
+            )
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+
+        // Walk through all events, oldest first, two at a time
+        let mut ids = Vec::new();
+        let mut url = "/api/0/buckets/id/events?limit=2&order=asc".to_string();
+        loop {
+            let res = client
+                .get(url.clone())
+                .header(Header::new("Host", "127.0.0.1:5600"))
+                .dispatch();
+            assert_eq!(res.status(), rocket::http::Status::Ok);
+            let next_cursor = res.headers().get_one("X-Next-Cursor").map(String::from);
+            let events: Vec<Value> = serde_json::from_str(&res.into_string().unwrap()).unwrap();
+            ids.extend(events.iter().map(|e| e["id"].as_i64().unwrap()));
+            match next_cursor {
+                Some(cursor) => {
+                    url = format!("/api/0/buckets/id/events?limit=2&order=asc&cursor={cursor}")
+                }
+                None => break,
+            }
+        }
+        assert_eq!(ids, vec![1, 2, 3]);
+
+        // Newest first is still the default
+        let res = client
+            .get("/api/0/buckets/id/events?limit=1")
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+        assert!(res.headers().get_one("X-Next-Cursor").is_some());
+        let events: Vec<Value> = serde_json::from_str(&res.into_string().unwrap()).unwrap();
+        assert_eq!(events[0]["id"], 3);
+
+        // Invalid cursor and order
+        let res = client
+            .get("/api/0/buckets/id/events?cursor=invalid")
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::BadRequest);
+        let res = client
+            .get("/api/0/buckets/id/events?order=random")
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::BadRequest);
+    }
+
     #[test]
     fn test_import_export() {
         let server = setup_testserver();
@@ -316,11 +394,46 @@ mod api_tests {
             }}}"#,
             )
             .dispatch();
-        assert_eq!(res.status(), rocket::http::Status::InternalServerError);
+        assert_eq!(res.status(), rocket::http::Status::Conflict);
+        let error: Value = serde_json::from_str(&res.into_string().unwrap()).unwrap();
         assert_eq!(
-            res.into_string().unwrap(),
-            r#"{"message":"Failed to import bucket: BucketAlreadyExists(\"id1\")"}"#
+            error["message"],
+            "Bucket 'id1' already exists, nothing was imported"
+        );
+        let summary: ImportSummary = serde_json::from_value(error["summary"].clone()).unwrap();
+        assert_eq!(summary.buckets.len(), 1);
+        assert_eq!(summary.buckets[0].bucket_id, "id1");
+        assert_eq!(summary.buckets[0].action, ImportAction::Failed);
+
+        // Import already existing bucket with a conflict strategy, as a dry run
+        let res = client
+            .post("/api/0/import?on_conflict=rename&dry_run=true")
+            .header(ContentType::JSON)
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body(r#"{"buckets": {"id1": {"id": "id1", "type": "type", "client": "client", "hostname": "hostname"}}}"#)
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+        let summary: ImportSummary = serde_json::from_str(&res.into_string().unwrap()).unwrap();
+        assert!(summary.dry_run);
+        assert_eq!(summary.buckets[0].action, ImportAction::Renamed);
+        assert_eq!(
+            summary.buckets[0].imported_as,
+            Some("id1-imported".to_string())
         );
+        let res = client
+            .get("/api/0/buckets/id1-imported")
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::NotFound);
+
+        // Invalid conflict strategy
+        let res = client
+            .post("/api/0/import?on_conflict=overwrite")
+            .header(ContentType::JSON)
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body(r#"{"buckets": {}}"#)
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::BadRequest);
 
         // Export single created bucket
         let res = client
@@ -346,12 +459,21 @@ mod api_tests {
         let data_start = b"\r\n";
         let content = serde_json::to_vec(&export).unwrap();
         let boundary_end = b"\r\n--a--";
+        // Every field of the form is imported, not only the one named buckets.json
+        let other_disposition = b"Content-Disposition: form-data; name=\"other.json\"\r\n";
+        let other_content = br#"{"buckets": {"id2": {"id": "id2", "type": "type", "client": "client", "hostname": "hostname"}}}"#;
+        let boundary = b"\r\n--a\r\n";
         let sum = [
             &boundary_start[..],
             &disposition[..],
             &content_type[..],
             &data_start[..],
             &content[..],
+            &boundary[..],
+            &other_disposition[..],
+            &content_type[..],
+            &data_start[..],
+            &other_content[..],
             &boundary_end[..],
         ]
         .concat();
@@ -385,13 +507,605 @@ mod api_tests {
         assert_eq!(res.status(), rocket::http::Status::Ok);
         let export: BucketsExport = serde_json::from_str(&res.into_string().unwrap()).unwrap();
         let mut buckets = export.buckets;
-        assert_eq!(buckets.len(), 1);
+        assert_eq!(buckets.len(), 2);
         let b = buckets.remove("id1").unwrap();
         assert_eq!(b.events.unwrap().take_inner().len(), 1);
+        buckets.remove("id2").unwrap();
 
         assert_eq!(buckets.len(), 0);
     }
 
+    #[test]
+    fn test_streamed_import_export() {
+        let server = setup_testserver();
+        let client = Client::untracked(server).expect("valid instance");
+
+        // Import bucket with NDJSON
+        let res = client
+            .post("/api/0/import")
+            .header(Header::new("Content-Type", "application/x-ndjson"))
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body(
+                r#"{"bucket": {"id": "id1", "type": "type", "client": "client", "hostname": "hostname"}}
+{"event": {"timestamp": "2000-01-01T00:00:00Z", "duration": 1.0, "data": {"app": "a"}}}
+{"event": {"timestamp": "2000-01-01T00:00:01Z", "duration": 1.5, "data": {"app": "b, c", "title": "t"}}}
+"#,
+            )
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+
+        // Events have to follow a bucket
+        let res = client
+            .post("/api/0/import")
+            .header(Header::new("Content-Type", "application/x-ndjson"))
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body(
+                r#"{"event": {"timestamp": "2000-01-01T00:00:00Z", "duration": 1.0, "data": {}}}"#,
+            )
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::BadRequest);
+
+        // Export as CSV, data keys become columns
+        let res = client
+            .get("/api/0/buckets/id1/export?format=csv")
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+        assert_eq!(res.content_type(), Some(ContentType::CSV));
+        let csv = res.into_string().unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(
+            lines[0],
+            "bucket_id,id,timestamp,duration,data.app,data.title"
+        );
+        assert!(lines[1].ends_with(",1,a,"));
+        assert!(lines[2].ends_with(r#",1.5,"b, c",t"#));
+
+        // Export as NDJSON
+        let res = client
+            .get("/api/0/export?format=ndjson")
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+        let ndjson = res.into_string().unwrap();
+        let records: Vec<ExportRecord> = ndjson
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(records.len(), 3);
+        assert!(matches!(&records[0], ExportRecord::Bucket(b) if b.id == "id1"));
+        assert!(matches!(&records[2], ExportRecord::Event(e) if e.data["title"] == "t"));
+
+        // Invalid format
+        let res = client
+            .get("/api/0/export?format=xml")
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::BadRequest);
+
+        // Delete bucket and import the NDJSON export again
+        let res = client
+            .delete("/api/0/buckets/id1")
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+        let res = client
+            .post("/api/0/import")
+            .header(Header::new("Content-Type", "application/x-ndjson"))
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body(ndjson)
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+
+        let res = client
+            .get("/api/0/buckets/id1/events/count")
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .dispatch();
+        assert_eq!(res.into_string().unwrap(), "2");
+    }
+
+    #[test]
+    fn test_ndjson_import() {
+        let server = setup_testserver();
+        let client = Client::untracked(server).expect("valid instance");
+
+        let ndjson = |bucket_id: &str, events: usize| -> String {
+            let mut lines = vec![format!(
+                r#"{{"bucket": {{"id": "{bucket_id}", "type": "type", "client": "client", "hostname": "hostname"}}}}"#
+            )];
+            for i in 0..events {
+                lines.push(format!(
+                    r#"{{"event": {{"timestamp": "2000-01-01T00:00:00Z", "duration": {i}, "data": {{}}}}}}"#
+                ));
+            }
+            lines.join("\n")
+        };
+        fn import(client: &Client, body: String) -> LocalResponse<'_> {
+            client
+                .post("/api/0/import")
+                .header(Header::new("Content-Type", "application/x-ndjson"))
+                .header(Header::new("Host", "127.0.0.1:5600"))
+                .body(body)
+                .dispatch()
+        }
+
+        // More events than are passed to the datastore at a time
+        let res = import(&client, ndjson("id1", 2500));
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+        let summary: ImportSummary = res.into_json().unwrap();
+        assert_eq!(summary.buckets[0].events_imported, 2500);
+
+        // Nothing is imported if any record is invalid
+        let body = format!("{}\nnot a record", ndjson("id2", 1500));
+        let res = import(&client, body);
+        assert_eq!(res.status(), rocket::http::Status::BadRequest);
+        let res = client
+            .get("/api/0/buckets/id2")
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::NotFound);
+
+        // Imports larger than the limit are refused instead of being cut off
+        let server = setup_testserver();
+        let limits = rocket::data::Limits::default().limit("ndjson", 1.kibibytes());
+        let figment = server.figment().clone().merge(("limits", limits));
+        let client = Client::untracked(server.configure(figment)).expect("valid instance");
+        let res = import(&client, ndjson("id3", 5));
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+        let res = import(&client, ndjson("id4", 50));
+        assert_eq!(res.status(), rocket::http::Status::PayloadTooLarge);
+        let res = client
+            .get("/api/0/buckets/id4")
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::NotFound);
+    }
+
+    #[test]
+    fn test_filtered_export() {
+        let server = setup_testserver();
+        let client = Client::untracked(server).expect("valid instance");
+
+        let res = client
+            .post("/api/0/import")
+            .header(ContentType::JSON)
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body(
+                r#"{"buckets": {
+                "window_a": {"id": "window_a", "type": "currentwindow", "client": "client", "hostname": "a", "events": [
+                    {"timestamp": "2000-01-01T00:00:00Z", "duration": 1.0, "data": {}},
+                    {"timestamp": "2000-02-01T00:00:00Z", "duration": 1.0, "data": {}}
+                ]},
+                "window_b": {"id": "window_b", "type": "currentwindow", "client": "client", "hostname": "b"},
+                "afk_a": {"id": "afk_a", "type": "afkstatus", "client": "client", "hostname": "a"}
+            }}"#,
+            )
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+
+        let export_ids = |query: &str| {
+            let res = client
+                .get(format!("/api/0/export?{query}"))
+                .header(Header::new("Host", "127.0.0.1:5600"))
+                .dispatch();
+            assert_eq!(res.status(), rocket::http::Status::Ok);
+            let export: BucketsExport = serde_json::from_str(&res.into_string().unwrap()).unwrap();
+            let mut ids: Vec<String> = export.buckets.into_keys().collect();
+            ids.sort();
+            ids
+        };
+        assert_eq!(export_ids("buckets=window_*"), vec!["window_a", "window_b"]);
+        assert_eq!(
+            export_ids("buckets=window_b,afk_*"),
+            vec!["afk_a", "window_b"]
+        );
+        assert_eq!(export_ids("hostname=a"), vec!["afk_a", "window_a"]);
+        assert_eq!(export_ids("type=afkstatus"), vec!["afk_a"]);
+        assert_eq!(
+            export_ids("hostname=a&type=currentwindow"),
+            vec!["window_a"]
+        );
+
+        // Time range, also for streamed exports
+        let res = client
+            .get("/api/0/export?buckets=window_a&start=2000-01-15T00:00:00Z")
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .dispatch();
+        let export: BucketsExport = serde_json::from_str(&res.into_string().unwrap()).unwrap();
+        let events = export.buckets["window_a"]
+            .events
+            .clone()
+            .unwrap()
+            .take_inner();
+        assert_eq!(events.len(), 1);
+        let res = client
+            .get("/api/0/buckets/window_a/export?format=csv&end=2000-01-15T00:00:00Z")
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .dispatch();
+        assert_eq!(res.into_string().unwrap().lines().count(), 2);
+
+        // Invalid filters
+        let res = client
+            .get("/api/0/export?start=yesterday")
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::BadRequest);
+        let res = client
+            .get("/api/0/export?buckets=[")
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::BadRequest);
+    }
+
+    #[test]
+    fn test_query_cache() {
+        let server = setup_testserver();
+        let client = Client::untracked(server).expect("valid instance");
+
+        let res = client
+            .post("/api/0/buckets/id")
+            .header(ContentType::JSON)
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body(r#"{"id": "id", "type": "type", "client": "client", "hostname": "hostname"}"#)
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+
+        let insert_event = |timestamp: &str| {
+            let res = client
+                .post("/api/0/buckets/id/events")
+                .header(ContentType::JSON)
+                .header(Header::new("Host", "127.0.0.1:5600"))
+                .body(format!(
+                    r#"[{{"timestamp": "{timestamp}", "duration": 1.0, "data": {{}}}}]"#
+                ))
+                .dispatch();
+            assert_eq!(res.status(), rocket::http::Status::Ok);
+        };
+        let query_count = |url: &str, query: &str| -> usize {
+            let res = client
+                .post(url.to_string())
+                .header(ContentType::JSON)
+                .header(Header::new("Host", "127.0.0.1:5600"))
+                .body(format!(
+                    r#"{{"timeperiods": ["2000-01-01T00:00:00Z/2000-01-02T00:00:00Z"], "query": {query}}}"#
+                ))
+                .dispatch();
+            assert_eq!(res.status(), rocket::http::Status::Ok);
+            let results: Value = serde_json::from_str(&res.into_string().unwrap()).unwrap();
+            results[0].as_array().unwrap().len()
+        };
+        let stats = || -> Value {
+            let res = client
+                .get("/api/0/query/cache")
+                .header(Header::new("Host", "127.0.0.1:5600"))
+                .dispatch();
+            serde_json::from_str(&res.into_string().unwrap()).unwrap()
+        };
+        let query = r#"["events = query_bucket(\"id\");", "RETURN = events;"]"#;
+        // Same query, only formatted differently
+        let query_formatted =
+            r##"["# all events", "  events = query_bucket(\"id\");", "", "RETURN = events;"]"##;
+
+        insert_event("2000-01-01T01:00:00Z");
+        assert_eq!(query_count("/api/0/query", query), 1);
+        assert_eq!(query_count("/api/0/query", query_formatted), 1);
+        assert_eq!(stats()["hits"], 1);
+        assert_eq!(stats()["misses"], 1);
+
+        // Changes outside of the time period keep the cached result
+        insert_event("2000-01-05T00:00:00Z");
+        assert_eq!(query_count("/api/0/query", query), 1);
+        assert_eq!(stats()["hits"], 2);
+
+        // Changes within the time period invalidate it
+        insert_event("2000-01-01T02:00:00Z");
+        assert_eq!(query_count("/api/0/query", query), 2);
+        assert_eq!(stats()["invalidations"], 1);
+        assert_eq!(stats()["misses"], 2);
+
+        // The cache can be bypassed
+        assert_eq!(query_count("/api/0/query?cache=false", query), 2);
+        assert_eq!(stats()["hits"], 2);
+        assert_eq!(stats()["misses"], 2);
+
+        let res = client
+            .delete("/api/0/query/cache")
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+        assert_eq!(stats()["entries"], 0);
+    }
+
+    #[test]
+    fn test_query_explain() {
+        let server = setup_testserver();
+        let client = Client::untracked(server).expect("valid instance");
+
+        let res = client
+            .post("/api/0/buckets/id")
+            .header(ContentType::JSON)
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body(r#"{"id": "id", "type": "type", "client": "client", "hostname": "hostname"}"#)
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+        let res = client
+            .post("/api/0/buckets/id/events")
+            .header(ContentType::JSON)
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body(r#"[{"timestamp": "2000-01-01T01:00:00Z", "duration": 1.0, "data": {}}]"#)
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+
+        let res = client
+            .post("/api/0/query?explain=true")
+            .header(ContentType::JSON)
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body(
+                r#"{
+                "timeperiods": ["2000-01-01T00:00:00Z/2000-01-02T00:00:00Z"],
+                "query": ["events = query_bucket(\"id\");", "return sum_durations(events);"]
+            }"#,
+            )
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+        let results: Value = serde_json::from_str(&res.into_string().unwrap()).unwrap();
+        assert_eq!(results[0]["result"], json!(1.0));
+        let explain = &results[0]["explain"];
+        assert_eq!(explain["events_loaded"], 1);
+        assert!(explain["bytes_loaded"].as_u64().unwrap() > 0);
+        assert_eq!(explain["statements"][0]["line"], 1);
+        assert_eq!(
+            explain["statements"][1]["code"],
+            "return sum_durations(events)"
+        );
+        let sum_durations = explain["builtins"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|b| b["name"] == "sum_durations")
+            .unwrap();
+        assert_eq!(sum_durations["calls"], 1);
+        assert_eq!(sum_durations["events_in"], 1);
+        assert_eq!(sum_durations["events_out"], 0);
+
+        // Explained results are neither taken from nor put in the cache
+        let res = client
+            .get("/api/0/query/cache")
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .dispatch();
+        let stats: Value = serde_json::from_str(&res.into_string().unwrap()).unwrap();
+        assert_eq!(stats["entries"], 0);
+        assert_eq!(stats["misses"], 0);
+    }
+
+    #[test]
+    fn test_query_limits() {
+        let state = endpoints::ServerState {
+            datastore: Mutex::new(aw_datastore::Datastore::new_in_memory(false)),
+            profiles: Profiles::in_memory(),
+            query_cache: QueryCache::new(),
+            asset_resolver: endpoints::AssetResolver::new(None),
+            device_id: "test_id".to_string(),
+        };
+        let mut aw_config = config::AWConfig::default();
+        aw_config.query.max_statements = 2;
+        aw_config.query.max_iterations = 2;
+        let server = endpoints::build_rocket(state, aw_config);
+        let client = Client::untracked(server).expect("valid instance");
+
+        let query = |code: &str| {
+            client
+                .post("/api/0/query")
+                .header(ContentType::JSON)
+                .header(Header::new("Host", "127.0.0.1:5600"))
+                .body(format!(
+                    r#"{{"timeperiods": ["2000-01-01T00:00:00Z/2000-01-02T00:00:00Z"], "query": {code}}}"#
+                ))
+                .dispatch()
+        };
+
+        let res = query(r#"["a = 1;", "RETURN = a;"]"#);
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+
+        let res = query(r#"["a = 1;", "b = a;", "RETURN = b;"]"#);
+        assert_eq!(res.status(), rocket::http::Status::BadRequest);
+        let body = res.into_string().unwrap();
+        assert!(body.contains("ResourceLimit"), "{}", body);
+
+        let res = query(r#"["for x in [1, 2, 3] {}", "RETURN = 1;"]"#);
+        assert_eq!(res.status(), rocket::http::Status::BadRequest);
+        let body = res.into_string().unwrap();
+        assert!(body.contains("loop iterations"), "{}", body);
+    }
+
+    #[test]
+    fn test_saved_queries() {
+        let server = setup_testserver();
+        let client = Client::untracked(server).expect("valid instance");
+
+        let saved_query = json!({
+            "query": ["RETURN = n * 2 + m;"],
+            "params": [{"name": "n"}, {"name": "m", "default": 1}]
+        });
+        let res = client
+            .post("/api/0/queries/double")
+            .header(ContentType::JSON)
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body(saved_query.to_string())
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::Created);
+
+        // Invalid parameter names are refused
+        let res = client
+            .post("/api/0/queries/invalid")
+            .header(ContentType::JSON)
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body(r#"{"query": ["RETURN = 1;"], "params": [{"name": "1n"}]}"#)
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::BadRequest);
+        // So are keywords and builtins, which the parameters can't be used as
+        for name in [
+            "for",
+            "in",
+            "def",
+            "fn",
+            "query_bucket",
+            "flood",
+            "TIMEINTERVAL",
+        ] {
+            let res = client
+                .post("/api/0/queries/invalid")
+                .header(ContentType::JSON)
+                .header(Header::new("Host", "127.0.0.1:5600"))
+                .body(json!({"query": ["RETURN = 1;"], "params": [{"name": name}]}).to_string())
+                .dispatch();
+            assert_eq!(res.status(), rocket::http::Status::BadRequest, "{name}");
+        }
+        // And so are queries with syntax errors, with where the error is
+        let res = client
+            .post("/api/0/queries/invalid")
+            .header(ContentType::JSON)
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body(r#"{"query": ["RETURN = 1;", "x = ;"]}"#)
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::BadRequest);
+        let error: Value = serde_json::from_str(&res.into_string().unwrap()).unwrap();
+        assert_eq!(error["line"], 2);
+        // Query names are identifiers like module names
+        let res = client
+            .post("/api/0/queries/not-a-name")
+            .header(ContentType::JSON)
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body(saved_query.to_string())
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::BadRequest);
+
+        // Saved queries are not settings
+        let res = client
+            .post("/api/0/settings/key")
+            .header(ContentType::JSON)
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body("1")
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::Created);
+        let res = client
+            .get("/api/0/settings")
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .dispatch();
+        let settings: Value = serde_json::from_str(&res.into_string().unwrap()).unwrap();
+        assert_eq!(settings, json!({"key": 1}));
+
+        let res = client
+            .get("/api/0/queries")
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+        let saved_queries: Value = serde_json::from_str(&res.into_string().unwrap()).unwrap();
+        assert_eq!(saved_queries, json!({ "double": saved_query }));
+
+        let res = client
+            .get("/api/0/queries/double")
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+        let got: Value = serde_json::from_str(&res.into_string().unwrap()).unwrap();
+        assert_eq!(got, saved_query);
+
+        let run = |params: Value| {
+            client
+                .post("/api/0/queries/double/run")
+                .header(ContentType::JSON)
+                .header(Header::new("Host", "127.0.0.1:5600"))
+                .body(
+                    json!({
+                        "timeperiods": [
+                            "2000-01-01T00:00:00Z/2000-01-02T00:00:00Z",
+                            "2000-01-02T00:00:00Z/2000-01-03T00:00:00Z"
+                        ],
+                        "params": params
+                    })
+                    .to_string(),
+                )
+                .dispatch()
+        };
+        let res = run(json!({"n": 3}));
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+        assert_eq!(res.into_string().unwrap(), "[7.0,7.0]");
+        // Results of different parameter values are cached separately
+        let res = run(json!({"n": 3, "m": 2}));
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+        assert_eq!(res.into_string().unwrap(), "[8.0,8.0]");
+
+        let res = run(json!({}));
+        assert_eq!(res.status(), rocket::http::Status::BadRequest);
+        let res = run(json!({"n": 3, "unknown": 1}));
+        assert_eq!(res.status(), rocket::http::Status::BadRequest);
+
+        let res = client
+            .delete("/api/0/queries/double")
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+        let res = client
+            .get("/api/0/queries/double")
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::NotFound);
+        let res = client
+            .delete("/api/0/queries/double")
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::NotFound);
+        let res = run(json!({"n": 3}));
+        assert_eq!(res.status(), rocket::http::Status::NotFound);
+    }
+
+    #[test]
+    fn test_health() {
+        let datastore = aw_datastore::Datastore::new_in_memory(false);
+        let state = endpoints::ServerState {
+            datastore: Mutex::new(datastore.clone()),
+            profiles: Profiles::in_memory(),
+            query_cache: QueryCache::new(),
+            asset_resolver: endpoints::AssetResolver::new(None),
+            device_id: "test_id".to_string(),
+        };
+        let server = endpoints::build_rocket(state, config::AWConfig::default());
+        let client = Client::untracked(server).expect("valid instance");
+
+        let res = client
+            .get("/api/0/health")
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+        let health: Value = serde_json::from_str(&res.into_string().unwrap()).unwrap();
+        assert_eq!(health["datastore"]["path"], Value::Null);
+        assert_eq!(health["datastore"]["db_version"], 5);
+        assert_eq!(health["datastore"]["backlog"], 0);
+        assert!(health["round_trip_ms"].is_number());
+
+        // The watchdog only counts a worker as stuck once it makes no progress for the timeout
+        let timeout = std::time::Duration::from_millis(100);
+        assert!(health::check_worker(&datastore, timeout).is_ok());
+        let mut import = datastore.import_stream(ImportConflict::Fail, false);
+        import.send(vec![]).unwrap();
+        std::thread::sleep(timeout * 3);
+        assert!(health::check_worker(&datastore, timeout).is_err());
+        import.send(vec![]).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        assert!(health::check_worker(&datastore, timeout).is_ok());
+        import.finish().unwrap();
+
+        // A stopped datastore worker makes the server unhealthy
+        datastore.close();
+        let res = client
+            .get("/api/0/health")
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::ServiceUnavailable);
+    }
+
     #[test]
     fn test_query() {
         let server = setup_testserver();
@@ -475,6 +1189,241 @@ mod api_tests {
             .dispatch();
         assert_eq!(res.status(), rocket::http::Status::InternalServerError);
         assert_eq!(res.into_string().unwrap(), r#"{"message":"EmptyQuery"}"#);
+
+        // Errors caused by an expression have its location
+        let res = client
+            .post("/api/0/query")
+            .header(ContentType::JSON)
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body(
+                r#"{
+                "timeperiods": ["2000-01-01T00:00:00Z/2020-01-01T00:00:00Z"],
+                "query": ["events = query_bucket(\"id\");", "return duration + 1;"]
+            }"#,
+            )
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::InternalServerError);
+        let error: Value = serde_json::from_str(&res.into_string().unwrap()).unwrap();
+        assert_eq!(
+            error,
+            json!({
+                "message": "VariableNotDefined(\"duration\")",
+                "line": 2,
+                "column": 8,
+                "snippet": "return duration + 1;\n       ^^^^^^^^",
+            })
+        );
+    }
+
+    #[test]
+    fn test_query_validate() {
+        let server = setup_testserver();
+        let client = Client::untracked(server).expect("valid instance");
+
+        let res = client
+            .post("/api/0/buckets/id")
+            .header(ContentType::JSON)
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body(r#"{"id": "id", "type": "type", "client": "client", "hostname": "hostname"}"#)
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+
+        let validate = |body: Value| -> Value {
+            let res = client
+                .post("/api/0/query/validate")
+                .header(ContentType::JSON)
+                .header(Header::new("Host", "127.0.0.1:5600"))
+                .body(body.to_string())
+                .dispatch();
+            assert_eq!(res.status(), rocket::http::Status::Ok);
+            serde_json::from_str(&res.into_string().unwrap()).unwrap()
+        };
+
+        assert_eq!(
+            validate(json!({
+                "query": ["events = query_bucket(\"id\");", "return limit_events(events, n);"],
+                "params": ["n"],
+            })),
+            json!({"valid": true, "diagnostics": []})
+        );
+        assert_eq!(
+            validate(json!({
+                "query": ["events = query_bucket(\"missing\");", "return len(events, 1);"],
+            })),
+            json!({
+                "valid": false,
+                "diagnostics": [
+                    {
+                        "severity": "warning",
+                        "message": "There is no bucket named missing",
+                        "line": 1,
+                        "column": 23,
+                        "snippet": "events = query_bucket(\"missing\");\n                      ^^^^^^^^^",
+                    },
+                    {
+                        "severity": "error",
+                        "message": "len(List, Number) does not match len(List) or len(Dict) or len(String)",
+                        "line": 2,
+                        "column": 8,
+                        "snippet": "return len(events, 1);\n       ^^^^^^^^^^^^^^",
+                    },
+                ],
+            })
+        );
+        assert_eq!(
+            validate(json!({"query": ["return 1"]})),
+            json!({
+                "valid": false,
+                "diagnostics": [{
+                    "severity": "error",
+                    "message": "Unexpected end of query",
+                    "line": 1,
+                    "column": 9,
+                    "snippet": "return 1\n        ^",
+                }],
+            })
+        );
+    }
+
+    #[test]
+    fn test_query_modules() {
+        let server = setup_testserver();
+        let client = Client::untracked(server).expect("valid instance");
+
+        let module = json!({"query": ["def double(n) { return n * 2; }"]});
+        let res = client
+            .post("/api/0/query/modules/math")
+            .header(ContentType::JSON)
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body(module.to_string())
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::Created);
+
+        // Modules have to be importable and parse
+        let res = client
+            .post("/api/0/query/modules/not-a-name")
+            .header(ContentType::JSON)
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body(module.to_string())
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::BadRequest);
+        let res = client
+            .post("/api/0/query/modules/broken")
+            .header(ContentType::JSON)
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body(r#"{"query": ["x = ;"]}"#)
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::BadRequest);
+        let error: Value = serde_json::from_str(&res.into_string().unwrap()).unwrap();
+        assert_eq!(error["line"], 1);
+
+        let res = client
+            .get("/api/0/query/modules")
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+        let modules: Value = serde_json::from_str(&res.into_string().unwrap()).unwrap();
+        assert_eq!(modules, json!({ "math": module }));
+
+        let res = client
+            .get("/api/0/query/modules/math")
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+        let got: Value = serde_json::from_str(&res.into_string().unwrap()).unwrap();
+        assert_eq!(got, module);
+
+        let query = || {
+            client
+                .post("/api/0/query")
+                .header(ContentType::JSON)
+                .header(Header::new("Host", "127.0.0.1:5600"))
+                .body(
+                    r#"{
+                    "timeperiods": ["2000-01-01T00:00:00Z/2000-01-02T00:00:00Z"],
+                    "query": ["import \"math\";", "return math.double(21);"]
+                }"#,
+                )
+                .dispatch()
+        };
+        let res = query();
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+        let results: Value = serde_json::from_str(&res.into_string().unwrap()).unwrap();
+        assert_eq!(results, json!([42.0]));
+
+        let res = client
+            .delete("/api/0/query/modules/math")
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+        let res = client
+            .get("/api/0/query/modules/math")
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::NotFound);
+        let res = client
+            .delete("/api/0/query/modules/math")
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::NotFound);
+
+        // The cached result of a query importing a deleted module is not used
+        let res = query();
+        assert_eq!(res.status(), rocket::http::Status::InternalServerError);
+    }
+
+    #[test]
+    fn test_query_modules_shared_cache() {
+        // Like the servers on TCP and on the Unix socket, which share a datastore and cache
+        let datastore = aw_datastore::Datastore::new_in_memory(false);
+        let profiles = Profiles::in_memory();
+        let query_cache = QueryCache::new();
+        let client = || {
+            let state = endpoints::ServerState {
+                datastore: Mutex::new(datastore.clone()),
+                profiles: profiles.clone(),
+                query_cache: query_cache.clone(),
+                asset_resolver: endpoints::AssetResolver::new(None),
+                device_id: "test_id".to_string(),
+            };
+            let rocket = endpoints::build_rocket(state, config::AWConfig::default());
+            Client::untracked(rocket).expect("valid instance")
+        };
+        let (tcp, socket) = (client(), client());
+
+        let set_module = |factor: u32| {
+            let res = tcp
+                .post("/api/0/query/modules/math")
+                .header(ContentType::JSON)
+                .header(Header::new("Host", "127.0.0.1:5600"))
+                .body(
+                    json!({ "query": [format!("def scale(n) {{ return n * {factor}; }}")] })
+                        .to_string(),
+                )
+                .dispatch();
+            assert_eq!(res.status(), rocket::http::Status::Created);
+        };
+        let query = || -> Value {
+            let res = socket
+                .post("/api/0/query")
+                .header(ContentType::JSON)
+                .header(Header::new("Host", "127.0.0.1:5600"))
+                .body(
+                    r#"{
+                    "timeperiods": ["2000-01-01T00:00:00Z/2000-01-02T00:00:00Z"],
+                    "query": ["import \"math\";", "return math.scale(21);"]
+                }"#,
+                )
+                .dispatch();
+            assert_eq!(res.status(), rocket::http::Status::Ok);
+            serde_json::from_str(&res.into_string().unwrap()).unwrap()
+        };
+
+        set_module(2);
+        assert_eq!(query(), json!([42.0]));
+        // Changing the module through one server stops the results cached by the other being used
+        set_module(3);
+        assert_eq!(query(), json!([63.0]));
     }
 
     fn set_setting_request(client: &Client, key: &str, value: &Value) -> Status {
@@ -669,6 +1618,107 @@ mod api_tests {
         assert_eq!(res.into_string().unwrap(), "null");
     }
 
+    #[test]
+    fn test_openapi() {
+        let server = setup_testserver();
+        // Route paths in OpenAPI syntax, e.g. /api/0/buckets/{bucket_id}, with their query
+        // parameters and whether they take any other query parameters as well
+        let route_params: Vec<(String, String, Vec<String>, bool)> = server
+            .routes()
+            .filter(|route| route.uri.path().starts_with("/api"))
+            .map(|route| {
+                let path = route
+                    .uri
+                    .path()
+                    .split('/')
+                    .map(|seg| match seg.strip_prefix('<') {
+                        Some(param) => format!("{{{}}}", param.trim_end_matches(&['>', '.'][..])),
+                        None => seg.to_string(),
+                    })
+                    .collect::<Vec<String>>()
+                    .join("/");
+                let mut params = Vec::new();
+                let mut catch_all = false;
+                for seg in route.uri.query().unwrap_or("").split('&') {
+                    match seg.strip_prefix('<').and_then(|s| s.strip_suffix('>')) {
+                        Some(param) if param.ends_with("..") => catch_all = true,
+                        Some(param) => params.push(param.to_string()),
+                        None => (),
+                    }
+                }
+                params.sort();
+                (
+                    route.method.as_str().to_lowercase(),
+                    path,
+                    params,
+                    catch_all,
+                )
+            })
+            .collect();
+        let routes: Vec<(String, String)> = route_params
+            .iter()
+            .map(|(method, path, _, _)| (method.clone(), path.clone()))
+            .collect();
+        let client = Client::untracked(server).expect("valid instance");
+
+        let res = client
+            .get("/api/0/openapi.json")
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+        let spec: Value = serde_json::from_str(&res.into_string().unwrap()).unwrap();
+        assert_eq!(spec["openapi"], "3.0.3");
+
+        // Every mounted route has to be described
+        for (method, path) in routes.iter() {
+            assert!(
+                spec["paths"][path][method].is_object(),
+                "{} {} is missing from the OpenAPI spec",
+                method,
+                path
+            );
+        }
+        // With the same query parameters, routes which take any query parameters may have more
+        for (method, path, params, catch_all) in route_params.iter() {
+            let mut described: Vec<String> = spec["paths"][path][method]["parameters"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .filter(|param| param["in"] == "query")
+                .map(|param| param["name"].as_str().unwrap().to_string())
+                .collect();
+            described.sort();
+            if *catch_all {
+                described.retain(|param| params.contains(param));
+            }
+            assert_eq!(
+                &described, params,
+                "Query parameters of {method} {path} differ from the OpenAPI spec"
+            );
+        }
+        // And every described operation has to exist
+        for (path, item) in spec["paths"].as_object().unwrap() {
+            for method in item.as_object().unwrap().keys() {
+                assert!(
+                    routes.contains(&(method.clone(), path.clone())),
+                    "{} {} is described in the OpenAPI spec but not mounted",
+                    method,
+                    path
+                );
+            }
+        }
+
+        // All schema references must resolve
+        let schemas = spec["components"]["schemas"].as_object().unwrap();
+        assert!(schemas.contains_key("Bucket"));
+        assert!(schemas.contains_key("Event"));
+        let spec_str = spec.to_string();
+        for reference in spec_str.split("\"#/components/schemas/").skip(1) {
+            let name = reference.split('"').next().unwrap();
+            assert!(schemas.contains_key(name), "Unresolved schema {}", name);
+        }
+    }
+
     #[test]
     fn test_cors_catching() {
         let server = setup_testserver();
@@ -681,4 +1731,417 @@ mod api_tests {
             .dispatch();
         assert_eq!(res.status(), rocket::http::Status::Ok);
     }
+
+    #[test]
+    fn test_config_reload() {
+        let server = setup_testserver();
+        let client = Client::untracked(server).expect("valid instance");
+        let reloader = endpoints::ConfigReloader::new(client.rocket());
+
+        let preflight = || {
+            client
+                .options("/api/0/buckets/")
+                .header(Header::new("Host", "127.0.0.1:5600"))
+                .header(Header::new("Origin", "http://example.org"))
+                .header(Header::new("Access-Control-Request-Method", "GET"))
+                .dispatch()
+        };
+        let res = preflight();
+        assert_eq!(res.headers().get_one("Access-Control-Allow-Origin"), None);
+
+        let dir = std::env::temp_dir().join(format!("aw-server-test-pages-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("index.html"), "custom visualization").unwrap();
+        let res = client
+            .get("/pages/custom/")
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .dispatch();
+        assert_eq!(res.status(), Status::NotFound);
+
+        let aw_config = config::AWConfig {
+            cors: vec!["http://example.org".to_string()],
+            custom_static: HashMap::from([(
+                "custom".to_string(),
+                dir.to_str().unwrap().to_string(),
+            )]),
+            ..Default::default()
+        };
+        reloader.reload(&aw_config);
+
+        let res = preflight();
+        assert_eq!(
+            res.headers().get_one("Access-Control-Allow-Origin"),
+            Some("http://example.org")
+        );
+        let res = client
+            .get("/pages/custom/")
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        assert_eq!(res.into_string().unwrap(), "custom visualization");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_profiles() {
+        let server = setup_testserver();
+        let client = Client::untracked(server).expect("valid instance");
+
+        let request = |method: Method, url: &str, body: &str| {
+            client
+                .req(method, url.to_string())
+                .header(ContentType::JSON)
+                .header(Header::new("Host", "127.0.0.1:5600"))
+                .body(body)
+                .dispatch()
+        };
+
+        let res = request(Method::Get, "/api/0/profiles/", "");
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+        assert_eq!(res.into_json::<Vec<String>>().unwrap(), vec!["default"]);
+
+        let res = request(Method::Post, "/api/0/profiles/work", "");
+        assert_eq!(res.status(), rocket::http::Status::Created);
+        let res = request(Method::Post, "/api/0/profiles/work", "");
+        assert_eq!(res.status(), rocket::http::Status::Conflict);
+        let res = request(Method::Post, "/api/0/profiles/default", "");
+        assert_eq!(res.status(), rocket::http::Status::Conflict);
+        let res = request(Method::Post, "/api/0/profiles/not.valid", "");
+        assert_eq!(res.status(), rocket::http::Status::BadRequest);
+
+        let res = request(Method::Get, "/api/0/profiles/", "");
+        assert_eq!(
+            res.into_json::<Vec<String>>().unwrap(),
+            vec!["default", "work"]
+        );
+
+        // The same bucket with different events in both profiles
+        let bucket = r#"{"id": "id", "type": "type", "client": "client", "hostname": "hostname"}"#;
+        for (base, events) in [("/api/0", 1), ("/api/0/profiles/work", 2)] {
+            let res = request(Method::Post, &format!("{base}/buckets/id"), bucket);
+            assert_eq!(res.status(), rocket::http::Status::Ok);
+            for i in 0..events {
+                let res = request(
+                    Method::Post,
+                    &format!("{base}/buckets/id/events"),
+                    &format!(
+                        r#"[{{"timestamp": "2000-01-01T00:00:0{i}Z", "duration": 1.0, "data": {{}}}}]"#
+                    ),
+                );
+                assert_eq!(res.status(), rocket::http::Status::Ok);
+            }
+        }
+
+        // Query strings are kept when routing to a profile
+        let res = request(
+            Method::Get,
+            "/api/0/profiles/work/buckets/id/events?limit=1",
+            "",
+        );
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+        assert_eq!(res.into_json::<Vec<Value>>().unwrap().len(), 1);
+
+        // Queries only see the events of their profile, also when cached
+        let query = r#"{"timeperiods": ["2000-01-01T00:00:00Z/2000-01-02T00:00:00Z"], "query": ["RETURN = query_bucket(\"id\");"]}"#;
+        for (base, events) in [
+            ("/api/0", 1),
+            ("/api/0/profiles/work", 2),
+            ("/api/0/profiles/default", 1),
+        ] {
+            let res = request(Method::Post, &format!("{base}/query/"), query);
+            assert_eq!(res.status(), rocket::http::Status::Ok);
+            let result = res.into_json::<Vec<Vec<Value>>>().unwrap();
+            assert_eq!(result[0].len(), events, "query for {base}");
+        }
+
+        // Requests for a profile which does not exist
+        let res = request(Method::Get, "/api/0/profiles/home/buckets/", "");
+        assert_eq!(res.status(), rocket::http::Status::NotFound);
+        let res: Value = res.into_json().unwrap();
+        assert_eq!(res["message"], "There's no profile named home");
+        let res = request(Method::Post, "/api/0/profiles/home/buckets/id", bucket);
+        assert_eq!(res.status(), rocket::http::Status::NotFound);
+
+        let res = request(Method::Delete, "/api/0/profiles/default", "");
+        assert_eq!(res.status(), rocket::http::Status::BadRequest);
+        let res = request(Method::Delete, "/api/0/profiles/work", "");
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+        let res = request(Method::Delete, "/api/0/profiles/work", "");
+        assert_eq!(res.status(), rocket::http::Status::NotFound);
+        let res = request(Method::Get, "/api/0/profiles/work/buckets/", "");
+        assert_eq!(res.status(), rocket::http::Status::NotFound);
+
+        // A profile created again with the same name does not get the cached results of the
+        // deleted one
+        let res = request(Method::Post, "/api/0/profiles/work", "");
+        assert_eq!(res.status(), rocket::http::Status::Created);
+        let res = request(Method::Post, "/api/0/profiles/work/buckets/id", bucket);
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+        let res = request(
+            Method::Post,
+            "/api/0/profiles/work/buckets/id/events",
+            r#"[{"timestamp": "2000-01-01T00:00:00Z", "duration": 1.0, "data": {}}]"#,
+        );
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+        let res = request(Method::Post, "/api/0/profiles/work/query/", query);
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+        assert_eq!(res.into_json::<Vec<Vec<Value>>>().unwrap()[0].len(), 1);
+
+        // The default profile is untouched
+        let res = request(Method::Get, "/api/0/buckets/id/events", "");
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+        assert_eq!(res.into_json::<Vec<Value>>().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_audit_log() {
+        let datastore = aw_datastore::Datastore::new_in_memory(false);
+        let state = endpoints::ServerState {
+            datastore: Mutex::new(datastore.clone()),
+            profiles: Profiles::in_memory(),
+            query_cache: QueryCache::new(),
+            asset_resolver: endpoints::AssetResolver::new(None),
+            device_id: "test_id".to_string(),
+        };
+        let aw_config = config::AWConfig {
+            audit_retention_days: 30,
+            ..Default::default()
+        };
+        let client =
+            Client::untracked(endpoints::build_rocket(state, aw_config)).expect("valid instance");
+
+        let request = |method: Method, url: &str, body: &str| {
+            client
+                .req(method, url.to_string())
+                .header(ContentType::JSON)
+                .header(Header::new("Host", "127.0.0.1:5600"))
+                .header(Header::new("User-Agent", "test-agent"))
+                .body(body)
+                .dispatch()
+        };
+        let audit_log = |url: &str| -> Vec<Value> {
+            let res = request(Method::Get, url, "");
+            assert_eq!(res.status(), rocket::http::Status::Ok);
+            res.into_json().unwrap()
+        };
+
+        // An entry older than the retention is pruned once the first entry since startup is recorded
+        let old_entry = aw_models::AuditEntry {
+            id: None,
+            timestamp: chrono::Utc::now() - chrono::Duration::days(31),
+            method: "DELETE".to_string(),
+            route: "/api/0/buckets/<bucket_id>".to_string(),
+            affected: vec!["old".to_string()],
+            status: 200,
+            user_agent: None,
+            client: None,
+        };
+        datastore.insert_audit_entry(&old_entry).unwrap();
+        assert_eq!(audit_log("/api/0/audit").len(), 1);
+
+        let bucket = r#"{"id": "id", "type": "type", "client": "client", "hostname": "hostname"}"#;
+        let res = request(Method::Post, "/api/0/buckets/id", bucket);
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+
+        let log = audit_log("/api/0/audit");
+        assert_eq!(log.len(), 1);
+        assert_eq!(log[0]["method"], "POST");
+        assert_eq!(log[0]["route"], "/api/0/buckets/<bucket_id>");
+        assert_eq!(log[0]["affected"], json!(["id"]));
+        assert_eq!(log[0]["status"], 200);
+        assert_eq!(log[0]["user_agent"], "test-agent");
+        assert_eq!(log[0]["client"], "client");
+
+        // Inserting new events and heartbeats is not recorded, replacing events is
+        let res = request(
+            Method::Post,
+            "/api/0/buckets/id/events",
+            r#"[{"timestamp": "2000-01-01T00:00:00Z", "duration": 1.0, "data": {}}]"#,
+        );
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+        let res = request(
+            Method::Post,
+            "/api/0/buckets/id/heartbeat?pulsetime=10",
+            r#"{"timestamp": "2000-01-01T00:00:05Z", "duration": 1.0, "data": {}}"#,
+        );
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+        assert_eq!(audit_log("/api/0/audit").len(), 1);
+        let res = request(
+            Method::Post,
+            "/api/0/buckets/id/events",
+            r#"[{"id": 1, "timestamp": "2000-01-01T00:00:00Z", "duration": 2.0, "data": {}}]"#,
+        );
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+
+        let res = request(Method::Delete, "/api/0/buckets/id/events/1", "");
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+        let res = request(Method::Post, "/api/0/settings/key", r#""value""#);
+        assert_eq!(res.status(), rocket::http::Status::Created);
+        // Failed attempts are recorded as well
+        let res = request(Method::Delete, "/api/0/buckets/nonexistent", "");
+        assert_eq!(res.status(), rocket::http::Status::NotFound);
+        let res = request(Method::Delete, "/api/0/buckets/id", "");
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+
+        let log = audit_log("/api/0/audit");
+        let summary: Vec<(&str, &str, Value, u64)> = log
+            .iter()
+            .map(|entry| {
+                (
+                    entry["method"].as_str().unwrap(),
+                    entry["route"].as_str().unwrap(),
+                    entry["affected"].clone(),
+                    entry["status"].as_u64().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("POST", "/api/0/buckets/<bucket_id>", json!(["id"]), 200),
+                (
+                    "POST",
+                    "/api/0/buckets/<bucket_id>/events",
+                    json!(["id", "1"]),
+                    200
+                ),
+                (
+                    "DELETE",
+                    "/api/0/buckets/<bucket_id>/events/<event_id>",
+                    json!(["id", "1"]),
+                    200
+                ),
+                ("POST", "/api/0/settings/<key>", json!(["key"]), 201),
+                (
+                    "DELETE",
+                    "/api/0/buckets/<bucket_id>",
+                    json!(["nonexistent"]),
+                    404
+                ),
+                ("DELETE", "/api/0/buckets/<bucket_id>", json!(["id"]), 200),
+            ]
+        );
+        // The client of a deleted bucket is still known
+        assert_eq!(log[5]["client"], "client");
+        assert_eq!(log[4]["client"], Value::Null);
+
+        // Dry runs of imports are not recorded
+        let export = r#"{"buckets": {"imported": {"id": "imported", "type": "type", "client": "client", "hostname": "hostname", "events": []}}}"#;
+        let res = request(Method::Post, "/api/0/import?dry_run=true", export);
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+        assert_eq!(audit_log("/api/0/audit").len(), 6);
+        let res = request(Method::Post, "/api/0/import", export);
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+        let log = audit_log("/api/0/audit");
+        assert_eq!(log[6]["route"], "/api/0/import");
+        assert_eq!(log[6]["affected"], json!(["imported"]));
+
+        // since
+        let since = log[6]["timestamp"].as_str().unwrap().replace('+', "%2B");
+        let log = audit_log(&format!("/api/0/audit?since={since}"));
+        assert_eq!(log.len(), 1);
+        assert_eq!(log[0]["route"], "/api/0/import");
+        assert_eq!(audit_log("/api/0/audit?limit=2").len(), 2);
+        let res = request(Method::Get, "/api/0/audit?since=yesterday", "");
+        assert_eq!(res.status(), rocket::http::Status::BadRequest);
+
+        // Profiles have an audit log of their own, creating them is in the default one
+        let res = request(Method::Post, "/api/0/profiles/work", "");
+        assert_eq!(res.status(), rocket::http::Status::Created);
+        let res = request(Method::Post, "/api/0/profiles/work/settings/key", "1");
+        assert_eq!(res.status(), rocket::http::Status::Created);
+        let log = audit_log("/api/0/profiles/work/audit");
+        assert_eq!(log.len(), 1);
+        assert_eq!(log[0]["route"], "/api/0/settings/<key>");
+        let log = audit_log("/api/0/audit");
+        assert_eq!(log.len(), 8);
+        assert_eq!(log[7]["route"], "/api/0/profiles/<profile>");
+        assert_eq!(log[7]["affected"], json!(["work"]));
+    }
+
+    #[test]
+    fn test_batch() {
+        let server = setup_testserver();
+        let client = Client::untracked(server).expect("valid instance");
+
+        let batch = json!([
+            {"op": "create_bucket", "bucket": {"id": "id", "type": "type", "client": "client", "hostname": "!local"}},
+            {"op": "insert_events", "bucket_id": "id", "events": [
+                {"timestamp": "2000-01-01T00:00:00Z", "duration": 1.0, "data": {}}
+            ]},
+            {"op": "heartbeat", "bucket_id": "id", "pulsetime": 10.0,
+                "event": {"timestamp": "2000-01-01T00:00:05Z", "duration": 0.0, "data": {}}},
+            {"op": "set_setting", "key": "a".repeat(200), "value": 1},
+            {"op": "set_setting", "key": "key", "value": {"nested": true}},
+            {"op": "insert_events", "bucket_id": "nonexistent", "events": []},
+            {"op": "insert_events", "bucket_id": "id", "events": [
+                {"id": 1, "timestamp": "2000-01-01T00:00:00Z", "duration": 2.0, "data": {}}
+            ]},
+        ]);
+        let res = client
+            .post("/api/0/batch")
+            .header(ContentType::JSON)
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body(batch.to_string())
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+        let results: Vec<Value> = res.into_json().unwrap();
+        assert_eq!(results.len(), 7);
+        assert_eq!(results[0], json!({"status": 200}));
+        assert_eq!(results[1]["status"], 200);
+        assert_eq!(results[1]["events"][0]["id"], 1);
+        assert_eq!(results[2]["events"][0]["duration"], 5.0);
+        assert_eq!(results[3]["status"], 400);
+        assert_eq!(results[3]["error"], "Too long key");
+        assert_eq!(results[4], json!({"status": 200}));
+        assert_eq!(results[5]["status"], 404);
+        assert_eq!(results[6]["events"][0]["id"], 1);
+
+        // The same as the requests of their own would have done
+        let res = client
+            .get("/api/0/buckets/id")
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .dispatch();
+        let bucket: Value = res.into_json().unwrap();
+        assert_eq!(bucket["data"]["device_id"], "test_id");
+        let res = client
+            .get("/api/0/settings/key")
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .dispatch();
+        assert_eq!(res.into_json::<Value>().unwrap(), json!({"nested": true}));
+
+        // Creating a bucket which already exists is not an error
+        let res = client
+            .post("/api/0/batch")
+            .header(ContentType::JSON)
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body(json!([batch[0]]).to_string())
+            .dispatch();
+        let results: Vec<Value> = res.into_json().unwrap();
+        assert_eq!(results, vec![json!({"status": 304})]);
+
+        // Buckets and settings changed by a batch are in the audit log
+        let res = client
+            .get("/api/0/audit")
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .dispatch();
+        let log: Vec<Value> = res.into_json().unwrap();
+        assert_eq!(log[0]["route"], "/api/0/batch");
+        // Including the events which were replaced by id
+        assert_eq!(
+            log[0]["affected"],
+            json!(["id", "a".repeat(200), "key", "nonexistent", "1"])
+        );
+        assert_eq!(log[0]["client"], "client");
+
+        // Unknown operations fail the whole batch
+        let res = client
+            .post("/api/0/batch")
+            .header(ContentType::JSON)
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body(r#"[{"op": "delete_bucket", "bucket_id": "id"}]"#)
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::UnprocessableEntity);
+    }
 }
@@ -0,0 +1,124 @@
+#![cfg(unix)]
+
+extern crate aw_datastore;
+
+use std::net::TcpListener;
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde_json::json;
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+/// Kills the server if the test fails, it would otherwise outlive the test and keep it hanging
+struct Server(Child);
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+    }
+}
+
+fn http_client() -> reqwest::blocking::Client {
+    // The server closes every connection after a response, see AWConfig::to_rocket_config
+    reqwest::blocking::Client::builder()
+        .pool_max_idle_per_host(0)
+        .build()
+        .unwrap()
+}
+
+fn start_server(port: u16, dbpath: &str) -> Server {
+    let server = Command::new(env!("CARGO_BIN_EXE_aw-server"))
+        .args(["--testing", "--no-legacy-import", "--port"])
+        .arg(port.to_string())
+        .arg("--dbpath")
+        .arg(dbpath)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("Failed to start aw-server");
+    let server = Server(server);
+
+    let client = http_client();
+    let start = Instant::now();
+    while client
+        .get(format!("http://127.0.0.1:{port}/api/0/info"))
+        .send()
+        .is_err()
+    {
+        assert!(
+            start.elapsed() < Duration::from_secs(30),
+            "aw-server did not start"
+        );
+        thread::sleep(Duration::from_millis(100));
+    }
+    server
+}
+
+/// Heartbeats sent right before the server is asked to stop must not be lost, which would happen
+/// if the process exited before the datastore committed them
+fn heartbeats_survive_signal(signal: libc::c_int, name: &str) {
+    let port = free_port();
+    let dbpath = std::env::temp_dir().join(format!(
+        "aw-server-test-shutdown-{}-{name}.db",
+        std::process::id()
+    ));
+    let _ = std::fs::remove_file(&dbpath);
+    let dbpath = dbpath.to_str().unwrap().to_string();
+
+    let mut server = start_server(port, &dbpath);
+    let client = http_client();
+    let url = format!("http://127.0.0.1:{port}/api/0/buckets/test-shutdown");
+    let res = client
+        .post(&url)
+        .json(&json!({"type": "test", "client": "test", "hostname": "test"}))
+        .send()
+        .unwrap();
+    assert!(res.status().is_success());
+
+    // Distinct data so that every heartbeat becomes an event of its own
+    let count = 50;
+    for i in 0..count {
+        let res = client
+            .post(format!("{url}/heartbeat?pulsetime=1"))
+            .json(&json!({
+                "timestamp": format!("2000-01-01T00:00:{i:02}Z"),
+                "duration": 0.0,
+                "data": {"i": i},
+            }))
+            .send()
+            .unwrap();
+        assert!(res.status().is_success());
+    }
+
+    unsafe {
+        assert_eq!(libc::kill(server.0.id() as libc::pid_t, signal), 0);
+    }
+    let status = server.0.wait().unwrap();
+    assert!(status.success(), "aw-server exited with {status}");
+
+    let datastore = aw_datastore::Datastore::new(dbpath.clone(), false);
+    let events = datastore
+        .get_events("test-shutdown", None, None, None)
+        .unwrap();
+    assert_eq!(events.len(), count);
+    datastore.close();
+    let _ = std::fs::remove_file(&dbpath);
+}
+
+#[test]
+fn test_sigterm_commits_heartbeats() {
+    heartbeats_survive_signal(libc::SIGTERM, "sigterm");
+}
+
+#[test]
+fn test_sigint_commits_heartbeats() {
+    heartbeats_survive_signal(libc::SIGINT, "sigint");
+}
@@ -39,9 +39,15 @@ pub use sort::{sort_by_duration, sort_by_timestamp};
 mod filter_keyvals;
 pub use filter_keyvals::{exclude_keyvals, filter_keyvals, filter_keyvals_regex};
 
+mod map_keyvals;
+pub use map_keyvals::{extract_keyvals_regex, map_keyvals, regex_extract, regex_replace};
+
 mod filter_period;
 pub use filter_period::filter_period_intersect;
 
+mod split_period;
+pub use split_period::split_by_period;
+
 mod split_url;
 pub use split_url::split_url_event;
 
@@ -0,0 +1,183 @@
+use fancy_regex::Regex;
+use serde_json::value::Value;
+
+use aw_models::Event;
+
+/// Replaces the value for a specified key with the result of f on it
+/// Will only change the value if it is a string
+///
+/// # Example
+/// ```ignore
+/// key: a
+/// f: to_uppercase
+/// input:  [a:"hello"][a:3][b:"hello"]
+/// output: [a:"HELLO"][a:3][b:"hello"]
+/// ```
+pub fn map_keyvals<F: FnMut(&str) -> String>(
+    mut events: Vec<Event>,
+    key: &str,
+    mut f: F,
+) -> Vec<Event> {
+    for event in events.iter_mut() {
+        if let Some(Value::String(value)) = event.data.get_mut(key) {
+            *value = f(value);
+        }
+    }
+    events
+}
+
+/// Adds the part of the value for a specified key which matches the regex as new_key
+/// Will only match if the value is a string, see regex_extract for which part is used
+///
+/// # Example
+/// ```ignore
+/// key: title
+/// regex: "#([0-9]+)"
+/// new_key: ticket
+/// input:  [title:"Fix #12"][title:"Lunch"]
+/// output: [title:"Fix #12", ticket:"12"][title:"Lunch"]
+/// ```
+pub fn extract_keyvals_regex(
+    mut events: Vec<Event>,
+    key: &str,
+    regex: &Regex,
+    new_key: &str,
+) -> Vec<Event> {
+    for event in events.iter_mut() {
+        let extracted = match event.data.get(key).and_then(|v| v.as_str()) {
+            Some(value) => regex_extract(value, regex),
+            None => None,
+        };
+        if let Some(extracted) = extracted {
+            event
+                .data
+                .insert(new_key.to_string(), Value::String(extracted));
+        }
+    }
+    events
+}
+
+/// Replaces all matches of the regex in text, the replacement can refer to groups as $1 or $name
+pub fn regex_replace(text: &str, regex: &Regex, replacement: &str) -> String {
+    let mut replaced = String::with_capacity(text.len());
+    let mut last_match = 0;
+    for captures in regex.captures_iter(text) {
+        let captures = match captures {
+            Ok(captures) => captures,
+            Err(err) => {
+                warn!("Failed to run regex: {}", err);
+                return text.to_string();
+            }
+        };
+        // Group 0 is always the whole match
+        let m = captures.get(0).unwrap();
+        replaced.push_str(&text[last_match..m.start()]);
+        captures.expand(replacement, &mut replaced);
+        last_match = m.end();
+    }
+    replaced.push_str(&text[last_match..]);
+    replaced
+}
+
+/// Finds the first match of the regex in text
+///
+/// Returns the first group of the match if the regex has groups, otherwise the whole match.
+pub fn regex_extract(text: &str, regex: &Regex) -> Option<String> {
+    match regex.captures(text) {
+        Ok(Some(captures)) => {
+            let m = match captures.len() {
+                1 => captures.get(0),
+                _ => captures.get(1),
+            };
+            Some(m.map_or("", |m| m.as_str()).to_string())
+        }
+        Ok(None) => None,
+        Err(err) => {
+            warn!("Failed to run regex: {}", err);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use chrono::{DateTime, Duration};
+    use fancy_regex::Regex;
+    use serde_json::json;
+
+    use aw_models::Event;
+
+    use super::{extract_keyvals_regex, map_keyvals, regex_extract, regex_replace};
+
+    #[test]
+    fn test_map_keyvals() {
+        let e1 = Event {
+            id: None,
+            timestamp: DateTime::from_str("2000-01-01T00:00:00Z").unwrap(),
+            duration: Duration::seconds(1),
+            data: json_map! {"key1": json!("Value1")},
+        };
+        let mut e2 = e1.clone();
+        e2.data = json_map! {"key1": json!(1)};
+        let mut e3 = e1.clone();
+        e3.data = json_map! {"key2": json!("Value3")};
+
+        let res = map_keyvals(vec![e1.clone(), e2.clone(), e3.clone()], "key1", |s| {
+            s.to_lowercase()
+        });
+        let mut expected_e1 = e1;
+        expected_e1.data = json_map! {"key1": json!("value1")};
+        assert_eq!(vec![expected_e1, e2, e3], res);
+    }
+
+    #[test]
+    fn test_extract_keyvals_regex() {
+        let e1 = Event {
+            id: None,
+            timestamp: DateTime::from_str("2000-01-01T00:00:00Z").unwrap(),
+            duration: Duration::seconds(1),
+            data: json_map! {"title": json!("Fix #12 and #13")},
+        };
+        let mut e2 = e1.clone();
+        e2.data = json_map! {"title": json!("Lunch")};
+
+        let regex = Regex::new("#([0-9]+)").unwrap();
+        let res = extract_keyvals_regex(vec![e1.clone(), e2.clone()], "title", &regex, "ticket");
+        let mut expected_e1 = e1;
+        expected_e1.data = json_map! {"title": json!("Fix #12 and #13"), "ticket": json!("12")};
+        assert_eq!(vec![expected_e1, e2], res);
+    }
+
+    #[test]
+    fn test_regex_replace() {
+        let regex = Regex::new(r" [—-] Mozilla Firefox$").unwrap();
+        assert_eq!(
+            regex_replace("Docs — Mozilla Firefox", &regex, ""),
+            "Docs".to_string()
+        );
+        assert_eq!(regex_replace("Docs", &regex, ""), "Docs".to_string());
+
+        let regex = Regex::new(r"(?P<first>\w+) (\w+)").unwrap();
+        assert_eq!(
+            regex_replace("hello world, good day", &regex, "$2 $first"),
+            "world hello, day good".to_string()
+        );
+    }
+
+    #[test]
+    fn test_regex_extract() {
+        let regex = Regex::new("[A-Z]+-[0-9]+").unwrap();
+        assert_eq!(
+            regex_extract("Fix ABC-123 now", &regex),
+            Some("ABC-123".to_string())
+        );
+        let regex = Regex::new("([A-Z]+)-[0-9]+").unwrap();
+        assert_eq!(
+            regex_extract("Fix ABC-123 now", &regex),
+            Some("ABC".to_string())
+        );
+        assert_eq!(regex_extract("Nothing", &regex), None);
+    }
+}
@@ -0,0 +1,166 @@
+use aw_models::Event;
+use chrono::{DateTime, Duration, FixedOffset, TimeZone, Utc};
+
+/// Splits events into consecutive periods of equal length covering start to end
+///
+/// Periods begin at midnight in the timezone of offset, and periods of whole weeks begin on
+/// Mondays. Events spanning several periods are cut at the boundaries of the periods and the parts
+/// of events outside of all periods are dropped. Periods without events are returned too.
+///
+/// # Example
+/// ```ignore
+/// period: 1d
+/// events: [a         ][b]
+/// output: [a  ]  |  [a     ][b]  |  []
+/// ```
+pub fn split_by_period(
+    events: Vec<Event>,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    period: Duration,
+    offset: FixedOffset,
+) -> Vec<(DateTime<FixedOffset>, Vec<Event>)> {
+    let period_ms = period.num_milliseconds();
+    assert!(period_ms > 0, "period must be positive");
+    // 1970-01-05 is the first Monday after the unix epoch
+    let anchor_day = match period_ms % Duration::weeks(1).num_milliseconds() {
+        0 => 5,
+        _ => 1,
+    };
+    let anchor = offset
+        .with_ymd_and_hms(1970, 1, anchor_day, 0, 0, 0)
+        .unwrap()
+        .with_timezone(&Utc);
+    let index_of = |dt: DateTime<Utc>| (dt - anchor).num_milliseconds().div_euclid(period_ms);
+    let start_of = |index: i64| anchor + Duration::milliseconds(index * period_ms);
+
+    if end <= start {
+        return Vec::new();
+    }
+    let first = index_of(start);
+    // The index of the period after the last one
+    let after_last = index_of(end - Duration::milliseconds(1)) + 1;
+    let mut periods: Vec<(DateTime<FixedOffset>, Vec<Event>)> = (first..after_last)
+        .map(|index| (start_of(index).with_timezone(&offset), Vec::new()))
+        .collect();
+
+    for event in events {
+        let event_start = event.timestamp;
+        let event_end = event.calculate_endtime();
+        if event.duration == Duration::zero() {
+            let index = index_of(event_start);
+            if (first..after_last).contains(&index) {
+                periods[(index - first) as usize].1.push(event);
+            }
+            continue;
+        }
+        let from = index_of(event_start).max(first);
+        let to = (index_of(event_end - Duration::milliseconds(1)) + 1).min(after_last);
+        for index in from..to {
+            let period_start = start_of(index);
+            let period_end = period_start + period;
+            let mut e = event.clone();
+            e.timestamp = std::cmp::max(event_start, period_start);
+            e.duration = std::cmp::min(event_end, period_end) - e.timestamp;
+            if e.duration > Duration::zero() {
+                periods[(index - first) as usize].1.push(e);
+            }
+        }
+    }
+    periods
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use chrono::{DateTime, Duration, FixedOffset, Utc};
+    use serde_json::json;
+
+    use aw_models::Event;
+
+    use super::split_by_period;
+
+    fn dt(s: &str) -> DateTime<Utc> {
+        DateTime::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn test_split_by_period_days() {
+        let e1 = Event {
+            id: None,
+            timestamp: dt("2000-01-01T20:00:00Z"),
+            duration: Duration::hours(6),
+            data: json_map! {"test": json!(1)},
+        };
+        let mut e2 = e1.clone();
+        e2.timestamp = dt("2000-01-02T12:00:00Z");
+        e2.duration = Duration::zero();
+
+        let res = split_by_period(
+            vec![e1.clone(), e2.clone()],
+            dt("2000-01-01T00:00:00Z"),
+            dt("2000-01-04T00:00:00Z"),
+            Duration::days(1),
+            FixedOffset::east_opt(0).unwrap(),
+        );
+        let starts: Vec<String> = res.iter().map(|(start, _)| start.to_rfc3339()).collect();
+        assert_eq!(
+            starts,
+            vec![
+                "2000-01-01T00:00:00+00:00",
+                "2000-01-02T00:00:00+00:00",
+                "2000-01-03T00:00:00+00:00"
+            ]
+        );
+
+        let mut e1_first = e1.clone();
+        e1_first.duration = Duration::hours(4);
+        let mut e1_second = e1;
+        e1_second.timestamp = dt("2000-01-02T00:00:00Z");
+        e1_second.duration = Duration::hours(2);
+        assert_eq!(res[0].1, vec![e1_first]);
+        assert_eq!(res[1].1, vec![e1_second, e2]);
+        assert_eq!(res[2].1, vec![]);
+    }
+
+    #[test]
+    fn test_split_by_period_offset() {
+        let e1 = Event {
+            id: None,
+            timestamp: dt("2000-01-01T21:00:00Z"),
+            duration: Duration::hours(2),
+            data: json_map! {"test": json!(1)},
+        };
+        // Midnight at UTC+2 is 22:00 UTC
+        let res = split_by_period(
+            vec![e1],
+            dt("2000-01-01T12:00:00Z"),
+            dt("2000-01-02T12:00:00Z"),
+            Duration::days(1),
+            FixedOffset::east_opt(2 * 3600).unwrap(),
+        );
+        assert_eq!(res.len(), 2);
+        assert_eq!(res[0].0.to_rfc3339(), "2000-01-01T00:00:00+02:00");
+        assert_eq!(res[0].1[0].duration, Duration::hours(1));
+        assert_eq!(res[1].0.to_rfc3339(), "2000-01-02T00:00:00+02:00");
+        assert_eq!(res[1].1[0].timestamp, dt("2000-01-01T22:00:00Z"));
+    }
+
+    #[test]
+    fn test_split_by_period_weeks() {
+        // 2000-01-05 is a Wednesday, so its week begins on Monday 2000-01-03
+        let res = split_by_period(
+            vec![],
+            dt("2000-01-05T00:00:00Z"),
+            dt("2000-01-12T00:00:00Z"),
+            Duration::weeks(1),
+            FixedOffset::east_opt(0).unwrap(),
+        );
+        let starts: Vec<String> = res.iter().map(|(start, _)| start.to_rfc3339()).collect();
+        assert_eq!(
+            starts,
+            vec!["2000-01-03T00:00:00+00:00", "2000-01-10T00:00:00+00:00"]
+        );
+    }
+}